@@ -0,0 +1,866 @@
+//! A tree of blocks and the traversals used to maintain the unstable blocks of a chain.
+//!
+//! The core of the crate compiles without `std`, using `alloc`, and is generic over the
+//! type of the blocks it stores (see `Block`). Support for `ic_btc_types::Block` is gated
+//! behind the `bitcoin` feature.
+#![no_std]
+
+extern crate alloc;
+
+#[cfg(feature = "bitcoin")]
+mod types;
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::{cmp, fmt, iter};
+
+/// A block that can be stored in a `BlockTree`.
+pub trait Block {
+    /// The hash identifying a block.
+    type Hash: Clone + Ord;
+
+    /// Returns the hash of the block.
+    fn block_hash(&self) -> Self::Hash;
+
+    /// Returns the hash of the block's parent.
+    fn prev_block_hash(&self) -> Self::Hash;
+}
+
+/// Represents a non-empty block chain as:
+/// * the first block of the chain
+/// * the successors to this block (which can be an empty list)
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockChain<'a, B> {
+    // The first block of this `BlockChain`, i.e. the one at the lowest height.
+    first: &'a B,
+    // The successor blocks of this `BlockChain`, i.e. the chain after the
+    // `first` block.
+    successors: Vec<&'a B>,
+}
+
+impl<'a, B: Block> BlockChain<'a, B> {
+    /// Creates a new `BlockChain` with the given `first` block and an empty list
+    /// of successors.
+    pub fn new(first: &'a B) -> Self {
+        Self {
+            first,
+            successors: vec![],
+        }
+    }
+
+    /// Creates a new `BlockChain` with the given `first` block and `successors`.
+    ///
+    /// This is mostly useful for tests to simplify the creation of a `BlockChain`.
+    pub fn new_with_successors(first: &'a B, successors: Vec<&'a B>) -> Self {
+        Self { first, successors }
+    }
+
+    /// Appends a new block to the list of `successors` of this `BlockChain`.
+    pub fn push(&mut self, block: &'a B) {
+        self.successors.push(block);
+    }
+
+    /// Returns the length of this `BlockChain`.
+    pub fn len(&self) -> usize {
+        self.successors.len() + 1
+    }
+
+    /// Always returns false, as a `BlockChain` contains at least one block.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn first(&self) -> &'a B {
+        self.first
+    }
+
+    pub fn tip(&self) -> &'a B {
+        match self.successors.last() {
+            None => {
+                // The chain consists of only one block, and that is the tip.
+                self.first
+            }
+            Some(tip) => tip,
+        }
+    }
+
+    /// Returns true if a block with the given hash is part of this `BlockChain`.
+    pub fn contains(&self, hash: &B::Hash) -> bool {
+        iter::once(&self.first)
+            .chain(self.successors.iter())
+            .any(|block| &block.block_hash() == hash)
+    }
+
+    /// Returns the height of each block in this `BlockChain`, in order, given the height of
+    /// its first block.
+    ///
+    /// Panics if the height of the tip doesn't fit in a `u32`.
+    pub fn as_heights(&self, anchor_height: u32) -> Vec<u32> {
+        (0..self.len() as u32)
+            .map(|i| {
+                anchor_height
+                    .checked_add(i)
+                    .expect("the height of the chain's tip must fit in a u32")
+            })
+            .collect()
+    }
+
+    /// Consumes this `BlockChain` and returns the entire chain of blocks.
+    pub fn into_chain(self) -> Vec<&'a B> {
+        let mut chain = vec![self.first];
+        chain.extend(self.successors);
+        chain
+    }
+}
+
+/// Error returned when attempting to create a `BlockChain` out of an empty
+/// list of blocks.
+#[derive(Debug)]
+pub struct EmptyChainError {}
+
+impl fmt::Display for EmptyChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cannot create a `BlockChain` from an empty chain")
+    }
+}
+
+/// An index of the blocks in a `BlockTree`, mapping the hash of every block to the hash of
+/// its parent and the block's position among its parent's children. The root is mapped to `None`.
+///
+/// Parent links are stored rather than full paths from the root to keep the index linear in
+/// the number of blocks, as the tree can be very deep.
+pub type BlockTreeIndex<H> = BTreeMap<H, Option<(H, usize)>>;
+
+/// Maintains a tree of connected blocks.
+///
+/// Every node carries opaque metadata of type `M` (e.g. the time a block was received),
+/// which defaults to `()` for trees that don't need any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTree<B, M = ()> {
+    pub root: B,
+    pub children: Vec<BlockTree<B, M>>,
+    pub meta: M,
+}
+
+impl<B: Block> BlockTree<B> {
+    /// Creates a new `BlockTree` with the given block as its root.
+    pub fn new(root: B) -> Self {
+        Self::with_meta(root, ())
+    }
+
+    /// Extends the tree with the given block.
+    ///
+    /// Blocks can extend the tree in the following cases:
+    ///   * The block is already present in the tree (no-op).
+    ///   * The block is a successor of a block already in the tree.
+    pub fn extend(&mut self, block: B) -> Result<(), BlockDoesNotExtendTree<B::Hash>> {
+        self.extend_with_meta(block, ())
+    }
+}
+
+impl<B: Block, M> BlockTree<B, M> {
+    /// Creates a new `BlockTree` with the given block and metadata as its root.
+    pub fn with_meta(root: B, meta: M) -> Self {
+        Self {
+            root,
+            children: vec![],
+            meta,
+        }
+    }
+
+    /// Returns all blocks in the tree with their depths
+    /// separated by heights.
+    pub fn blocks_with_depths_by_heights(&self) -> Vec<Vec<(&B, u32)>> {
+        let mut blocks_with_depths_by_heights: Vec<Vec<(&B, u32)>> = vec![vec![]];
+        self.blocks_with_depths_by_heights_helper(&mut blocks_with_depths_by_heights, 0);
+        blocks_with_depths_by_heights
+    }
+
+    fn blocks_with_depths_by_heights_helper<'a>(
+        &'a self,
+        blocks_with_depth_by_height: &mut Vec<Vec<(&'a B, u32)>>,
+        height: usize,
+    ) -> u32 {
+        let mut depth: u32 = 0;
+        for child in self.children.iter() {
+            depth = cmp::max(
+                depth,
+                child.blocks_with_depths_by_heights_helper(blocks_with_depth_by_height, height + 1),
+            );
+        }
+        depth += 1;
+
+        if height >= blocks_with_depth_by_height.len() {
+            blocks_with_depth_by_height.resize(height + 1, vec![]);
+        }
+
+        blocks_with_depth_by_height[height].push((&self.root, depth));
+
+        depth
+    }
+
+    /// Returns the number of tips in the tree.
+    pub fn num_tips(&self) -> u32 {
+        if self.children.is_empty() {
+            1
+        } else {
+            self.children.iter().map(|c| c.num_tips()).sum()
+        }
+    }
+
+    /// Same as `extend`, but tags the added block with the given metadata.
+    ///
+    /// If the block is already present in the tree, its metadata is left unchanged.
+    pub fn extend_with_meta(
+        &mut self,
+        block: B,
+        meta: M,
+    ) -> Result<(), BlockDoesNotExtendTree<B::Hash>> {
+        if self.contains(&block) {
+            // The block is already present in the tree. Nothing to do.
+            return Ok(());
+        }
+
+        // Check if the block is a successor to any of the blocks in the tree.
+        match self.find_mut(&block.prev_block_hash()) {
+            Some((block_subtree, _)) => {
+                assert!(block_subtree.root.block_hash() == block.prev_block_hash());
+                // Add the block as a successor.
+                block_subtree
+                    .children
+                    .push(BlockTree::with_meta(block, meta));
+                Ok(())
+            }
+            None => Err(BlockDoesNotExtendTree(block.block_hash())),
+        }
+    }
+
+    /// Returns all the blockchains in the tree.
+    ///
+    /// The result is never empty, as a tree without children contains a single blockchain
+    /// made of its root. Callers (e.g. the fork choice of the canister's unstable blocks)
+    /// rely on this.
+    pub fn blockchains(&self) -> Vec<BlockChain<'_, B>> {
+        if self.children.is_empty() {
+            return vec![BlockChain {
+                first: &self.root,
+                successors: vec![],
+            }];
+        }
+
+        let mut tips = vec![];
+        for child in self.children.iter() {
+            tips.extend(
+                child
+                    .blockchains()
+                    .into_iter()
+                    .map(|bc| BlockChain {
+                        first: &self.root,
+                        successors: bc.into_chain(),
+                    })
+                    .collect::<Vec<BlockChain<B>>>(),
+            );
+        }
+
+        tips
+    }
+
+    /// Returns a `BlockChain` starting from the anchor and ending with the `tip`.
+    ///
+    /// If the `tip` doesn't exist in the tree, `None` is returned.
+    pub fn get_chain_with_tip<'a>(&'a self, tip: &B::Hash) -> Option<BlockChain<'a, B>> {
+        // Compute the chain in reverse order, as that's more efficient, and then
+        // reverse it to get the answer in the correct order.
+        self.get_chain_with_tip_reverse(tip).map(|mut chain| {
+            // Safe to unwrap as the `chain` would contain at least the root of the
+            // `BlockTree` it was produced from.
+            // This would be the first block since the chain is in reverse order.
+            let first = chain.pop().unwrap();
+            // Reverse the chain to get the list of `successors` in the right order.
+            chain.reverse();
+            BlockChain {
+                first,
+                successors: chain,
+            }
+        })
+    }
+
+    // Do a depth-first search to find the blockchain that ends with the given `tip`.
+    // For performance reasons, the list is returned in the reverse order, starting
+    // from `tip` and ending with `anchor`.
+    fn get_chain_with_tip_reverse<'a>(&'a self, tip: &B::Hash) -> Option<Vec<&'a B>> {
+        if self.root.block_hash() == *tip {
+            return Some(vec![&self.root]);
+        }
+
+        for child in self.children.iter() {
+            if let Some(mut chain) = child.get_chain_with_tip_reverse(tip) {
+                chain.push(&self.root);
+                return Some(chain);
+            }
+        }
+
+        None
+    }
+
+    /// Builds an index of all the blocks in the tree.
+    ///
+    /// The index can be passed to `get_chain_with_tip_indexed` for faster lookups, and must be
+    /// rebuilt whenever the tree is modified.
+    pub fn index(&self) -> BlockTreeIndex<B::Hash> {
+        let mut index = BlockTreeIndex::new();
+        index.insert(self.root.block_hash(), None);
+
+        // A stack is used rather than recursion, as the tree can be very deep.
+        let mut stack = vec![self];
+        while let Some(tree) = stack.pop() {
+            let block_hash = tree.root.block_hash();
+            for (child_idx, child) in tree.children.iter().enumerate() {
+                index.insert(
+                    child.root.block_hash(),
+                    Some((block_hash.clone(), child_idx)),
+                );
+                stack.push(child);
+            }
+        }
+
+        index
+    }
+
+    /// Same as `get_chain_with_tip`, but uses the given index to locate the `tip` rather than
+    /// searching the entire tree, making the lookup proportional to the length of the chain.
+    ///
+    /// The index must have been built from the current state of the tree. The path it leads to
+    /// is checked against the tree, so a stale index returns `None` rather than a wrong chain.
+    pub fn get_chain_with_tip_indexed<'a>(
+        &'a self,
+        index: &BlockTreeIndex<B::Hash>,
+        tip: &B::Hash,
+    ) -> Option<BlockChain<'a, B>> {
+        // Follow the parent links up to the root to compute the path to the `tip`.
+        let mut path = vec![];
+        let mut block_hash = tip;
+        while let Some((parent_hash, child_idx)) = index.get(block_hash)? {
+            path.push((*child_idx, block_hash));
+            block_hash = parent_hash;
+        }
+        if *block_hash != self.root.block_hash() {
+            return None;
+        }
+
+        // Follow the path from the root back down to the `tip`.
+        let mut tree = self;
+        let mut successors = Vec::with_capacity(path.len());
+        for (child_idx, block_hash) in path.into_iter().rev() {
+            tree = tree.children.get(child_idx)?;
+            if tree.root.block_hash() != *block_hash {
+                return None;
+            }
+            successors.push(&tree.root);
+        }
+
+        Some(BlockChain {
+            first: &self.root,
+            successors,
+        })
+    }
+
+    /// Returns the number of blocks in the longest run of blocks where every block, except
+    /// the last, has exactly one child.
+    ///
+    /// This is a diagnostic for deciding whether compacting the tree is worthwhile. Such runs
+    /// could be stored in a single node as a vector of blocks, making traversals proportional
+    /// to the number of forks rather than the number of blocks. The compaction would need to
+    /// split a node whenever a block extends the middle of its run, and to preserve the
+    /// flattened serialization format of the tree for backward-compatibility.
+    pub fn longest_linear_run(&self) -> u32 {
+        let mut longest_run = 0;
+
+        // A stack of the subtrees to visit along with the length of the run ending at their root.
+        // A stack is used rather than recursion, as the tree can be very deep.
+        let mut stack = vec![(self, 1)];
+        while let Some((tree, run)) = stack.pop() {
+            longest_run = longest_run.max(run);
+
+            // A run continues only through a block with a single child.
+            let child_run = if tree.children.len() == 1 { run + 1 } else { 1 };
+            for child in tree.children.iter() {
+                stack.push((child, child_run));
+            }
+        }
+
+        longest_run
+    }
+
+    pub fn depth(&self) -> u128 {
+        let mut res: u128 = 0;
+        for child in self.children.iter() {
+            res = cmp::max(res, child.depth());
+        }
+        res += 1;
+        res
+    }
+
+    // Returns a `BlockTree` where the hash of the root block matches the provided `block_hash`
+    // along with its depth if it exists, and `None` otherwise.
+    pub fn find_mut<'a>(
+        &'a mut self,
+        blockhash: &B::Hash,
+    ) -> Option<(&'a mut BlockTree<B, M>, u32)> {
+        fn find_mut_helper<'a, B: Block, M>(
+            block_tree: &'a mut BlockTree<B, M>,
+            blockhash: &B::Hash,
+            depth: u32,
+        ) -> Option<(&'a mut BlockTree<B, M>, u32)> {
+            if block_tree.root.block_hash() == *blockhash {
+                return Some((block_tree, depth));
+            }
+
+            for child in block_tree.children.iter_mut() {
+                if let res @ Some(_) = find_mut_helper(child, blockhash, depth + 1) {
+                    return res;
+                }
+            }
+
+            None
+        }
+
+        find_mut_helper(self, blockhash, 0)
+    }
+
+    /// Returns all blocks in the tree along with their metadata, in depth-first order
+    /// starting from the root.
+    pub fn blocks_with_meta(&self) -> Vec<(&B, &M)> {
+        let mut blocks = vec![];
+
+        // A stack is used rather than recursion, as the tree can be very deep.
+        let mut stack = vec![self];
+        while let Some(tree) = stack.pop() {
+            blocks.push((&tree.root, &tree.meta));
+            // Children are pushed in reverse to visit them in their original order.
+            stack.extend(tree.children.iter().rev());
+        }
+
+        blocks
+    }
+
+    // Returns true if a block exists in the tree, false otherwise.
+    fn contains(&self, block: &B) -> bool {
+        if self.root.block_hash() == block.block_hash() {
+            return true;
+        }
+
+        for child in self.children.iter() {
+            if child.contains(block) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+impl<B: Block + PartialEq, M: PartialEq> BlockTree<B, M> {
+    /// Returns true if both trees contain the same blocks with the same metadata and parents.
+    ///
+    /// Unlike `==`, the order of the children of a block is ignored, so trees built by inserting
+    /// the same forks in a different order are structurally equal.
+    pub fn structurally_eq(&self, other: &BlockTree<B, M>) -> bool {
+        // A stack is used rather than recursion, as the tree can be very deep.
+        let mut stack = vec![(self, other)];
+        while let Some((tree, other_tree)) = stack.pop() {
+            if tree.root != other_tree.root
+                || tree.meta != other_tree.meta
+                || tree.children.len() != other_tree.children.len()
+            {
+                return false;
+            }
+
+            // Match the children by their block hash. Blocks are unique within a tree, so
+            // every child matches at most one child of the other tree.
+            let other_children: BTreeMap<B::Hash, &BlockTree<B, M>> = other_tree
+                .children
+                .iter()
+                .map(|child| (child.root.block_hash(), child))
+                .collect();
+            for child in tree.children.iter() {
+                match other_children.get(&child.root.block_hash()) {
+                    Some(other_child) => stack.push((child, other_child)),
+                    None => return false,
+                }
+            }
+        }
+
+        true
+    }
+}
+
+/// Returns the hashes of the blocks in each of the blockchains in the tree, in the same
+/// order as `BlockTree::blockchains`.
+///
+/// Unlike `BlockTree::blockchains`, the result doesn't borrow the tree.
+pub fn blockchains_hashes<B: Block, M>(block_tree: &BlockTree<B, M>) -> Vec<Vec<B::Hash>> {
+    block_tree
+        .blockchains()
+        .into_iter()
+        .map(|chain| {
+            chain
+                .into_chain()
+                .into_iter()
+                .map(|block| block.block_hash())
+                .collect()
+        })
+        .collect()
+}
+
+/// An error thrown when trying to add a block that isn't a successor
+/// of any block in the tree.
+#[derive(Debug)]
+pub struct BlockDoesNotExtendTree<H>(pub H);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A block identified by a number, which is enough to exercise the tree without `bitcoin`.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    struct TestBlock {
+        hash: u32,
+        prev_hash: u32,
+    }
+
+    impl TestBlock {
+        fn new(hash: u32, prev_hash: u32) -> Self {
+            Self { hash, prev_hash }
+        }
+    }
+
+    impl Block for TestBlock {
+        type Hash = u32;
+
+        fn block_hash(&self) -> u32 {
+            self.hash
+        }
+
+        fn prev_block_hash(&self) -> u32 {
+            self.prev_hash
+        }
+    }
+
+    // Builds the tree:
+    //
+    //   0 -> 1 -> 2 -> 3
+    //        \
+    //         -> 4
+    fn build_tree() -> BlockTree<TestBlock> {
+        let mut tree = BlockTree::new(TestBlock::new(0, 0));
+        for (hash, prev_hash) in [(1, 0), (2, 1), (3, 2), (4, 1)] {
+            tree.extend(TestBlock::new(hash, prev_hash)).unwrap();
+        }
+        tree
+    }
+
+    // Builds a tree where each block at depth `i` has `num_children[i]` children. The blocks
+    // are numbered in the order they're created, starting with the root at 0.
+    fn build_tree_with_shape(num_children: &[u32]) -> BlockTree<TestBlock> {
+        fn build(tree: &mut BlockTree<TestBlock>, num_children: &[u32], next_hash: &mut u32) {
+            if num_children.is_empty() {
+                return;
+            }
+
+            for _ in 0..num_children[0] {
+                let mut subtree = BlockTree::new(TestBlock::new(*next_hash, tree.root.hash));
+                *next_hash += 1;
+                build(&mut subtree, &num_children[1..], next_hash);
+                tree.children.push(subtree);
+            }
+        }
+
+        let mut tree = BlockTree::new(TestBlock::new(0, 0));
+        build(&mut tree, num_children, &mut 1);
+        tree
+    }
+
+    // Trees of various shapes, from a single block to a chain and bushy trees.
+    fn trees_of_various_shapes() -> Vec<BlockTree<TestBlock>> {
+        let shapes: [&[u32]; 6] = [
+            &[],
+            &[1, 1, 1],
+            &[3],
+            &[2, 1, 2],
+            &[1, 3, 1, 2],
+            &[2, 2, 2, 2],
+        ];
+        shapes
+            .iter()
+            .map(|shape| build_tree_with_shape(shape))
+            .collect()
+    }
+
+    // Builds a chain of blocks numbered from `first`, the first of which extends `prev_hash`.
+    fn build_chain(first: u32, prev_hash: u32, len: u32) -> Vec<TestBlock> {
+        (first..first + len)
+            .map(|hash| TestBlock::new(hash, if hash == first { prev_hash } else { hash - 1 }))
+            .collect()
+    }
+
+    fn hashes(chain: BlockChain<TestBlock>) -> Vec<u32> {
+        chain
+            .into_chain()
+            .into_iter()
+            .map(|block| block.block_hash())
+            .collect()
+    }
+
+    #[test]
+    fn extend_and_traverse() {
+        let mut tree = build_tree();
+
+        assert_eq!(tree.num_tips(), 2);
+        assert_eq!(tree.depth(), 4);
+        assert_eq!(tree.longest_linear_run(), 2);
+        assert_eq!(
+            blockchains_hashes(&tree),
+            vec![vec![0, 1, 2, 3], vec![0, 1, 4]]
+        );
+
+        // Extending with a block that's already in the tree is a no-op.
+        tree.extend(TestBlock::new(4, 1)).unwrap();
+        assert_eq!(tree, build_tree());
+
+        // A block whose parent isn't in the tree doesn't extend it.
+        assert_eq!(
+            tree.extend(TestBlock::new(6, 5)).map_err(|err| err.0),
+            Err(6)
+        );
+    }
+
+    #[test]
+    fn as_heights_of_the_highest_chain() {
+        let tree = build_tree();
+        let chain = tree.get_chain_with_tip(&3).unwrap();
+        assert_eq!(
+            chain.as_heights(u32::MAX - 3),
+            vec![u32::MAX - 3, u32::MAX - 2, u32::MAX - 1, u32::MAX]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "the height of the chain's tip must fit in a u32")]
+    fn as_heights_panics_on_overflow() {
+        let tree = build_tree();
+        tree.get_chain_with_tip(&3)
+            .unwrap()
+            .as_heights(u32::MAX - 2);
+    }
+
+    #[test]
+    fn indexed_and_non_indexed_chains_are_identical() {
+        let tree = build_tree();
+        let index = tree.index();
+        assert_eq!(
+            hashes(tree.get_chain_with_tip_indexed(&index, &4).unwrap()),
+            vec![0, 1, 4]
+        );
+
+        for tree in trees_of_various_shapes() {
+            let index = tree.index();
+            for block in tree
+                .blockchains()
+                .into_iter()
+                .flat_map(|chain| chain.into_chain())
+            {
+                let block_hash = block.block_hash();
+                assert_eq!(
+                    tree.get_chain_with_tip_indexed(&index, &block_hash),
+                    tree.get_chain_with_tip(&block_hash)
+                );
+            }
+
+            // Unknown tips aren't found in either case.
+            assert_eq!(tree.get_chain_with_tip_indexed(&index, &1_000), None);
+            assert_eq!(tree.get_chain_with_tip(&1_000), None);
+        }
+    }
+
+    #[test]
+    fn stale_index_returns_none() {
+        let index = build_tree().index();
+
+        // The same blocks, with the children of block 1 in a different order.
+        let mut reordered_tree = BlockTree::new(TestBlock::new(0, 0));
+        for (hash, prev_hash) in [(1, 0), (4, 1), (2, 1), (3, 2)] {
+            reordered_tree
+                .extend(TestBlock::new(hash, prev_hash))
+                .unwrap();
+        }
+        assert_eq!(reordered_tree.get_chain_with_tip_indexed(&index, &3), None);
+        assert_eq!(reordered_tree.get_chain_with_tip_indexed(&index, &4), None);
+
+        // A tree missing some of the indexed blocks.
+        let mut pruned_tree = BlockTree::new(TestBlock::new(0, 0));
+        pruned_tree.extend(TestBlock::new(1, 0)).unwrap();
+        assert_eq!(pruned_tree.get_chain_with_tip_indexed(&index, &3), None);
+        assert_eq!(pruned_tree.get_chain_with_tip_indexed(&index, &4), None);
+        assert_eq!(
+            hashes(pruned_tree.get_chain_with_tip_indexed(&index, &1).unwrap()),
+            vec![0, 1]
+        );
+
+        // A tree with a different root.
+        let other_tree = BlockTree::new(TestBlock::new(5, 0));
+        assert_eq!(other_tree.get_chain_with_tip_indexed(&index, &0), None);
+    }
+
+    #[test]
+    fn blockchains_are_never_empty() {
+        fn count_leaves(tree: &BlockTree<TestBlock>) -> usize {
+            if tree.children.is_empty() {
+                return 1;
+            }
+            tree.children.iter().map(count_leaves).sum()
+        }
+
+        for tree in trees_of_various_shapes() {
+            let blockchains = tree.blockchains();
+            assert!(!blockchains.is_empty());
+
+            // Every chain starts at the root and there's one chain per leaf.
+            for chain in blockchains.iter() {
+                assert_eq!(chain.first().block_hash(), tree.root.block_hash());
+            }
+            assert_eq!(blockchains.len(), count_leaves(&tree));
+        }
+    }
+
+    #[test]
+    fn blockchains_hashes_match_blockchains() {
+        for tree in trees_of_various_shapes() {
+            let chains_hashes = blockchains_hashes(&tree);
+            let blockchains = tree.blockchains();
+
+            assert_eq!(chains_hashes.len(), blockchains.len());
+            for (chain_hashes, chain) in chains_hashes.into_iter().zip(blockchains) {
+                assert_eq!(chain_hashes, hashes(chain));
+            }
+        }
+
+        // The chains are ordered depth-first, following the order in which the children
+        // were added.
+        assert_eq!(
+            blockchains_hashes(&build_tree_with_shape(&[2, 2])),
+            vec![vec![0, 1, 2], vec![0, 1, 3], vec![0, 4, 5], vec![0, 4, 6]]
+        );
+    }
+
+    #[test]
+    fn longest_linear_run() {
+        let chain = build_chain(0, 0, 1_000);
+        let mut tree = BlockTree::new(chain[0].clone());
+        assert_eq!(tree.longest_linear_run(), 1);
+
+        for block in chain.iter().skip(1) {
+            tree.extend(block.clone()).unwrap();
+        }
+        assert_eq!(tree.longest_linear_run(), 1_000);
+
+        // Fork the chain at block 299. This splits the chain into a run of blocks 0-299
+        // and a run of blocks 300-999.
+        for block in build_chain(1_000, 299, 10) {
+            tree.extend(block).unwrap();
+        }
+        assert_eq!(tree.longest_linear_run(), 700);
+    }
+
+    #[test]
+    fn metadata_survives_traversal() {
+        // Tag every block with the time it was received.
+        let chain = build_chain(0, 0, 3);
+        let fork = build_chain(3, 0, 1);
+        let mut tree: BlockTree<TestBlock, u64> = BlockTree::with_meta(chain[0].clone(), 100);
+        tree.extend_with_meta(chain[1].clone(), 101).unwrap();
+        tree.extend_with_meta(chain[2].clone(), 102).unwrap();
+        tree.extend_with_meta(fork[0].clone(), 200).unwrap();
+
+        // Extending with a block that's already in the tree keeps its original metadata.
+        tree.extend_with_meta(chain[1].clone(), 999).unwrap();
+
+        let receive_times: Vec<(u32, u64)> = tree
+            .blocks_with_meta()
+            .into_iter()
+            .map(|(block, meta)| (block.block_hash(), *meta))
+            .collect();
+        assert_eq!(receive_times, vec![(0, 100), (1, 101), (2, 102), (3, 200)]);
+
+        let (subtree, depth) = tree.find_mut(&2).unwrap();
+        assert_eq!((subtree.meta, depth), (102, 2));
+
+        // Traversals that don't involve metadata behave as with a tree without metadata.
+        assert_eq!(tree.num_tips(), 2);
+        assert_eq!(hashes(tree.get_chain_with_tip(&2).unwrap()), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn structural_equality_ignores_the_order_of_children() {
+        let chain = build_chain(0, 0, 3);
+        let fork_1 = build_chain(3, 0, 2);
+        let fork_2 = build_chain(5, 1, 1);
+
+        let build_tree = |forks: &[&[TestBlock]]| {
+            let mut tree = BlockTree::new(chain[0].clone());
+            for fork in forks {
+                for block in fork.iter() {
+                    tree.extend(block.clone()).unwrap();
+                }
+            }
+            tree
+        };
+
+        // Insert the forks of both block 0 and block 1 in different orders.
+        let tree = build_tree(&[&chain[1..], &fork_1[..], &fork_2[..]]);
+        let other_tree = build_tree(&[&fork_1[..], &chain[1..2], &fork_2[..], &chain[2..]]);
+        assert_ne!(tree, other_tree);
+        assert!(tree.structurally_eq(&other_tree));
+        assert!(other_tree.structurally_eq(&tree));
+
+        // A tree missing a fork isn't structurally equal.
+        let partial_tree = build_tree(&[&chain[1..], &fork_1[..]]);
+        assert!(!tree.structurally_eq(&partial_tree));
+        assert!(!partial_tree.structurally_eq(&tree));
+
+        // The same blocks with a different parent aren't structurally equal.
+        let linear_tree = build_tree(&[&chain[1..]]);
+        let mut flat_tree = BlockTree::new(chain[0].clone());
+        for block in chain[1..].iter() {
+            flat_tree.children.push(BlockTree::new(block.clone()));
+        }
+        assert!(!linear_tree.structurally_eq(&flat_tree));
+    }
+
+    #[test]
+    fn chain_contains() {
+        let blocks = build_chain(0, 0, 3);
+
+        let chain = BlockChain::new_with_successors(&blocks[0], vec![&blocks[1], &blocks[2]]);
+        for block in blocks.iter() {
+            assert!(chain.contains(&block.block_hash()));
+        }
+        assert!(!chain.contains(&3));
+
+        // A chain with only one block.
+        let chain = BlockChain::new(&blocks[0]);
+        assert!(chain.contains(&0));
+        assert!(!chain.contains(&1));
+    }
+
+    #[test]
+    fn chain_as_heights() {
+        let blocks = build_chain(0, 0, 3);
+
+        let chain = BlockChain::new_with_successors(&blocks[0], vec![&blocks[1], &blocks[2]]);
+        assert_eq!(chain.as_heights(0), vec![0, 1, 2]);
+        assert_eq!(chain.as_heights(100), vec![100, 101, 102]);
+
+        let chain = BlockChain::new(&blocks[0]);
+        assert_eq!(chain.as_heights(7), vec![7]);
+    }
+}
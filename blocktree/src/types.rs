@@ -0,0 +1,27 @@
+use crate::{Block, BlockTree};
+use ic_btc_interface::Network;
+use ic_btc_types::{Block as BtcBlock, BlockHash};
+
+impl Block for BtcBlock {
+    type Hash = BlockHash;
+
+    fn block_hash(&self) -> BlockHash {
+        BtcBlock::block_hash(self)
+    }
+
+    fn prev_block_hash(&self) -> BlockHash {
+        BlockHash::from(self.header().prev_blockhash)
+    }
+}
+
+impl<M> BlockTree<BtcBlock, M> {
+    // Returns the maximum sum of block difficulties from the root to a leaf inclusive.
+    pub fn difficulty_based_depth(&self, network: Network) -> u128 {
+        let mut res: u128 = 0;
+        for child in self.children.iter() {
+            res = core::cmp::max(res, child.difficulty_based_depth(network));
+        }
+        res += self.root.difficulty(network) as u128;
+        res
+    }
+}
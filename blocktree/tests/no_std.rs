@@ -0,0 +1,37 @@
+//! Confirms that the core of the crate compiles without `std` by building it for a target
+//! that has no standard library. To run it:
+//!
+//! ```text
+//! rustup target add thumbv7m-none-eabi
+//! cargo test -p ic-btc-blocktree --features no_std_build_test
+//! ```
+#![cfg(feature = "no_std_build_test")]
+
+use std::process::Command;
+
+// A target without a standard library.
+const NO_STD_TARGET: &str = "thumbv7m-none-eabi";
+
+#[test]
+fn builds_without_std() {
+    let status = Command::new(env!("CARGO"))
+        .args([
+            "build",
+            "--manifest-path",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"),
+            "--no-default-features",
+            "--target",
+            NO_STD_TARGET,
+            // A separate target directory avoids waiting on the lock of the one running the test.
+            "--target-dir",
+            concat!(env!("CARGO_TARGET_TMPDIR"), "/no_std"),
+        ])
+        .status()
+        .expect("failed to run cargo");
+
+    assert!(
+        status.success(),
+        "ic-btc-blocktree must compile for {}",
+        NO_STD_TARGET
+    );
+}
@@ -6,18 +6,37 @@
 //!   --network testnet \
 //!   --output balances.bin \
 //!   --utxos-dump-path utxos-dump.csv
-use bitcoin::{Address as BitcoinAddress, Script};
+//!
+//! To only aggregate the balances of some addresses, pass a file containing one address of the
+//! given network per line with `--address-filter addresses.txt`.
+//!
+//! A manifest describing the balances is written next to the output, at `<output>.manifest`.
+//! To record the height the balances are computed at, pass `--at-height <height>`. A state
+//! seeded with the balances is then rejected unless it starts at that height.
+//!
+//! To skip UTXOs whose outpoint already appeared earlier in the dump, pass
+//! `--detect-duplicates`. This keeps every outpoint in memory, so it's disabled by default.
+//!
+//! To write the balances to stdout instead, pass `--output -`. In that case no manifest is
+//! written, and progress messages are always printed to stderr.
+//!
+//! When writing to a file, the stable btreemap is backed by the output file itself, so the
+//! balances are written incrementally rather than first building the entire stable memory in
+//! RAM. Writing to stdout still requires building the stable memory in RAM.
+use bitcoin::{
+    util::address::Payload, Address as BitcoinAddress, Network as BitcoinNetwork, Script,
+};
 use clap::Parser;
 use ic_btc_canister::types::{into_bitcoin_network, Address};
-use ic_btc_interface::Network;
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use ic_btc_interface::{Height, Network};
+use ic_stable_structures::{DefaultMemoryImpl, FileMemory, Memory, StableBTreeMap};
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
 use std::{
-    collections::BTreeMap,
-    fs::File,
-    io::{BufRead, BufReader, Write},
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
     str::FromStr,
 };
 
@@ -27,34 +46,166 @@ struct Args {
     #[clap(long, value_hint = clap::ValueHint::DirPath)]
     utxos_dump_path: PathBuf,
 
-    /// The path to store the output in.
+    /// The path to store the output in, or `-` to write it to stdout.
     #[clap(long, value_hint = clap::ValueHint::DirPath)]
     output: PathBuf,
 
     /// The bitcoin network.
     #[clap(long)]
     network: Network,
+
+    /// The path of a newline-delimited list of addresses. If set, only the balances
+    /// of these addresses are computed.
+    #[clap(long, value_hint = clap::ValueHint::FilePath)]
+    address_filter: Option<PathBuf>,
+
+    /// The height of the UTXOs dump. If set, it's recorded in the manifest as the height the
+    /// balances are computed at, i.e. the starting height of a state seeded with them.
+    #[clap(long)]
+    at_height: Option<Height>,
+
+    /// If set, UTXOs with an outpoint that was already seen in the dump are skipped with a
+    /// warning. All the outpoints are kept in memory, so this is off by default.
+    #[clap(long)]
+    detect_duplicates: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+// Returns true if the given output refers to stdout.
+fn is_stdout(output: &Path) -> bool {
+    output == Path::new("-")
+}
 
-    // Read the UTXOs from the UTXOs dump.
-    let utxos_file = File::open(args.utxos_dump_path).unwrap();
-    let reader = BufReader::new(utxos_file);
+// Writes the given bytes to the given output, using `stdout` if the output is `-`.
+fn write_output(output: &Path, bytes: &[u8], mut stdout: impl Write) -> io::Result<()> {
+    if is_stdout(output) {
+        stdout.write_all(bytes)?;
+        stdout.flush()
+    } else {
+        File::create(output)?.write_all(bytes)
+    }
+}
+
+// Opens the given output file as a memory, truncating any existing content.
+fn open_output_memory(output: &Path) -> io::Result<FileMemory> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+    Ok(FileMemory::new(file))
+}
+
+// Shuffles the balances. Based on anecdotal evidence, inserting the elements in a random
+// order is ~40% more space efficient than inserting the elements in sorted order.
+fn shuffle_balances(balances: BTreeMap<Address, u64>) -> Vec<(Address, u64)> {
+    let mut balances: Vec<_> = balances.into_iter().collect();
+    let mut rng = ChaCha8Rng::seed_from_u64(1);
+    balances.shuffle(&mut rng);
+    balances
+}
 
-    // Compute the balances. We use a standard BTreeMap here for speed.
+// Writes the balances into a stable btreemap backed by the given memory.
+fn write_balances<M: Memory>(memory: M, balances: Vec<(Address, u64)>) {
+    let mut stable_balances: StableBTreeMap<Address, u64, _> = StableBTreeMap::init(memory);
+    for (address, amount) in balances.into_iter() {
+        stable_balances.insert(address, amount);
+    }
+}
+
+// Returns the path of the manifest describing the given output.
+fn manifest_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".manifest");
+    PathBuf::from(path)
+}
+
+// Writes a manifest describing the computed balances, with one `key=value` pair per line.
+fn write_manifest(
+    path: &Path,
+    network: Network,
+    at_height: Option<Height>,
+    num_balances: usize,
+) -> std::io::Result<()> {
+    let mut manifest = File::create(path)?;
+    writeln!(manifest, "network={}", network)?;
+    if let Some(at_height) = at_height {
+        writeln!(manifest, "at_height={}", at_height)?;
+    }
+    writeln!(manifest, "num_balances={}", num_balances)
+}
+
+// Returns true if the given address can be used on the given network.
+fn is_address_for_network(address: &BitcoinAddress, network: Network) -> bool {
+    match (address.network, into_bitcoin_network(network)) {
+        (address_network, network) if address_network == network => true,
+        // Base58 addresses are the same on all the test networks, and are parsed as testnet
+        // addresses.
+        (BitcoinNetwork::Testnet, BitcoinNetwork::Regtest) => matches!(
+            address.payload,
+            Payload::PubkeyHash(_) | Payload::ScriptHash(_)
+        ),
+        _ => false,
+    }
+}
+
+// Reads a newline-delimited list of addresses of the given network, ignoring empty lines.
+// Returns an error mentioning the line number if an address is malformed or belongs to another
+// network.
+fn read_address_filter(path: &Path, network: Network) -> io::Result<BTreeSet<Address>> {
+    let mut addresses = BTreeSet::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid_address = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {} address {}", i + 1, reason, line),
+            )
+        };
+        let address = BitcoinAddress::from_str(line).map_err(|_| invalid_address("malformed"))?;
+        if !is_address_for_network(&address, network) {
+            return Err(invalid_address(&format!("non-{}", network)));
+        }
+        addresses.insert(address.into());
+    }
+
+    Ok(addresses)
+}
+
+// Computes the balances of the addresses in the given UTXOs dump.
+// If an address filter is given, only the balances of these addresses are computed.
+// If `detect_duplicates` is set, UTXOs with an already seen outpoint are skipped.
+fn compute_balances<R: BufRead>(
+    reader: R,
+    network: Network,
+    address_filter: Option<&BTreeSet<Address>>,
+    detect_duplicates: bool,
+) -> BTreeMap<Address, u64> {
+    // We use a standard BTreeMap here for speed.
     let mut balances: BTreeMap<Address, u64> = BTreeMap::new();
+    let mut seen_outpoints: BTreeSet<(String, u32)> = BTreeSet::new();
     for (i, line) in reader.lines().enumerate() {
         let line = line.unwrap();
         let parts: Vec<_> = line.split(',').collect();
 
+        let txid = parts[1];
+        let vout: u32 = parts[2].parse().unwrap();
         let amount: u64 = parts[3].parse().unwrap();
         let address_str = parts[5];
         let script = parts[6];
 
         if i % 100_000 == 0 {
-            println!("Processed {} UTXOs", i);
+            eprintln!("Processed {} UTXOs", i);
+        }
+
+        if detect_duplicates && !seen_outpoints.insert((txid.to_string(), vout)) {
+            eprintln!("Skipping duplicate outpoint {}:{}", txid, vout);
+            continue;
         }
 
         // Load the address. The UTXO dump tool we use doesn't output all the addresses
@@ -64,13 +215,19 @@ fn main() {
         } else {
             BitcoinAddress::from_script(
                 &Script::from(hex::decode(script).expect("script must be valid hex")),
-                into_bitcoin_network(args.network),
+                into_bitcoin_network(network),
             )
         };
 
         if let Some(address) = address {
             let address: Address = address.into();
 
+            if let Some(address_filter) = address_filter {
+                if !address_filter.contains(&address) {
+                    continue;
+                }
+            }
+
             // Update the balance of the address.
             if amount != 0 {
                 balances
@@ -81,30 +238,263 @@ fn main() {
         }
     }
 
-    // Shuffle the balances. Based on anecdotal evidence, inserting the elements in a random
-    // order is ~40% more space efficient than inserting the elements in sorted order.
-    println!("Shuffling...");
-    let mut balances: Vec<_> = balances.into_iter().collect();
-    let mut rng = ChaCha8Rng::seed_from_u64(1);
-    balances.shuffle(&mut rng);
+    balances
+}
 
-    println!("Writing to stable structure...");
-    let memory = DefaultMemoryImpl::default();
-    let mut stable_balances: StableBTreeMap<Address, u64, _> = StableBTreeMap::init(memory.clone());
+fn main() {
+    let args = Args::parse();
 
-    // Write the balances into a stable btreemap.
-    for (address, amount) in balances.into_iter() {
-        stable_balances.insert(address, amount);
+    let address_filter = args.address_filter.as_deref().map(|path| {
+        read_address_filter(path, args.network).unwrap_or_else(|err| {
+            panic!(
+                "couldn't read the address filter {}: {}",
+                path.display(),
+                err
+            )
+        })
+    });
+
+    // Read the UTXOs from the UTXOs dump.
+    let utxos_file = File::open(args.utxos_dump_path).unwrap();
+    let reader = BufReader::new(utxos_file);
+
+    // Compute the balances.
+    let balances = compute_balances(
+        reader,
+        args.network,
+        address_filter.as_ref(),
+        args.detect_duplicates,
+    );
+
+    eprintln!("Shuffling...");
+    let num_balances = balances.len();
+    let balances = shuffle_balances(balances);
+
+    if is_stdout(&args.output) {
+        // Stdout isn't seekable, so the stable structure is built in RAM before writing it.
+        eprintln!("Writing to stable structure...");
+        let memory = DefaultMemoryImpl::default();
+        write_balances(memory.clone(), balances);
+
+        eprintln!("Writing stable structure to output...");
+        match write_output(&args.output, &memory.borrow(), io::stdout().lock()) {
+            Err(err) => panic!("couldn't write to {}: {}", args.output.display(), err),
+            Ok(_) => eprintln!("successfully wrote balances to {}", args.output.display()),
+        };
+
+        eprintln!("Not writing a manifest, as the balances were written to stdout.");
+        return;
     }
 
-    println!("Writing stable structure to file...");
-    let mut balances_file = match File::create(&args.output) {
-        Err(err) => panic!("couldn't create {}: {}", args.output.display(), err),
-        Ok(file) => file,
+    // Write the stable structure directly into the output file.
+    eprintln!("Writing stable structure to output...");
+    match open_output_memory(&args.output) {
+        Err(err) => panic!("couldn't write to {}: {}", args.output.display(), err),
+        Ok(memory) => {
+            write_balances(memory, balances);
+            eprintln!("successfully wrote balances to {}", args.output.display());
+        }
     };
 
-    match balances_file.write_all(&memory.borrow()) {
-        Err(err) => panic!("couldn't write to {}: {}", args.output.display(), err),
-        Ok(_) => println!("successfully wrote balances to {}", args.output.display()),
+    let manifest_path = manifest_path(&args.output);
+    match write_manifest(&manifest_path, args.network, args.at_height, num_balances) {
+        Err(err) => panic!("couldn't write to {}: {}", manifest_path.display(), err),
+        Ok(_) => eprintln!("successfully wrote manifest to {}", manifest_path.display()),
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn address_filter() {
+        let address_1 = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let address_2 = "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy";
+        let address_3 = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+        // A dump where each address has two UTXOs.
+        let mut utxos_dump = String::new();
+        for (i, address) in [
+            address_1, address_2, address_3, address_1, address_2, address_3,
+        ]
+        .iter()
+        .enumerate()
+        {
+            utxos_dump.push_str(&format!("{},txid,0,{},p2pkh,{},\n", i, 1000 + i, address));
+        }
+
+        // The filter file has surrounding whitespace and an empty line.
+        let mut filter_file = NamedTempFile::new().unwrap();
+        writeln!(filter_file, "{}\n\n  {}  ", address_1, address_3).unwrap();
+        let address_filter = read_address_filter(filter_file.path(), Network::Mainnet).unwrap();
+
+        let balances = compute_balances(
+            utxos_dump.as_bytes(),
+            Network::Mainnet,
+            Some(&address_filter),
+            false,
+        );
+
+        assert_eq!(
+            balances,
+            BTreeMap::from([
+                (Address::from_str(address_1).unwrap(), 1000 + 1003),
+                (Address::from_str(address_3).unwrap(), 1002 + 1005),
+            ])
+        );
+
+        // Without a filter, all the addresses are included.
+        let balances = compute_balances(utxos_dump.as_bytes(), Network::Mainnet, None, false);
+        assert_eq!(balances.len(), 3);
+    }
+
+    #[test]
+    fn address_filter_rejects_invalid_addresses() {
+        let mainnet_address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let testnet_address = "mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn";
+        let regtest_address = "bcrt1qs758ursh4q9z627kt3pp5yysm78ddny6txaqgw";
+
+        let read = |contents: &str, network: Network| {
+            let mut filter_file = NamedTempFile::new().unwrap();
+            write!(filter_file, "{}", contents).unwrap();
+            read_address_filter(filter_file.path(), network).map_err(|err| err.to_string())
+        };
+
+        assert_eq!(
+            read(
+                &format!("{}\n\ninvalid\n", mainnet_address),
+                Network::Mainnet
+            ),
+            Err("line 3: malformed address invalid".to_string())
+        );
+        assert_eq!(
+            read(
+                &format!("{}\n{}\n", mainnet_address, testnet_address),
+                Network::Mainnet
+            ),
+            Err(format!("line 2: non-mainnet address {}", testnet_address))
+        );
+        assert_eq!(
+            read(&format!("{}\n", regtest_address), Network::Testnet),
+            Err(format!("line 1: non-testnet address {}", regtest_address))
+        );
+
+        // Base58 addresses are shared between testnet and regtest.
+        assert_eq!(
+            read(
+                &format!("{}\n{}\n", testnet_address, regtest_address),
+                Network::Regtest
+            )
+            .unwrap()
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn duplicate_outpoints_are_counted_once() {
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+        // The first UTXO appears twice in the dump.
+        let utxos_dump = format!(
+            "0,txid_1,0,1000,p2pkh,{address},\n\
+             1,txid_1,1,2000,p2pkh,{address},\n\
+             2,txid_1,0,1000,p2pkh,{address},\n",
+            address = address
+        );
+
+        let balances = compute_balances(utxos_dump.as_bytes(), Network::Mainnet, None, true);
+        assert_eq!(
+            balances,
+            BTreeMap::from([(Address::from_str(address).unwrap(), 3000)])
+        );
+
+        // Without detecting duplicates, the duplicated UTXO is double-counted.
+        let balances = compute_balances(utxos_dump.as_bytes(), Network::Mainnet, None, false);
+        assert_eq!(
+            balances,
+            BTreeMap::from([(Address::from_str(address).unwrap(), 4000)])
+        );
+    }
+
+    #[test]
+    fn write_output_to_stdout() {
+        let bytes = vec![1, 2, 3];
+
+        // With `-`, the bytes are written to stdout.
+        let mut stdout = vec![];
+        write_output(Path::new("-"), &bytes, &mut stdout).unwrap();
+        assert_eq!(stdout, bytes);
+
+        // Otherwise, the bytes are written to the given path.
+        let output = NamedTempFile::new().unwrap();
+        let mut stdout = vec![];
+        write_output(output.path(), &bytes, &mut stdout).unwrap();
+        assert!(stdout.is_empty());
+        assert_eq!(std::fs::read(output.path()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn writing_to_file_matches_writing_in_memory() {
+        let utxos_dump: String = (0..1_000)
+            .map(|i| {
+                format!(
+                    "{},txid_{},0,{},p2pkh,{},\n",
+                    i,
+                    i,
+                    1000 + i,
+                    [
+                        "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+                        "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy"
+                    ][i % 2]
+                )
+            })
+            .collect();
+        let balances = compute_balances(utxos_dump.as_bytes(), Network::Mainnet, None, false);
+
+        // Build the stable structure in RAM, as is done when writing to stdout.
+        let memory = DefaultMemoryImpl::default();
+        write_balances(memory.clone(), shuffle_balances(balances.clone()));
+        let mut expected = vec![];
+        write_output(Path::new("-"), &memory.borrow(), &mut expected).unwrap();
+
+        // Build the stable structure directly in the output file.
+        let output = NamedTempFile::new().unwrap();
+        write_balances(
+            open_output_memory(output.path()).unwrap(),
+            shuffle_balances(balances),
+        );
+
+        assert_eq!(std::fs::read(output.path()).unwrap(), expected);
+    }
+
+    #[test]
+    fn manifest_records_height() {
+        let output = NamedTempFile::new().unwrap();
+        let manifest_path = manifest_path(output.path());
+        assert_eq!(
+            manifest_path.file_name().unwrap().to_str().unwrap(),
+            format!(
+                "{}.manifest",
+                output.path().file_name().unwrap().to_str().unwrap()
+            )
+        );
+
+        write_manifest(&manifest_path, Network::Testnet, Some(2_500_000), 3).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&manifest_path).unwrap(),
+            "network=testnet\nat_height=2500000\nnum_balances=3\n"
+        );
+
+        // Without a height, none is recorded.
+        write_manifest(&manifest_path, Network::Testnet, None, 3).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&manifest_path).unwrap(),
+            "network=testnet\nnum_balances=3\n"
+        );
+
+        std::fs::remove_file(manifest_path).unwrap();
+    }
+}
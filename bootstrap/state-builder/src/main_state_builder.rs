@@ -113,6 +113,9 @@ fn main() {
     with_state_mut(|s| {
         s.utxos.utxos.large_utxos = large_utxos;
 
+        // The UTXOs were loaded directly, so their hash must be computed from scratch.
+        s.utxos.rebuild_utxo_set_hash();
+
         s.utxos.next_height = args.anchor_height;
 
         // Ingest the blocks.
@@ -0,0 +1,19 @@
+//! Feeds arbitrary bytes into the deserialization of blocks and block trees.
+//!
+//! Deserialization must return an error on malformed input rather than panic.
+//!
+//! Run with `cargo fuzz run block_deserialization` from the `canister` directory.
+#![no_main]
+use bitcoin::consensus::Decodable;
+use ic_btc_canister::unstable_blocks::UnstableBlocks;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(block) = bitcoin::Block::consensus_decode(data) {
+        let block = ic_btc_types::Block::new(block);
+        let _ = block.block_hash();
+    }
+
+    // The unstable blocks contain a `BlockTree`, which has a custom deserializer.
+    let _ = ciborium::de::from_reader::<UnstableBlocks, _>(data);
+});
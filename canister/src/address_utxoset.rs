@@ -5,7 +5,10 @@ use crate::{
     UtxoSet,
 };
 use ic_btc_types::{Block, OutPoint};
-use std::{collections::BTreeSet, sync::Arc};
+use std::{
+    collections::{btree_set, BTreeSet},
+    sync::Arc,
+};
 
 /// A struct that tracks the UTXO set of a given address.
 ///
@@ -84,10 +87,21 @@ impl<'a> AddressUtxoSet<'a> {
         let removed_outpoints_2 = Arc::clone(&removed_outpoints);
         let full_utxo_set = self.full_utxo_set;
 
+        // Outpoints of the UTXOs added by the unstable blocks. These take precedence over the
+        // underlying UTXO set so that a UTXO is never returned twice, even if it's visible on
+        // both sides of the stable/unstable boundary (e.g. while its block is being ingested).
+        let added_outpoints: BTreeSet<OutPoint> = self
+            .added_utxos
+            .iter()
+            .map(|utxo| utxo.outpoint.clone())
+            .collect();
+
         let stable_utxos = self
             .full_utxo_set
             .get_address_outpoints(&self.address, &offset)
-            .filter(move |outpoint| !removed_outpoints.contains(outpoint))
+            .filter(move |outpoint| {
+                !removed_outpoints.contains(outpoint) && !added_outpoints.contains(outpoint)
+            })
             .map(move |outpoint| {
                 // Look up the UTXO corresponding to the given outpoint.
                 let (tx_out, height) = full_utxo_set.get_utxo(&outpoint).unwrap_or_else(|| {
@@ -111,6 +125,136 @@ impl<'a> AddressUtxoSet<'a> {
 
         MultiIter::new(stable_utxos, unstable_utxos)
     }
+
+    /// Returns an iterator with the address's UTXOs, as `into_iter` does, after applying the
+    /// given blocks, which must extend the blocks applied so far.
+    ///
+    /// Unlike calling `apply_block` for each block, the blocks are applied lazily as UTXOs are
+    /// consumed, starting from the tip. A UTXO created by one of the blocks can no longer be
+    /// spent by the blocks that remain to be applied, so it's returned as soon as its block is
+    /// applied. This way, consumers that only need the first UTXOs (e.g. a page) don't walk the
+    /// entire chain.
+    pub fn into_lazy_iter(
+        self,
+        blocks: Vec<&'a Block>,
+        offset: Option<Utxo>,
+    ) -> LazyAddressUtxos<'a> {
+        LazyAddressUtxos {
+            pending_blocks: blocks,
+            address_utxo_set: Some(self),
+            offset,
+            ready: BTreeSet::new().into_iter(),
+            rest: None,
+            num_blocks_applied: 0,
+        }
+    }
+}
+
+/// An iterator over the UTXOs of an address that applies blocks on demand, as returned by
+/// `AddressUtxoSet::into_lazy_iter`.
+pub struct LazyAddressUtxos<'a> {
+    // The blocks that remain to be applied, in chain order. The last one is applied first.
+    pending_blocks: Vec<&'a Block>,
+
+    // The address's UTXO set, until all the pending blocks have been applied.
+    address_utxo_set: Option<AddressUtxoSet<'a>>,
+
+    offset: Option<Utxo>,
+
+    // The UTXOs of the most recently applied block that remain to be returned.
+    ready: btree_set::IntoIter<Utxo>,
+
+    // The remaining UTXOs once all the pending blocks have been applied.
+    rest: Option<Box<dyn Iterator<Item = Utxo> + 'a>>,
+
+    num_blocks_applied: usize,
+}
+
+impl<'a> LazyAddressUtxos<'a> {
+    /// Returns the number of blocks that have been applied so far.
+    pub fn num_blocks_applied(&self) -> usize {
+        self.num_blocks_applied
+    }
+
+    fn apply_next_block(&mut self, block: &Block) {
+        let address_utxo_set = self
+            .address_utxo_set
+            .as_mut()
+            .expect("blocks are applied before the address's UTXO set is consumed");
+        let block_hash = block.block_hash();
+
+        for outpoint in address_utxo_set
+            .unstable_blocks
+            .get_removed_outpoints(&block_hash, &address_utxo_set.address)
+        {
+            address_utxo_set.removed_outpoints.insert(outpoint.clone());
+        }
+
+        let mut utxos = BTreeSet::new();
+        for outpoint in address_utxo_set
+            .unstable_blocks
+            .get_added_outpoints(&block_hash, &address_utxo_set.address)
+        {
+            let (txout, height) = address_utxo_set
+                .unstable_blocks
+                .get_tx_out(outpoint)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "tx out for outpoint {:?} must exist in added outpoints",
+                        outpoint
+                    );
+                });
+            let utxo = Utxo {
+                outpoint: outpoint.clone(),
+                value: txout.value,
+                height,
+            };
+
+            // All the blocks that could spend the UTXO have already been applied.
+            let is_spent = address_utxo_set.removed_outpoints.contains(outpoint);
+            let is_after_offset = match &self.offset {
+                Some(offset) => &utxo >= offset,
+                None => true,
+            };
+            if !is_spent && is_after_offset {
+                utxos.insert(utxo);
+            }
+
+            // The UTXO has been accounted for, so exclude it from the remaining UTXOs, which
+            // may include it if its block is being ingested.
+            address_utxo_set.removed_outpoints.insert(outpoint.clone());
+        }
+
+        self.ready = utxos.into_iter();
+        self.num_blocks_applied += 1;
+    }
+}
+
+impl<'a> Iterator for LazyAddressUtxos<'a> {
+    type Item = Utxo;
+
+    fn next(&mut self) -> Option<Utxo> {
+        loop {
+            if let Some(utxo) = self.ready.next() {
+                return Some(utxo);
+            }
+
+            if let Some(rest) = self.rest.as_mut() {
+                return rest.next();
+            }
+
+            match self.pending_blocks.pop() {
+                Some(block) => self.apply_next_block(block),
+                None => {
+                    let address_utxo_set = self
+                        .address_utxo_set
+                        .take()
+                        .expect("the address's UTXO set must be available");
+                    self.rest = Some(Box::new(address_utxo_set.into_iter(self.offset.take())));
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -279,4 +423,71 @@ mod test {
             }]
         );
     }
+
+    #[test]
+    fn lazy_iter_walks_only_the_blocks_it_needs() {
+        let network = Network::Mainnet;
+        let address = random_p2pkh_address(network);
+
+        // A chain where every block pays the address, and the block at height 5 also spends the
+        // UTXO created at height 2.
+        let mut blocks = vec![BlockBuilder::genesis()
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build()];
+        for height in 1..10 {
+            let mut tx = TransactionBuilder::coinbase().with_output(&address, 1000 + height);
+            if height == 5 {
+                let spent_txid = blocks[2].txdata()[0].txid();
+                tx = TransactionBuilder::new()
+                    .with_input(OutPoint::new(spent_txid, 0))
+                    .with_output(&address, 1000 + height);
+            }
+            let block = BlockBuilder::with_prev_header(blocks.last().unwrap().header())
+                .with_transaction(tx.build())
+                .build();
+            blocks.push(block);
+        }
+
+        let utxo_set = UtxoSet::new(network);
+        let mut unstable_blocks = UnstableBlocks::new(&utxo_set, 2, blocks[0].clone(), network);
+        for block in blocks[1..].iter() {
+            unstable_blocks::push(&mut unstable_blocks, &utxo_set, block.clone()).unwrap();
+        }
+
+        let mut eager = AddressUtxoSet::new(address.clone(), &utxo_set, &unstable_blocks);
+        for block in blocks.iter() {
+            eager.apply_block(block);
+        }
+        let expected: Vec<_> = eager.into_iter(None).collect();
+        assert_eq!(expected.len(), 9);
+
+        // Requesting only the first UTXO only walks the tip.
+        let mut lazy = AddressUtxoSet::new(address.clone(), &utxo_set, &unstable_blocks)
+            .into_lazy_iter(blocks.iter().collect(), None);
+        assert_eq!(lazy.next().map(|utxo| utxo.height), Some(9));
+        assert_eq!(lazy.num_blocks_applied(), 1);
+
+        // Requesting all the UTXOs walks the entire chain and returns the same UTXOs.
+        let mut lazy = AddressUtxoSet::new(address.clone(), &utxo_set, &unstable_blocks)
+            .into_lazy_iter(blocks.iter().collect(), None);
+        assert_eq!(lazy.by_ref().collect::<Vec<_>>(), expected);
+        assert_eq!(lazy.num_blocks_applied(), blocks.len());
+
+        // Offsets are respected.
+        let offset = Utxo {
+            outpoint: OutPoint::new(blocks[4].txdata()[0].txid(), 0),
+            value: 0,
+            height: 4,
+        };
+        let lazy = AddressUtxoSet::new(address, &utxo_set, &unstable_blocks)
+            .into_lazy_iter(blocks.iter().collect(), Some(offset));
+        assert_eq!(
+            lazy.map(|utxo| utxo.height).collect::<Vec<_>>(),
+            vec![4, 3, 1, 0]
+        );
+    }
 }
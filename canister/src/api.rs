@@ -1,15 +1,20 @@
 mod fee_percentiles;
 mod get_balance;
 mod get_block_headers;
+mod get_transaction;
 mod get_utxos;
 mod metrics;
 mod send_transaction;
 pub(crate) mod set_config;
 pub use fee_percentiles::get_current_fee_percentiles;
 pub(crate) use fee_percentiles::get_current_fee_percentiles_impl;
+pub use fee_percentiles::get_fee_percentiles_at;
 pub use get_balance::get_balance;
 pub use get_balance::get_balance_query;
 pub use get_block_headers::get_block_headers;
+pub use get_transaction::get_transaction;
+pub use get_transaction::get_transaction_query;
+pub(crate) use get_utxos::clear_utxos_cache;
 pub use get_utxos::get_utxos;
 pub use get_utxos::get_utxos_query;
 pub use metrics::get_metrics;
@@ -1,6 +1,6 @@
 use crate::{
     charge_cycles,
-    runtime::{performance_counter, print},
+    runtime::{performance_counter, print, time},
     state::{FeePercentilesCache, State},
     unstable_blocks::{self, UnstableBlocks},
     verify_has_enough_cycles, with_state, with_state_mut,
@@ -11,6 +11,18 @@ use ic_btc_types::{Block, Transaction};
 /// The number of transactions to include in the percentiles calculation.
 const NUM_TRANSACTIONS: u32 = 10_000;
 
+/// The fallback fee used when none is configured: Bitcoin Core's default minimum relay fee of
+/// 1 satoshi per byte. The minimum relay fee is the same on every network, so a single default
+/// is used for all of them.
+pub const DEFAULT_FALLBACK_FEE_PER_BYTE: MillisatoshiPerByte = 1_000;
+
+/// Returns the fee returned in place of each fee percentile when no fees are available.
+fn fallback_fee_per_byte(state: &State) -> MillisatoshiPerByte {
+    state
+        .fallback_fee_per_byte
+        .unwrap_or(DEFAULT_FALLBACK_FEE_PER_BYTE)
+}
+
 /// Returns the 100 fee percentiles of the chain's 10,000 most recent transactions.
 pub fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
     verify_has_enough_cycles(with_state(|s| s.fees.get_current_fee_percentiles_maximum));
@@ -61,23 +73,51 @@ fn get_current_fee_percentiles_with_number_of_transactions(
 
     // There are no fees to report when there are no transactions in unstable blocks.
     // This doesn't realistically happen on mainnet, but may happen in local development
-    // with regtest. In which case, the last cached result of fees is returned.
+    // with regtest. In which case, the last cached result of fees is returned, or the
+    // fallback fee for every percentile if there is no cached result.
+    //
+    // The fallback fee isn't cached, so that changes to it take effect immediately.
     if fees_per_byte.is_empty() {
         if let Some(cache) = &state.fee_percentiles_cache {
             return cache.fee_percentiles.clone();
         }
+        return vec![fallback_fee_per_byte(state); MAX_PERCENTILE as usize + 1];
     }
-
     let fee_percentiles = percentiles(fees_per_byte);
 
     state.fee_percentiles_cache = Some(FeePercentilesCache {
         tip_block_hash,
         fee_percentiles: fee_percentiles.clone(),
+        computed_at: Some(time()),
     });
 
     fee_percentiles
 }
 
+/// Returns the fee percentiles at the given points (e.g. `[10, 50, 90]`) of the chain's 10,000
+/// most recent transactions, in the order of the points. Points above 100 are treated as 100.
+///
+/// Unlike `get_current_fee_percentiles`, only the requested percentiles are computed, and the
+/// result isn't cached. If there are no fees, the fallback fee is returned for each point.
+pub fn get_fee_percentiles_at(state: &State, points: &[u8]) -> Vec<MillisatoshiPerByte> {
+    let main_chain = unstable_blocks::get_main_chain(&state.unstable_blocks);
+    let mut fees_per_byte = get_fees_per_byte(
+        main_chain.into_chain(),
+        &state.unstable_blocks,
+        NUM_TRANSACTIONS,
+    );
+
+    if fees_per_byte.is_empty() {
+        return vec![fallback_fee_per_byte(state); points.len()];
+    }
+
+    fees_per_byte.sort_unstable();
+    points
+        .iter()
+        .map(|point| nearest_rank(&fees_per_byte, std::cmp::min(*point as u32, MAX_PERCENTILE)))
+        .collect()
+}
+
 /// Computes the fees per byte of the last `number_of_transactions` transactions on the main chain.
 /// Fees are returned in a reversed order, starting with the most recent ones, followed by the older ones.
 /// Eg. for transactions [..., Tn-2, Tn-1, Tn] fees would be [Fn, Fn-1, Fn-2, ...].
@@ -149,18 +189,23 @@ fn percentiles(mut values: Vec<u64>) -> Vec<u64> {
         return vec![];
     }
     values.sort_unstable();
-    const MAX_PERCENTILE: u32 = 100;
-    let ceil_div = |a, b| a / b + if a % b == 0 { 0 } else { 1 };
     (0..MAX_PERCENTILE + 1)
-        .map(|p| {
-            // `ordinal_rank = ceil(p/100 * n)`.
-            let ordinal_rank = ceil_div(p * values.len() as u32, MAX_PERCENTILE);
-            let index = std::cmp::max(0, ordinal_rank as i32 - 1);
-            values[index as usize]
-        })
+        .map(|p| nearest_rank(&values, p))
         .collect()
 }
 
+const MAX_PERCENTILE: u32 = 100;
+
+/// Returns the `p`-th percentile of the given non-empty, sorted values using the nearest-rank
+/// method.
+fn nearest_rank(sorted_values: &[u64], p: u32) -> u64 {
+    let ceil_div = |a, b| a / b + if a % b == 0 { 0 } else { 1 };
+    // `ordinal_rank = ceil(p/100 * n)`.
+    let ordinal_rank = ceil_div(p * sorted_values.len() as u32, MAX_PERCENTILE);
+    let index = std::cmp::max(0, ordinal_rank as i32 - 1);
+    sorted_values[index as usize]
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -174,6 +219,7 @@ mod test {
     use ic_btc_interface::{Fees, InitConfig, Network, Satoshi};
     use ic_btc_types::OutPoint;
     use std::iter::FromIterator;
+    use std::time::Duration;
 
     /// Covers an inclusive range of `[0, 100]` percentiles.
     const PERCENTILE_BUCKETS: usize = 101;
@@ -465,6 +511,41 @@ mod test {
         });
     }
 
+    #[test]
+    fn get_fee_percentiles_at_requested_points() {
+        let number_of_blocks = 8;
+        let blocks = generate_blocks(10_000, number_of_blocks);
+        let stability_threshold = blocks.len() as u128;
+        init_state(blocks, stability_threshold);
+
+        with_state_mut(|state| {
+            // The fees are [0, 8, 16, 25, 33, 42, 50, 58] millisatoshi per byte.
+            assert_eq!(
+                get_fee_percentiles_at(state, &[10, 50, 90]),
+                vec![0, 25, 58]
+            );
+
+            // Points are returned in the requested order, and match the full percentiles.
+            let points = [99, 0, 37, 75, 200];
+            let all_percentiles = get_current_fee_percentiles_impl(state);
+            assert_eq!(
+                get_fee_percentiles_at(state, &points),
+                vec![
+                    all_percentiles[99],
+                    all_percentiles[0],
+                    all_percentiles[37],
+                    all_percentiles[75],
+                    all_percentiles[100],
+                ]
+            );
+
+            assert_eq!(
+                get_fee_percentiles_at(state, &[]),
+                Vec::<MillisatoshiPerByte>::new()
+            );
+        });
+    }
+
     #[test]
     fn get_current_fee_percentiles_requested_number_of_txs_is_equal_to_the_number_of_actual_txs() {
         let number_of_blocks = 5;
@@ -520,8 +601,57 @@ mod test {
             assert_eq!(fees.len(), 0);
         });
 
+        // Without any fees, the fallback fee is returned.
         let percentiles = get_current_fee_percentiles();
-        assert_eq!(percentiles.len(), 0);
+        assert_eq!(percentiles, vec![DEFAULT_FALLBACK_FEE_PER_BYTE; 101]);
+    }
+
+    #[test]
+    fn returns_fallback_fee_for_chain_of_empty_blocks() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(10),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        // Blocks with only a coinbase transaction, which has no fee.
+        let mut prev_header = *genesis_block(network).header();
+        for _ in 0..5 {
+            let block = BlockBuilder::with_prev_header(&prev_header).build();
+            prev_header = *block.header();
+            with_state_mut(|s| state::insert_block(s, block).unwrap());
+        }
+
+        assert_eq!(
+            get_current_fee_percentiles(),
+            vec![DEFAULT_FALLBACK_FEE_PER_BYTE; 101]
+        );
+    }
+
+    #[test]
+    fn returns_configured_fallback_fee_for_chain_of_empty_blocks() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(10),
+            network: Some(network),
+            fallback_fee_per_byte: Some(Some(2_000)),
+            ..Default::default()
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        with_state_mut(|s| state::insert_block(s, block).unwrap());
+
+        assert_eq!(get_current_fee_percentiles(), vec![2_000; 101]);
+        assert_eq!(
+            with_state(|s| get_fee_percentiles_at(s, &[10, 50, 90])),
+            vec![2_000; 3]
+        );
+
+        // Changing the fallback fee takes effect without a change of the tip.
+        with_state_mut(|s| s.fallback_fee_per_byte = Some(3_000));
+        assert_eq!(get_current_fee_percentiles(), vec![3_000; 101]);
+        assert!(with_state(|s| s.fee_percentiles_cache.is_none()));
     }
 
     #[test]
@@ -577,11 +707,47 @@ mod test {
         });
     }
 
+    #[test]
+    fn fee_percentiles_age_increases_with_time() {
+        let number_of_blocks = 5;
+        let blocks = generate_blocks(10_000, number_of_blocks);
+        let stability_threshold = 2;
+        init_state(blocks, stability_threshold);
+
+        // There's no age before the fee percentiles are computed.
+        with_state(|state| assert_eq!(state.fee_percentiles_age(0), None));
+
+        get_current_fee_percentiles();
+
+        with_state(|state| {
+            let computed_at = state
+                .fee_percentiles_cache
+                .as_ref()
+                .unwrap()
+                .computed_at
+                .unwrap();
+
+            assert_eq!(
+                state.fee_percentiles_age(computed_at),
+                Some(Duration::from_secs(0))
+            );
+            assert_eq!(
+                state.fee_percentiles_age(computed_at + 60),
+                Some(Duration::from_secs(60))
+            );
+            assert_eq!(
+                state.fee_percentiles_age(computed_at + 3_600),
+                Some(Duration::from_secs(3_600))
+            );
+        });
+    }
+
     #[test]
     fn charges_cycles() {
         crate::init(InitConfig {
             fees: Some(Fees {
                 get_current_fee_percentiles: 10,
+                get_current_fee_percentiles_maximum: 100,
                 ..Default::default()
             }),
             ..Default::default()
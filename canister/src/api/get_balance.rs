@@ -311,6 +311,7 @@ mod test {
         crate::init(InitConfig {
             fees: Some(Fees {
                 get_balance: 10,
+                get_balance_maximum: 100,
                 ..Default::default()
             }),
             ..Default::default()
@@ -27,7 +27,7 @@ struct Stats {
 
 fn verify_and_return_effective_range(
     request: &GetBlockHeadersRequest,
-) -> Result<(u32, u32), GetBlockHeadersError> {
+) -> Result<(u32, u32, u32), GetBlockHeadersError> {
     let chain_height = with_state(main_chain_height);
 
     if request.start_height > chain_height {
@@ -67,13 +67,13 @@ fn verify_and_return_effective_range(
         effective_start_height + MAX_BLOCK_HEADERS_PER_RESPONSE - 1,
     );
 
-    Ok((effective_start_height, effective_end_height))
+    Ok((effective_start_height, effective_end_height, chain_height))
 }
 
 fn get_block_headers_internal(
     request: &GetBlockHeadersRequest,
 ) -> Result<(GetBlockHeadersResponse, Stats), GetBlockHeadersError> {
-    let (start_height, end_height) = verify_and_return_effective_range(request)?;
+    let (start_height, end_height, chain_height) = verify_and_return_effective_range(request)?;
 
     let mut stats: Stats = Stats::default();
 
@@ -84,9 +84,12 @@ fn get_block_headers_internal(
     let mut vec_headers: Vec<Vec<u8>> = with_state(|s| {
         s.stable_block_headers
             .get_block_headers_in_range(std::ops::RangeInclusive::new(start_height, end_height))
-            .map(|header_blob| header_blob.into())
-            .collect()
-    });
+            .map(|headers| headers.map(|header_blob| header_blob.into()).collect())
+            .map_err(|_| GetBlockHeadersError::HeadersPruned {
+                start_height,
+                end_height,
+            })
+    })?;
 
     let ins_after_stable_blocks = performance_counter();
 
@@ -117,6 +120,7 @@ fn get_block_headers_internal(
     Ok((
         GetBlockHeadersResponse {
             tip_height: end_height,
+            chain_height,
             block_headers: vec_headers,
         },
         stats,
@@ -301,6 +305,7 @@ mod test {
             response,
             GetBlockHeadersResponse {
                 tip_height: 0,
+                chain_height: 0,
                 block_headers: vec![genesis_header_blob.clone()]
             }
         );
@@ -318,6 +323,7 @@ mod test {
             response,
             GetBlockHeadersResponse {
                 tip_height: 0,
+                chain_height: 0,
                 block_headers: vec![genesis_header_blob]
             }
         );
@@ -355,6 +361,7 @@ mod test {
             .unwrap(),
             GetBlockHeadersResponse {
                 tip_height: 0,
+                chain_height: 1,
                 block_headers: vec![genesis_header_blob.clone()]
             }
         );
@@ -375,6 +382,7 @@ mod test {
             .unwrap(),
             GetBlockHeadersResponse {
                 tip_height: 1,
+                chain_height: 1,
                 block_headers: vec![block_header_blob.clone()]
             }
         );
@@ -389,6 +397,7 @@ mod test {
             .unwrap(),
             GetBlockHeadersResponse {
                 tip_height: 1,
+                chain_height: 1,
                 block_headers: vec![block_header_blob.clone()]
             }
         );
@@ -403,6 +412,7 @@ mod test {
             .unwrap(),
             GetBlockHeadersResponse {
                 tip_height: 1,
+                chain_height: 1,
                 block_headers: vec![genesis_header_blob.clone(), block_header_blob.clone()]
             }
         );
@@ -417,6 +427,7 @@ mod test {
             .unwrap(),
             GetBlockHeadersResponse {
                 tip_height: 1,
+                chain_height: 1,
                 block_headers: vec![genesis_header_blob.clone(), block_header_blob.clone()]
             }
         );
@@ -484,6 +495,7 @@ mod test {
             response,
             GetBlockHeadersResponse {
                 tip_height,
+                chain_height: total_num_blocks - 1,
                 block_headers: blobs[start_height as usize..=tip_height as usize].into()
             }
         );
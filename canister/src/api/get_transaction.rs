@@ -0,0 +1,218 @@
+use crate::{
+    charge_cycles, state::main_chain_height, unstable_blocks, verify_has_enough_cycles, with_state,
+};
+use ic_btc_interface::{GetTransactionError, GetTransactionRequest, GetTransactionResponse};
+use ic_btc_types::Txid;
+
+/// Retrieves a transaction in the main chain by its txid, along with its number of
+/// confirmations.
+///
+/// Transactions in stable blocks are looked up in the transaction index, and so can only be
+/// retrieved if the index was enabled when their blocks were ingested.
+pub fn get_transaction(
+    request: GetTransactionRequest,
+) -> Result<GetTransactionResponse, GetTransactionError> {
+    verify_has_enough_cycles(with_state(|s| s.fees.get_transaction_maximum));
+    charge_cycles(with_state(|s| s.fees.get_transaction));
+
+    get_transaction_private(request)
+}
+
+/// Retrieves a transaction in the main chain by its txid, along with its number of
+/// confirmations, while not charging for the execution, used only for queries.
+pub fn get_transaction_query(
+    request: GetTransactionRequest,
+) -> Result<GetTransactionResponse, GetTransactionError> {
+    get_transaction_private(request)
+}
+
+fn get_transaction_private(
+    request: GetTransactionRequest,
+) -> Result<GetTransactionResponse, GetTransactionError> {
+    let txid = Txid::from(request.txid);
+
+    with_state(|state| {
+        let tx_index = state
+            .utxos
+            .tx_index
+            .as_ref()
+            .ok_or(GetTransactionError::TxIndexDisabled)?;
+        let tip_height = main_chain_height(state);
+
+        // Look for the transaction in the unstable blocks of the main chain first. The blocks
+        // containing it are looked up in the index of the unstable transactions, and may be
+        // in forks that aren't part of the main chain.
+        let main_chain = unstable_blocks::get_main_chain(&state.unstable_blocks).into_chain();
+        for (block_hash, position) in state.unstable_blocks.get_blocks_with_tx(&txid) {
+            if let Some(depth) = main_chain
+                .iter()
+                .position(|block| &block.block_hash() == block_hash)
+            {
+                let block_height = state.stable_height() + depth as u32;
+                let tx = &main_chain[depth].internal_bitcoin_block().txdata[*position];
+                return Ok(GetTransactionResponse {
+                    transaction: bitcoin::consensus::serialize(tx),
+                    confirmations: tip_height - block_height + 1,
+                });
+            }
+        }
+
+        let (transaction, block_height) = tx_index
+            .get(&txid)
+            .ok_or(GetTransactionError::TransactionNotFound)?;
+        Ok(GetTransactionResponse {
+            transaction,
+            confirmations: tip_height - block_height + 1,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block,
+        state::{ingest_stable_blocks_into_utxoset, insert_block},
+        test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder},
+        with_state_mut,
+    };
+    use ic_btc_interface::{Fees, Flag, InitConfig, Network, NetworkInRequest, Txid as PublicTxid};
+    use ic_btc_types::Transaction;
+
+    fn get_transaction_request(tx: &Transaction) -> GetTransactionRequest {
+        GetTransactionRequest {
+            txid: PublicTxid::from(tx.txid()),
+            network: NetworkInRequest::Regtest,
+        }
+    }
+
+    fn serialize(tx: &Transaction) -> Vec<u8> {
+        bitcoin::consensus::serialize(&bitcoin::Transaction::from(tx.clone()))
+    }
+
+    #[test]
+    fn error_if_tx_index_is_disabled() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(1),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        let coinbase = genesis_block(network).txdata()[0].clone();
+        assert_eq!(
+            get_transaction(get_transaction_request(&coinbase)),
+            Err(GetTransactionError::TxIndexDisabled)
+        );
+    }
+
+    #[test]
+    fn returns_transactions_from_stable_and_unstable_blocks() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(1),
+            network: Some(network),
+            txindex: Some(Flag::Enabled),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+        let genesis_coinbase = genesis_block(network).txdata()[0].clone();
+        let tx_1 = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(tx_1.clone())
+            .build();
+        let tx_2 = TransactionBuilder::coinbase()
+            .with_output(&address, 2000)
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(tx_2.clone())
+            .build();
+
+        // Genesis block and block_1 should be stable, while block_2 should be unstable.
+        with_state_mut(|state| {
+            insert_block(state, block_1).unwrap();
+            insert_block(state, block_2).unwrap();
+            ingest_stable_blocks_into_utxoset(state);
+            assert_eq!(state.stable_height(), 2);
+        });
+
+        for (tx, confirmations) in [(genesis_coinbase, 3), (tx_1, 2), (tx_2, 1)] {
+            assert_eq!(
+                get_transaction(get_transaction_request(&tx)),
+                Ok(GetTransactionResponse {
+                    transaction: serialize(&tx),
+                    confirmations,
+                })
+            );
+        }
+
+        let unknown_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 3000)
+            .build();
+        assert_eq!(
+            get_transaction(get_transaction_request(&unknown_tx)),
+            Err(GetTransactionError::TransactionNotFound)
+        );
+    }
+
+    #[test]
+    fn ignores_transactions_in_forks() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(10),
+            network: Some(network),
+            txindex: Some(Flag::Enabled),
+            ..Default::default()
+        });
+
+        // A chain of two blocks along with a shorter fork, which isn't part of the main chain.
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let tx_1 = block_1.txdata()[0].clone();
+        let fork_tx_1 = fork_1.txdata()[0].clone();
+        with_state_mut(|state| {
+            insert_block(state, block_1).unwrap();
+            insert_block(state, block_2).unwrap();
+            insert_block(state, fork_1).unwrap();
+        });
+
+        assert_eq!(
+            get_transaction(get_transaction_request(&tx_1)),
+            Ok(GetTransactionResponse {
+                transaction: serialize(&tx_1),
+                confirmations: 2,
+            })
+        );
+        assert_eq!(
+            get_transaction(get_transaction_request(&fork_tx_1)),
+            Err(GetTransactionError::TransactionNotFound)
+        );
+    }
+
+    #[test]
+    fn charges_cycles() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            network: Some(network),
+            txindex: Some(Flag::Enabled),
+            fees: Some(Fees {
+                get_transaction: 10,
+                get_transaction_maximum: 100,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let coinbase = genesis_block(network).txdata()[0].clone();
+        get_transaction(get_transaction_request(&coinbase)).unwrap();
+        assert_eq!(crate::runtime::get_cycles_balance(), 10);
+
+        // Queries aren't charged.
+        get_transaction_query(get_transaction_request(&coinbase)).unwrap();
+        assert_eq!(crate::runtime::get_cycles_balance(), 10);
+    }
+}
@@ -9,6 +9,7 @@ use ic_btc_interface::{GetUtxosError, GetUtxosResponse, Utxo as PublicUtxo, Utxo
 use ic_btc_types::{Block, BlockHash, OutPoint, Txid};
 use serde_bytes::ByteBuf;
 use std::str::FromStr;
+use std::{cell::RefCell, collections::VecDeque};
 
 // The maximum number of UTXOs that are allowed to be included in a single
 // `GetUtxosResponse`.
@@ -22,6 +23,57 @@ use std::str::FromStr;
 // than 10_000 `Utxo`s are returned in a single response.
 const MAX_UTXOS_PER_RESPONSE: usize = 1_000;
 
+// The key of a cached `get_utxos` response: the address, the requested page, if any, and the
+// tip of the main chain the response was computed at.
+type UtxosCacheKey = (String, Option<Vec<u8>>, BlockHash);
+
+thread_local! {
+    // A cache of `get_utxos` responses. It can be rebuilt at any time, so it's kept outside of
+    // the state, and is neither persisted across upgrades nor compared along with the state.
+    //
+    // Only update calls fill the cache, as the changes that a query call makes to the heap are
+    // discarded once the call returns. Query calls are still served from the cache when the
+    // response was computed by an earlier update call.
+    static UTXOS_CACHE: RefCell<UtxosCache> = RefCell::new(UtxosCache::default());
+}
+
+// An LRU cache of `get_utxos` responses. Each response holds a single page of UTXOs, so the
+// memory of the cache is bounded by its capacity.
+//
+// The responses are only valid for the tip they were computed at, so all the entries are
+// evicted as soon as the tip changes.
+#[derive(Default)]
+struct UtxosCache {
+    entries: VecDeque<(UtxosCacheKey, GetUtxosResponse)>,
+    num_misses: u64,
+}
+
+impl UtxosCache {
+    // Returns the cached response of the given key, marking it as the most recently used.
+    fn get(&mut self, key: &UtxosCacheKey) -> Option<GetUtxosResponse> {
+        // All the entries share the same tip, so checking one of them is enough.
+        if matches!(self.entries.front(), Some(((_, _, tip), _)) if *tip != key.2) {
+            self.entries.clear();
+            return None;
+        }
+
+        let i = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(i).expect("index must be valid");
+        let response = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(response)
+    }
+
+    // Adds the given freshly computed response to the cache, evicting the least recently used
+    // entries beyond the given capacity.
+    fn insert(&mut self, key: UtxosCacheKey, response: GetUtxosResponse, capacity: usize) {
+        while self.entries.len() >= capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, response));
+    }
+}
+
 // Various profiling stats for tracking the performance of `get_utxos`.
 #[derive(Default, Debug)]
 struct Stats {
@@ -48,7 +100,8 @@ fn get_utxos_private(
         match &request.filter {
             None => {
                 // No filter is specified. Return all UTXOs for the address.
-                get_utxos_internal(state, &request.address, 0, None, MAX_UTXOS_PER_RESPONSE)
+                // Only update calls, which are the ones charged, can fill the cache.
+                get_utxos_cached(state, &request.address, None, charge_fees)
             }
             Some(UtxosFilter::MinConfirmations(min_confirmations)) => {
                 // Return UTXOs with the requested number of confirmations.
@@ -60,13 +113,9 @@ fn get_utxos_private(
                     MAX_UTXOS_PER_RESPONSE,
                 )
             }
-            Some(UtxosFilter::Page(page)) => get_utxos_internal(
-                state,
-                &request.address,
-                0,
-                Some(page.to_vec()),
-                MAX_UTXOS_PER_RESPONSE,
-            ),
+            Some(UtxosFilter::Page(page)) => {
+                get_utxos_cached(state, &request.address, Some(page.to_vec()), charge_fees)
+            }
         }
     })?;
 
@@ -108,6 +157,66 @@ pub fn get_utxos_query(request: GetUtxosRequest) -> Result<GetUtxosResponse, Get
     get_utxos_private(request, false)
 }
 
+// Returns the UTXOs of the given address without a minimum number of confirmations, starting
+// from the given page, if any.
+//
+// The response is served from `UTXOS_CACHE` if the same page was already returned for the
+// current tip of the main chain. Without a minimum number of confirmations, the response only
+// depends on the main chain, so it remains valid for as long as the tip doesn't change.
+//
+// A computed response is only added to the cache if `fill_cache` is set, which must only be the
+// case in update calls. See `UTXOS_CACHE`.
+fn get_utxos_cached(
+    state: &State,
+    address: &str,
+    page: Option<Vec<u8>>,
+    fill_cache: bool,
+) -> Result<(GetUtxosResponse, Stats), GetUtxosError> {
+    let capacity = state.utxos_cache_capacity() as usize;
+    if capacity == 0 {
+        return get_utxos_internal(state, address, 0, page, MAX_UTXOS_PER_RESPONSE);
+    }
+
+    let key = (
+        address.to_string(),
+        page,
+        unstable_blocks::get_main_chain(&state.unstable_blocks)
+            .tip()
+            .block_hash(),
+    );
+    if let Some(response) = UTXOS_CACHE.with(|cache| cache.borrow_mut().get(&key)) {
+        let stats = Stats {
+            ins_total: performance_counter(),
+            ..Stats::default()
+        };
+        return Ok((response, stats));
+    }
+
+    let (response, stats) =
+        get_utxos_internal(state, address, 0, key.1.clone(), MAX_UTXOS_PER_RESPONSE)?;
+    UTXOS_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.num_misses += 1;
+        if fill_cache {
+            cache.insert(key, response.clone(), capacity);
+        }
+    });
+    Ok((response, stats))
+}
+
+// Clears the cache of `get_utxos` responses, e.g. because the state or the config that the
+// responses were computed with has changed.
+pub(crate) fn clear_utxos_cache() {
+    UTXOS_CACHE.with(|cache| *cache.borrow_mut() = UtxosCache::default());
+}
+
+// Returns the number of `get_utxos` responses that had to be computed rather than read from
+// the cache since it was last cleared.
+#[cfg(test)]
+fn utxos_cache_num_misses() -> u64 {
+    UTXOS_CACHE.with(|cache| cache.borrow().num_misses)
+}
+
 // Returns the set of UTXOs for a given bitcoin address.
 //
 // Transactions with confirmations < `min_confirmations` are not considered.
@@ -209,16 +318,18 @@ fn get_utxos_from_chain(
         });
     }
 
-    let mut address_utxos = state.get_utxos(address);
+    let address_utxos = state.get_utxos(address);
 
     let mut tip_block_hash = chain.first().block_hash();
     let mut tip_block_height = state.utxos.next_height();
 
     let blocks_with_depths_by_heights = state.unstable_blocks.blocks_with_depths_by_heights();
 
-    // Apply unstable blocks to the UTXO set.
+    // Collect the unstable blocks to apply to the UTXO set. They're applied lazily, as UTXOs
+    // are retrieved below, so that only the blocks needed for the requested page are walked.
     let ins_start = performance_counter();
-    for (i, block) in chain.into_chain().iter().enumerate() {
+    let mut blocks = vec![];
+    for (i, block) in chain.into_chain().into_iter().enumerate() {
         if get_stability_count(&blocks_with_depths_by_heights[i], block.block_hash())
             < min_confirmations as i32
         {
@@ -228,7 +339,7 @@ fn get_utxos_from_chain(
         }
         tip_block_hash = block.block_hash();
         tip_block_height = state.utxos.next_height() + (i as u32);
-        address_utxos.apply_block(block);
+        blocks.push(block);
     }
     stats.ins_apply_unstable_blocks = performance_counter() - ins_start;
 
@@ -239,8 +350,9 @@ fn get_utxos_from_chain(
     let (utxos_to_take, overflow) = utxo_limit.overflowing_add(1);
     assert!(!overflow, "overflow when computing utxos to take");
 
+    let mut address_utxos = address_utxos.into_lazy_iter(blocks, offset);
     let mut utxos: Vec<_> = address_utxos
-        .into_iter(offset)
+        .by_ref()
         .take(utxos_to_take)
         .map(|utxo| {
             // Convert UTXOs to their public representation.
@@ -257,6 +369,16 @@ fn get_utxos_from_chain(
         })
         .collect();
 
+    if let Some(max) = state.max_utxos_per_query {
+        // Count the remaining UTXOs to check whether the address exceeds the limit. Counting
+        // stops at `max + 1`, as the exact number of UTXOs isn't needed to reject the request.
+        let remaining = (max.saturating_add(1) as usize).saturating_sub(utxos.len());
+        let count = (utxos.len() + address_utxos.take(remaining).count()) as u64;
+        if count > max {
+            return Err(GetUtxosError::TooManyUtxos { count, max });
+        }
+    }
+
     // If there are remaining UTXOs, then add the pagination offset to the response.
     let rest = utxos.split_off(utxos.len().min(utxo_limit));
     let next_page = rest.first().map(|next| {
@@ -814,6 +936,7 @@ mod test {
 
         // Extend block 0 (again) with block 1 that spends the 1000 satoshis to address 3
         // This causes a fork.
+        let block_1_tx = tx;
         let tx = TransactionBuilder::new()
             .with_input(ic_btc_types::OutPoint::new(coinbase_tx.txid(), 0))
             .with_output(&address_3, 1000)
@@ -826,8 +949,8 @@ mod test {
             state::insert_block(state, block_1_prime.clone()).unwrap();
         });
 
-        // Because block 1 and block 1' contest with each other, neither of them are included
-        // in the UTXOs. Only the UTXOs of block 0 are returned.
+        // Block 1 and block 1' contest with each other. Block 1 was seen first, so it remains
+        // on the main chain and address 3 doesn't receive any UTXOs.
         assert_eq!(
             get_utxos(GetUtxosRequest {
                 address: address_2.to_string(),
@@ -835,9 +958,16 @@ mod test {
             })
             .unwrap(),
             GetUtxosResponse {
-                utxos: vec![],
-                tip_block_hash: block_0.block_hash().to_vec(),
-                tip_height: 1,
+                utxos: vec![Utxo {
+                    outpoint: OutPoint {
+                        txid: block_1_tx.txid().into(),
+                        vout: 0,
+                    },
+                    value: 1000,
+                    height: 2,
+                }],
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 2,
                 next_page: None,
             }
         );
@@ -849,18 +979,24 @@ mod test {
             .unwrap(),
             GetUtxosResponse {
                 utxos: vec![],
-                tip_block_hash: block_0.block_hash().to_vec(),
-                tip_height: 1,
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 2,
                 next_page: None,
             }
         );
+        // Address 1 has no UTXOs since they were spent by block 1.
         assert_eq!(
             get_utxos(GetUtxosRequest {
                 address: address_1.to_string(),
                 filter: None,
             })
             .unwrap(),
-            block_0_utxos
+            GetUtxosResponse {
+                utxos: vec![],
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 2,
+                next_page: None,
+            }
         );
 
         // Now extend block 1' with another block that transfers the funds to address 4.
@@ -940,6 +1076,318 @@ mod test {
         );
     }
 
+    #[test]
+    fn utxos_of_abandoned_fork_are_removed() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        crate::init(InitConfig {
+            stability_threshold: Some(2),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        // Create a block that gives 1000 satoshis to the address.
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(coinbase_tx.clone())
+            .build();
+        with_state_mut(|state| {
+            state::insert_block(state, block_1.clone()).unwrap();
+        });
+
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                filter: None,
+            })
+            .unwrap(),
+            GetUtxosResponse {
+                utxos: vec![Utxo {
+                    outpoint: OutPoint {
+                        txid: coinbase_tx.txid().into(),
+                        vout: 0,
+                    },
+                    value: 1000,
+                    height: 1,
+                }],
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 1,
+                next_page: None,
+            }
+        );
+
+        // Create a longer fork that doesn't include the address's UTXO.
+        let fork = BlockChainBuilder::fork(&genesis_block(network), 2).build();
+        with_state_mut(|state| {
+            for block in fork.iter() {
+                state::insert_block(state, block.clone()).unwrap();
+            }
+        });
+
+        // The fork is now the main chain, so the UTXO is no longer returned.
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                filter: None,
+            })
+            .unwrap(),
+            GetUtxosResponse {
+                utxos: vec![],
+                tip_block_hash: fork[1].block_hash().to_vec(),
+                tip_height: 2,
+                next_page: None,
+            }
+        );
+    }
+
+    #[test]
+    fn utxos_report_the_height_of_their_block() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(2),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+
+        // Build a chain where every other block pays the address, recording the height
+        // of each paying transaction.
+        let mut expected_heights = vec![];
+        let mut prev_header = *genesis_block(network).header();
+        for height in 1..=10 {
+            let mut block = BlockBuilder::with_prev_header(&prev_header);
+            if height % 2 == 1 {
+                let tx = TransactionBuilder::coinbase()
+                    .with_output(&address, height as u64)
+                    .build();
+                expected_heights.push((
+                    OutPoint {
+                        txid: tx.txid().into(),
+                        vout: 0,
+                    },
+                    height,
+                ));
+                block = block.with_transaction(tx);
+            }
+            let block = block.build();
+
+            with_state_mut(|state| {
+                state::insert_block(state, block.clone()).unwrap();
+                state::drain_ingestion(state);
+            });
+            prev_header = *block.header();
+        }
+
+        // Some of the UTXOs are in stable blocks, and others are in unstable blocks.
+        let stable_height = crate::with_state(|s| s.stable_height());
+        assert!(stable_height > 1 && stable_height <= 9);
+
+        let mut heights: Vec<_> = get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            filter: None,
+        })
+        .unwrap()
+        .utxos
+        .into_iter()
+        .map(|utxo| {
+            assert_eq!(utxo.value, utxo.height as u64);
+            (utxo.outpoint, utxo.height)
+        })
+        .collect();
+        heights.sort_by_key(|(_, height)| *height);
+
+        assert_eq!(heights, expected_heights);
+    }
+
+    #[test]
+    fn utxos_of_blocks_on_a_fork_are_excluded() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(10),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+
+        // Block 2 and its fork are at the same height and both pay the address, but only
+        // block 2 is on the main chain, as it's extended by block 3.
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let main_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(main_tx.clone())
+            .build();
+        let fork_block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 2000)
+                    .build(),
+            )
+            .build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+
+        with_state_mut(|state| {
+            for block in [block_1, fork_block_2, block_2, block_3.clone()] {
+                state::insert_block(state, block).unwrap();
+            }
+        });
+
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: address.to_string(),
+                filter: None,
+            })
+            .unwrap(),
+            GetUtxosResponse {
+                utxos: vec![Utxo {
+                    outpoint: OutPoint {
+                        txid: main_tx.txid().into(),
+                        vout: 0,
+                    },
+                    value: 1000,
+                    height: 2,
+                }],
+                tip_block_hash: block_3.block_hash().to_vec(),
+                tip_height: 3,
+                next_page: None,
+            }
+        );
+    }
+
+    #[test]
+    fn get_utxos_too_many_utxos() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(1),
+            network: Some(network),
+            max_utxos_per_query: Some(Some(2)),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+        let request = || GetUtxosRequest {
+            address: address.to_string(),
+            filter: None,
+        };
+
+        // Give the address one UTXO per block.
+        let mut prev_header = *genesis_block(network).header();
+        for value in 1..=3 {
+            let block = BlockBuilder::with_prev_header(&prev_header)
+                .with_transaction(
+                    TransactionBuilder::coinbase()
+                        .with_output(&address, value)
+                        .build(),
+                )
+                .build();
+            with_state_mut(|state| {
+                state::insert_block(state, block.clone()).unwrap();
+            });
+            prev_header = *block.header();
+
+            if value <= 2 {
+                assert_eq!(get_utxos(request()).unwrap().utxos.len(), value as usize);
+            }
+        }
+
+        // The address now has more UTXOs than the limit.
+        assert_eq!(
+            get_utxos(request()),
+            Err(GetUtxosError::TooManyUtxos { count: 3, max: 2 })
+        );
+    }
+
+    #[test]
+    fn get_utxos_is_served_from_cache() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(2),
+            network: Some(network),
+            utxos_cache_capacity: Some(10),
+            ..Default::default()
+        });
+
+        // Give the address one UTXO per block.
+        let address = random_p2pkh_address(network);
+        let mut prev_header = *genesis_block(network).header();
+        let mut insert_block_paying_address = || {
+            let block = BlockBuilder::with_prev_header(&prev_header)
+                .with_transaction(
+                    TransactionBuilder::coinbase()
+                        .with_output(&address, 1000)
+                        .build(),
+                )
+                .build();
+            prev_header = *block.header();
+            with_state_mut(|state| state::insert_block(state, block).unwrap());
+        };
+        insert_block_paying_address();
+
+        let request = |filter| GetUtxosRequest {
+            address: address.to_string(),
+            filter,
+        };
+
+        // A second identical query under an unchanged tip is served from the cache.
+        let response = get_utxos(request(None)).unwrap();
+        assert_eq!(response.utxos.len(), 1);
+        assert_eq!(get_utxos(request(None)).unwrap(), response);
+        assert_eq!(utxos_cache_num_misses(), 1);
+
+        // Queries with a minimum number of confirmations bypass the cache.
+        get_utxos(request(Some(UtxosFilter::MinConfirmations(2)))).unwrap();
+        assert_eq!(utxos_cache_num_misses(), 1);
+
+        // A tip change invalidates the cache.
+        insert_block_paying_address();
+        assert_eq!(get_utxos(request(None)).unwrap().utxos.len(), 2);
+        assert_eq!(utxos_cache_num_misses(), 2);
+    }
+
+    #[test]
+    fn get_utxos_query_is_served_from_but_does_not_fill_cache() {
+        let network = Network::Regtest;
+        crate::init(InitConfig {
+            stability_threshold: Some(2),
+            network: Some(network),
+            utxos_cache_capacity: Some(10),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build();
+        with_state_mut(|state| state::insert_block(state, block).unwrap());
+
+        let request = || GetUtxosRequest {
+            address: address.to_string(),
+            filter: None,
+        };
+
+        // Queries compute the response every time, as they don't fill the cache.
+        let response = get_utxos_query(request()).unwrap();
+        assert_eq!(response.utxos.len(), 1);
+        assert_eq!(get_utxos_query(request()).unwrap(), response);
+        assert_eq!(utxos_cache_num_misses(), 2);
+
+        // Once an update call fills the cache, queries are served from it.
+        assert_eq!(get_utxos(request()).unwrap(), response);
+        assert_eq!(utxos_cache_num_misses(), 3);
+        assert_eq!(get_utxos_query(request()).unwrap(), response);
+        assert_eq!(utxos_cache_num_misses(), 3);
+    }
+
     #[test]
     fn get_utxos_min_confirmations_greater_than_chain_height() {
         let network = Network::Regtest;
@@ -1355,9 +1803,12 @@ mod test {
             }
         });
 
-        // Because the forks are of equal length, `A`, the root of the fork,
-        // is considered the tip at zero confirmations.
-        assert_tip_at_confirmations(0, chain[0].block_hash());
+        // Because the forks are of equal length, the fork that was seen first is the main
+        // chain, and its tip `F` is considered the tip at zero confirmations.
+        assert_tip_at_confirmations(0, chain[5].block_hash());
+
+        // `A`, the root of the fork, is the tip at 1+ confirmations.
+        assert_tip_at_confirmations(1, chain[0].block_hash());
 
         // Extend the first fork by one block.
         let chain_6 = BlockBuilder::with_prev_header(chain[5].header()).build();
@@ -73,6 +73,11 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.unstable_blocks.num_tips() as f64,
             "The number of tips in the unstable block tree.",
         )?;
+        w.encode_gauge(
+            "unstable_blocks_fork_ratio",
+            state.unstable_blocks.fork_ratio(),
+            "The ratio of forks to blocks in the unstable block tree.",
+        )?;
         w.encode_gauge(
             "unstable_blocks_total",
             state::get_unstable_blocks(state).len() as f64,
@@ -117,6 +122,30 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.syncing_state.num_insert_block_errors as f64,
             "The number of errors occurred when inserting a block.",
         )?;
+        w.encode_gauge(
+            "orphan_pool_size",
+            state.orphan_pool.len() as f64,
+            "The number of blocks in the orphan pool.",
+        )?;
+        w.encode_counter(
+            "num_ingestion_inconsistencies",
+            state.syncing_state.num_ingestion_inconsistencies as f64,
+            "The number of inconsistencies found when ingesting stable blocks.",
+        )?;
+        w.encode_gauge(
+            "ingestion_halted",
+            if state.syncing_state.ingestion_halted {
+                1.0
+            } else {
+                0.0
+            },
+            "Whether ingesting stable blocks is halted due to an inconsistency.",
+        )?;
+        w.encode_counter(
+            "num_partial_response_timeouts",
+            state.syncing_state.num_partial_response_timeouts as f64,
+            "The number of partial responses discarded because they made no progress in time.",
+        )?;
 
         // Profiling
         encode_instruction_histogram(w, &state.metrics.get_utxos_total)?;
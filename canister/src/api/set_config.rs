@@ -1,14 +1,59 @@
+use crate::{
+    block_header_store::{validate_retention, RetentionError},
+    runtime::print,
+    state::{
+        validate_blocks_source, validate_fees, validate_utxos_cache_capacity,
+        CacheCapacityTooLarge, FeeError, SourceError,
+    },
+};
 use ic_btc_interface::SetConfigRequest;
 use std::convert::TryInto;
 
-pub async fn set_config(request: SetConfigRequest) {
+/// An error returned by `set_config` when the request contains an invalid value, in which
+/// case none of the request is applied.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SetConfigError {
+    InvalidFees(FeeError),
+    InvalidBlocksSource(SourceError),
+    InvalidHeaderRetention(RetentionError),
+    InvalidUtxosCacheCapacity(CacheCapacityTooLarge),
+}
+
+pub async fn set_config(request: SetConfigRequest) -> Result<(), SetConfigError> {
     if is_watchdog_caller() {
         // The watchdog canister can only set the API access flag.
         set_api_access(request);
+        Ok(())
     } else {
         verify_caller().await;
-        set_config_no_verification(request);
+        set_config_with_validation(request)
+    }
+}
+
+// Validates the request before applying it.
+//
+// Only requests to the `set_config` endpoint are validated. The config given on upgrade is
+// applied as-is, so that an upgrade never fails on a config that used to be accepted.
+fn set_config_with_validation(request: SetConfigRequest) -> Result<(), SetConfigError> {
+    if let Some(fees) = &request.fees {
+        validate_fees(fees).map_err(SetConfigError::InvalidFees)?;
+    }
+
+    if let Some(blocks_source) = request.blocks_source {
+        validate_blocks_source(blocks_source).map_err(SetConfigError::InvalidBlocksSource)?;
+    }
+
+    if let Some(Some(header_retention)) = &request.header_retention {
+        validate_retention(header_retention).map_err(SetConfigError::InvalidHeaderRetention)?;
+    }
+
+    if let Some(utxos_cache_capacity) = request.utxos_cache_capacity {
+        validate_utxos_cache_capacity(utxos_cache_capacity)
+            .map_err(SetConfigError::InvalidUtxosCacheCapacity)?;
     }
+
+    set_config_no_verification(request);
+    Ok(())
 }
 
 fn is_watchdog_caller() -> bool {
@@ -26,15 +71,18 @@ fn is_watchdog_caller() -> bool {
 fn set_api_access(request: SetConfigRequest) {
     crate::with_state_mut(|s| {
         if let Some(api_access) = request.api_access {
-            s.api_access = api_access;
+            s.set_api_access(api_access);
         }
     });
 }
 
 pub(crate) fn set_config_no_verification(request: SetConfigRequest) {
+    // The cached `get_utxos` responses may depend on the updated config, e.g. on the page size.
+    crate::api::clear_utxos_cache();
+
     crate::with_state_mut(|s| {
         if let Some(syncing) = request.syncing {
-            s.syncing_state.syncing = syncing;
+            s.set_syncing(syncing);
         }
 
         if let Some(fees) = request.fees {
@@ -50,7 +98,7 @@ pub(crate) fn set_config_no_verification(request: SetConfigRequest) {
         }
 
         if let Some(api_access) = request.api_access {
-            s.api_access = api_access;
+            s.set_api_access(api_access);
         }
 
         if let Some(disable_api_if_not_fully_synced) = request.disable_api_if_not_fully_synced {
@@ -64,6 +112,42 @@ pub(crate) fn set_config_no_verification(request: SetConfigRequest) {
         if let Some(lazily_evaluate_fee_percentiles) = request.lazily_evaluate_fee_percentiles {
             s.lazily_evaluate_fee_percentiles = lazily_evaluate_fee_percentiles;
         }
+
+        if let Some(max_utxos_per_query) = request.max_utxos_per_query {
+            s.max_utxos_per_query = max_utxos_per_query;
+        }
+
+        if let Some(fallback_fee_per_byte) = request.fallback_fee_per_byte {
+            s.fallback_fee_per_byte = fallback_fee_per_byte;
+        }
+
+        if let Some(header_retention) = request.header_retention {
+            // An invalid retention policy is ignored rather than failing the upgrade.
+            if let Err(err) = s.stable_block_headers.set_retention(header_retention) {
+                print(&format!(
+                    "Ignoring the invalid header retention {:?}: {:?}",
+                    header_retention, err
+                ));
+            }
+        }
+
+        if let Some(utxos_cache_capacity) = request.utxos_cache_capacity {
+            // An invalid cache capacity is ignored rather than failing the upgrade.
+            if let Err(err) = s.set_utxos_cache_capacity(utxos_cache_capacity) {
+                print(&format!(
+                    "Ignoring the invalid utxos cache capacity: {:?}",
+                    err
+                ));
+            }
+        }
+
+        if let Some(blocks_source) = request.blocks_source {
+            s.blocks_source = blocks_source;
+        }
+
+        if request.resume_ingestion == Some(true) {
+            s.syncing_state.ingestion_halted = false;
+        }
     });
 }
 
@@ -92,9 +176,9 @@ async fn verify_caller() {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{init, with_state};
+    use crate::{init, state::Endpoint, with_state};
     use candid::Principal;
-    use ic_btc_interface::{Fees, Flag, InitConfig};
+    use ic_btc_interface::{Fees, Flag, HeaderRetention, InitConfig};
     use proptest::prelude::*;
 
     #[test]
@@ -192,7 +276,8 @@ mod test {
                 send_transaction_per_byte,
                 get_block_headers_base,
                 get_block_headers_cycles_per_ten_instructions,
-                get_block_headers_maximum
+                get_block_headers_maximum,
+                ..Default::default()
             };
 
             set_config_no_verification(SetConfigRequest {
@@ -204,6 +289,95 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_set_invalid_fees() {
+        init(InitConfig::default());
+        let fees_before = with_state(|s| s.fees.clone());
+
+        // Invalid fees are rejected, along with the rest of the request.
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                fees: Some(Fees {
+                    get_balance: 10,
+                    get_balance_maximum: 5,
+                    ..Default::default()
+                }),
+                stability_threshold: Some(17),
+                ..Default::default()
+            }),
+            Err(SetConfigError::InvalidFees(FeeError::BaseAboveMaximum(
+                Endpoint::GetBalance
+            )))
+        );
+        with_state(|s| {
+            assert_eq!(s.fees, fees_before);
+            assert_ne!(s.unstable_blocks.stability_threshold(), 17);
+        });
+
+        // Valid fees are applied.
+        let fees = Fees {
+            get_balance: 10,
+            get_balance_maximum: 50,
+            ..Default::default()
+        };
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                fees: Some(fees.clone()),
+                ..Default::default()
+            }),
+            Ok(())
+        );
+        assert_eq!(with_state(|s| s.fees.clone()), fees);
+    }
+
+    #[test]
+    fn test_set_blocks_source() {
+        init(InitConfig::default());
+
+        // The anonymous principal is rejected.
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                blocks_source: Some(Principal::anonymous()),
+                ..Default::default()
+            }),
+            Err(SetConfigError::InvalidBlocksSource(
+                SourceError::AnonymousPrincipal
+            ))
+        );
+        assert_eq!(
+            with_state(|s| s.blocks_source),
+            Principal::management_canister()
+        );
+
+        // Any other principal is accepted.
+        let blocks_source = Principal::from_slice(&[1]);
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                blocks_source: Some(blocks_source),
+                ..Default::default()
+            }),
+            Ok(())
+        );
+        assert_eq!(with_state(|s| s.blocks_source), blocks_source);
+    }
+
+    #[test]
+    fn test_set_fees_on_upgrade_are_not_validated() {
+        init(InitConfig::default());
+
+        // A config that was accepted before fees were validated is still applied on upgrade.
+        let fees = Fees {
+            get_current_fee_percentiles: 123,
+            get_current_fee_percentiles_maximum: 0,
+            ..Default::default()
+        };
+        set_config_no_verification(SetConfigRequest {
+            fees: Some(fees.clone()),
+            ..Default::default()
+        });
+        assert_eq!(with_state(|s| s.fees.clone()), fees);
+    }
+
     #[test]
     fn test_set_config_no_verification_for_setting_api_access() {
         init(InitConfig::default());
@@ -264,4 +438,167 @@ mod test {
             assert_eq!(with_state(|s| s.lazily_evaluate_fee_percentiles), *flag);
         }
     }
+
+    #[test]
+    fn test_set_max_utxos_per_query() {
+        init(InitConfig::default());
+
+        for max_utxos_per_query in [Some(100), None, Some(0)] {
+            set_config_no_verification(SetConfigRequest {
+                max_utxos_per_query: Some(max_utxos_per_query),
+                ..Default::default()
+            });
+
+            assert_eq!(with_state(|s| s.max_utxos_per_query), max_utxos_per_query);
+        }
+    }
+
+    #[test]
+    fn test_set_fallback_fee_per_byte() {
+        init(InitConfig::default());
+
+        for fallback_fee_per_byte in [Some(2_000), None, Some(0)] {
+            set_config_no_verification(SetConfigRequest {
+                fallback_fee_per_byte: Some(fallback_fee_per_byte),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                with_state(|s| s.fallback_fee_per_byte),
+                fallback_fee_per_byte
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_header_retention() {
+        init(InitConfig::default());
+
+        let retention = HeaderRetention {
+            depth: crate::block_header_store::MIN_RETENTION_DEPTH,
+            checkpoint_interval: 100,
+        };
+        for header_retention in [Some(retention), None] {
+            set_config_no_verification(SetConfigRequest {
+                header_retention: Some(header_retention),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                with_state(|s| s.stable_block_headers.retention()),
+                header_retention
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_invalid_header_retention() {
+        init(InitConfig::default());
+
+        // Invalid retention policies are rejected, along with the rest of the request.
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                header_retention: Some(Some(HeaderRetention {
+                    depth: 0,
+                    checkpoint_interval: 100,
+                })),
+                stability_threshold: Some(17),
+                ..Default::default()
+            }),
+            Err(SetConfigError::InvalidHeaderRetention(
+                RetentionError::DepthTooSmall {
+                    depth: 0,
+                    min: crate::block_header_store::MIN_RETENTION_DEPTH,
+                }
+            ))
+        );
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                header_retention: Some(Some(HeaderRetention {
+                    depth: crate::block_header_store::MIN_RETENTION_DEPTH,
+                    checkpoint_interval: 0,
+                })),
+                ..Default::default()
+            }),
+            Err(SetConfigError::InvalidHeaderRetention(
+                RetentionError::ZeroCheckpointInterval
+            ))
+        );
+        with_state(|s| {
+            assert_eq!(s.stable_block_headers.retention(), None);
+            assert_ne!(s.unstable_blocks.stability_threshold(), 17);
+        });
+    }
+
+    #[test]
+    fn test_set_invalid_header_retention_on_upgrade_is_ignored() {
+        init(InitConfig::default());
+
+        // An invalid retention policy given on upgrade is ignored, while the rest of the
+        // config is still applied.
+        set_config_no_verification(SetConfigRequest {
+            header_retention: Some(Some(HeaderRetention {
+                depth: 0,
+                checkpoint_interval: 100,
+            })),
+            stability_threshold: Some(17),
+            ..Default::default()
+        });
+        with_state(|s| {
+            assert_eq!(s.stable_block_headers.retention(), None);
+            assert_eq!(s.unstable_blocks.stability_threshold(), 17);
+        });
+    }
+
+    #[test]
+    fn test_set_utxos_cache_capacity() {
+        init(InitConfig::default());
+
+        for utxos_cache_capacity in [100, 0] {
+            set_config_no_verification(SetConfigRequest {
+                utxos_cache_capacity: Some(utxos_cache_capacity),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                with_state(|s| s.utxos_cache_capacity()),
+                utxos_cache_capacity
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_too_large_utxos_cache_capacity() {
+        init(InitConfig::default());
+
+        let capacity = crate::state::MAX_UTXOS_CACHE_CAPACITY + 1;
+        assert_eq!(
+            set_config_with_validation(SetConfigRequest {
+                utxos_cache_capacity: Some(capacity),
+                stability_threshold: Some(17),
+                ..Default::default()
+            }),
+            Err(SetConfigError::InvalidUtxosCacheCapacity(
+                CacheCapacityTooLarge {
+                    capacity,
+                    max: crate::state::MAX_UTXOS_CACHE_CAPACITY,
+                }
+            ))
+        );
+        with_state(|s| {
+            assert_eq!(s.utxos_cache_capacity(), 0);
+            assert_ne!(s.unstable_blocks.stability_threshold(), 17);
+        });
+
+        // On upgrade, the invalid capacity is ignored while the rest of the config is applied.
+        set_config_no_verification(SetConfigRequest {
+            utxos_cache_capacity: Some(capacity),
+            stability_threshold: Some(17),
+            ..Default::default()
+        });
+        with_state(|s| {
+            assert_eq!(s.utxos_cache_capacity(), 0);
+            assert_eq!(s.unstable_blocks.stability_threshold(), 17);
+        });
+    }
 }
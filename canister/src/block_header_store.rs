@@ -1,11 +1,19 @@
-use crate::{memory::Memory, types::BlockHeaderBlob};
+use crate::{
+    memory::Memory,
+    types::{BlockHeaderBlob, Slicing},
+};
 use bitcoin::consensus::{Decodable, Encodable};
 use bitcoin::BlockHeader;
-use ic_btc_interface::Height;
+use ic_btc_interface::{HeaderRetention, Height};
 use ic_btc_types::{Block, BlockHash};
+use ic_btc_validation::DIFFICULTY_ADJUSTMENT_INTERVAL;
 use ic_stable_structures::StableBTreeMap;
 use serde::{Deserialize, Serialize};
 
+/// The minimum depth of a `HeaderRetention`. Validating a new header requires the headers up to
+/// a difficulty adjustment interval back, so these are always retained.
+pub const MIN_RETENTION_DEPTH: u32 = DIFFICULTY_ADJUSTMENT_INTERVAL;
+
 /// Stores block headers and indexes them by block hash and height.
 #[derive(Serialize, Deserialize)]
 pub struct BlockHeaderStore {
@@ -18,6 +26,78 @@ pub struct BlockHeaderStore {
     // NOTE: Stable structures don't need to be serialized.
     #[serde(skip, default = "init_block_heights")]
     pub block_heights: StableBTreeMap<Height, BlockHash, Memory>,
+
+    /// A map of a block hash to its corresponding block height.
+    ///
+    /// NOTE: Headers inserted before this index was introduced aren't in it until
+    /// `backfill_block_hash_heights` is done.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "init_block_hash_heights")]
+    pub block_hash_heights: StableBTreeMap<BlockHash, Height, Memory>,
+
+    /// An optional policy for pruning old headers. All headers are kept if not set.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    retention: Option<HeaderRetention>,
+
+    /// The height of the highest header in the store, or `None` if it's unknown, i.e. if the
+    /// store is empty or predates this field and no header has been inserted since.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    tip_height: Option<Height>,
+
+    /// The lowest height from which all the headers up to the tip are retained. Headers below
+    /// it have been pruned by a retention policy, except for checkpoints.
+    ///
+    /// The headers are pruned in order of height, so this is also the height from which
+    /// `prune_headers` resumes pruning.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    retained_from: Height,
+
+    /// The height from which `block_hash_heights` is yet to be backfilled, or `None` if the
+    /// index is complete.
+    /// NOTE: serde(default) is used here for backward-compatibility. A store that predates
+    /// this field also predates the index, so it's backfilled from the start.
+    #[serde(default = "default_hash_heights_backfill")]
+    hash_heights_backfill: Option<Height>,
+}
+
+fn default_hash_heights_backfill() -> Option<Height> {
+    Some(0)
+}
+
+/// An error returned by `BlockHeaderStore::set_retention`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetentionError {
+    /// The depth is below `MIN_RETENTION_DEPTH`.
+    DepthTooSmall { depth: u32, min: u32 },
+    /// The checkpoint interval is zero.
+    ZeroCheckpointInterval,
+}
+
+/// Checks that the given retention policy can be set with `BlockHeaderStore::set_retention`.
+pub fn validate_retention(retention: &HeaderRetention) -> Result<(), RetentionError> {
+    if retention.depth < MIN_RETENTION_DEPTH {
+        return Err(RetentionError::DepthTooSmall {
+            depth: retention.depth,
+            min: MIN_RETENTION_DEPTH,
+        });
+    }
+    if retention.checkpoint_interval == 0 {
+        return Err(RetentionError::ZeroCheckpointInterval);
+    }
+
+    Ok(())
+}
+
+/// An error returned when some of the requested headers have been pruned.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HeadersPruned;
+
+// Returns true if the header at the given height is retained as a checkpoint.
+fn is_checkpoint(retention: &HeaderRetention, height: Height) -> bool {
+    height % retention.checkpoint_interval == 0
 }
 
 // NOTE: `PartialEq` is only available in tests as it would be impractically
@@ -28,6 +108,11 @@ impl PartialEq for BlockHeaderStore {
         use crate::test_utils::is_stable_btreemap_equal;
         is_stable_btreemap_equal(&self.block_headers, &other.block_headers)
             && is_stable_btreemap_equal(&self.block_heights, &other.block_heights)
+            && is_stable_btreemap_equal(&self.block_hash_heights, &other.block_hash_heights)
+            && self.retention == other.retention
+            && self.tip_height == other.tip_height
+            && self.retained_from == other.retained_from
+            && self.hash_heights_backfill == other.hash_heights_backfill
     }
 }
 
@@ -36,7 +121,92 @@ impl BlockHeaderStore {
         Self {
             block_headers: init_block_headers(),
             block_heights: init_block_heights(),
+            block_hash_heights: init_block_hash_heights(),
+            retention: None,
+            tip_height: None,
+            retained_from: 0,
+            hash_heights_backfill: None,
+        }
+    }
+
+    /// Indexes the heights of the headers that were inserted before `block_hash_heights` was
+    /// introduced, resuming from where the previous call left off.
+    ///
+    /// Returns `Slicing::Paused` if `should_time_slice` asked to stop before the index is complete.
+    pub fn backfill_block_hash_heights(
+        &mut self,
+        mut should_time_slice: impl FnMut() -> bool,
+    ) -> Slicing<(), ()> {
+        let start = match self.hash_heights_backfill {
+            Some(start) => start,
+            None => return Slicing::Done(()),
+        };
+
+        for (height, block_hash) in self.block_heights.range(start..) {
+            self.block_hash_heights.insert(block_hash, height);
+
+            if should_time_slice() {
+                self.hash_heights_backfill = Some(height + 1);
+                return Slicing::Paused(());
+            }
         }
+
+        self.hash_heights_backfill = None;
+        Slicing::Done(())
+    }
+
+    /// Returns the retention policy of the store, if any.
+    pub fn retention(&self) -> Option<HeaderRetention> {
+        self.retention
+    }
+
+    /// Sets the retention policy of the store.
+    ///
+    /// Headers that are no longer retained are pruned incrementally by `prune_headers`, as
+    /// there may be too many of them to prune in a single message. Pruning resumes from the
+    /// lowest retained height, so headers that have already been pruned aren't visited again.
+    ///
+    /// Pruned headers can no longer be retrieved from the store, so the retained depth must be
+    /// at least `MIN_RETENTION_DEPTH` for new headers to be validated.
+    pub fn set_retention(
+        &mut self,
+        retention: Option<HeaderRetention>,
+    ) -> Result<(), RetentionError> {
+        if let Some(retention) = &retention {
+            validate_retention(retention)?;
+        }
+
+        self.retention = retention;
+        Ok(())
+    }
+
+    /// Prunes the headers that fell out of the retained window before the retention policy was
+    /// set, resuming from where the previous call left off. Headers that fall out of the window
+    /// afterwards are pruned as they're inserted.
+    ///
+    /// The tip height of a store that predates tracking it is only known once a header is
+    /// inserted, so pruning is deferred until then rather than scanning the store for it.
+    ///
+    /// Returns `Slicing::Paused` if `should_time_slice` asked to stop before pruning is complete.
+    pub fn prune_headers(
+        &mut self,
+        mut should_time_slice: impl FnMut() -> bool,
+    ) -> Slicing<(), ()> {
+        let (retention, tip_height) = match (self.retention, self.tip_height) {
+            (Some(retention), Some(tip_height)) => (retention, tip_height),
+            _ => return Slicing::Done(()),
+        };
+
+        let window_start = (tip_height + 1).saturating_sub(retention.depth);
+        while self.retained_from < window_start {
+            self.prune_lowest_retained(&retention);
+
+            if should_time_slice() {
+                return Slicing::Paused(());
+            }
+        }
+
+        Slicing::Done(())
     }
 
     /// Inserts a block's header and hash into the store.
@@ -52,9 +222,55 @@ impl BlockHeaderStore {
     }
 
     /// Inserts a block's header and hash into the store.
+    ///
+    /// If a retention policy is set, the header that falls out of the retained window is pruned
+    /// unless it's a checkpoint. If older headers are still to be pruned by `prune_headers`, the
+    /// header is left for it to prune.
     pub fn insert(&mut self, block_hash: BlockHash, header_blob: BlockHeaderBlob, height: Height) {
         self.block_headers.insert(block_hash.clone(), header_blob);
+        self.block_hash_heights.insert(block_hash.clone(), height);
         self.block_heights.insert(height, block_hash);
+        self.tip_height = Some(
+            self.tip_height
+                .map_or(height, |tip_height| tip_height.max(height)),
+        );
+
+        if let Some(retention) = self.retention {
+            if height.checked_sub(retention.depth) == Some(self.retained_from) {
+                self.prune_lowest_retained(&retention);
+            }
+        }
+    }
+
+    // Prunes the header at the lowest retained height, unless it's a checkpoint, and moves
+    // the lowest retained height up by one.
+    fn prune_lowest_retained(&mut self, retention: &HeaderRetention) {
+        let height = self.retained_from;
+        if !is_checkpoint(retention, height) {
+            if let Some(block_hash) = self.block_heights.remove(&height) {
+                self.block_headers.remove(&block_hash);
+                self.block_hash_heights.remove(&block_hash);
+            }
+        }
+        self.retained_from = height + 1;
+    }
+
+    /// Returns true if any header may have been pruned by a retention policy.
+    pub fn has_pruned_headers(&self) -> bool {
+        self.retained_from > 0
+    }
+
+    /// Returns an error if some of the headers at the given heights may have been pruned by a
+    /// retention policy, i.e. if the range starts below the lowest retained height.
+    pub fn check_retained(
+        &self,
+        heights: &std::ops::RangeInclusive<Height>,
+    ) -> Result<(), HeadersPruned> {
+        if *heights.start() < self.retained_from {
+            Err(HeadersPruned)
+        } else {
+            Ok(())
+        }
     }
 
     pub fn get_with_block_hash(&self, block_hash: &BlockHash) -> Option<BlockHeader> {
@@ -63,6 +279,21 @@ impl BlockHeaderStore {
             .map(deserialize_block_header)
     }
 
+    /// Returns the height and header of the block with the given hash, if it's in the store.
+    ///
+    /// NOTE: Headers that predate `block_hash_heights` aren't found until
+    /// `backfill_block_hash_heights` is done.
+    pub fn get_by_hash(&self, block_hash: &BlockHash) -> Option<(Height, BlockHeader)> {
+        let height = self.block_hash_heights.get(block_hash)?;
+        let header = self.get_with_block_hash(block_hash)?;
+        Some((height, header))
+    }
+
+    /// Returns the hash of the block at the given height, if it's in the store.
+    pub fn get_block_hash_with_height(&self, height: Height) -> Option<BlockHash> {
+        self.block_heights.get(&height)
+    }
+
     pub fn get_with_height(&self, height: u32) -> Option<BlockHeader> {
         self.block_heights.get(&height).map(|block_hash| {
             self.block_headers
@@ -73,17 +304,23 @@ impl BlockHeaderStore {
     }
 
     /// Returns iterator on block headers in the range `heights`.
+    ///
+    /// Returns an error if the range starts below the lowest retained height, as the returned
+    /// headers would otherwise not be contiguous (see `check_retained`).
     pub fn get_block_headers_in_range(
         &self,
         heights: std::ops::RangeInclusive<Height>,
-    ) -> impl Iterator<Item = BlockHeaderBlob> + '_ {
-        self.block_heights
+    ) -> Result<impl Iterator<Item = BlockHeaderBlob> + '_, HeadersPruned> {
+        self.check_retained(&heights)?;
+
+        Ok(self
+            .block_heights
             .range(heights)
-            .map(move |(_, block_hash)| self.block_headers.get(&block_hash).unwrap())
+            .map(move |(_, block_hash)| self.block_headers.get(&block_hash).unwrap()))
     }
 }
 
-fn deserialize_block_header(block_header_blob: BlockHeaderBlob) -> BlockHeader {
+pub(crate) fn deserialize_block_header(block_header_blob: BlockHeaderBlob) -> BlockHeader {
     BlockHeader::consensus_decode(block_header_blob.as_slice())
         .expect("block header decoding must succeed")
 }
@@ -96,14 +333,265 @@ fn init_block_heights() -> StableBTreeMap<u32, BlockHash, Memory> {
     StableBTreeMap::init(crate::memory::get_block_heights_memory())
 }
 
+fn init_block_hash_heights() -> StableBTreeMap<BlockHash, u32, Memory> {
+    StableBTreeMap::init(crate::memory::get_block_hash_heights_memory())
+}
+
 #[cfg(test)]
 mod test {
     use bitcoin::consensus::Encodable;
     use proptest::proptest;
 
     use crate::{
-        block_header_store::BlockHeaderStore, test_utils::BlockBuilder, types::BlockHeaderBlob,
+        block_header_store::{
+            default_hash_heights_backfill, BlockHeaderStore, HeadersPruned, RetentionError,
+            MIN_RETENTION_DEPTH,
+        },
+        test_utils::BlockBuilder,
+        types::{BlockHeaderBlob, Slicing},
     };
+    use bitcoin::BlockHeader;
+    use ic_btc_interface::HeaderRetention;
+
+    // Inserts a chain of `num_blocks` blocks into the store and returns their headers.
+    fn insert_chain(store: &mut BlockHeaderStore, num_blocks: u32) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for i in 0..num_blocks {
+            let block = match headers.last() {
+                Some(prev_header) => BlockBuilder::with_prev_header(prev_header).build(),
+                None => BlockBuilder::genesis().build(),
+            };
+            headers.push(*block.header());
+            store.insert_block(&block, i);
+        }
+        headers
+    }
+
+    // Asserts that the store only contains the headers at the given heights.
+    fn assert_retained_heights(
+        store: &BlockHeaderStore,
+        headers: &[BlockHeader],
+        expected_heights: &[u32],
+    ) {
+        for (height, header) in headers.iter().enumerate() {
+            let height = height as u32;
+            if expected_heights.contains(&height) {
+                assert_eq!(store.get_with_height(height), Some(*header));
+                assert_eq!(
+                    store.get_with_block_hash(&header.block_hash().into()),
+                    Some(*header)
+                );
+            } else {
+                assert_eq!(store.get_with_height(height), None);
+                assert_eq!(store.get_with_block_hash(&header.block_hash().into()), None);
+            }
+        }
+        assert_eq!(store.block_heights.len(), expected_heights.len() as u64);
+        assert_eq!(store.block_headers.len(), expected_heights.len() as u64);
+        assert_eq!(
+            store.block_hash_heights.len(),
+            expected_heights.len() as u64
+        );
+    }
+
+    #[test]
+    fn get_by_hash() {
+        let mut store = BlockHeaderStore::init();
+        let headers = insert_chain(&mut store, 10);
+
+        for (height, header) in headers.iter().enumerate() {
+            assert_eq!(
+                store.get_by_hash(&header.block_hash().into()),
+                Some((height as u32, *header))
+            );
+        }
+
+        let unknown_block = BlockBuilder::with_prev_header(&headers[9]).build();
+        assert_eq!(store.get_by_hash(&unknown_block.block_hash()), None);
+    }
+
+    #[test]
+    fn backfills_block_hash_heights() {
+        let mut store = BlockHeaderStore::init();
+        let headers = insert_chain(&mut store, 10);
+
+        // Simulate headers that were inserted before the index existed.
+        for header in headers.iter() {
+            store.block_hash_heights.remove(&header.block_hash().into());
+        }
+        store.hash_heights_backfill = default_hash_heights_backfill();
+        assert_eq!(store.get_by_hash(&headers[4].block_hash().into()), None);
+
+        // Backfill the index, pausing after every header.
+        let mut num_slices = 0;
+        while store.backfill_block_hash_heights(|| true) == Slicing::Paused(()) {
+            num_slices += 1;
+        }
+        assert_eq!(num_slices, headers.len());
+
+        for (height, header) in headers.iter().enumerate() {
+            assert_eq!(
+                store.get_by_hash(&header.block_hash().into()),
+                Some((height as u32, *header))
+            );
+        }
+        assert_eq!(
+            store.backfill_block_hash_heights(|| true),
+            Slicing::Done(())
+        );
+    }
+
+    #[test]
+    fn prunes_headers_outside_of_retention_window() {
+        let mut store = BlockHeaderStore::init();
+        store
+            .set_retention(Some(HeaderRetention {
+                depth: MIN_RETENTION_DEPTH,
+                checkpoint_interval: 25,
+            }))
+            .unwrap();
+
+        let num_headers = MIN_RETENTION_DEPTH + 100;
+        let headers = insert_chain(&mut store, num_headers);
+
+        let expected_heights: Vec<u32> = vec![0, 25, 50, 75]
+            .into_iter()
+            .chain(100..num_headers)
+            .collect();
+        assert_retained_heights(&store, &headers, &expected_heights);
+    }
+
+    #[test]
+    fn setting_retention_prunes_existing_headers() {
+        let mut store = BlockHeaderStore::init();
+        let num_headers = MIN_RETENTION_DEPTH + 50;
+        let headers = insert_chain(&mut store, num_headers);
+        assert_retained_heights(&store, &headers, &(0..num_headers).collect::<Vec<_>>());
+
+        store
+            .set_retention(Some(HeaderRetention {
+                depth: MIN_RETENTION_DEPTH,
+                checkpoint_interval: 20,
+            }))
+            .unwrap();
+
+        // Setting the retention doesn't prune headers by itself.
+        assert_retained_heights(&store, &headers, &(0..num_headers).collect::<Vec<_>>());
+
+        // Prune the headers, pausing after every header below the retained window.
+        let mut num_slices = 0;
+        while store.prune_headers(|| true) == Slicing::Paused(()) {
+            num_slices += 1;
+        }
+        assert_eq!(num_slices, 50);
+        assert_eq!(store.prune_headers(|| true), Slicing::Done(()));
+
+        let expected_heights: Vec<u32> =
+            vec![0, 20, 40].into_iter().chain(50..num_headers).collect();
+        assert_retained_heights(&store, &headers, &expected_heights);
+    }
+
+    #[test]
+    fn removing_retention_stops_pruning() {
+        let mut store = BlockHeaderStore::init();
+        let num_headers = MIN_RETENTION_DEPTH + 50;
+        let headers = insert_chain(&mut store, num_headers);
+
+        store
+            .set_retention(Some(HeaderRetention {
+                depth: MIN_RETENTION_DEPTH,
+                checkpoint_interval: 20,
+            }))
+            .unwrap();
+        assert_eq!(store.prune_headers(|| true), Slicing::Paused(()));
+        store.set_retention(None).unwrap();
+        assert_eq!(store.prune_headers(|| true), Slicing::Done(()));
+
+        // Pruning stopped after visiting the first header, which is a checkpoint.
+        let expected_heights: Vec<u32> = (0..num_headers).collect();
+        assert_retained_heights(&store, &headers, &expected_heights);
+    }
+
+    #[test]
+    fn setting_retention_again_resumes_pruning() {
+        let mut store = BlockHeaderStore::init();
+        let num_headers = MIN_RETENTION_DEPTH + 50;
+        let headers = insert_chain(&mut store, num_headers);
+
+        let retention = HeaderRetention {
+            depth: MIN_RETENTION_DEPTH,
+            checkpoint_interval: 20,
+        };
+        store.set_retention(Some(retention)).unwrap();
+        for _ in 0..30 {
+            assert_eq!(store.prune_headers(|| true), Slicing::Paused(()));
+        }
+
+        // Setting the retention again doesn't visit the pruned headers again.
+        store.set_retention(Some(retention)).unwrap();
+        let mut num_slices = 0;
+        while store.prune_headers(|| true) == Slicing::Paused(()) {
+            num_slices += 1;
+        }
+        assert_eq!(num_slices, 20);
+
+        let expected_heights: Vec<u32> =
+            vec![0, 20, 40].into_iter().chain(50..num_headers).collect();
+        assert_retained_heights(&store, &headers, &expected_heights);
+        assert!(store.check_retained(&(50..=num_headers)).is_ok());
+        assert_eq!(store.check_retained(&(40..=60)), Err(HeadersPruned));
+    }
+
+    #[test]
+    fn rejects_invalid_retention() {
+        let mut store = BlockHeaderStore::init();
+        insert_chain(&mut store, 10);
+
+        assert_eq!(
+            store.set_retention(Some(HeaderRetention {
+                depth: 0,
+                checkpoint_interval: 20,
+            })),
+            Err(RetentionError::DepthTooSmall {
+                depth: 0,
+                min: MIN_RETENTION_DEPTH
+            })
+        );
+        assert_eq!(
+            store.set_retention(Some(HeaderRetention {
+                depth: MIN_RETENTION_DEPTH,
+                checkpoint_interval: 0,
+            })),
+            Err(RetentionError::ZeroCheckpointInterval)
+        );
+        assert_eq!(store.retention, None);
+        assert!(!store.has_pruned_headers());
+    }
+
+    #[test]
+    fn get_block_headers_in_pruned_range_returns_error() {
+        let mut store = BlockHeaderStore::init();
+        store
+            .set_retention(Some(HeaderRetention {
+                depth: MIN_RETENTION_DEPTH,
+                checkpoint_interval: 25,
+            }))
+            .unwrap();
+        let num_headers = MIN_RETENTION_DEPTH + 100;
+        let headers = insert_chain(&mut store, num_headers);
+
+        assert!(store.get_block_headers_in_range(0..=25).is_err());
+        assert!(store.get_block_headers_in_range(90..=110).is_err());
+
+        // Ranges of retained headers, including ones that extend above the tip, are returned.
+        let retained: Vec<BlockHeaderBlob> = store
+            .get_block_headers_in_range(100..=num_headers + 10)
+            .unwrap()
+            .collect();
+        let expected: Vec<BlockHeaderBlob> =
+            headers[100..].iter().map(BlockHeaderBlob::from).collect();
+        assert_eq!(retained, expected);
+    }
 
     #[test]
     fn test_get_block_headers_in_range() {
@@ -126,7 +614,7 @@ mod test {
             range_length in 1..=block_num)|{
                 let requested_end = start_range + range_length - 1;
 
-                let res: Vec<BlockHeaderBlob>= store.get_block_headers_in_range(std::ops::RangeInclusive::new(start_range as u32, requested_end as u32)).collect();
+                let res: Vec<BlockHeaderBlob>= store.get_block_headers_in_range(std::ops::RangeInclusive::new(start_range as u32, requested_end as u32)).unwrap().collect();
 
                 let end_range = std::cmp::min(requested_end, block_num - 1);
 
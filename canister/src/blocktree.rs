@@ -1,327 +1,56 @@
-use ic_btc_interface::Network;
+//! The tree of unstable blocks, built on the `std`-free core in `ic_btc_blocktree`.
+//!
+//! The aliases below fix the block type to `ic_btc_types::Block`, whose integration with the
+//! core comes from its `bitcoin` feature. Trees are serialized with the `serde` module.
 use ic_btc_types::{Block, BlockHash};
-use std::fmt;
-mod serde;
-
-/// Represents a non-empty block chain as:
-/// * the first block of the chain
-/// * the successors to this block (which can be an empty list)
-#[derive(Debug, PartialEq, Eq)]
-pub struct BlockChain<'a> {
-    // The first block of this `BlockChain`, i.e. the one at the lowest height.
-    first: &'a Block,
-    // The successor blocks of this `BlockChain`, i.e. the chain after the
-    // `first` block.
-    successors: Vec<&'a Block>,
-}
-
-impl<'a> BlockChain<'a> {
-    /// Creates a new `BlockChain` with the given `first` block and an empty list
-    /// of successors.
-    pub fn new(first: &'a Block) -> Self {
-        Self {
-            first,
-            successors: vec![],
-        }
-    }
-
-    /// This is only useful for tests to simplify the creation of a `BlockChain`.
-    #[cfg(test)]
-    pub fn new_with_successors(first: &'a Block, successors: Vec<&'a Block>) -> Self {
-        Self { first, successors }
-    }
-
-    /// Appends a new block to the list of `successors` of this `BlockChain`.
-    pub fn push(&mut self, block: &'a Block) {
-        self.successors.push(block);
-    }
-
-    /// Returns the length of this `BlockChain`.
-    pub fn len(&self) -> usize {
-        self.successors.len() + 1
-    }
-
-    pub fn first(&self) -> &'a Block {
-        self.first
-    }
-
-    pub fn tip(&self) -> &'a Block {
-        match self.successors.last() {
-            None => {
-                // The chain consists of only one block, and that is the tip.
-                self.first
-            }
-            Some(tip) => tip,
-        }
-    }
-
-    /// Consumes this `BlockChain` and returns the entire chain of blocks.
-    pub fn into_chain(self) -> Vec<&'a Block> {
-        let mut chain = vec![self.first];
-        chain.extend(self.successors);
-        chain
-    }
-}
-
-/// Error returned when attempting to create a `BlockChain` out of an empty
-/// list of blocks.
-#[derive(Debug)]
-pub struct EmptyChainError {}
-
-impl fmt::Display for EmptyChainError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "cannot create a `BlockChain` from an empty chain")
-    }
-}
-
-/// Maintains a tree of connected blocks.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct BlockTree {
-    pub root: Block,
-    pub children: Vec<BlockTree>,
-}
-
-impl BlockTree {
-    /// Creates a new `BlockTree` with the given block as its root.
-    pub fn new(root: Block) -> Self {
-        Self {
-            root,
-            children: vec![],
-        }
-    }
-
-    /// Returns all blocks in the tree with their depths
-    /// separated by heights.
-    pub fn blocks_with_depths_by_heights(&self) -> Vec<Vec<(&Block, u32)>> {
-        let mut blocks_with_depths_by_heights: Vec<Vec<(&Block, u32)>> = vec![vec![]];
-        self.blocks_with_depths_by_heights_helper(&mut blocks_with_depths_by_heights, 0);
-        blocks_with_depths_by_heights
-    }
-
-    fn blocks_with_depths_by_heights_helper<'a>(
-        &'a self,
-        blocks_with_depth_by_height: &mut Vec<Vec<(&'a Block, u32)>>,
-        height: usize,
-    ) -> u32 {
-        let mut depth: u32 = 0;
-        for child in self.children.iter() {
-            depth = std::cmp::max(
-                depth,
-                child.blocks_with_depths_by_heights_helper(blocks_with_depth_by_height, height + 1),
-            );
-        }
-        depth += 1;
-
-        if height >= blocks_with_depth_by_height.len() {
-            blocks_with_depth_by_height.resize(height + 1, vec![]);
-        }
-
-        blocks_with_depth_by_height[height].push((&self.root, depth));
-
-        depth
-    }
-
-    /// Returns the number of tips in the tree.
-    pub fn num_tips(&self) -> u32 {
-        if self.children.is_empty() {
-            1
-        } else {
-            self.children.iter().map(|c| c.num_tips()).sum()
-        }
-    }
-
-    /// Extends the tree with the given block.
-    ///
-    /// Blocks can extend the tree in the following cases:
-    ///   * The block is already present in the tree (no-op).
-    ///   * The block is a successor of a block already in the tree.
-    pub fn extend(&mut self, block: Block) -> Result<(), BlockDoesNotExtendTree> {
-        if self.contains(&block) {
-            // The block is already present in the tree. Nothing to do.
-            return Ok(());
-        }
-
-        // Check if the block is a successor to any of the blocks in the tree.
-        match self.find_mut(&block.header().prev_blockhash.into()) {
-            Some((block_subtree, _)) => {
-                assert_eq!(
-                    block_subtree.root.block_hash().to_vec(),
-                    block.header().prev_blockhash.to_vec()
-                );
-                // Add the block as a successor.
-                block_subtree.children.push(BlockTree::new(block));
-                Ok(())
-            }
-            None => Err(BlockDoesNotExtendTree(block.block_hash())),
-        }
-    }
-
-    /// Returns all the blockchains in the tree.
-    pub fn blockchains(&self) -> Vec<BlockChain> {
-        if self.children.is_empty() {
-            return vec![BlockChain {
-                first: &self.root,
-                successors: vec![],
-            }];
-        }
-
-        let mut tips = vec![];
-        for child in self.children.iter() {
-            tips.extend(
-                child
-                    .blockchains()
-                    .into_iter()
-                    .map(|bc| BlockChain {
-                        first: &self.root,
-                        successors: bc.into_chain(),
-                    })
-                    .collect::<Vec<BlockChain>>(),
-            );
-        }
-
-        tips
-    }
-
-    /// Returns a `BlockChain` starting from the anchor and ending with the `tip`.
-    ///
-    /// If the `tip` doesn't exist in the tree, `None` is returned.
-    pub fn get_chain_with_tip<'a>(&'a self, tip: &BlockHash) -> Option<BlockChain<'a>> {
-        // Compute the chain in reverse order, as that's more efficient, and then
-        // reverse it to get the answer in the correct order.
-        self.get_chain_with_tip_reverse(tip).map(|mut chain| {
-            // Safe to unwrap as the `chain` would contain at least the root of the
-            // `BlockTree` it was produced from.
-            // This would be the first block since the chain is in reverse order.
-            let first = chain.pop().unwrap();
-            // Reverse the chain to get the list of `successors` in the right order.
-            chain.reverse();
-            BlockChain {
-                first,
-                successors: chain,
-            }
-        })
-    }
-
-    // Do a depth-first search to find the blockchain that ends with the given `tip`.
-    // For performance reasons, the list is returned in the reverse order, starting
-    // from `tip` and ending with `anchor`.
-    fn get_chain_with_tip_reverse<'a>(&'a self, tip: &BlockHash) -> Option<Vec<&'a Block>> {
-        if self.root.block_hash() == *tip {
-            return Some(vec![&self.root]);
-        }
-
-        for child in self.children.iter() {
-            if let Some(mut chain) = child.get_chain_with_tip_reverse(tip) {
-                chain.push(&self.root);
-                return Some(chain);
-            }
-        }
-
-        None
-    }
-
-    // Returns the maximum sum of block difficulties from the root to a leaf inclusive.
-    pub fn difficulty_based_depth(&self, network: Network) -> u128 {
-        let mut res: u128 = 0;
-        for child in self.children.iter() {
-            res = std::cmp::max(res, child.difficulty_based_depth(network));
-        }
-        res += self.root.difficulty(network) as u128;
-        res
-    }
-
-    pub fn depth(&self) -> u128 {
-        let mut res: u128 = 0;
-        for child in self.children.iter() {
-            res = std::cmp::max(res, child.depth());
-        }
-        res += 1;
-        res
-    }
 
-    // Returns a `BlockTree` where the hash of the root block matches the provided `block_hash`
-    // along with its depth if it exists, and `None` otherwise.
-    pub fn find_mut<'a>(&'a mut self, blockhash: &BlockHash) -> Option<(&'a mut BlockTree, u32)> {
-        fn find_mut_helper<'a>(
-            block_tree: &'a mut BlockTree,
-            blockhash: &BlockHash,
-            depth: u32,
-        ) -> Option<(&'a mut BlockTree, u32)> {
-            if block_tree.root.block_hash() == *blockhash {
-                return Some((block_tree, depth));
-            }
-
-            for child in block_tree.children.iter_mut() {
-                if let res @ Some(_) = find_mut_helper(child, blockhash, depth + 1) {
-                    return res;
-                }
-            }
-
-            None
-        }
-
-        find_mut_helper(self, blockhash, 0)
-    }
+pub use ic_btc_blocktree::BlockDoesNotExtendTree;
 
-    // Returns true if a block exists in the tree, false otherwise.
-    fn contains(&self, block: &Block) -> bool {
-        if self.root.block_hash() == block.block_hash() {
-            return true;
-        }
+pub(crate) mod serde;
 
-        for child in self.children.iter() {
-            if child.contains(block) {
-                return true;
-            }
-        }
+/// A non-empty chain of blocks. See `ic_btc_blocktree::BlockChain`.
+pub type BlockChain<'a> = ic_btc_blocktree::BlockChain<'a, Block>;
 
-        false
-    }
-}
+/// A tree of connected blocks. See `ic_btc_blocktree::BlockTree`.
+pub type BlockTree<M = ()> = ic_btc_blocktree::BlockTree<Block, M>;
 
-/// An error thrown when trying to add a block that isn't a successor
-/// of any block in the tree.
-#[derive(Debug)]
-pub struct BlockDoesNotExtendTree(pub BlockHash);
+/// An index of the blocks in a `BlockTree`. See `ic_btc_blocktree::BlockTreeIndex`.
+pub type BlockTreeIndex = ic_btc_blocktree::BlockTreeIndex<BlockHash>;
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::test_utils::{BlockBuilder, BlockChainBuilder};
+    use ic_btc_interface::Network;
     use proptest::collection::vec as pvec;
     use proptest::prelude::*;
     use test_strategy::proptest;
 
     // For generating arbitrary BlockTrees.
-    impl Arbitrary for BlockTree {
-        type Parameters = Option<()>;
-        type Strategy = BoxedStrategy<Self>;
-
-        fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
-            fn build_block_tree(tree: &mut BlockTree, num_children: &[u8]) {
-                // Add children.
-                if num_children.is_empty() {
-                    return;
-                }
+    fn arb_block_tree() -> BoxedStrategy<BlockTree> {
+        fn build_block_tree(tree: &mut BlockTree, num_children: &[u8]) {
+            // Add children.
+            if num_children.is_empty() {
+                return;
+            }
 
-                for _ in 0..num_children[0] {
-                    let mut subtree =
-                        BlockTree::new(BlockBuilder::with_prev_header(tree.root.header()).build());
+            for _ in 0..num_children[0] {
+                let mut subtree =
+                    BlockTree::new(BlockBuilder::with_prev_header(tree.root.header()).build());
 
-                    build_block_tree(&mut subtree, &num_children[1..]);
-                    tree.children.push(subtree);
-                }
+                build_block_tree(&mut subtree, &num_children[1..]);
+                tree.children.push(subtree);
             }
-
-            // Each depth can have up to 3 children, up to a depth of 10.
-            pvec(1..3u8, 0..10)
-                .prop_map(|num_children| {
-                    let mut tree = BlockTree::new(BlockBuilder::genesis().build());
-                    build_block_tree(&mut tree, &num_children);
-                    tree
-                })
-                .boxed()
         }
+
+        // Each depth can have up to 3 children, up to a depth of 10.
+        pvec(1..3u8, 0..10)
+            .prop_map(|num_children| {
+                let mut tree = BlockTree::new(BlockBuilder::genesis().build());
+                build_block_tree(&mut tree, &num_children);
+                tree
+            })
+            .boxed()
     }
 
     #[test]
@@ -330,10 +59,7 @@ mod test {
 
         assert_eq!(
             block_tree.blockchains(),
-            vec![BlockChain {
-                first: &block_tree.root,
-                successors: vec![],
-            }]
+            vec![BlockChain::new(&block_tree.root)]
         );
     }
 
@@ -574,17 +300,84 @@ mod test {
             tree.extend(block).unwrap();
         }
 
-        let mut bytes = vec![];
-        ciborium::ser::into_writer(&tree, &mut bytes).unwrap();
-        let new_tree: BlockTree = ciborium::de::from_reader(&bytes[..]).unwrap();
+        let new_tree = deserialize_tree(&serialize_tree(&tree)).unwrap();
         assert_eq!(tree, new_tree);
     }
 
-    #[proptest]
-    fn serialize_deserialize(tree: BlockTree) {
+    // A `BlockTree` that is serialized as it is within the unstable blocks.
+    #[derive(Debug, ::serde::Serialize, ::serde::Deserialize)]
+    struct SerializedTree(#[serde(with = "super::serde")] BlockTree);
+
+    fn serialize_tree(tree: &BlockTree) -> Vec<u8> {
         let mut bytes = vec![];
-        ciborium::ser::into_writer(&tree, &mut bytes).unwrap();
-        let new_tree: BlockTree = ciborium::de::from_reader(&bytes[..]).unwrap();
+        ciborium::ser::into_writer(&SerializedTree(tree.clone()), &mut bytes).unwrap();
+        bytes
+    }
+
+    fn deserialize_tree(bytes: &[u8]) -> Result<BlockTree, ciborium::de::Error<std::io::Error>> {
+        ciborium::de::from_reader::<SerializedTree, _>(bytes).map(|tree| tree.0)
+    }
+
+    // Serializes a flattened tree as-is, allowing to produce trees that `serde::serialize` never would.
+    fn serialize_flattened(flattened_tree: &[(&bitcoin::Block, usize)]) -> Vec<u8> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&flattened_tree, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn deserializing_malformed_block_trees_returns_an_error() {
+        let chain = BlockChainBuilder::new(2).build();
+        let root = chain[0].internal_bitcoin_block();
+        let child = chain[1].internal_bitcoin_block();
+
+        // Regression inputs that used to cause a panic.
+        let regressions = vec![
+            // An empty tree.
+            serialize_flattened(&[]),
+            // Data after the end of the tree.
+            serialize_flattened(&[(root, 0), (child, 0)]),
+            // More children than there are blocks.
+            serialize_flattened(&[(root, 2), (child, 0)]),
+        ];
+
+        for bytes in regressions {
+            assert!(deserialize_tree(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn deserializing_arbitrary_bytes_returns_an_error() {
+        let tree = BlockTree::new(BlockBuilder::genesis().build());
+        let valid_bytes = serialize_tree(&tree);
+
+        let corpus: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x00],
+            vec![0xff; 64],
+            // A sequence of two integers rather than blocks.
+            vec![0x82, 0x01, 0x02],
+            // A truncated tree.
+            valid_bytes[..valid_bytes.len() / 2].to_vec(),
+        ];
+
+        for bytes in corpus {
+            assert!(deserialize_tree(&bytes).is_err());
+            // Decoding the bytes as a bitcoin block must not panic either.
+            let _ = <bitcoin::Block as bitcoin::consensus::Decodable>::consensus_decode(&bytes[..]);
+        }
+    }
+
+    #[proptest]
+    fn deserializing_random_bytes_does_not_panic(
+        #[strategy(pvec(any::<u8>(), 0..1_000))] bytes: Vec<u8>,
+    ) {
+        let _ = deserialize_tree(&bytes);
+    }
+
+    #[proptest]
+    fn serialize_deserialize(#[strategy(arb_block_tree())] tree: BlockTree) {
+        let new_tree = deserialize_tree(&serialize_tree(&tree)).unwrap();
         assert_eq!(tree, new_tree);
     }
 }
@@ -1,10 +1,14 @@
+//! The serialization of a `BlockTree`, used with `#[serde(with = "crate::blocktree::serde")]`.
+//!
+//! The tree is defined in `ic_btc_blocktree`, so its serialization, along with the canister
+//! specific features it depends on, lives here rather than in a `Serialize` implementation.
 use super::BlockTree;
 use bitcoin::Block as BitcoinBlock;
 use ic_btc_types::Block;
 use serde::{
-    de::{Deserializer, SeqAccess, Visitor},
+    de::{Deserializer, Error, SeqAccess, Visitor},
     ser::SerializeSeq,
-    Deserialize, Serialize, Serializer,
+    Serializer,
 };
 use std::fmt;
 
@@ -12,45 +16,41 @@ use std::fmt;
 //
 // This flattening is necessary as a recursive data structure can cause a stack
 // overflow if the structure is very deep.
-impl Serialize for BlockTree {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        #[cfg(feature = "canbench-rs")]
-        let _p = canbench_rs::bench_scope("serialize_blocktree");
-
-        // Flatten a block tree into a list.
-        fn flatten<'a>(tree: &'a BlockTree, flattened_tree: &mut Vec<(&'a BitcoinBlock, usize)>) {
-            flattened_tree.push((tree.root.internal_bitcoin_block(), tree.children.len()));
+pub fn serialize<S: Serializer>(tree: &BlockTree, serializer: S) -> Result<S::Ok, S::Error> {
+    #[cfg(feature = "canbench-rs")]
+    let _p = canbench_rs::bench_scope("serialize_blocktree");
 
-            for child in &tree.children {
-                flatten(child, flattened_tree);
-            }
-        }
+    // Flatten a block tree into a list.
+    fn flatten<'a>(tree: &'a BlockTree, flattened_tree: &mut Vec<(&'a BitcoinBlock, usize)>) {
+        flattened_tree.push((tree.root.internal_bitcoin_block(), tree.children.len()));
 
-        let mut flattened_tree = vec![];
-        {
-            #[cfg(feature = "canbench-rs")]
-            let _p = canbench_rs::bench_scope("serialize_blocktree_flatten");
-            flatten(self, &mut flattened_tree);
+        for child in &tree.children {
+            flatten(child, flattened_tree);
         }
+    }
 
+    let mut flattened_tree = vec![];
+    {
         #[cfg(feature = "canbench-rs")]
-        let _p = canbench_rs::bench_scope("serialize_blocktree_serialize_seq");
+        let _p = canbench_rs::bench_scope("serialize_blocktree_flatten");
+        flatten(tree, &mut flattened_tree);
+    }
 
-        let mut seq = serializer.serialize_seq(Some(flattened_tree.len()))?;
-        for e in flattened_tree {
-            seq.serialize_element(&e)?;
-        }
-        seq.end()
+    #[cfg(feature = "canbench-rs")]
+    let _p = canbench_rs::bench_scope("serialize_blocktree_serialize_seq");
+
+    let mut seq = serializer.serialize_seq(Some(flattened_tree.len()))?;
+    for e in flattened_tree {
+        seq.serialize_element(&e)?;
     }
+    seq.end()
 }
 
-impl<'de> Deserialize<'de> for BlockTree {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        deserializer.deserialize_seq(BlockTreeDeserializer)
-    }
+pub fn deserialize<'de, D>(deserializer: D) -> Result<BlockTree, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_seq(BlockTreeDeserializer)
 }
 
 struct BlockTreeDeserializer;
@@ -63,26 +63,24 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
     }
 
     fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
-        fn next<'de, A: SeqAccess<'de>>(seq: &mut A) -> Option<(Block, usize)> {
+        fn next<'de, A: SeqAccess<'de>>(seq: &mut A) -> Result<Option<(Block, usize)>, A::Error> {
             // A feature to upgrade from a release canister that has the old pre_upgrade
             // that serialized the entire Block (as opposed to only the `BitcoinBlock` inside it).
             #[cfg(feature = "legacy_preupgrade")]
-            return seq
-                .next_element()
-                .expect("reading next element must succeed");
+            return seq.next_element();
 
             #[cfg(not(feature = "legacy_preupgrade"))]
-            return seq
-                .next_element::<(BitcoinBlock, usize)>()
-                .expect("reading next element must succeed")
-                .map(|(b, n)| (Block::new(b), n));
+            return Ok(seq
+                .next_element::<(BitcoinBlock, usize)>()?
+                .map(|(b, n)| (Block::new(b), n)));
         }
 
         // A stack containing a `BlockTree` along with how many children remain to be added to it.
         let mut stack: Vec<(BlockTree, usize)> = Vec::new();
 
         // Read the root and add it to the stack.
-        let (root, children_to_add) = next(&mut seq).unwrap();
+        let (root, children_to_add) = next(&mut seq)?
+            .ok_or_else(|| A::Error::custom("expected the root of the BlockTree"))?;
         stack.push((BlockTree::new(root), children_to_add));
 
         while let Some((tree, children_to_add)) = stack.pop() {
@@ -92,8 +90,10 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
                     Some(parent) => parent.0.children.push(tree),
                     None => {
                         // There's no parent to this tree. Deserialization is complete.
-                        // Assert that there's no more data to deserialize.
-                        assert_eq!(next(&mut seq), None);
+                        // Verify that there's no more data to deserialize.
+                        if next(&mut seq)?.is_some() {
+                            return Err(A::Error::custom("unexpected data after the BlockTree"));
+                        }
                         return Ok(tree);
                     }
                 }
@@ -103,7 +103,9 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
                 stack.push((tree, children_to_add - 1));
 
                 // Add the child to the stack.
-                let (child, grand_children_to_add) = next(&mut seq).unwrap();
+                let (child, grand_children_to_add) = next(&mut seq)?.ok_or_else(|| {
+                    A::Error::custom("expected more blocks while deserializing BlockTree")
+                })?;
                 stack.push((BlockTree::new(child), grand_children_to_add));
             }
         }
@@ -0,0 +1,250 @@
+//! A minimal parser of watch-only output descriptors, used to derive the addresses a wallet
+//! tracks.
+//!
+//! Only single-key descriptors of the form `pkh(KEY)` and `wpkh(KEY)` are supported, where
+//! `KEY` is an extended public key with an optional key origin and an unhardened derivation
+//! path, e.g. `wpkh([d34db33f/84'/0'/0']xpub.../0/*)`. A trailing `*` makes the descriptor
+//! ranged. A descriptor checksum, if present, is ignored.
+use crate::types::{into_bitcoin_network, Address};
+use bitcoin::{
+    secp256k1::Secp256k1,
+    util::bip32::{ChildNumber, ExtendedPubKey},
+    Address as BitcoinAddress, PublicKey,
+};
+use ic_btc_interface::Network;
+use std::str::FromStr;
+
+/// The maximum gap limit of a ranged descriptor, bounding the number of addresses derived.
+///
+/// This is well below the number of addresses `State::get_utxos_multi` accepts, so that a
+/// wallet with some used addresses can still be scanned.
+pub const MAX_GAP_LIMIT: u32 = 100;
+
+/// An error returned when deriving the addresses of a descriptor.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DescriptorError {
+    /// The descriptor isn't of the form `pkh(KEY)` or `wpkh(KEY)`.
+    UnsupportedDescriptor,
+    /// The extended public key of the descriptor is malformed.
+    MalformedKey,
+    /// The derivation path is malformed, or contains hardened steps, which can't be derived
+    /// from an extended public key.
+    InvalidDerivationPath,
+    /// The gap limit is above `MAX_GAP_LIMIT`.
+    GapLimitTooLarge { gap_limit: u32, max: u32 },
+}
+
+#[derive(Clone, Copy)]
+enum ScriptType {
+    P2pkh,
+    P2wpkh,
+}
+
+/// A parsed watch-only descriptor, from which addresses can be derived.
+pub struct Descriptor {
+    script_type: ScriptType,
+    xpub: ExtendedPubKey,
+    path: Vec<ChildNumber>,
+    is_ranged: bool,
+    network: Network,
+}
+
+impl Descriptor {
+    /// Parses the given descriptor, whose addresses are derived for the given network.
+    pub fn parse(descriptor: &str, network: Network) -> Result<Self, DescriptorError> {
+        // Strip the checksum, if any.
+        let descriptor = descriptor.split('#').next().unwrap_or_default().trim();
+
+        let (script_type, key) = if let Some(key) = strip_function(descriptor, "wpkh") {
+            (ScriptType::P2wpkh, key)
+        } else if let Some(key) = strip_function(descriptor, "pkh") {
+            (ScriptType::P2pkh, key)
+        } else {
+            return Err(DescriptorError::UnsupportedDescriptor);
+        };
+
+        // Skip the key origin, if any, as it isn't needed for deriving addresses.
+        let key = if key.starts_with('[') {
+            match key.find(']') {
+                Some(end) => &key[end + 1..],
+                None => return Err(DescriptorError::MalformedKey),
+            }
+        } else {
+            key
+        };
+
+        let mut parts = key.split('/');
+        let xpub = ExtendedPubKey::from_str(parts.next().unwrap_or_default())
+            .map_err(|_| DescriptorError::MalformedKey)?;
+
+        let mut path = vec![];
+        let mut is_ranged = false;
+        for part in parts {
+            if is_ranged {
+                // The wildcard must be the last step of the path.
+                return Err(DescriptorError::InvalidDerivationPath);
+            }
+
+            if part == "*" {
+                is_ranged = true;
+                continue;
+            }
+
+            let index = part
+                .parse::<u32>()
+                .map_err(|_| DescriptorError::InvalidDerivationPath)?;
+            path.push(
+                ChildNumber::from_normal_idx(index)
+                    .map_err(|_| DescriptorError::InvalidDerivationPath)?,
+            );
+        }
+
+        Ok(Self {
+            script_type,
+            xpub,
+            path,
+            is_ranged,
+            network,
+        })
+    }
+
+    /// Returns true if the descriptor ends with a `*`, i.e. if it has an address per index.
+    pub fn is_ranged(&self) -> bool {
+        self.is_ranged
+    }
+
+    /// Derives the address at the given index. The index is ignored if the descriptor isn't
+    /// ranged, as it has a single address.
+    pub fn address(&self, index: u32) -> Result<Address, DescriptorError> {
+        let mut path = self.path.clone();
+        if self.is_ranged {
+            path.push(
+                ChildNumber::from_normal_idx(index)
+                    .map_err(|_| DescriptorError::InvalidDerivationPath)?,
+            );
+        }
+
+        let child = self
+            .xpub
+            .derive_pub(&Secp256k1::verification_only(), &path)
+            .map_err(|_| DescriptorError::InvalidDerivationPath)?;
+        let public_key = PublicKey::new(child.public_key);
+        let network = into_bitcoin_network(self.network);
+        let address = match self.script_type {
+            ScriptType::P2pkh => BitcoinAddress::p2pkh(&public_key, network),
+            ScriptType::P2wpkh => BitcoinAddress::p2wpkh(&public_key, network)
+                .expect("derived public keys are compressed"),
+        };
+        Ok(Address::from(address))
+    }
+}
+
+/// Returns an error if the given gap limit is above `MAX_GAP_LIMIT`.
+pub fn validate_gap_limit(gap_limit: u32) -> Result<(), DescriptorError> {
+    if gap_limit > MAX_GAP_LIMIT {
+        return Err(DescriptorError::GapLimitTooLarge {
+            gap_limit,
+            max: MAX_GAP_LIMIT,
+        });
+    }
+
+    Ok(())
+}
+
+/// Derives the addresses of the given descriptor.
+///
+/// For a ranged descriptor, the addresses at indices `0..gap_limit` are derived. Otherwise,
+/// the descriptor's single address is returned. The gap limit can't exceed `MAX_GAP_LIMIT`.
+pub fn derive_addresses(
+    descriptor: &str,
+    gap_limit: u32,
+    network: Network,
+) -> Result<Vec<Address>, DescriptorError> {
+    validate_gap_limit(gap_limit)?;
+    let descriptor = Descriptor::parse(descriptor, network)?;
+
+    if descriptor.is_ranged() {
+        (0..gap_limit)
+            .map(|index| descriptor.address(index))
+            .collect()
+    } else {
+        Ok(vec![descriptor.address(0)?])
+    }
+}
+
+// Returns the argument of the given function in the descriptor, e.g. `KEY` for `wpkh(KEY)`.
+fn strip_function<'a>(descriptor: &'a str, function: &str) -> Option<&'a str> {
+    descriptor
+        .strip_prefix(function)?
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The master public key of the first test vector of BIP-32.
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn derives_addresses_of_ranged_descriptors() {
+        let descriptor = format!("wpkh([d34db33f/84'/0'/0']{}/0/*)#checksum", XPUB);
+        let addresses = derive_addresses(&descriptor, 5, Network::Mainnet).unwrap();
+        assert_eq!(addresses.len(), 5);
+        assert!(addresses
+            .iter()
+            .all(|address| address.to_string().starts_with("bc1q")));
+
+        // A larger gap limit derives the same addresses first.
+        let more_addresses = derive_addresses(&descriptor, 10, Network::Mainnet).unwrap();
+        assert_eq!(more_addresses[..5], addresses[..]);
+
+        let descriptor = format!("pkh({}/0/*)", XPUB);
+        let addresses = derive_addresses(&descriptor, 5, Network::Mainnet).unwrap();
+        assert!(addresses
+            .iter()
+            .all(|address| address.to_string().starts_with('1')));
+    }
+
+    #[test]
+    fn derives_the_single_address_of_non_ranged_descriptors() {
+        let descriptor = format!("wpkh({}/0/3)", XPUB);
+        let address = derive_addresses(&descriptor, 5, Network::Mainnet).unwrap();
+
+        let ranged_descriptor = format!("wpkh({}/0/*)", XPUB);
+        let addresses = derive_addresses(&ranged_descriptor, 5, Network::Mainnet).unwrap();
+        assert_eq!(address, vec![addresses[3].clone()]);
+    }
+
+    #[test]
+    fn invalid_descriptors() {
+        assert_eq!(
+            derive_addresses(&format!("sh(wpkh({}/0/*))", XPUB), 5, Network::Mainnet),
+            Err(DescriptorError::UnsupportedDescriptor)
+        );
+        assert_eq!(
+            derive_addresses("wpkh(xpub123/0/*)", 5, Network::Mainnet),
+            Err(DescriptorError::MalformedKey)
+        );
+        assert_eq!(
+            derive_addresses(&format!("wpkh({}/0'/*)", XPUB), 5, Network::Mainnet),
+            Err(DescriptorError::InvalidDerivationPath)
+        );
+        assert_eq!(
+            derive_addresses(&format!("wpkh({}/*/0)", XPUB), 5, Network::Mainnet),
+            Err(DescriptorError::InvalidDerivationPath)
+        );
+        assert_eq!(
+            derive_addresses(
+                &format!("wpkh({}/0/*)", XPUB),
+                MAX_GAP_LIMIT + 1,
+                Network::Mainnet
+            ),
+            Err(DescriptorError::GapLimitTooLarge {
+                gap_limit: MAX_GAP_LIMIT + 1,
+                max: MAX_GAP_LIMIT
+            })
+        );
+    }
+}
@@ -1,17 +1,18 @@
 use crate::{
     api::get_current_fee_percentiles_impl,
-    runtime::{call_get_successors, cycles_burn, print},
-    state::{self, ResponseToProcess},
+    runtime::{call_get_successors, cycles_burn, print, time},
+    state::{self, InsertBlockError, ResponseToProcess},
     types::{
-        GetSuccessorsCompleteResponse, GetSuccessorsRequest, GetSuccessorsRequestInitial,
-        GetSuccessorsResponse,
+        decode_reassembled_block, BlockHeaderBlob, GetSuccessorsCompleteResponse,
+        GetSuccessorsRequest, GetSuccessorsResponse,
     },
 };
 use crate::{with_state, with_state_mut};
 use bitcoin::consensus::Decodable;
 use bitcoin::Block as BitcoinBlock;
 use ic_btc_interface::Flag;
-use ic_btc_types::{Block, BlockHash};
+use ic_btc_types::Block;
+use ic_btc_validation::ValidateHeaderError;
 
 /// The heartbeat of the Bitcoin canister.
 ///
@@ -21,6 +22,13 @@ pub async fn heartbeat() {
 
     maybe_burn_cycles();
 
+    if with_state(|s| s.syncing_state.ingestion_halted) {
+        // The state is inconsistent, so no blocks are fetched or inserted until a controller
+        // resumes ingestion.
+        print("Ingestion is halted due to an inconsistency. Skipping...");
+        return;
+    }
+
     if ingest_stable_blocks_into_utxoset() {
         // Exit the heartbeat if stable blocks had been ingested.
         // This is a precaution to not exceed the instructions limit.
@@ -54,6 +62,12 @@ async fn maybe_fetch_blocks() -> bool {
         None => return false,
     };
 
+    with_state_mut(|s| {
+        if s.time_out_partial_response(time()) {
+            print("Partial response made no progress in time. Discarding it...");
+        }
+    });
+
     // Request additional blocks.
     let maybe_request = maybe_get_successors_request();
     let request = match maybe_request {
@@ -79,6 +93,7 @@ async fn maybe_fetch_blocks() -> bool {
                 s.syncing_state.num_get_successors_rejects += 1;
                 print(&format!("Error fetching blocks: [{:?}] {}", code, msg));
                 s.syncing_state.response_to_process = None;
+                s.syncing_state.partial_response_last_progress = None;
                 return;
             }
         };
@@ -99,7 +114,15 @@ async fn maybe_fetch_blocks() -> bool {
                     "Received partial response before processing previous response."
                 );
                 s.syncing_state.response_to_process =
-                    Some(ResponseToProcess::Partial(partial_response, 0));
+                    Some(if partial_response.remaining_follow_ups == 0 {
+                        // There are no follow-ups to wait for, so the block is already complete.
+                        ResponseToProcess::Complete(GetSuccessorsCompleteResponse {
+                            blocks: vec![partial_response.partial_block],
+                            next: partial_response.next,
+                        })
+                    } else {
+                        ResponseToProcess::Partial(partial_response, 0)
+                    });
             }
             GetSuccessorsResponse::FollowUp(mut block_bytes) => {
                 // Received a follow-up response.
@@ -111,24 +134,40 @@ async fn maybe_fetch_blocks() -> bool {
                     other => unreachable!("Cannot receive follow-up response without a previous partial response. Previous response found: {:?}", other)
                 };
 
-                // Append block to partial response and increment # pages processed.
-                partial_response.partial_block.append(&mut block_bytes);
                 follow_up_index += 1;
-
-                // If the response is now complete, store a complete response to process.
-                // Otherwise, store the updated partial response.
-                s.syncing_state.response_to_process = Some(
-                    if follow_up_index == partial_response.remaining_follow_ups {
-                        ResponseToProcess::Complete(GetSuccessorsCompleteResponse {
-                            blocks: vec![partial_response.partial_block],
-                            next: partial_response.next,
-                        })
-                    } else {
-                        ResponseToProcess::Partial(partial_response, follow_up_index)
-                    },
-                );
+                partial_response.partial_block.append(&mut block_bytes);
+                if follow_up_index < partial_response.remaining_follow_ups {
+                    // Store the partial response along with the appended page.
+                    s.syncing_state.response_to_process = Some(ResponseToProcess::Partial(
+                        partial_response,
+                        follow_up_index,
+                    ));
+                } else {
+                    // This is the last page, so the block is complete. It's decoded here to
+                    // validate its length, and kept decoded until it's processed.
+                    match decode_reassembled_block(&partial_response.partial_block) {
+                        Ok(block) => {
+                            s.syncing_state.response_to_process =
+                                Some(ResponseToProcess::Reassembled(block, partial_response.next));
+                        }
+                        Err(err) => {
+                            // Drop the response, so that the blocks are fetched again.
+                            print(&format!(
+                                "ERROR: Cannot reassemble block from partial response. Err: {:?}",
+                                err
+                            ));
+                            s.syncing_state.num_block_deserialize_errors += 1;
+                        }
+                    }
+                }
             }
         };
+
+        // Record the time of the progress made on a partial response, if any.
+        s.syncing_state.partial_response_last_progress = match s.syncing_state.response_to_process {
+            Some(ResponseToProcess::Partial(_, _)) => Some(time()),
+            _ => None,
+        };
     });
 
     // A request to fetch new blocks has been made.
@@ -136,12 +175,32 @@ async fn maybe_fetch_blocks() -> bool {
 }
 
 fn ingest_stable_blocks_into_utxoset() -> bool {
-    with_state_mut(state::ingest_stable_blocks_into_utxoset)
+    with_state_mut(|state| {
+        let has_state_changed = state::ingest_stable_blocks_into_utxoset(state);
+
+        let num_removed = state.compact_after_ingestion();
+        if num_removed > 0 {
+            print(&format!(
+                "Removed {} already ingested blocks from the unstable blocks.",
+                num_removed
+            ));
+        }
+
+        has_state_changed
+    })
 }
 
 // Process a `GetSuccessorsResponse` if one is available.
 fn maybe_process_response() {
     with_state_mut(|state| {
+        let num_evicted_orphans = state.clear_orphan_pool(time());
+        if num_evicted_orphans > 0 {
+            print(&format!(
+                "Evicted {} stale blocks from the orphan pool.",
+                num_evicted_orphans
+            ));
+        }
+
         let response_to_process = state.syncing_state.response_to_process.take();
 
         match response_to_process {
@@ -150,6 +209,10 @@ fn maybe_process_response() {
                     "Inserting {} blocks from response...",
                     response.blocks.len()
                 ));
+
+                // Start the batch with a fresh header cache to bound its size.
+                state.header_cache.clear();
+
                 for block_bytes in response.blocks.iter() {
                     // Deserialize the block.
                     let block = match BitcoinBlock::consensus_decode(block_bytes.as_slice()) {
@@ -168,23 +231,21 @@ fn maybe_process_response() {
                         }
                     };
 
-                    if let Err(err) = state::insert_block(state, Block::new(block)) {
-                        print(&format!(
-                            "ERROR: Failed to insert block. Err: {:?}, Block bytes: {:?}",
-                            err, block_bytes,
-                        ));
-
+                    if !insert_response_block(state, Block::new(block)) {
                         // Return, the remaining blocks in the response are dropped.
-                        state.syncing_state.num_insert_block_errors += 1;
                         return;
                     }
                 }
 
-                print(&format!(
-                    "Inserting {} next block headers...",
-                    response.next.len()
-                ));
-                state::insert_next_block_headers(state, &response.next);
+                finish_processing_response(state, &response.next);
+            }
+            Some(ResponseToProcess::Reassembled(block, next)) => {
+                print("Inserting a block reassembled from a partial response...");
+
+                state.header_cache.clear();
+                if insert_response_block(state, block) {
+                    finish_processing_response(state, &next);
+                }
             }
             other => {
                 if other.is_some() {
@@ -203,6 +264,69 @@ fn maybe_process_response() {
     });
 }
 
+// Inserts a block of a response into the state.
+//
+// Returns false if the block couldn't be inserted and the remaining blocks of the response
+// must be dropped.
+fn insert_response_block(state: &mut state::State, block: Block) -> bool {
+    match state::insert_block(state, block.clone()) {
+        Ok(()) => {
+            // The block may be the parent of blocks in the orphan pool.
+            state::try_connect_orphans(state);
+        }
+        Err(InsertBlockError::InvalidHeader(ValidateHeaderError::PrevHeaderNotFound)) => {
+            print(&format!(
+                "ERROR: Block {:?} doesn't extend any known block. Adding it to the orphan pool.",
+                block.block_hash(),
+            ));
+
+            // Keep the block in case its parent arrives later, and continue
+            // with the remaining blocks in the response.
+            state.syncing_state.num_insert_block_errors += 1;
+            let block_hash = block.block_hash();
+            if let Err(err) = state::add_orphan(state, block, time()) {
+                print(&format!(
+                    "ERROR: Block {:?} can't be added to the orphan pool. Err: {:?}",
+                    block_hash, err,
+                ));
+            }
+        }
+        Err(err @ (InsertBlockError::TooManyForks | InsertBlockError::BelowStableHeight)) => {
+            print(&format!(
+                "ERROR: Skipping block {:?}. Err: {:?}",
+                block.block_hash(),
+                err,
+            ));
+
+            // Skip the block and continue with the remaining blocks in the
+            // response, as they may extend the main chain.
+            state.syncing_state.num_insert_block_errors += 1;
+        }
+        Err(err) => {
+            print(&format!(
+                "ERROR: Failed to insert block {:?}. Err: {:?}",
+                block.block_hash(),
+                err,
+            ));
+
+            state.syncing_state.num_insert_block_errors += 1;
+            return false;
+        }
+    }
+
+    true
+}
+
+// Completes the processing of a response once its blocks have been inserted.
+fn finish_processing_response(state: &mut state::State, next: &[BlockHeaderBlob]) {
+    if let Some(reorg) = state.reconsider_best_chain(time()) {
+        print(&format!("The main chain has been reorganized: {:?}", reorg));
+    }
+
+    print(&format!("Inserting {} next block headers...", next.len()));
+    state::insert_next_block_headers(state, next);
+}
+
 fn maybe_compute_fee_percentiles() {
     if with_state(|s| s.lazily_evaluate_fee_percentiles == Flag::Enabled) {
         return;
@@ -214,7 +338,7 @@ fn maybe_compute_fee_percentiles() {
 // Retrieves a `GetSuccessorsRequest` to send to the adapter.
 fn maybe_get_successors_request() -> Option<GetSuccessorsRequest> {
     with_state(|state| match &state.syncing_state.response_to_process {
-        Some(ResponseToProcess::Complete(_)) => {
+        Some(ResponseToProcess::Complete(_)) | Some(ResponseToProcess::Reassembled(_, _)) => {
             // There's already a complete response waiting to be processed.
             None
         }
@@ -225,19 +349,7 @@ fn maybe_get_successors_request() -> Option<GetSuccessorsRequest> {
         }
         None => {
             // No response is present. Send an initial request for new blocks.
-            let mut processed_block_hashes: Vec<BlockHash> = state::get_unstable_blocks(state)
-                .iter()
-                .map(|b| b.block_hash())
-                .collect();
-
-            // We are guaranteed that there's always at least one block.
-            let anchor = processed_block_hashes.remove(0);
-
-            Some(GetSuccessorsRequest::Initial(GetSuccessorsRequestInitial {
-                network: state.network(),
-                anchor,
-                processed_block_hashes,
-            }))
+            Some(state::build_get_successors_request(state))
         }
     })
 }
@@ -271,7 +383,7 @@ mod test {
         utxo_set::IngestingBlock,
     };
     use bitcoin::BlockHeader;
-    use ic_btc_interface::{InitConfig, Network};
+    use ic_btc_interface::{InitConfig, Network, SetConfigRequest};
 
     fn build_block(prev_header: &BlockHeader, address: Address, num_transactions: u128) -> Block {
         let mut block = BlockBuilder::with_prev_header(prev_header);
@@ -368,6 +480,48 @@ mod test {
         assert_eq!(with_state(state::main_chain_height), 0);
     }
 
+    #[async_std::test]
+    async fn does_not_fetch_blocks_while_ingestion_is_halted() {
+        let network = Network::Regtest;
+
+        init(InitConfig {
+            stability_threshold: Some(0),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        with_state_mut(|s| {
+            s.syncing_state.ingestion_halted = true;
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next: vec![],
+            },
+        )));
+
+        heartbeat().await;
+        heartbeat().await;
+
+        // Assert that the block has not been inserted.
+        assert_eq!(with_state(state::main_chain_height), 0);
+
+        // Once ingestion is resumed, the block is fetched and inserted.
+        crate::api::set_config::set_config_no_verification(SetConfigRequest {
+            resume_ingestion: Some(true),
+            ..Default::default()
+        });
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+    }
+
     #[async_std::test]
     async fn time_slices_large_blocks() {
         let network = Network::Regtest;
@@ -653,6 +807,15 @@ mod test {
         )));
         heartbeat().await;
 
+        // The block has been reassembled and is kept decoded until it's processed.
+        with_state(|s| {
+            assert_eq!(
+                s.syncing_state.response_to_process,
+                Some(ResponseToProcess::Reassembled(block.clone(), vec![]))
+            )
+        });
+        assert_eq!(maybe_get_successors_request(), None);
+
         // The response hasn't been fully processed yet, so the balance should still be zero.
         assert_eq!(
             crate::api::get_balance(crate::types::GetBalanceRequest {
@@ -677,6 +840,98 @@ mod test {
         );
     }
 
+    #[async_std::test]
+    async fn paginated_response_with_trailing_bytes_is_dropped() {
+        let network = Network::Regtest;
+
+        init(InitConfig {
+            stability_threshold: Some(0),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Partial(
+            GetSuccessorsPartialResponse {
+                partial_block: block_bytes[0..40].to_vec(),
+                next: vec![],
+                remaining_follow_ups: 2,
+            },
+        )));
+        heartbeat().await;
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::FollowUp(
+            block_bytes[40..80].to_vec(),
+        )));
+        heartbeat().await;
+
+        // The last page has bytes beyond the end of the block.
+        let mut last_page = block_bytes[80..].to_vec();
+        last_page.push(0);
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::FollowUp(
+            last_page,
+        )));
+        heartbeat().await;
+
+        with_state(|s| {
+            assert_eq!(s.syncing_state.response_to_process, None);
+            assert_eq!(s.syncing_state.num_block_deserialize_errors, 1);
+        });
+    }
+
+    #[async_std::test]
+    async fn partial_response_without_follow_ups_is_processed() {
+        let network = Network::Regtest;
+
+        init(InitConfig {
+            stability_threshold: Some(0),
+            network: Some(network),
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(network);
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build();
+
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        // A partial response that already contains the whole block.
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Partial(
+            GetSuccessorsPartialResponse {
+                partial_block: block_bytes,
+                next: vec![],
+                remaining_follow_ups: 0,
+            },
+        )));
+
+        // Fetch blocks.
+        heartbeat().await;
+
+        // The response is complete and no follow-up request is needed.
+        assert_eq!(maybe_get_successors_request(), None);
+
+        // Process response.
+        heartbeat().await;
+
+        assert_eq!(
+            crate::api::get_balance(crate::types::GetBalanceRequest {
+                address: address.to_string(),
+                min_confirmations: None
+            })
+            .unwrap(),
+            1000
+        );
+    }
+
     #[async_std::test]
     async fn handles_block_deserialize_errors() {
         init(InitConfig::default());
@@ -745,6 +1000,43 @@ mod test {
         });
     }
 
+    #[async_std::test]
+    async fn blocks_received_before_their_parent_are_inserted() {
+        let network = Network::Regtest;
+
+        init(InitConfig {
+            network: Some(network),
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        let encode = |block: &Block| {
+            let mut block_bytes = vec![];
+            block.consensus_encode(&mut block_bytes).unwrap();
+            block_bytes
+        };
+
+        // The child is received before its parent.
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![encode(&block_2), encode(&block_1)],
+                next: vec![],
+            },
+        )));
+
+        // Fetch response.
+        heartbeat().await;
+
+        // Process response.
+        heartbeat().await;
+
+        // Both blocks are part of the main chain.
+        assert_eq!(with_state(state::main_chain_height), 2);
+        with_state(|s| assert!(s.orphan_pool.is_empty()));
+    }
+
     #[async_std::test]
     async fn block_headers_are_not_inserted_above_instructions_threshold() {
         let network = Network::Regtest;
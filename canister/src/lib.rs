@@ -2,6 +2,7 @@ mod address_utxoset;
 mod api;
 mod block_header_store;
 mod blocktree;
+pub mod descriptor;
 mod guard;
 mod heartbeat;
 pub mod memory;
@@ -20,17 +21,19 @@ mod validation;
 
 use crate::{
     api::set_config::set_config_no_verification,
-    runtime::{msg_cycles_accept, msg_cycles_available},
+    runtime::{msg_cycles_accept, msg_cycles_available, print},
     state::State,
     types::{into_bitcoin_network, HttpRequest, HttpResponse},
 };
+pub use api::get_fee_percentiles_at;
 pub use api::get_metrics;
 pub use api::send_transaction;
 pub use api::set_config;
 pub use heartbeat::heartbeat;
 use ic_btc_interface::{
     Config, Flag, GetBalanceError, GetBalanceRequest, GetBlockHeadersError, GetBlockHeadersRequest,
-    GetBlockHeadersResponse, GetCurrentFeePercentilesRequest, GetUtxosError, GetUtxosRequest,
+    GetBlockHeadersResponse, GetCurrentFeePercentilesRequest, GetTransactionError,
+    GetTransactionRequest, GetTransactionResponse, GetUtxosError, GetUtxosRequest,
     GetUtxosResponse, InitConfig, MillisatoshiPerByte, Network, Satoshi, SetConfigRequest,
 };
 use ic_btc_types::Block;
@@ -77,28 +80,85 @@ fn set_state(state: State) {
         );
         *cell.borrow_mut() = Some(state)
     });
+
+    // The cached responses were computed with the previous state.
+    api::clear_utxos_cache();
 }
 
 /// Initializes the state of the Bitcoin canister.
 pub fn init(init_config: InitConfig) {
-    let config = Config::from(init_config);
+    let Config {
+        stability_threshold,
+        network,
+        blocks_source,
+        syncing,
+        fees,
+        api_access,
+        disable_api_if_not_fully_synced,
+        watchdog_canister,
+        burn_cycles,
+        lazily_evaluate_fee_percentiles,
+        max_utxos_per_query,
+        utxos_cache_capacity,
+        fallback_fee_per_byte,
+        header_retention,
+        txindex,
+    } = Config::from(init_config);
+
     set_state(State::new(
-        config
-            .stability_threshold
+        stability_threshold
             .try_into()
             .expect("stability threshold too large"),
-        config.network,
-        genesis_block(config.network),
+        network,
+        genesis_block(network),
     ));
 
-    with_state_mut(|s| s.blocks_source = config.blocks_source);
-    with_state_mut(|s| s.api_access = config.api_access);
-    with_state_mut(|s| s.syncing_state.syncing = config.syncing);
-    with_state_mut(|s| s.disable_api_if_not_fully_synced = config.disable_api_if_not_fully_synced);
-    with_state_mut(|s| s.watchdog_canister = config.watchdog_canister);
-    with_state_mut(|s| s.burn_cycles = config.burn_cycles);
-    with_state_mut(|s| s.lazily_evaluate_fee_percentiles = config.lazily_evaluate_fee_percentiles);
-    with_state_mut(|s| s.fees = config.fees);
+    with_state_mut(|s| s.blocks_source = blocks_source);
+    with_state_mut(|s| s.api_access = api_access);
+    with_state_mut(|s| s.syncing_state.syncing = syncing);
+    with_state_mut(|s| s.disable_api_if_not_fully_synced = disable_api_if_not_fully_synced);
+    with_state_mut(|s| s.watchdog_canister = watchdog_canister);
+    with_state_mut(|s| s.burn_cycles = burn_cycles);
+    with_state_mut(|s| s.lazily_evaluate_fee_percentiles = lazily_evaluate_fee_percentiles);
+    with_state_mut(|s| s.fees = fees);
+    with_state_mut(|s| s.max_utxos_per_query = max_utxos_per_query);
+    with_state_mut(|s| s.fallback_fee_per_byte = fallback_fee_per_byte);
+    with_state_mut(|s| {
+        // An invalid retention policy is ignored rather than failing the installation.
+        if let Err(err) = s.stable_block_headers.set_retention(header_retention) {
+            print(&format!(
+                "Ignoring the invalid header retention {:?}: {:?}",
+                header_retention, err
+            ));
+        }
+    });
+    with_state_mut(|s| {
+        // An invalid cache capacity is ignored rather than failing the installation.
+        if let Err(err) = s.set_utxos_cache_capacity(utxos_cache_capacity) {
+            print(&format!(
+                "Ignoring the invalid utxos cache capacity: {:?}",
+                err
+            ));
+        }
+    });
+    if txindex == Flag::Enabled {
+        with_state_mut(|s| s.utxos.enable_tx_index());
+    }
+
+    log_tip_changes();
+}
+
+// Logs every change of the main chain's tip. Tip observers aren't persisted across upgrades,
+// so this is called both on init and on post upgrade.
+fn log_tip_changes() {
+    with_state(|s| {
+        state::subscribe_to_tip_changes(s, |event| {
+            print(&format!(
+                "The main chain's tip changed to {:?} at height {}.",
+                event.tip, event.height
+            ))
+        })
+    });
 }
 
 pub fn get_current_fee_percentiles(
@@ -147,6 +207,24 @@ pub fn get_block_headers(
     api::get_block_headers(request)
 }
 
+pub fn get_transaction(
+    request: GetTransactionRequest,
+) -> Result<GetTransactionResponse, GetTransactionError> {
+    verify_api_access();
+    verify_network(request.network.into());
+    verify_synced();
+    api::get_transaction(request)
+}
+
+pub fn get_transaction_query(
+    request: GetTransactionRequest,
+) -> Result<GetTransactionResponse, GetTransactionError> {
+    verify_api_access();
+    verify_network(request.network.into());
+    verify_synced();
+    api::get_transaction_query(request)
+}
+
 pub fn get_config() -> Config {
     with_state(|s| Config {
         stability_threshold: s.unstable_blocks.stability_threshold() as u128,
@@ -159,14 +237,22 @@ pub fn get_config() -> Config {
         watchdog_canister: s.watchdog_canister,
         burn_cycles: s.burn_cycles,
         lazily_evaluate_fee_percentiles: s.lazily_evaluate_fee_percentiles,
+        max_utxos_per_query: s.max_utxos_per_query,
+        utxos_cache_capacity: s.utxos_cache_capacity(),
+        fallback_fee_per_byte: s.fallback_fee_per_byte,
+        header_retention: s.stable_block_headers.retention(),
+        txindex: if s.utxos.tx_index.is_some() {
+            Flag::Enabled
+        } else {
+            Flag::Disabled
+        },
     })
 }
 
 pub fn pre_upgrade() {
     // Serialize the state.
     let mut state_bytes = vec![];
-    with_state(|state| ciborium::ser::into_writer(state, &mut state_bytes))
-        .expect("failed to encode state");
+    with_state(|state| state.save(&mut state_bytes)).expect("failed to encode state");
 
     // Write the length of the serialized bytes to memory, followed by the
     // by the bytes themselves.
@@ -189,9 +275,10 @@ pub fn post_upgrade(config_update: Option<SetConfigRequest>) {
     memory.read(4, &mut state_bytes);
 
     // Deserialize and set the state.
-    let state: State = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
+    let state = State::load(&*state_bytes).expect("failed to load state");
 
     set_state(state);
+    log_tip_changes();
 
     // Update the state based on the provided configuration.
     if let Some(config_update) = config_update {
@@ -277,17 +364,20 @@ fn verify_synced() {
 
 /// Returns true if the canister is synced with the network, false otherwise.
 pub(crate) fn is_synced() -> bool {
-    with_state(|state| {
-        let main_chain_height = main_chain_height(state);
-        main_chain_height + SYNCED_THRESHOLD
-            >= max(
-                state
-                    .unstable_blocks
-                    .next_block_headers_max_height()
-                    .unwrap_or(0),
-                main_chain_height,
-            )
-    })
+    with_state(is_state_synced)
+}
+
+/// Returns true if the given state is synced with the network, false otherwise.
+pub(crate) fn is_state_synced(state: &State) -> bool {
+    let main_chain_height = main_chain_height(state);
+    main_chain_height + SYNCED_THRESHOLD
+        >= max(
+            state
+                .unstable_blocks
+                .next_block_headers_max_height()
+                .unwrap_or(0),
+            main_chain_height,
+        )
 }
 
 #[cfg(test)]
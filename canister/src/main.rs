@@ -1,8 +1,9 @@
 use ic_btc_canister::types::{HttpRequest, HttpResponse};
 use ic_btc_interface::{
     Config, GetBalanceRequest, GetBlockHeadersRequest, GetBlockHeadersResponse,
-    GetCurrentFeePercentilesRequest, GetUtxosRequest, GetUtxosResponse, InitConfig,
-    MillisatoshiPerByte, Satoshi, SendTransactionRequest, SetConfigRequest,
+    GetCurrentFeePercentilesRequest, GetTransactionRequest, GetTransactionResponse,
+    GetUtxosRequest, GetUtxosResponse, InitConfig, MillisatoshiPerByte, Satoshi,
+    SendTransactionRequest, SetConfigRequest,
 };
 use ic_cdk::api::call::ManualReply;
 use ic_cdk_macros::{heartbeat, init, inspect_message, post_upgrade, pre_upgrade, query, update};
@@ -85,6 +86,29 @@ pub fn bitcoin_get_block_headers(
     }
 }
 
+#[update(manual_reply = true)]
+pub fn bitcoin_get_transaction(
+    request: GetTransactionRequest,
+) -> ManualReply<GetTransactionResponse> {
+    match ic_btc_canister::get_transaction(request) {
+        Ok(response) => ManualReply::one(response),
+        Err(e) => ManualReply::reject(format!("get_transaction failed: {:?}", e).as_str()),
+    }
+}
+
+#[query(manual_reply = true)]
+pub fn bitcoin_get_transaction_query(
+    request: GetTransactionRequest,
+) -> ManualReply<GetTransactionResponse> {
+    if ic_cdk::api::data_certificate().is_none() {
+        return ManualReply::reject("get_transaction_query cannot be called in replicated mode");
+    }
+    match ic_btc_canister::get_transaction_query(request) {
+        Ok(response) => ManualReply::one(response),
+        Err(e) => ManualReply::reject(format!("get_transaction_query failed: {:?}", e).as_str()),
+    }
+}
+
 #[update(manual_reply = true)]
 async fn bitcoin_send_transaction(request: SendTransactionRequest) -> ManualReply<()> {
     match ic_btc_canister::send_transaction(request).await {
@@ -105,9 +129,12 @@ pub fn get_config() -> Config {
     ic_btc_canister::get_config()
 }
 
-#[update]
-async fn set_config(request: SetConfigRequest) {
-    ic_btc_canister::set_config(request).await
+#[update(manual_reply = true)]
+async fn set_config(request: SetConfigRequest) -> ManualReply<()> {
+    match ic_btc_canister::set_config(request).await {
+        Ok(()) => ManualReply::one(()),
+        Err(e) => ManualReply::reject(format!("set_config failed: {:?}", e).as_str()),
+    }
 }
 
 #[query]
@@ -121,6 +148,7 @@ fn inspect_message() {
     let inspected_method_name = ic_cdk::api::call::method_name();
     if inspected_method_name.as_str() != "bitcoin_get_balance_query"
         && inspected_method_name.as_str() != "bitcoin_get_utxos_query"
+        && inspected_method_name.as_str() != "bitcoin_get_transaction_query"
     {
         ic_cdk::api::call::accept_message();
     }
@@ -17,6 +17,10 @@ const MEDIUM_UTXOS: MemoryId = MemoryId::new(3);
 const BALANCES: MemoryId = MemoryId::new(4);
 const BLOCK_HEADERS: MemoryId = MemoryId::new(5);
 const BLOCK_HEIGHTS: MemoryId = MemoryId::new(6);
+const BLOCK_HASH_HEIGHTS: MemoryId = MemoryId::new(7);
+const TX_INDEX: MemoryId = MemoryId::new(8);
+const TX_INDEX_DATA: MemoryId = MemoryId::new(9);
+const USED_ADDRESSES: MemoryId = MemoryId::new(10);
 
 #[cfg(feature = "file_memory")]
 type InnerMemory = FileMemory;
@@ -68,6 +72,13 @@ pub fn set_memory(memory: InnerMemory) {
     MEMORY_MANAGER.with(|memory_manager| memory_manager.replace(Some(MemoryManager::init(memory))));
 }
 
+/// Replaces the memory with a new, empty one, so that a state created afterwards doesn't share
+/// its stable structures with the states created before it.
+#[cfg(all(test, not(feature = "file_memory")))]
+pub fn reset_memory() {
+    set_memory(InnerMemory::default());
+}
+
 pub fn get_upgrades_memory() -> Memory {
     with_memory_manager(|m| m.get(UPGRADES))
 }
@@ -96,6 +107,22 @@ pub fn get_block_heights_memory() -> Memory {
     with_memory_manager(|m| m.get(BLOCK_HEIGHTS))
 }
 
+pub fn get_block_hash_heights_memory() -> Memory {
+    with_memory_manager(|m| m.get(BLOCK_HASH_HEIGHTS))
+}
+
+pub fn get_tx_index_memory() -> Memory {
+    with_memory_manager(|m| m.get(TX_INDEX))
+}
+
+pub fn get_tx_index_data_memory() -> Memory {
+    with_memory_manager(|m| m.get(TX_INDEX_DATA))
+}
+
+pub fn get_used_addresses_memory() -> Memory {
+    with_memory_manager(|m| m.get(USED_ADDRESSES))
+}
+
 /// Writes the bytes at the specified offset, growing the memory size if needed.
 pub fn write<M: MemoryTrait>(memory: &M, offset: u64, bytes: &[u8]) {
     let last_byte = offset
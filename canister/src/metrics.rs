@@ -246,4 +246,30 @@ mod test {
             vec![(f64::INFINITY, 2.0)]
         );
     }
+
+    #[test]
+    fn metrics_round_trip() {
+        let mut metrics = Metrics::default();
+        metrics.get_utxos_total.observe(1);
+        metrics.get_utxos_total.observe(700 * M);
+        metrics.get_balance_total.observe(10_000 * M + 1);
+        metrics.block_insertion.observe(0);
+        metrics.send_transaction_count = 3;
+        metrics.cycles_burnt = Some(1_000);
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&metrics, &mut bytes).unwrap();
+        let new_metrics: Metrics = ciborium::de::from_reader(&bytes[..]).unwrap();
+
+        assert_eq!(
+            new_metrics.get_utxos_total.buckets().collect::<Vec<_>>(),
+            metrics.get_utxos_total.buckets().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            new_metrics.get_balance_total.buckets().collect::<Vec<_>>(),
+            metrics.get_balance_total.buckets().collect::<Vec<_>>()
+        );
+        assert_eq!(new_metrics.get_utxos_total.sum, metrics.get_utxos_total.sum);
+        assert!(new_metrics == metrics);
+    }
 }
@@ -218,6 +218,70 @@ pub fn time() -> u64 {
         .as_secs()
 }
 
+/// An abstraction over the instruction counter and the clock of the canister, allowing
+/// them to be injected.
+pub trait Runtime {
+    /// Returns the current instruction count.
+    fn performance_counter(&self) -> u64;
+
+    /// Returns the current time in seconds.
+    fn time(&self) -> u64;
+}
+
+/// The runtime of the canister, backed by the functions in this module.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CanisterRuntime;
+
+impl Runtime for CanisterRuntime {
+    fn performance_counter(&self) -> u64 {
+        performance_counter()
+    }
+
+    fn time(&self) -> u64 {
+        time()
+    }
+}
+
+/// A runtime with a fixed time, and an instruction counter that is incremented by a fixed
+/// step whenever it's read.
+///
+/// Clones of a `MockRuntime` share the same instruction counter.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct MockRuntime {
+    performance_counter: std::rc::Rc<std::cell::Cell<u64>>,
+    performance_counter_step: u64,
+    time: u64,
+}
+
+#[cfg(test)]
+impl MockRuntime {
+    pub fn new(time: u64, performance_counter_step: u64) -> Self {
+        Self {
+            performance_counter: Default::default(),
+            performance_counter_step,
+            time,
+        }
+    }
+
+    pub fn performance_counter_reset(&self) {
+        self.performance_counter.set(0);
+    }
+}
+
+#[cfg(test)]
+impl Runtime for MockRuntime {
+    fn performance_counter(&self) -> u64 {
+        let performance_counter = self.performance_counter.get() + self.performance_counter_step;
+        self.performance_counter.set(performance_counter);
+        performance_counter
+    }
+
+    fn time(&self) -> u64 {
+        self.time
+    }
+}
+
 #[cfg(target_arch = "wasm32")]
 pub fn cycles_burn() -> u128 {
     ic_cdk::api::cycles_burn(ic_cdk::api::canister_balance128())
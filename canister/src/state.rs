@@ -1,22 +1,54 @@
 use crate::{
     address_utxoset::AddressUtxoSet,
-    block_header_store::BlockHeaderStore,
+    block_header_store::{deserialize_block_header, BlockHeaderStore, HeadersPruned},
+    blocktree::BlockChain,
     metrics::Metrics,
-    runtime::{inc_performance_counter, performance_counter, print, time},
+    runtime::{inc_performance_counter, print, time, CanisterRuntime, Runtime},
     types::{
-        into_bitcoin_network, Address, BlockHeaderBlob, GetSuccessorsCompleteResponse,
-        GetSuccessorsPartialResponse, Slicing,
+        default_stability_threshold, into_bitcoin_network, network_from_genesis_hash, Address,
+        BlockHeaderBlob, GetSuccessorsCompleteResponse, GetSuccessorsPartialResponse,
+        GetSuccessorsRequest, GetSuccessorsRequestInitial, Slicing, Utxo,
     },
     unstable_blocks::{self, UnstableBlocks},
-    validation::ValidationContext,
+    utxo_set::time_slice_predicate,
+    validation::{HeaderCache, ValidationContext},
     UtxoSet,
 };
-use bitcoin::{consensus::Decodable, BlockHeader};
+use bitcoin::{
+    consensus::{Decodable, Encodable},
+    hashes::{sha256d, Hash},
+    BlockHeader,
+};
 use candid::Principal;
 use ic_btc_interface::{Fees, Flag, Height, MillisatoshiPerByte, Network};
-use ic_btc_types::{Block, BlockHash, OutPoint};
-use ic_btc_validation::{validate_header, ValidateHeaderError as InsertBlockError};
+use ic_btc_types::{Block, BlockHash, OutPoint, Transaction, Txid};
+use ic_btc_validation::{validate_header, HeaderStore, ValidateHeaderError};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::TryFrom,
+};
+
+mod descriptor_scan;
+mod fee_estimation;
+mod fork_ratio;
+mod orphans;
+mod persistence;
+#[cfg(not(target_arch = "wasm32"))]
+mod reindex;
+mod tip_observers;
+
+pub use descriptor_scan::{DescriptorUtxosError, TooManyAddresses, MAX_MULTI_ADDRESSES};
+pub use fee_estimation::{FeePercentilesCache, MAX_ESTIMATED_CONFIRMATION_BLOCKS};
+use fork_ratio::is_rejected_anchor_fork;
+pub use fork_ratio::MAX_FORK_RATIO;
+pub use orphans::{add_orphan, try_connect_orphans, AddOrphanError, Orphan};
+use orphans::{default_max_orphan_age, default_max_orphan_pool_size};
+pub use persistence::{LoadStateError, NetworkConsistencyError};
+#[cfg(not(target_arch = "wasm32"))]
+pub use reindex::{AuditError, ReindexError};
+use tip_observers::TipObserversKey;
+pub use tip_observers::{subscribe_to_tip_changes, TipChanged};
 
 /// A structure used to maintain the entire state.
 // NOTE: `PartialEq` is only available in tests as it would be impractically
@@ -50,6 +82,8 @@ pub struct State {
     pub metrics: Metrics,
 
     /// Flag to control access to the APIs provided by the canister.
+    /// Prefer `State::set_api_access` over setting this field directly, so that changes are
+    /// recorded in `api_access_transitions`.
     pub api_access: Flag,
 
     /// Flag to determine if the API should be automatically disabled
@@ -70,6 +104,76 @@ pub struct State {
     /// NOTE: serde(default) is used here for backward-compatibility.
     #[serde(default)]
     pub lazily_evaluate_fee_percentiles: Flag,
+
+    /// The maximum number of UTXOs an address can have for `get_utxos` to serve it.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub max_utxos_per_query: Option<u64>,
+
+    /// Blocks whose parent isn't known yet, held until their parent is inserted.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub orphan_pool: Vec<Orphan>,
+
+    /// The age in seconds after which a block in the orphan pool is considered stale.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default = "default_max_orphan_age")]
+    pub max_orphan_age: u64,
+
+    /// The maximum total size in bytes of the blocks in the orphan pool.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default = "default_max_orphan_pool_size")]
+    pub max_orphan_pool_size: usize,
+
+    /// The tip of the main chain as of the last call to `reconsider_best_chain`.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub main_chain_tip: Option<BlockHash>,
+
+    /// The most recent reorgs reported by `reconsider_best_chain`, oldest first.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub reorg_history: VecDeque<ReorgEvent>,
+
+    /// The time in seconds after which a partial response that made no progress is discarded.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default = "default_partial_response_timeout")]
+    pub partial_response_timeout: u64,
+
+    /// The hashes of the blocks recently inserted into the unstable blocks.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub recent_block_hashes: RecentBlockHashes,
+
+    /// A cache of the stable block headers read when validating headers.
+    #[serde(skip)]
+    pub header_cache: HeaderCache,
+
+    /// The changes of the `api_access` flag made with `State::set_api_access`.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub api_access_transitions: FlagTransitions,
+
+    /// The changes of the `syncing` flag made with `State::set_syncing`.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub syncing_transitions: FlagTransitions,
+
+    /// The maximum number of `get_utxos` responses that are cached. Zero disables the cache.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    utxos_cache_capacity: u32,
+
+    /// The fee returned for every fee percentile when there are no fees to compute them from.
+    /// Falls back to `fee_percentiles::DEFAULT_FALLBACK_FEE_PER_BYTE` if not set.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub fallback_fee_per_byte: Option<MillisatoshiPerByte>,
+
+    /// The key of the observers registered for this state with `subscribe_to_tip_changes`.
+    /// The key isn't serialized, so a loaded state starts without observers.
+    #[serde(skip)]
+    tip_observers_key: TipObserversKey,
 }
 
 impl State {
@@ -97,333 +201,3585 @@ impl State {
             watchdog_canister: None,
             burn_cycles: Flag::Disabled,
             lazily_evaluate_fee_percentiles: Flag::Disabled,
+            max_utxos_per_query: None,
+            orphan_pool: vec![],
+            max_orphan_age: default_max_orphan_age(),
+            max_orphan_pool_size: default_max_orphan_pool_size(),
+            main_chain_tip: None,
+            reorg_history: VecDeque::new(),
+            partial_response_timeout: default_partial_response_timeout(),
+            recent_block_hashes: RecentBlockHashes::default(),
+            header_cache: HeaderCache::default(),
+            api_access_transitions: FlagTransitions::default(),
+            syncing_transitions: FlagTransitions::default(),
+            utxos_cache_capacity: 0,
+            fallback_fee_per_byte: None,
+            tip_observers_key: TipObserversKey::default(),
         }
     }
 
-    pub fn network(&self) -> Network {
-        self.utxos.network()
+    /// Same as `State::new`, but with the default stability threshold of the given network
+    /// (see `types::default_stability_threshold`).
+    pub fn new_with_network_defaults(network: Network, genesis_block: Block) -> Self {
+        Self::new(default_stability_threshold(network), network, genesis_block)
     }
 
-    /// The height of the latest stable block.
-    pub fn stable_height(&self) -> Height {
-        self.utxos.next_height()
+    /// Discards the partial response being processed if it made no progress for longer
+    /// than `partial_response_timeout`, so that a fresh fetch can take place.
+    ///
+    /// Returns true if the partial response was discarded.
+    pub fn time_out_partial_response(&mut self, now: u64) -> bool {
+        if !matches!(
+            self.syncing_state.response_to_process,
+            Some(ResponseToProcess::Partial(_, _))
+        ) {
+            return false;
+        }
+
+        match self.syncing_state.partial_response_last_progress {
+            Some(last_progress)
+                if now.saturating_sub(last_progress) > self.partial_response_timeout => {}
+            Some(_) => return false,
+            None => {
+                // The partial response predates progress tracking. Start tracking it now.
+                self.syncing_state.partial_response_last_progress = Some(now);
+                return false;
+            }
+        }
+
+        self.syncing_state.response_to_process = None;
+        self.syncing_state.partial_response_last_progress = None;
+        self.syncing_state.num_partial_response_timeouts += 1;
+        true
     }
 
-    /// Returns the UTXO set of a given bitcoin address.
-    pub fn get_utxos(&self, address: Address) -> AddressUtxoSet<'_> {
-        AddressUtxoSet::new(address, &self.utxos, &self.unstable_blocks)
+    pub fn network(&self) -> Network {
+        self.utxos.network()
     }
-}
 
-/// Inserts a block into the state.
-/// Returns an error if the block doesn't extend any known block in the state.
-pub fn insert_block(state: &mut State, block: Block) -> Result<(), InsertBlockError> {
-    let start = performance_counter();
-    validate_header(
-        &into_bitcoin_network(state.network()),
-        &ValidationContext::new(state, block.header())
-            .map_err(|_| InsertBlockError::PrevHeaderNotFound)?,
-        block.header(),
-        time(),
-    )?;
+    /// Removes the blocks that have already been fully ingested into the UTXO set from the
+    /// unstable blocks, so that the anchor is the next block to ingest.
+    ///
+    /// Ingestion pops every block once it's ingested, so this only has an effect if popping
+    /// lagged behind ingestion. Returns the number of removed blocks.
+    pub fn compact_after_ingestion(&mut self) -> usize {
+        let mut num_removed = 0;
+        loop {
+            let anchor_hash = self.unstable_blocks.anchor().block_hash();
+            let anchor_height = match self.stable_block_headers.get_by_hash(&anchor_hash) {
+                Some((height, _)) if height < self.stable_height() => height,
+                // The anchor hasn't been fully ingested yet.
+                _ => return num_removed,
+            };
 
-    unstable_blocks::push(&mut state.unstable_blocks, &state.utxos, block)
-        .expect("Inserting a block with a validated header must succeed.");
+            // The anchor's successor is the block ingested after it, if that's already known,
+            // and otherwise its successor on the main chain.
+            let next_anchor_hash = match self
+                .stable_block_headers
+                .get_block_hash_with_height(anchor_height + 1)
+            {
+                Some(block_hash) => block_hash,
+                None => match unstable_blocks::get_main_chain(&self.unstable_blocks)
+                    .into_chain()
+                    .get(1)
+                {
+                    Some(block) => block.block_hash(),
+                    None => return num_removed,
+                },
+            };
 
-    let instructions_count = performance_counter() - start;
-    state.metrics.block_insertion.observe(instructions_count);
-    Ok(())
-}
+            if unstable_blocks::advance_anchor(
+                &mut self.unstable_blocks,
+                &next_anchor_hash,
+                self.utxos.next_height(),
+            )
+            .is_none()
+            {
+                return num_removed;
+            }
 
-/// Pops any blocks in `UnstableBlocks` that are considered stable and ingests them to the UTXO set.
-///
-/// NOTE: This method does a form of time-slicing to stay within the instruction limit, and
-/// multiple calls may be required for all the stable blocks to be ingested.
-///
-/// Returns a bool indicating whether or not the state has changed.
-pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
-    fn pop_block(state: &mut State, ingested_block_hash: BlockHash) {
-        let stable_height = state.stable_height();
-        // Pop the stable block.
-        let popped_block = unstable_blocks::pop(&mut state.unstable_blocks, stable_height);
+            // The removed blocks are no longer in the unstable blocks.
+            self.recent_block_hashes.clear();
+            self.notify_tip_observers();
+            num_removed += 1;
+        }
+    }
 
-        // Sanity check that we just popped the same block that was ingested.
-        assert_eq!(popped_block.unwrap().block_hash(), ingested_block_hash);
+    /// Sets the canister from which blocks are retrieved, after validating it with
+    /// `validate_blocks_source`.
+    pub fn set_blocks_source(&mut self, principal: Principal) -> Result<(), SourceError> {
+        validate_blocks_source(principal)?;
+
+        print(&format!(
+            "Changing blocks source from {} to {}",
+            self.blocks_source, principal
+        ));
+        self.blocks_source = principal;
+        Ok(())
     }
 
-    let prev_state = (
-        state.utxos.next_height(),
-        &state.utxos.ingesting_block.clone(),
-    );
-    let has_state_changed = |state: &State| -> bool {
-        prev_state != (state.utxos.next_height(), &state.utxos.ingesting_block)
-    };
+    /// Sets the flag controlling access to the APIs, recording the change if the flag flips.
+    pub fn set_api_access(&mut self, flag: Flag) {
+        if self.api_access != flag {
+            print(&format!(
+                "Changing api_access from {:?} to {:?}",
+                self.api_access, flag
+            ));
+            self.api_access = flag;
+            self.api_access_transitions.record(time());
+        }
+    }
 
-    // Finish ingesting the stable block that's partially ingested, if that exists.
-    print("Running ingest_block_continue...");
-    match state.utxos.ingest_block_continue() {
-        None => {}
-        Some(Slicing::Paused(())) => return has_state_changed(state),
-        Some(Slicing::Done((ingested_block_hash, stats))) => {
-            state.metrics.block_ingestion_stats = stats;
-            pop_block(state, ingested_block_hash)
+    /// Sets the flag controlling whether new blocks are fetched, recording the change if the
+    /// flag flips.
+    pub fn set_syncing(&mut self, flag: Flag) {
+        if self.syncing_state.syncing != flag {
+            print(&format!(
+                "Changing syncing from {:?} to {:?}",
+                self.syncing_state.syncing, flag
+            ));
+            self.syncing_state.syncing = flag;
+            self.syncing_transitions.record(time());
         }
     }
 
-    // Check if there are any stable blocks and ingest those into the UTXO set.
-    print("Looking for new stable blocks to ingest...");
-    while let Some(new_stable_block) = unstable_blocks::peek(&state.unstable_blocks) {
-        print(&format!(
-            "Ingesting new stable block {:?}...",
-            new_stable_block.block_hash()
-        ));
+    /// The height of the latest stable block.
+    pub fn stable_height(&self) -> Height {
+        self.utxos.next_height()
+    }
 
-        // Store the block's header.
-        state
-            .stable_block_headers
-            .insert_block(new_stable_block, state.utxos.next_height());
+    /// Returns the UTXOs whose `scriptPubKey` equals the given script, in descending order by
+    /// height. Unlike `get_utxos`, this also covers scripts that don't map to a standard address.
+    ///
+    /// NOTE: UTXOs aren't indexed by script, so this does a full scan of the UTXO set and is
+    /// only suitable for small UTXO sets (e.g. in tests and tooling), and isn't available in the
+    /// canister. The scan doesn't account for a block that's partially ingested.
+    ///
+    /// Provably unspendable outputs (e.g. `OP_RETURN` outputs) are excluded unless
+    /// `include_unspendable` is set. As they're never added to the UTXO set, only those of
+    /// unstable blocks can be included.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn get_utxos_by_script(
+        &self,
+        script: &bitcoin::Script,
+        include_unspendable: bool,
+    ) -> Vec<Utxo> {
+        let script = script.as_bytes();
+        let mut utxos = BTreeSet::new();
+        let mut spent_outpoints = BTreeSet::new();
+
+        // Apply the blocks of the main chain, starting with the anchor.
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks);
+        for (i, block) in main_chain.into_chain().into_iter().enumerate() {
+            let height = self.stable_height() + i as u32;
+            for tx in block.txdata() {
+                for input in tx.input() {
+                    if !input.previous_output.is_null() {
+                        spent_outpoints.insert(OutPoint::from(&input.previous_output));
+                    }
+                }
+
+                for (vout, output) in tx.output().iter().enumerate() {
+                    if output.script_pubkey.as_bytes() == script
+                        && (include_unspendable || !output.script_pubkey.is_provably_unspendable())
+                    {
+                        utxos.insert(Utxo {
+                            outpoint: OutPoint::new(tx.txid(), vout as u32),
+                            value: output.value,
+                            height,
+                        });
+                    }
+                }
+            }
+        }
 
-        match state.utxos.ingest_block(new_stable_block.clone()) {
-            Slicing::Paused(()) => return has_state_changed(state),
-            Slicing::Done((ingested_block_hash, stats)) => {
-                state.metrics.block_ingestion_stats = stats;
-                pop_block(state, ingested_block_hash)
+        // Add the matching UTXOs of the stable blocks.
+        for (outpoint, (tx_out, height)) in self.utxos.utxos.iter() {
+            if tx_out.script_pubkey == script {
+                utxos.insert(Utxo {
+                    outpoint,
+                    value: tx_out.value,
+                    height,
+                });
             }
         }
+
+        utxos
+            .into_iter()
+            .filter(|utxo| !spent_outpoints.contains(&utxo.outpoint))
+            .collect()
     }
 
-    has_state_changed(state)
-}
+    /// Returns the number of confirmations of the given UTXO on the main chain, i.e. one for a
+    /// UTXO created in the tip, two for one created in the tip's parent, etc.
+    ///
+    /// Returns `None` if the outpoint is unknown or has been spent on the main chain.
+    pub fn confirmations(&self, outpoint: &OutPoint) -> Option<u32> {
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain();
+        let tip_height = self.stable_height() + main_chain.len() as u32 - 1;
+
+        let mut height = None;
+        for (i, block) in main_chain.into_iter().enumerate() {
+            for tx in block.txdata() {
+                if tx
+                    .input()
+                    .iter()
+                    .any(|input| OutPoint::from(&input.previous_output) == *outpoint)
+                {
+                    // The outpoint has been spent.
+                    return None;
+                }
+
+                if tx.txid() == outpoint.txid && (outpoint.vout as usize) < tx.output().len() {
+                    height = Some(self.stable_height() + i as u32);
+                }
+            }
+        }
 
-pub fn insert_next_block_headers(state: &mut State, next_block_headers: &[BlockHeaderBlob]) {
-    // The limit at which no further next block headers are processed.
-    // Note that the actual limit available on system subnets is 50B. The threshold is set
-    // lower to be conservative.
-    const MAX_INSTRUCTIONS_THRESHOLD: u64 = 30_000_000_000;
+        // If the outpoint isn't created in an unstable block, look it up in the stable UTXOs.
+        let height = height.or_else(|| self.utxos.get_utxo(outpoint).map(|(_, height)| height))?;
+        Some(tip_height - height + 1)
+    }
 
-    for block_header_blob in next_block_headers.iter() {
-        if inc_performance_counter() > MAX_INSTRUCTIONS_THRESHOLD {
-            print("Reaching instruction threshold while inserting next block headers. Breaking...");
-            break;
+    /// Returns the UTXOs added and removed by the blocks of the main chain in the height range
+    /// `(from_height, to_height]`, i.e. the changes that turn the UTXO set as of `from_height`
+    /// into the UTXO set as of `to_height`. UTXOs that are both created and spent within the
+    /// range are in neither list. Both lists are in descending order by height.
+    ///
+    /// Only the unstable blocks are kept in full, so `None` is returned if `from_height` is
+    /// below the stable height, if `to_height` is above the main chain's tip, or if
+    /// `from_height` is greater than `to_height`.
+    pub fn utxo_delta(
+        &self,
+        from_height: Height,
+        to_height: Height,
+    ) -> Option<(Vec<Utxo>, Vec<Utxo>)> {
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain();
+        let tip_height = self.stable_height() + main_chain.len() as u32 - 1;
+        if from_height < self.stable_height() || to_height > tip_height || from_height > to_height {
+            return None;
         }
 
-        let block_header = match BlockHeader::consensus_decode(block_header_blob.as_slice()) {
-            Ok(header) => header,
-            Err(err) => {
-                print(&format!(
-                    "ERROR: Failed decode block header. Err: {:?}, Block header: {:?}",
-                    err, block_header_blob,
-                ));
-                return;
+        // Collect the UTXOs created by the blocks up to `to_height`, along with the outpoints
+        // spent by the blocks in the range.
+        let mut created = BTreeMap::new();
+        let mut spent = BTreeSet::new();
+        for (i, block) in main_chain.into_iter().enumerate() {
+            let height = self.stable_height() + i as u32;
+            if height > to_height {
+                break;
             }
-        };
 
-        if state.unstable_blocks.has_next_block_header(&block_header) {
-            // Already processed this block header. Skip...
-            continue;
+            for tx in block.txdata() {
+                if height > from_height {
+                    for input in tx.input() {
+                        if !input.previous_output.is_null() {
+                            spent.insert(OutPoint::from(&input.previous_output));
+                        }
+                    }
+                }
+
+                for (vout, output) in tx.output().iter().enumerate() {
+                    if !output.script_pubkey.is_provably_unspendable() {
+                        let outpoint = OutPoint::new(tx.txid(), vout as u32);
+                        created.insert(
+                            outpoint.clone(),
+                            Utxo {
+                                outpoint,
+                                value: output.value,
+                                height,
+                            },
+                        );
+                    }
+                }
+            }
         }
 
-        let validation_result =
-            match ValidationContext::new_with_next_block_headers(state, &block_header)
-                .map_err(|_| InsertBlockError::PrevHeaderNotFound)
-            {
-                Ok(store) => validate_header(
-                    &into_bitcoin_network(state.network()),
-                    &store,
-                    &block_header,
-                    time(),
-                ),
-                Err(err) => Err(err),
+        let mut removed = BTreeSet::new();
+        for outpoint in spent {
+            match created.remove(&outpoint) {
+                // The UTXO was both created and spent within the range.
+                Some(utxo) if utxo.height > from_height => {}
+                Some(utxo) => {
+                    removed.insert(utxo);
+                }
+                None => {
+                    if let Some((tx_out, height)) = self.utxos.get_utxo(&outpoint) {
+                        removed.insert(Utxo {
+                            outpoint,
+                            value: tx_out.value,
+                            height,
+                        });
+                    }
+                }
+            }
+        }
+
+        let added: BTreeSet<_> = created
+            .into_values()
+            .filter(|utxo| utxo.height > from_height)
+            .collect();
+
+        Some((added.into_iter().collect(), removed.into_iter().collect()))
+    }
+
+    /// Returns a report of the outputs created and spent by the blocks of the main chain
+    /// that haven't been ingested into the UTXO set yet, starting with the anchor.
+    ///
+    /// Provably unspendable outputs are excluded, as they're never added to the UTXO set.
+    pub fn utxo_flow_report(&self) -> UtxoFlowReport {
+        let mut report = UtxoFlowReport::default();
+
+        for block in unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain() {
+            let mut num_created = 0;
+            let mut num_spent = 0;
+            for tx in block.txdata() {
+                num_spent += tx
+                    .input()
+                    .iter()
+                    .filter(|input| !input.previous_output.is_null())
+                    .count() as u64;
+                num_created += tx
+                    .output()
+                    .iter()
+                    .filter(|output| !output.script_pubkey.is_provably_unspendable())
+                    .count() as u64;
+            }
+
+            report.num_created += num_created;
+            report.num_spent += num_spent;
+
+            let net_change = num_created as i64 - num_spent as i64;
+            let is_largest = match &report.largest_block_contribution {
+                Some((_, largest)) => net_change > *largest,
+                None => true,
             };
+            if is_largest {
+                report.largest_block_contribution = Some((block.block_hash(), net_change));
+            }
+        }
 
-        if let Err(err) = validation_result {
-            print(&format!(
-                "ERROR: Failed to validate block header. Err: {:?}, Block header: {:?}",
-                err, block_header,
-            ));
+        report.net_change = report.num_created as i64 - report.num_spent as i64;
+        report
+    }
 
-            return;
+    /// Returns the total value of the UTXOs of the stable blocks.
+    ///
+    /// NOTE: This does a full scan of the UTXO set, and is meant for monitoring rather than
+    /// being called frequently. It's only available outside of the canister.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn total_supply(&self) -> u64 {
+        self.utxos.get_total_supply()
+    }
+
+    /// Returns the total value of the UTXOs of the main chain, i.e. `total_supply` along with
+    /// the value created and spent by the unstable blocks of the main chain.
+    ///
+    /// As with `get_utxos_by_script`, a block that's partially ingested isn't accounted for.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn total_supply_including_unstable(&self) -> u64 {
+        let mut supply = self.total_supply();
+        let mut unstable_outputs = BTreeMap::new();
+
+        for block in unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain() {
+            for tx in block.txdata() {
+                for input in tx.input() {
+                    if input.previous_output.is_null() {
+                        continue;
+                    }
+
+                    let outpoint = OutPoint::from(&input.previous_output);
+                    let value = match unstable_outputs.remove(&outpoint) {
+                        Some(value) => value,
+                        None => self
+                            .utxos
+                            .get_utxo(&outpoint)
+                            .map(|(tx_out, _)| tx_out.value)
+                            .unwrap_or(0),
+                    };
+                    supply = supply.saturating_sub(value);
+                }
+
+                for (vout, output) in tx.output().iter().enumerate() {
+                    if !output.script_pubkey.is_provably_unspendable() {
+                        unstable_outputs
+                            .insert(OutPoint::new(tx.txid(), vout as u32), output.value);
+                        supply += output.value;
+                    }
+                }
+            }
         }
 
-        if let Err(err) = state
-            .unstable_blocks
-            .insert_next_block_header(block_header, state.stable_height())
-        {
-            print(&format!(
-                "ERROR: Failed to insert next block header. Err: {:?}, Block header: {:?}",
-                err, block_header,
-            ));
-            return;
+        supply
+    }
+
+    /// Returns the hash of the block at the given height of the main chain, or `None` if the
+    /// height is above the main chain's tip.
+    pub fn hash_at_height(&self, height: Height) -> Option<BlockHash> {
+        match height.checked_sub(self.stable_height()) {
+            Some(index) => unstable_blocks::get_main_chain(&self.unstable_blocks)
+                .into_chain()
+                .get(index as usize)
+                .map(|block| block.block_hash()),
+            None => self.stable_block_headers.get_block_hash_with_height(height),
         }
     }
-}
 
-pub fn main_chain_height(state: &State) -> Height {
-    unstable_blocks::get_main_chain_length(&state.unstable_blocks) as u32
-        + state.utxos.next_height()
-        - 1
-}
+    /// Returns true if the block with the given hash is on the main chain, i.e. it's either
+    /// stable or on the main chain of the unstable blocks.
+    ///
+    /// Blocks on forks, as well as unknown blocks, aren't on the main chain. If stable headers
+    /// have been pruned, an unknown block may be a pruned main chain block, and `None` is
+    /// returned.
+    pub fn is_on_main_chain(&self, hash: &BlockHash) -> Option<bool> {
+        // Only the headers of main chain blocks are made stable.
+        if self
+            .stable_block_headers
+            .get_with_block_hash(hash)
+            .is_some()
+            || unstable_blocks::get_main_chain(&self.unstable_blocks).contains(hash)
+        {
+            return Some(true);
+        }
 
-pub fn get_unstable_blocks(state: &State) -> Vec<&Block> {
-    unstable_blocks::get_blocks(&state.unstable_blocks)
-}
+        if unstable_blocks::get_blocks(&self.unstable_blocks)
+            .iter()
+            .any(|block| &block.block_hash() == hash)
+            || !self.stable_block_headers.has_pruned_headers()
+        {
+            Some(false)
+        } else {
+            None
+        }
+    }
 
-// The maximum size in bytes of a bitcoin script for it to be considered "small".
-const TX_OUT_SCRIPT_MAX_SIZE_SMALL: u32 = 25;
+    /// Returns the block at the given height of the main chain.
+    ///
+    /// Only the unstable blocks are kept in full, so `None` is returned for heights that have
+    /// already been ingested into the UTXO set, as well as for heights above the main chain's tip.
+    pub fn get_block(&self, height: Height) -> Option<Block> {
+        let index = height.checked_sub(self.stable_height())?;
+        unstable_blocks::get_main_chain(&self.unstable_blocks)
+            .into_chain()
+            .get(index as usize)
+            .map(|block| (*block).clone())
+    }
 
-// The maximum size in bytes of a bitcoin script for it to be considered "medium".
-const TX_OUT_SCRIPT_MAX_SIZE_MEDIUM: u32 = 201;
+    /// Returns the coinbase transaction of the block at the given height of the main chain.
+    ///
+    /// As with `get_block`, only the coinbase transactions of unstable blocks are available.
+    pub fn get_coinbase(&self, height: Height) -> Option<Transaction> {
+        let index = height.checked_sub(self.stable_height())?;
+        unstable_blocks::get_main_chain(&self.unstable_blocks)
+            .into_chain()
+            .get(index as usize)
+            .and_then(|block| block.txdata().first().cloned())
+    }
 
-// A transaction output's value in satoshis is a `u64`, which is 8 bytes.
-const TX_OUT_VALUE_SIZE: u32 = 8;
+    /// Calls `f` with every block of the main chain along with its height, from the genesis
+    /// block to the tip.
+    ///
+    /// Only the headers of stable blocks are kept, so stable blocks are passed without their
+    /// transactions.
+    ///
+    /// Returns an error, without calling `f`, if some of the stable headers have been pruned by
+    /// a retention policy (see `HeaderRetention`).
+    pub fn for_each_main_chain_block<F: FnMut(&Block, Height)>(
+        &self,
+        mut f: F,
+    ) -> Result<(), HeadersPruned> {
+        let stable_height = self.stable_height();
+        if stable_height > 0 {
+            let header_blobs = self
+                .stable_block_headers
+                .get_block_headers_in_range(0..=stable_height - 1)?;
+            for (height, header_blob) in (0..).zip(header_blobs) {
+                let block = Block::new(bitcoin::Block {
+                    header: deserialize_block_header(header_blob),
+                    txdata: vec![],
+                });
+                f(&block, height);
+            }
+        }
 
-const TX_OUT_MAX_SIZE_SMALL: u32 = TX_OUT_SCRIPT_MAX_SIZE_SMALL + TX_OUT_VALUE_SIZE;
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain();
+        for (i, block) in main_chain.into_iter().enumerate() {
+            f(block, stable_height + i as u32);
+        }
 
-const TX_OUT_MAX_SIZE_MEDIUM: u32 = TX_OUT_SCRIPT_MAX_SIZE_MEDIUM + TX_OUT_VALUE_SIZE;
+        Ok(())
+    }
 
-// The height is a `u32`, which is 4 bytes.
-const HEIGHT_SIZE: u32 = 4;
+    /// Returns the chain of unstable blocks starting from the anchor and ending with `tip`.
+    ///
+    /// Unlike `unstable_blocks::get_chain_with_tip`, a `tip` that has already become stable is
+    /// distinguished from an unknown one.
+    pub fn get_chain_with_tip(&self, tip: &BlockHash) -> ChainWithTip<'_> {
+        if let Some(chain) = unstable_blocks::get_chain_with_tip(&self.unstable_blocks, tip) {
+            return ChainWithTip::Unstable(chain);
+        }
 
-/// The size of a key in the UTXOs map, which is an outpoint.
-pub const UTXO_KEY_SIZE: usize = OutPoint::size() as usize;
+        match self.stable_block_headers.get_by_hash(tip) {
+            Some((height, _)) => ChainWithTip::BelowAnchor(height),
+            None => ChainWithTip::Unknown,
+        }
+    }
 
-/// The max size of a value in the "small UTXOs" map.
-pub const UTXO_VALUE_MAX_SIZE_SMALL: usize = (TX_OUT_MAX_SIZE_SMALL + HEIGHT_SIZE) as usize;
+    /// Returns information about the block with the given hash at the given verbosity, or
+    /// `None` if the block is unknown.
+    ///
+    /// Only the unstable blocks are kept in full. For stable blocks, only their header is
+    /// returned, regardless of the verbosity.
+    pub fn get_block_info(
+        &self,
+        block_hash: &BlockHash,
+        verbosity: BlockVerbosity,
+    ) -> Option<BlockInfo> {
+        let chain = match unstable_blocks::get_chain_with_tip(&self.unstable_blocks, block_hash) {
+            Some(chain) => chain,
+            None => {
+                return self
+                    .stable_block_headers
+                    .get_by_hash(block_hash)
+                    .map(|(height, header)| BlockInfo::StableHeader { height, header });
+            }
+        };
 
-/// The max size of a value in the "medium UTXOs" map.
-pub const UTXO_VALUE_MAX_SIZE_MEDIUM: usize = (TX_OUT_MAX_SIZE_MEDIUM + HEIGHT_SIZE) as usize;
+        let height = self.stable_height() + chain.len() as u32 - 1;
+        let block = chain.tip();
+        Some(match verbosity {
+            BlockVerbosity::Raw => {
+                let mut bytes = vec![];
+                block
+                    .consensus_encode(&mut bytes)
+                    .expect("block must be encodable");
+                BlockInfo::Raw(bytes)
+            }
+            BlockVerbosity::Txids => BlockInfo::Txids {
+                height,
+                header: *block.header(),
+                txids: block.txdata().iter().map(|tx| tx.txid()).collect(),
+            },
+            BlockVerbosity::Transactions => BlockInfo::Transactions {
+                height,
+                header: *block.header(),
+                transactions: block.txdata().to_vec(),
+            },
+        })
+    }
 
-/// A response awaiting to be processed.
-#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+    /// Returns true if the given proof shows that the transaction with the given txid is
+    /// included in the block with the given hash.
+    ///
+    /// Returns false if the block isn't known.
+    ///
+    /// If the block is unstable, its body is known and the proof must have exactly the depth of
+    /// the block's merkle tree. Only the headers of stable blocks are kept, so as with SPV
+    /// clients, a proof for a stable block can't be tied to the depth of its tree, and a crafted
+    /// 64-byte transaction could be passed off as an inner node of the tree.
+    pub fn verify_merkle_proof(
+        &self,
+        block_hash: &BlockHash,
+        txid: &Txid,
+        proof: &MerkleProof,
+    ) -> bool {
+        let header = match unstable_blocks::get_chain_with_tip(&self.unstable_blocks, block_hash) {
+            Some(chain) => {
+                let block = chain.tip();
+                // The depth of the tree is the number of transactions rounded up to a power of
+                // two, in bits. In particular, an empty proof is only valid for a block with a
+                // single transaction.
+                let num_txs = block.txdata().len();
+                let tree_depth = num_txs.next_power_of_two().trailing_zeros() as usize;
+                if proof.index as usize >= num_txs || proof.siblings.len() != tree_depth {
+                    return false;
+                }
+                *block.header()
+            }
+            None => match self.stable_block_headers.get_by_hash(block_hash) {
+                Some((_, header)) => header,
+                None => return false,
+            },
+        };
+
+        let mut hash = txid.as_bytes().to_vec();
+        let mut index = proof.index;
+        for sibling in proof.siblings.iter() {
+            let mut data = Vec::with_capacity(64);
+            if index % 2 == 0 {
+                data.extend_from_slice(&hash);
+                data.extend_from_slice(sibling);
+            } else {
+                data.extend_from_slice(sibling);
+                data.extend_from_slice(&hash);
+            }
+            hash = sha256d::Hash::hash(&data).into_inner().to_vec();
+            index /= 2;
+        }
+
+        // The index must be fully consumed by the path to the root.
+        index == 0 && hash[..] == header.merkle_root.into_inner()[..]
+    }
+
+    /// Returns the headers of the main chain, from genesis to the current tip, as a
+    /// concatenation of consensus-encoded headers.
+    ///
+    /// Returns an error if some of the stable headers have been pruned by a retention policy.
+    pub fn export_main_chain_headers(&self) -> Result<Vec<u8>, HeadersPruned> {
+        let stable_height = self.stable_height();
+        let mut headers = vec![];
+        if stable_height > 0 {
+            for header_blob in self
+                .stable_block_headers
+                .get_block_headers_in_range(0..=stable_height - 1)?
+            {
+                headers.extend_from_slice(header_blob.as_slice());
+            }
+        }
+
+        for block in unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain() {
+            block
+                .header()
+                .consensus_encode(&mut headers)
+                .expect("encoding a header must succeed");
+        }
+
+        Ok(headers)
+    }
+
+    /// Validates and loads the headers of a main chain, as exported by
+    /// `State::export_main_chain_headers`, returning the hash of the last header.
+    ///
+    /// Headers at heights already in the state's main chain must match it. The remaining
+    /// headers are validated and loaded as next block headers, so that the blocks they
+    /// describe can be fetched.
+    pub fn import_main_chain_headers(
+        &mut self,
+        headers: &[u8],
+    ) -> Result<BlockHash, ImportHeadersError> {
+        if headers.is_empty() || headers.len() % HEADER_SIZE != 0 {
+            return Err(ImportHeadersError::Malformed);
+        }
+
+        let tip_height = main_chain_height(self);
+        let mut last_hash = None;
+        for (height, header_bytes) in headers.chunks(HEADER_SIZE).enumerate() {
+            let height = height as Height;
+            let header = BlockHeader::consensus_decode(header_bytes)
+                .map_err(|_| ImportHeadersError::Malformed)?;
+            let block_hash = BlockHash::from(header.block_hash());
+
+            if height <= tip_height {
+                // The header must match the main chain, if its block is still known.
+                if let Some(known_hash) = self.hash_at_height(height) {
+                    if known_hash != block_hash {
+                        return Err(ImportHeadersError::ConflictsWithMainChain { height });
+                    }
+                }
+            } else if !self.unstable_blocks.has_next_block_header(&header) {
+                ValidationContext::new_with_next_block_headers(self, &header)
+                    .map_err(|_| ValidateHeaderError::PrevHeaderNotFound)
+                    .and_then(|store| {
+                        validate_header(
+                            &into_bitcoin_network(self.network()),
+                            &store,
+                            &header,
+                            time(),
+                        )
+                    })
+                    .map_err(|err| ImportHeadersError::InvalidHeader { height, err })?;
+
+                let stable_height = self.stable_height();
+                self.unstable_blocks
+                    .insert_next_block_header(header, stable_height)
+                    .map_err(|_| ImportHeadersError::InvalidHeader {
+                        height,
+                        err: ValidateHeaderError::PrevHeaderNotFound,
+                    })?;
+            }
+
+            last_hash = Some(block_hash);
+        }
+
+        Ok(last_hash.expect("headers cannot be empty"))
+    }
+
+    /// Returns the UTXO set of a given bitcoin address.
+    pub fn get_utxos(&self, address: Address) -> AddressUtxoSet<'_> {
+        AddressUtxoSet::new(address, &self.utxos, &self.unstable_blocks)
+    }
+
+    /// Returns the maximum number of `get_utxos` responses that are cached.
+    pub fn utxos_cache_capacity(&self) -> u32 {
+        self.utxos_cache_capacity
+    }
+
+    /// Sets the maximum number of `get_utxos` responses that are cached. A capacity of zero
+    /// disables the cache.
+    pub fn set_utxos_cache_capacity(&mut self, capacity: u32) -> Result<(), CacheCapacityTooLarge> {
+        validate_utxos_cache_capacity(capacity)?;
+        self.utxos_cache_capacity = capacity;
+        Ok(())
+    }
+
+    /// Returns the UTXOs of the given address on the main chain, split into the confirmed UTXOs
+    /// of stable blocks and the unconfirmed UTXOs of unstable blocks.
+    ///
+    /// Both lists are in descending order by height. Stable UTXOs that are spent by an unstable
+    /// block are in neither list.
+    pub fn get_utxos_split(&self, address: Address) -> (Vec<Utxo>, Vec<Utxo>) {
+        let mut address_utxos = self.get_utxos(address);
+        for block in unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain() {
+            address_utxos.apply_block(block);
+        }
+
+        let stable_height = self.stable_height();
+        address_utxos
+            .into_iter(None)
+            .partition(|utxo| utxo.height < stable_height)
+    }
+
+    /// Returns the `top_n` addresses with the largest stable balances, ordered by
+    /// descending balance. Addresses with a zero balance are excluded.
+    pub fn rich_list(&self, top_n: usize) -> Vec<(Address, u64)> {
+        self.utxos.top_balances(top_n)
+    }
+
+    /// Returns an order-independent hash of the stable UTXOs, or `None` while it's being rebuilt.
+    /// See `UtxoSet::utxo_set_hash` for more details.
+    pub fn utxo_set_hash(&self) -> Option<[u8; 32]> {
+        self.utxos.utxo_set_hash()
+    }
+
+    /// Re-evaluates the main chain and records its tip, given the current time in seconds.
+    ///
+    /// Returns the details of the reorg if the main chain no longer contains the tip recorded by
+    /// the previous call. Extensions of the main chain aren't reported, and neither is the first
+    /// call, as there's no previous tip to compare with. Reported reorgs are also added to the
+    /// reorg history, which holds up to `MAX_REORG_HISTORY` reorgs.
+    pub fn reconsider_best_chain(&mut self, now: u64) -> Option<ReorgEvent> {
+        // Switching to another fork only affects the unstable blocks. The stable UTXO set is
+        // only modified when ingesting blocks, so it must remain untouched here.
+        let stable_height = self.utxos.next_height();
+        let num_utxos = self.utxos.utxos_len();
+        let reorg = self.reconsider_best_chain_inner(now);
+        debug_assert_eq!(
+            (self.utxos.next_height(), self.utxos.utxos_len()),
+            (stable_height, num_utxos),
+            "Reconsidering the best chain must not modify the stable UTXO set."
+        );
+        reorg
+    }
+
+    fn reconsider_best_chain_inner(&mut self, now: u64) -> Option<ReorgEvent> {
+        self.notify_tip_observers();
+
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks);
+        let new_tip = main_chain.tip().block_hash();
+        let old_tip = self.main_chain_tip.replace(new_tip.clone())?;
+
+        if main_chain.contains(&old_tip)
+            || self
+                .stable_block_headers
+                .get_with_block_hash(&old_tip)
+                .is_some()
+        {
+            // The main chain has been extended, or the old tip has become stable since.
+            return None;
+        }
+
+        let (fork_point, num_disconnected_blocks) =
+            match unstable_blocks::get_chain_with_tip(&self.unstable_blocks, &old_tip) {
+                Some(old_chain) => {
+                    // Both chains start at the anchor, so they share at least one block.
+                    let old_chain = old_chain.into_chain();
+                    let num_common_blocks = old_chain
+                        .iter()
+                        .zip(main_chain.into_chain())
+                        .take_while(|(old_block, new_block)| {
+                            old_block.block_hash() == new_block.block_hash()
+                        })
+                        .count();
+                    (
+                        Some(old_chain[num_common_blocks - 1].block_hash()),
+                        Some((old_chain.len() - num_common_blocks) as u32),
+                    )
+                }
+                // The old tip has been discarded along with its fork when a block on the new main
+                // chain became stable.
+                None => (None, None),
+            };
+
+        let reorg = ReorgEvent {
+            timestamp: now,
+            old_tip,
+            new_tip,
+            fork_point,
+            num_disconnected_blocks,
+        };
+
+        if self.reorg_history.len() >= MAX_REORG_HISTORY {
+            self.reorg_history.pop_front();
+        }
+        self.reorg_history.push_back(reorg.clone());
+
+        Some(reorg)
+    }
+
+    /// Returns up to `limit` of the most recent reorgs, newest first.
+    pub fn recent_reorgs(&self, limit: usize) -> Vec<ReorgEvent> {
+        self.reorg_history
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns a compact overview of the state.
+    pub fn summary(&self) -> StateSummary {
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks);
+        StateSummary {
+            network: self.network(),
+            stable_height: self.stable_height(),
+            main_chain_height: main_chain_height(self),
+            unstable_block_count: get_unstable_blocks(self).len(),
+            tip_hash: main_chain.tip().block_hash(),
+            is_synced: crate::is_state_synced(self),
+            num_sync_errors: self.syncing_state.num_get_successors_rejects
+                + self.syncing_state.num_block_deserialize_errors
+                + self.syncing_state.num_insert_block_errors,
+        }
+    }
+
+    /// Returns the base fee charged for requests to the given endpoint.
+    pub fn fee(&self, endpoint: Endpoint) -> u128 {
+        match endpoint {
+            Endpoint::GetUtxos => self.fees.get_utxos_base,
+            Endpoint::GetBalance => self.fees.get_balance,
+            Endpoint::GetCurrentFeePercentiles => self.fees.get_current_fee_percentiles,
+            Endpoint::SendTransaction => self.fees.send_transaction_base,
+            Endpoint::GetBlockHeaders => self.fees.get_block_headers_base,
+            Endpoint::GetTransaction => self.fees.get_transaction,
+        }
+    }
+
+    /// Sets the base fee charged for requests to the given endpoint, leaving all other fees
+    /// unchanged. The fee is validated as in `State::set_fees`.
+    pub fn set_fee(&mut self, endpoint: Endpoint, fee: u128) -> Result<(), FeeError> {
+        let mut fees = self.fees.clone();
+        let field = match endpoint {
+            Endpoint::GetUtxos => &mut fees.get_utxos_base,
+            Endpoint::GetBalance => &mut fees.get_balance,
+            Endpoint::GetCurrentFeePercentiles => &mut fees.get_current_fee_percentiles,
+            Endpoint::SendTransaction => &mut fees.send_transaction_base,
+            Endpoint::GetBlockHeaders => &mut fees.get_block_headers_base,
+            Endpoint::GetTransaction => &mut fees.get_transaction,
+        };
+        *field = fee;
+        self.set_fees(fees)
+    }
+
+    /// Replaces all the fees, after validating them with `validate_fees`. If any fee is
+    /// invalid, none of the fees are updated.
+    pub fn set_fees(&mut self, fees: Fees) -> Result<(), FeeError> {
+        validate_fees(&fees)?;
+        self.fees = fees;
+        Ok(())
+    }
+}
+
+/// Validates that the given fees are within a sane range.
+///
+/// Every fee must be at most `MAX_FEE`, and the base fee of an endpoint must not exceed its
+/// maximum fee, as no request to the endpoint could be accepted otherwise. Zero fees are
+/// allowed, as they're used on development networks.
+pub fn validate_fees(fees: &Fees) -> Result<(), FeeError> {
+    // The fees of each endpoint, starting with its base fee, along with its maximum fee.
+    let endpoint_fees = [
+        (
+            Endpoint::GetUtxos,
+            vec![
+                fees.get_utxos_base,
+                fees.get_utxos_cycles_per_ten_instructions,
+                fees.get_utxos_maximum,
+            ],
+            Some(fees.get_utxos_maximum),
+        ),
+        (
+            Endpoint::GetBalance,
+            vec![fees.get_balance, fees.get_balance_maximum],
+            Some(fees.get_balance_maximum),
+        ),
+        (
+            Endpoint::GetCurrentFeePercentiles,
+            vec![
+                fees.get_current_fee_percentiles,
+                fees.get_current_fee_percentiles_maximum,
+            ],
+            Some(fees.get_current_fee_percentiles_maximum),
+        ),
+        (
+            Endpoint::SendTransaction,
+            vec![fees.send_transaction_base, fees.send_transaction_per_byte],
+            None,
+        ),
+        (
+            Endpoint::GetBlockHeaders,
+            vec![
+                fees.get_block_headers_base,
+                fees.get_block_headers_cycles_per_ten_instructions,
+                fees.get_block_headers_maximum,
+            ],
+            Some(fees.get_block_headers_maximum),
+        ),
+        (
+            Endpoint::GetTransaction,
+            vec![fees.get_transaction, fees.get_transaction_maximum],
+            Some(fees.get_transaction_maximum),
+        ),
+    ];
+
+    for (endpoint, values, maximum) in endpoint_fees.iter() {
+        if values.iter().any(|fee| *fee > MAX_FEE) {
+            return Err(FeeError::TooLarge(*endpoint));
+        }
+
+        if let Some(maximum) = maximum {
+            if values[0] > *maximum {
+                return Err(FeeError::BaseAboveMaximum(*endpoint));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates that blocks can be retrieved from the given principal.
+///
+/// The anonymous principal is rejected, as no blocks can ever be retrieved from it and syncing
+/// would silently stall.
+pub fn validate_blocks_source(principal: Principal) -> Result<(), SourceError> {
+    if principal == Principal::anonymous() {
+        return Err(SourceError::AnonymousPrincipal);
+    }
+
+    Ok(())
+}
+
+/// The largest fee, in cycles, that `validate_fees` accepts.
+pub const MAX_FEE: u128 = 1_000_000_000_000;
+
+/// The largest capacity of the `get_utxos` cache that `State::set_utxos_cache_capacity` accepts.
+/// Each cached response holds a single page of UTXOs, so this bounds the cache's memory.
+pub const MAX_UTXOS_CACHE_CAPACITY: u32 = 1_000;
+
+/// An error returned by `State::set_utxos_cache_capacity` when the capacity exceeds
+/// `MAX_UTXOS_CACHE_CAPACITY`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CacheCapacityTooLarge {
+    pub capacity: u32,
+    pub max: u32,
+}
+
+/// Checks that the given capacity can be set with `State::set_utxos_cache_capacity`.
+pub fn validate_utxos_cache_capacity(capacity: u32) -> Result<(), CacheCapacityTooLarge> {
+    if capacity > MAX_UTXOS_CACHE_CAPACITY {
+        return Err(CacheCapacityTooLarge {
+            capacity,
+            max: MAX_UTXOS_CACHE_CAPACITY,
+        });
+    }
+
+    Ok(())
+}
+
+/// A change of the main chain other than an extension, as returned by
+/// `State::reconsider_best_chain`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReorgEvent {
+    /// The time in seconds at which the reorg was detected.
+    pub timestamp: u64,
+    /// The tip of the main chain before the reorg.
+    pub old_tip: BlockHash,
+    /// The tip of the main chain after the reorg.
+    pub new_tip: BlockHash,
+    /// The last block that the old and new main chains have in common.
+    /// `None` if the old main chain has been discarded from the unstable blocks.
+    pub fork_point: Option<BlockHash>,
+    /// The number of blocks of the old main chain after the fork point.
+    /// `None` if the old main chain has been discarded from the unstable blocks.
+    pub num_disconnected_blocks: Option<u32>,
+}
+
+/// A compact overview of the state, as returned by `State::summary`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateSummary {
+    pub network: Network,
+    pub stable_height: Height,
+    pub main_chain_height: Height,
+    /// The number of unstable blocks, including the anchor.
+    pub unstable_block_count: usize,
+    /// The hash of the main chain's tip.
+    pub tip_hash: BlockHash,
+    pub is_synced: bool,
+    /// The total number of rejected `GetSuccessors` calls, blocks that failed to deserialize,
+    /// and blocks that failed to be inserted.
+    pub num_sync_errors: u64,
+}
+
+/// The outputs created and spent by the unstable blocks, as returned by
+/// `State::utxo_flow_report`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UtxoFlowReport {
+    pub num_created: u64,
+    pub num_spent: u64,
+    /// The change in the size of the UTXO set, i.e. `num_created - num_spent`.
+    pub net_change: i64,
+    /// The block with the largest net change, along with that change. If several blocks
+    /// have the same net change, the lowest one is reported.
+    pub largest_block_contribution: Option<(BlockHash, i64)>,
+}
+
+/// The verbosity of the information returned by `State::get_block_info`, mirroring the
+/// verbosity levels of Bitcoin Core's `getblock`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockVerbosity {
+    /// Verbosity 0: the consensus-encoded block.
+    Raw,
+    /// Verbosity 1: the block's header along with the IDs of its transactions.
+    Txids,
+    /// Verbosity 2: the block's header along with its decoded transactions.
+    Transactions,
+}
+
+impl TryFrom<u8> for BlockVerbosity {
+    type Error = String;
+
+    fn try_from(verbosity: u8) -> Result<Self, Self::Error> {
+        match verbosity {
+            0 => Ok(Self::Raw),
+            1 => Ok(Self::Txids),
+            2 => Ok(Self::Transactions),
+            _ => Err(format!("Unsupported verbosity: {}", verbosity)),
+        }
+    }
+}
+
+/// Information about a block, as returned by `State::get_block_info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockInfo {
+    /// The consensus-encoded block.
+    Raw(Vec<u8>),
+    Txids {
+        height: Height,
+        header: BlockHeader,
+        txids: Vec<Txid>,
+    },
+    Transactions {
+        height: Height,
+        header: BlockHeader,
+        transactions: Vec<Transaction>,
+    },
+    /// The header of a stable block, whose transactions are no longer available.
+    StableHeader { height: Height, header: BlockHeader },
+}
+
+/// The result of `State::get_chain_with_tip`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChainWithTip<'a> {
+    /// The chain of unstable blocks from the anchor to the tip.
+    Unstable(BlockChain<'a>),
+    /// The tip is a stable block at the given height, i.e. below the anchor.
+    BelowAnchor(Height),
+    /// The tip isn't a known block.
+    Unknown,
+}
+
+/// A proof that a transaction is included in a block, as checked by
+/// `State::verify_merkle_proof`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// The index of the transaction in the block.
+    pub index: u32,
+    /// The hashes along the path from the transaction to the merkle root, starting with the
+    /// sibling of the transaction, in internal byte order.
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// The endpoints that are charged a fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    GetUtxos,
+    GetBalance,
+    GetCurrentFeePercentiles,
+    SendTransaction,
+    GetBlockHeaders,
+    GetTransaction,
+}
+
+/// An error returned by `validate_fees`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FeeError {
+    /// A fee of the given endpoint exceeds `MAX_FEE`.
+    TooLarge(Endpoint),
+    /// The base fee of the given endpoint exceeds its maximum fee.
+    BaseAboveMaximum(Endpoint),
+}
+
+/// An error returned when inserting a block.
+#[derive(Debug, PartialEq)]
+pub enum InsertBlockError {
+    /// The header of the block is invalid.
+    InvalidHeader(ValidateHeaderError),
+    /// The block starts a new fork on top of the anchor with less work than the main chain,
+    /// while the ratio of forks in the unstable blocks is above `MAX_FORK_RATIO`.
+    TooManyForks,
+    /// The block is known to belong to a network other than the state's network.
+    WrongNetwork,
+    /// The block's parent is a stable block, i.e. the block's height is at or below the
+    /// stable height, so it can no longer become part of the main chain.
+    BelowStableHeight,
+}
+
+impl From<ValidateHeaderError> for InsertBlockError {
+    fn from(err: ValidateHeaderError) -> Self {
+        Self::InvalidHeader(err)
+    }
+}
+
+/// An error returned by `validate_blocks_source`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SourceError {
+    /// The anonymous principal can't be used as a blocks source.
+    AnonymousPrincipal,
+}
+
+/// An error returned by `State::import_main_chain_headers`.
+#[derive(Debug, PartialEq)]
+pub enum ImportHeadersError {
+    /// The bytes aren't a non-empty concatenation of consensus-encoded headers.
+    Malformed,
+    /// The header at the given height differs from the state's main chain.
+    ConflictsWithMainChain { height: Height },
+    /// The header at the given height failed validation.
+    InvalidHeader {
+        height: Height,
+        err: ValidateHeaderError,
+    },
+}
+
+/// Inserts a block into the state.
+/// Returns an error if the block doesn't extend any known block in the state.
+pub fn insert_block(state: &mut State, block: Block) -> Result<(), InsertBlockError> {
+    insert_block_with_runtime(state, block, &CanisterRuntime)
+}
+
+/// Same as `insert_block`, but uses the given runtime for the current time and for
+/// counting instructions.
+pub fn insert_block_with_runtime<R: Runtime>(
+    state: &mut State,
+    block: Block,
+    runtime: &R,
+) -> Result<(), InsertBlockError> {
+    let block_hash = block.block_hash();
+    if state.recent_block_hashes.touch(&block_hash) {
+        // The block was recently inserted, so inserting it again is a no-op.
+        return Ok(());
+    }
+
+    if belongs_to_other_network(&block, state.network()) {
+        return Err(InsertBlockError::WrongNetwork);
+    }
+
+    // Blocks extending a block below the anchor would fork below the anchor, which isn't
+    // possible as the stable blocks are no longer part of the block tree. The anchor's header
+    // may already be stored while it's being ingested, so heights are compared rather than
+    // checking whether the parent's header is stored.
+    if let Some((parent_height, _)) = state
+        .stable_block_headers
+        .get_by_hash(&BlockHash::from(block.header().prev_blockhash))
+    {
+        if parent_height < state.stable_height() {
+            return Err(InsertBlockError::BelowStableHeight);
+        }
+    }
+
+    let start = runtime.performance_counter();
+    let validation_context = ValidationContext::new(state, block.header())
+        .map_err(|_| ValidateHeaderError::PrevHeaderNotFound)?;
+    let height = validation_context.height() + 1;
+    validate_header(
+        &into_bitcoin_network(state.network()),
+        &validation_context,
+        block.header(),
+        runtime.time(),
+    )?;
+
+    // New forks on top of the anchor are only rejected once the header is known to be valid.
+    if is_rejected_anchor_fork(state, &block) {
+        return Err(InsertBlockError::TooManyForks);
+    }
+
+    unstable_blocks::push(&mut state.unstable_blocks, &state.utxos, block)
+        .expect("Inserting a block with a validated header must succeed.");
+    state.notify_tip_observers_of_block(&block_hash, height);
+    state.recent_block_hashes.insert(block_hash);
+
+    let instructions_count = runtime.performance_counter() - start;
+    state.metrics.block_insertion.observe(instructions_count);
+    Ok(())
+}
+
+/// Same as `insert_block`, but returns the hash of the main chain's tip after the insertion.
+pub fn insert_block_returning_tip(
+    state: &mut State,
+    block: Block,
+) -> Result<BlockHash, InsertBlockError> {
+    insert_block(state, block)?;
+    Ok(unstable_blocks::get_main_chain(&state.unstable_blocks)
+        .tip()
+        .block_hash())
+}
+
+// Returns true if the block is known to belong to a network other than the given one, i.e. if
+// it's the genesis block of another network or builds directly on top of one.
+//
+// Such blocks can't connect to the state's chain anyway, but are rejected explicitly so that
+// they aren't mistaken for orphans whose parent hasn't arrived yet.
+fn belongs_to_other_network(block: &Block, network: Network) -> bool {
+    let block_hash = block.block_hash();
+    let prev_block_hash = BlockHash::from(block.header().prev_blockhash);
+    [block_hash, prev_block_hash].iter().any(|hash| {
+        matches!(network_from_genesis_hash(hash), Some(other_network) if other_network != network)
+    })
+}
+
+/// The maximum number of reorgs held in the reorg history of the state.
+pub const MAX_REORG_HISTORY: usize = 100;
+
+// The size of a consensus-encoded block header.
+const HEADER_SIZE: usize = 80;
+
+// The maximum number of hashes held in `RecentBlockHashes`.
+const MAX_RECENT_BLOCK_HASHES: usize = 16;
+
+/// A record of the changes of a flag.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlagTransitions {
+    /// The number of times the flag changed.
+    pub count: u64,
+
+    /// The time in seconds of the most recent change, if any.
+    pub last_transition: Option<u64>,
+}
+
+impl FlagTransitions {
+    fn record(&mut self, now: u64) {
+        self.count += 1;
+        self.last_transition = Some(now);
+    }
+}
+
+/// A small LRU of the hashes of recently inserted blocks, used by `insert_block` to
+/// short-circuit duplicates without validating their headers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentBlockHashes(VecDeque<BlockHash>);
+
+impl RecentBlockHashes {
+    /// Returns true if the given hash is in the LRU, marking it as the most recently used.
+    fn touch(&mut self, block_hash: &BlockHash) -> bool {
+        match self.0.iter().position(|hash| hash == block_hash) {
+            Some(i) => {
+                let hash = self.0.remove(i).expect("index must be valid");
+                self.0.push_back(hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds the given hash to the LRU, evicting the least recently used hash if it's full.
+    fn insert(&mut self, block_hash: BlockHash) {
+        if self.touch(&block_hash) {
+            return;
+        }
+
+        if self.0.len() >= MAX_RECENT_BLOCK_HASHES {
+            self.0.pop_front();
+        }
+        self.0.push_back(block_hash);
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn default_partial_response_timeout() -> u64 {
+    // Ten minutes.
+    600
+}
+
+/// Pops any blocks in `UnstableBlocks` that are considered stable and ingests them to the UTXO set.
+///
+/// NOTE: This method does a form of time-slicing to stay within the instruction limit, and
+/// multiple calls may be required for all the stable blocks to be ingested.
+///
+/// If the block popped from the unstable blocks doesn't match the block that was ingested,
+/// the UTXO set and the unstable blocks are no longer consistent with each other. Rather than
+/// trapping, the inconsistency is recorded and ingestion is halted.
+///
+/// Returns a bool indicating whether or not the state has changed.
+pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
+    // Pops the stable block, returning false if it isn't the block that was ingested.
+    fn pop_block(state: &mut State, ingested_block_hash: BlockHash) -> bool {
+        let stable_height = state.stable_height();
+        // Pop the stable block.
+        let popped_block = unstable_blocks::pop(&mut state.unstable_blocks, stable_height);
+
+        // The popped block and the forks discarded along with it are no longer in the
+        // unstable blocks, so they must not be treated as duplicates.
+        state.recent_block_hashes.clear();
+
+        // Discarding the forks can switch the main chain to a shorter fork.
+        state.notify_tip_observers();
+
+        // Sanity check that we just popped the same block that was ingested.
+        let popped_block_hash = popped_block.map(|block| block.block_hash());
+        if popped_block_hash.as_ref() == Some(&ingested_block_hash) {
+            return true;
+        }
+
+        print(&format!(
+            "ERROR: Ingested block {:?}, but popped block {:?}. Halting ingestion.",
+            ingested_block_hash, popped_block_hash
+        ));
+        state.syncing_state.num_ingestion_inconsistencies += 1;
+        state.syncing_state.ingestion_halted = true;
+        false
+    }
+
+    // Records the stats of the partially ingested block, so that they reflect its progress.
+    fn record_partial_stats(state: &mut State) {
+        if let Some(stats) = state.utxos.ingesting_block_stats() {
+            state.metrics.block_ingestion_stats = stats.clone();
+        }
+    }
+
+    if state.syncing_state.ingestion_halted {
+        print("Ingestion is halted due to an inconsistency. Skipping...");
+        return false;
+    }
+
+    // Headers that predate the block hash index can't be looked up by hash, e.g. to reject
+    // blocks below the stable height, until it's backfilled.
+    let utxos = &mut state.utxos;
+    if state
+        .stable_block_headers
+        .backfill_block_hash_heights(|| utxos.should_time_slice())
+        == Slicing::Paused(())
+    {
+        print("Backfilling the block hash index...");
+        return true;
+    }
+
+    // Prune the headers that fell out of the retained window when the retention was set.
+    let utxos = &mut state.utxos;
+    if state
+        .stable_block_headers
+        .prune_headers(|| utxos.should_time_slice())
+        == Slicing::Paused(())
+    {
+        print("Pruning block headers...");
+        return true;
+    }
+
+    // The UTXO set must not change while its hash is being rebuilt, e.g. after an upgrade from
+    // a state that predates it.
+    if state.utxos.rebuild_utxo_set_hash() == Slicing::Paused(()) {
+        print("Rebuilding the UTXO set hash...");
+        return true;
+    }
+
+    let prev_state = (
+        state.utxos.next_height(),
+        &state.utxos.ingesting_block.clone(),
+    );
+    let has_state_changed = |state: &State| -> bool {
+        prev_state != (state.utxos.next_height(), &state.utxos.ingesting_block)
+    };
+
+    // Finish ingesting the stable block that's partially ingested, if that exists.
+    print("Running ingest_block_continue...");
+    match state.utxos.ingest_block_continue() {
+        None => {}
+        Some(Slicing::Paused(())) => {
+            record_partial_stats(state);
+            return has_state_changed(state);
+        }
+        Some(Slicing::Done((ingested_block_hash, stats))) => {
+            state.metrics.block_ingestion_stats = stats;
+            if !pop_block(state, ingested_block_hash) {
+                return has_state_changed(state);
+            }
+        }
+    }
+
+    // Check if there are any stable blocks and ingest those into the UTXO set.
+    print("Looking for new stable blocks to ingest...");
+    while let Some(new_stable_block) = unstable_blocks::peek(&state.unstable_blocks) {
+        print(&format!(
+            "Ingesting new stable block {:?}...",
+            new_stable_block.block_hash()
+        ));
+
+        // Store the block's header.
+        state
+            .stable_block_headers
+            .insert_block(new_stable_block, state.utxos.next_height());
+
+        match state.utxos.ingest_block(new_stable_block.clone()) {
+            Slicing::Paused(()) => {
+                record_partial_stats(state);
+                return has_state_changed(state);
+            }
+            Slicing::Done((ingested_block_hash, stats)) => {
+                state.metrics.block_ingestion_stats = stats;
+                if !pop_block(state, ingested_block_hash) {
+                    return has_state_changed(state);
+                }
+            }
+        }
+    }
+
+    has_state_changed(state)
+}
+
+/// Same as `ingest_stable_blocks_into_utxoset`, but time-slices once `instruction_budget`
+/// instructions have been executed in this call, as reported by the instruction counter of the
+/// given runtime.
+///
+/// `MAX_INSTRUCTIONS_THRESHOLD` is the budget used by `ingest_stable_blocks_into_utxoset`.
+pub fn ingest_stable_blocks_into_utxoset_with_runtime<R: Runtime + Clone + 'static>(
+    state: &mut State,
+    runtime: &R,
+    instruction_budget: u64,
+) -> bool {
+    let should_time_slice = state
+        .utxos
+        .set_should_time_slice(time_slice_predicate(runtime.clone(), instruction_budget));
+    let has_state_changed = ingest_stable_blocks_into_utxoset(state);
+    state.utxos.set_should_time_slice(should_time_slice);
+    has_state_changed
+}
+
+/// Calls `ingest_stable_blocks_into_utxoset` until the state stops changing, resetting
+/// the performance counter between rounds.
+///
+/// Returns the number of rounds in which the state has changed.
+#[cfg(test)]
+pub fn drain_ingestion(state: &mut State) -> u32 {
+    let mut num_rounds = 0;
+    loop {
+        let has_state_changed = ingest_stable_blocks_into_utxoset(state);
+        crate::runtime::performance_counter_reset();
+        if !has_state_changed {
+            return num_rounds;
+        }
+        num_rounds += 1;
+    }
+}
+
+pub fn insert_next_block_headers(state: &mut State, next_block_headers: &[BlockHeaderBlob]) {
+    // The limit at which no further next block headers are processed.
+    // Note that the actual limit available on system subnets is 50B. The threshold is set
+    // lower to be conservative.
+    const MAX_INSTRUCTIONS_THRESHOLD: u64 = 30_000_000_000;
+
+    for block_header_blob in next_block_headers.iter() {
+        if inc_performance_counter() > MAX_INSTRUCTIONS_THRESHOLD {
+            print("Reaching instruction threshold while inserting next block headers. Breaking...");
+            break;
+        }
+
+        let block_header = match BlockHeader::consensus_decode(block_header_blob.as_slice()) {
+            Ok(header) => header,
+            Err(err) => {
+                print(&format!(
+                    "ERROR: Failed decode block header. Err: {:?}, Block header: {:?}",
+                    err, block_header_blob,
+                ));
+                return;
+            }
+        };
+
+        if state.unstable_blocks.has_next_block_header(&block_header) {
+            // Already processed this block header. Skip...
+            continue;
+        }
+
+        let validation_result =
+            match ValidationContext::new_with_next_block_headers(state, &block_header)
+                .map_err(|_| ValidateHeaderError::PrevHeaderNotFound)
+            {
+                Ok(store) => validate_header(
+                    &into_bitcoin_network(state.network()),
+                    &store,
+                    &block_header,
+                    time(),
+                ),
+                Err(err) => Err(err),
+            };
+
+        if let Err(err) = validation_result {
+            print(&format!(
+                "ERROR: Failed to validate block header. Err: {:?}, Block header: {:?}",
+                err, block_header,
+            ));
+
+            return;
+        }
+
+        if let Err(err) = state
+            .unstable_blocks
+            .insert_next_block_header(block_header, state.stable_height())
+        {
+            print(&format!(
+                "ERROR: Failed to insert next block header. Err: {:?}, Block header: {:?}",
+                err, block_header,
+            ));
+            return;
+        }
+    }
+}
+
+/// Returns the height of the main chain's tip.
+///
+/// The main chain always contains at least the anchor block, so the smallest valid state
+/// (i.e. one containing only the genesis block) has a main chain height of zero.
+pub fn main_chain_height(state: &State) -> Height {
+    let main_chain_length = unstable_blocks::get_main_chain_length(&state.unstable_blocks) as u32;
+    (main_chain_length + state.utxos.next_height())
+        .checked_sub(1)
+        .expect("the main chain must contain at least the anchor block")
+}
+
+pub fn get_unstable_blocks(state: &State) -> Vec<&Block> {
+    unstable_blocks::get_blocks(&state.unstable_blocks)
+}
+
+/// Builds the initial `GetSuccessorsRequest` for fetching new blocks.
+///
+/// The anchor of the request is the anchor of the unstable blocks (i.e. the block following the
+/// stable tip), and all the other unstable blocks are marked as processed so that they aren't
+/// sent again.
+pub fn build_get_successors_request(state: &State) -> GetSuccessorsRequest {
+    let mut processed_block_hashes: Vec<BlockHash> = get_unstable_blocks(state)
+        .iter()
+        .map(|b| b.block_hash())
+        .collect();
+
+    // We are guaranteed that there's always at least one block.
+    let anchor = processed_block_hashes.remove(0);
+
+    GetSuccessorsRequest::Initial(GetSuccessorsRequestInitial {
+        network: state.network(),
+        anchor,
+        processed_block_hashes,
+    })
+}
+
+// The maximum size in bytes of a bitcoin script for it to be considered "small".
+const TX_OUT_SCRIPT_MAX_SIZE_SMALL: u32 = 25;
+
+// The maximum size in bytes of a bitcoin script for it to be considered "medium".
+const TX_OUT_SCRIPT_MAX_SIZE_MEDIUM: u32 = 201;
+
+// A transaction output's value in satoshis is a `u64`, which is 8 bytes.
+const TX_OUT_VALUE_SIZE: u32 = 8;
+
+const TX_OUT_MAX_SIZE_SMALL: u32 = TX_OUT_SCRIPT_MAX_SIZE_SMALL + TX_OUT_VALUE_SIZE;
+
+const TX_OUT_MAX_SIZE_MEDIUM: u32 = TX_OUT_SCRIPT_MAX_SIZE_MEDIUM + TX_OUT_VALUE_SIZE;
+
+// The height is a `u32`, which is 4 bytes.
+const HEIGHT_SIZE: u32 = 4;
+
+/// The size of a key in the UTXOs map, which is an outpoint.
+pub const UTXO_KEY_SIZE: usize = OutPoint::size() as usize;
+
+/// The max size of a value in the "small UTXOs" map.
+pub const UTXO_VALUE_MAX_SIZE_SMALL: usize = (TX_OUT_MAX_SIZE_SMALL + HEIGHT_SIZE) as usize;
+
+/// The max size of a value in the "medium UTXOs" map.
+pub const UTXO_VALUE_MAX_SIZE_MEDIUM: usize = (TX_OUT_MAX_SIZE_MEDIUM + HEIGHT_SIZE) as usize;
+
+/// A response awaiting to be processed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum ResponseToProcess {
     /// A complete response that is ready to be processed.
     Complete(GetSuccessorsCompleteResponse),
 
-    /// A partial response that needs more follow-up requests for it to be complete.
-    /// The partial response is stored along with the number of pages of the complete
-    /// response that has been processed.
-    Partial(GetSuccessorsPartialResponse, u8),
-}
+    /// A partial response that needs more follow-up requests for it to be complete.
+    /// The partial response is stored along with the number of pages of the complete
+    /// response that has been processed.
+    Partial(GetSuccessorsPartialResponse, u8),
+
+    /// A block reassembled from a partial response and its follow-ups, along with the next
+    /// block headers of the response, that is ready to be processed.
+    Reassembled(Block, Vec<BlockHeaderBlob>),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+pub struct SyncingState {
+    /// Whether or not new blocks should be fetched from the network.
+    /// Prefer `State::set_syncing` over setting this field directly, so that changes are
+    /// recorded in `State::syncing_transitions`.
+    pub syncing: Flag,
+
+    /// A flag used to ensure that only one request for fetching blocks is
+    /// being sent at a time.
+    pub is_fetching_blocks: bool,
+
+    /// A response that needs to be processed.
+    pub response_to_process: Option<ResponseToProcess>,
+
+    /// The number of rejects received when calling GetSuccessors.
+    pub num_get_successors_rejects: u64,
+
+    /// The number of errors occurred when deserializing blocks.
+    pub num_block_deserialize_errors: u64,
+
+    /// The number of errors occurred when inserting a block.
+    pub num_insert_block_errors: u64,
+
+    /// The number of times the block popped from the unstable blocks didn't match the
+    /// block ingested into the UTXO set.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub num_ingestion_inconsistencies: u64,
+
+    /// Whether ingesting stable blocks is halted due to an inconsistency.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub ingestion_halted: bool,
+
+    /// The time in seconds at which the partial response being processed last made progress.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub partial_response_last_progress: Option<u64>,
+
+    /// The number of partial responses discarded because they made no progress in time.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub num_partial_response_timeouts: u64,
+}
+
+impl Default for SyncingState {
+    fn default() -> Self {
+        Self {
+            syncing: Flag::Enabled,
+            is_fetching_blocks: false,
+            response_to_process: None,
+            num_get_successors_rejects: 0,
+            num_block_deserialize_errors: 0,
+            num_insert_block_errors: 0,
+            num_ingestion_inconsistencies: 0,
+            ingestion_halted: false,
+            partial_response_last_progress: None,
+            num_partial_response_timeouts: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::block_header_store::MIN_RETENTION_DEPTH;
+    use crate::test_utils::{
+        build_chain, random_p2pkh_address, BlockBuilder, BlockChainBuilder, TransactionBuilder,
+    };
+    use ic_btc_interface::HeaderRetention;
+    use ic_btc_types::Transaction;
+
+    #[test]
+    fn block_ingestion_stats_are_updated() {
+        let stability_threshold = 0;
+        let num_blocks = 3;
+        let num_transactions_per_block = 10;
+        let network = Network::Regtest;
+        let blocks = build_chain(network, num_blocks, num_transactions_per_block);
+
+        let mut state = State::new(stability_threshold, network, blocks[0].clone());
+
+        assert_eq!(state.stable_height(), 0);
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+
+        // The genesis block is now stable. Ingest it.
+        let metrics_before = state.metrics.block_ingestion_stats.clone();
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 1);
+
+        // Verify that the stats have been updated.
+        assert_ne!(metrics_before, state.metrics.block_ingestion_stats);
+
+        // Ingest the next block. This time, the performance counter is set so that
+        // the ingestion is time-sliced.
+        crate::runtime::set_performance_counter_step(100_000_000);
+
+        insert_block(&mut state, blocks[2].clone()).unwrap();
+        let metrics_before = state.metrics.block_ingestion_stats.clone();
+        let mut num_rounds = 0;
+        while state.stable_height() == 1 {
+            ingest_stable_blocks_into_utxoset(&mut state);
+            crate::runtime::performance_counter_reset();
+            num_rounds += 1;
+
+            // The stats are updated even while the block is partially ingested.
+            assert_ne!(metrics_before, state.metrics.block_ingestion_stats);
+        }
+
+        // Assert that the block has been ingested.
+        assert_eq!(state.stable_height(), 2);
+
+        // Assert that the block ingestion has been time-sliced.
+        assert!(num_rounds > 1);
+
+        // Assert the stats have been updated.
+        assert_ne!(metrics_before, state.metrics.block_ingestion_stats);
+    }
+
+    #[test]
+    fn block_ingestion_stats_grow_during_pauses() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 10);
+
+        let mut state = State::new(0, network, blocks[0].clone());
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 1);
+
+        // Returns the total number of instructions in the recorded stats.
+        let ins_total =
+            |state: &State| state.metrics.block_ingestion_stats.get_labels_and_values()[0].1;
+
+        // Force block 1 to be ingested over several pauses.
+        crate::runtime::set_performance_counter_step(100_000_000);
+        insert_block(&mut state, blocks[2].clone()).unwrap();
+        let mut totals = vec![];
+        while state.stable_height() == 1 {
+            ingest_stable_blocks_into_utxoset(&mut state);
+            crate::runtime::performance_counter_reset();
+            totals.push(ins_total(&state));
+        }
+
+        assert!(totals.len() > 1);
+        assert!(totals.windows(2).all(|window| window[0] < window[1]));
+    }
+
+    #[test]
+    fn reconsider_best_chain_reports_reorg() {
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_block_3 = BlockBuilder::with_prev_header(fork_block_2.header()).build();
+
+        let mut state = State::new(10, network, genesis_block);
+        insert_block(&mut state, block_1.clone()).unwrap();
+        insert_block(&mut state, block_2.clone()).unwrap();
+
+        // The first call only records the tip.
+        assert_eq!(state.reconsider_best_chain(0), None);
+        assert_eq!(state.main_chain_tip, Some(block_2.block_hash()));
+
+        // A longer fork arrives out of order.
+        add_orphan(&mut state, fork_block_3.clone(), 0).unwrap();
+        insert_block(&mut state, fork_block_2).unwrap();
+        assert_eq!(try_connect_orphans(&mut state), 1);
+
+        assert_eq!(
+            state.reconsider_best_chain(10),
+            Some(ReorgEvent {
+                timestamp: 10,
+                old_tip: block_2.block_hash(),
+                new_tip: fork_block_3.block_hash(),
+                fork_point: Some(block_1.block_hash()),
+                num_disconnected_blocks: Some(1),
+            })
+        );
+
+        // Extending the main chain isn't a reorg.
+        let fork_block_4 = BlockBuilder::with_prev_header(fork_block_3.header()).build();
+        insert_block(&mut state, fork_block_4).unwrap();
+        assert_eq!(state.reconsider_best_chain(20), None);
+    }
+
+    #[test]
+    fn reorgs_leave_stable_utxo_set_untouched() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 3);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.reconsider_best_chain(0), None);
+
+        let stable_height = state.stable_height();
+        let utxo_set_hash = state.utxo_set_hash();
+
+        // A longer fork on top of the anchor replaces the unstable part of the main chain.
+        let mut prev_header = *state.unstable_blocks.anchor().header();
+        for _ in 0..blocks.len() {
+            let block = BlockBuilder::with_prev_header(&prev_header).build();
+            prev_header = *block.header();
+            insert_block(&mut state, block).unwrap();
+        }
+        assert!(state.reconsider_best_chain(10).is_some());
+
+        assert_eq!(state.stable_height(), stable_height);
+        assert_eq!(state.utxo_set_hash(), utxo_set_hash);
+    }
+
+    #[test]
+    fn flag_transitions_are_recorded() {
+        let network = Network::Regtest;
+        let mut state = State::new(0, network, BlockBuilder::genesis().build());
+        assert_eq!(state.api_access_transitions, FlagTransitions::default());
+        assert_eq!(state.syncing_transitions, FlagTransitions::default());
+
+        // Setting a flag to its current value isn't a transition.
+        state.set_api_access(Flag::Enabled);
+        state.set_syncing(Flag::Enabled);
+        assert_eq!(state.api_access_transitions.count, 0);
+        assert_eq!(state.syncing_transitions.count, 0);
+
+        let before = time();
+        state.set_api_access(Flag::Disabled);
+        assert_eq!(state.api_access, Flag::Disabled);
+        assert_eq!(state.api_access_transitions.count, 1);
+        assert!(state.api_access_transitions.last_transition.unwrap() >= before);
+        assert_eq!(state.syncing_transitions.count, 0);
+
+        state.set_api_access(Flag::Enabled);
+        state.set_syncing(Flag::Disabled);
+        assert_eq!(state.api_access, Flag::Enabled);
+        assert_eq!(state.api_access_transitions.count, 2);
+        assert_eq!(state.syncing_state.syncing, Flag::Disabled);
+        assert_eq!(state.syncing_transitions.count, 1);
+        assert!(state.syncing_transitions.last_transition.unwrap() >= before);
+    }
+
+    #[test]
+    fn recent_reorgs_are_newest_first() {
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build();
+        let mut state = State::new(1_000, network, genesis_block.clone());
+
+        // Two forks on top of the genesis block.
+        let mut tips = [*genesis_block.header(), *genesis_block.header()];
+        let mut extend_fork = |state: &mut State, fork: usize, num_blocks: u32| {
+            for _ in 0..num_blocks {
+                let block = BlockBuilder::with_prev_header(&tips[fork]).build();
+                tips[fork] = *block.header();
+                insert_block(state, block).unwrap();
+            }
+        };
+
+        extend_fork(&mut state, 0, 1);
+        assert_eq!(state.reconsider_best_chain(0), None);
+
+        // Make the shorter fork the longest one, over and over again.
+        let num_reorgs = MAX_REORG_HISTORY as u64 + 5;
+        for i in 1..=num_reorgs {
+            extend_fork(&mut state, (i % 2) as usize, 2);
+            assert!(state.reconsider_best_chain(i).is_some());
+        }
+
+        let timestamps = |reorgs: Vec<ReorgEvent>| -> Vec<u64> {
+            reorgs.into_iter().map(|reorg| reorg.timestamp).collect()
+        };
+        assert_eq!(
+            timestamps(state.recent_reorgs(3)),
+            vec![num_reorgs, num_reorgs - 1, num_reorgs - 2]
+        );
+
+        // Only the most recent reorgs are kept.
+        let all_reorgs = state.recent_reorgs(usize::MAX);
+        assert_eq!(all_reorgs.len(), MAX_REORG_HISTORY);
+        assert_eq!(all_reorgs.last().unwrap().timestamp, 6);
+        assert_eq!(all_reorgs[0].new_tip, BlockHash::from(tips[1].block_hash()));
+    }
+
+    #[test]
+    fn utxo_set_hash_is_independent_of_insertion_order() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 6, 3);
+        let fork_block = BlockBuilder::with_prev_header(blocks[1].header()).build();
+
+        // Insert the fork first in one state, and last in the other.
+        let mut state_1 = State::new(2, network, blocks[0].clone());
+        insert_block(&mut state_1, blocks[1].clone()).unwrap();
+        insert_block(&mut state_1, fork_block.clone()).unwrap();
+        for block in blocks[2..].iter() {
+            insert_block(&mut state_1, block.clone()).unwrap();
+        }
+        drain_ingestion(&mut state_1);
+
+        // The second state ingests the same blocks, so it needs a memory of its own.
+        crate::memory::reset_memory();
+        let mut state_2 = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state_2, block.clone()).unwrap();
+        }
+        insert_block(&mut state_2, fork_block).unwrap();
+        drain_ingestion(&mut state_2);
+
+        assert_eq!(state_1.stable_height(), state_2.stable_height());
+        assert_eq!(state_1.utxo_set_hash(), state_2.utxo_set_hash());
+
+        // The hash changes as more UTXOs become stable.
+        let hash_before = state_1.utxo_set_hash();
+        let block = BlockBuilder::with_prev_header(blocks[5].header()).build();
+        insert_block(&mut state_1, block).unwrap();
+        drain_ingestion(&mut state_1);
+        assert_ne!(state_1.utxo_set_hash(), hash_before);
+    }
+
+    #[test]
+    fn summary() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        drain_ingestion(&mut state);
+        state.syncing_state.num_get_successors_rejects = 1;
+        state.syncing_state.num_insert_block_errors = 2;
+
+        assert_eq!(
+            state.summary(),
+            StateSummary {
+                network,
+                stable_height: 3,
+                main_chain_height: 4,
+                unstable_block_count: 2,
+                tip_hash: blocks[4].block_hash(),
+                is_synced: true,
+                num_sync_errors: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn build_get_successors_request_includes_all_unstable_blocks() {
+        let network = Network::Regtest;
+        let chain = BlockChainBuilder::new(3).build();
+
+        let mut state = State::new(3, network, chain[0].clone());
+        for block in chain[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+
+        match build_get_successors_request(&state) {
+            GetSuccessorsRequest::Initial(request) => {
+                assert_eq!(request.network, network);
+                assert_eq!(request.anchor, chain[0].block_hash());
+                assert_eq!(
+                    request.processed_block_hashes,
+                    vec![chain[1].block_hash(), chain[2].block_hash()]
+                );
+            }
+            GetSuccessorsRequest::FollowUp(_) => panic!("expected an initial request"),
+        }
+    }
+
+    #[test]
+    fn partial_response_times_out() {
+        let network = Network::Regtest;
+        let mut state = State::new(0, network, crate::genesis_block(network));
+        state.partial_response_timeout = 100;
+
+        let partial_response = ResponseToProcess::Partial(
+            crate::types::GetSuccessorsPartialResponse {
+                partial_block: vec![1, 2, 3],
+                next: vec![],
+                remaining_follow_ups: 2,
+            },
+            1,
+        );
+        state.syncing_state.response_to_process = Some(partial_response);
+        state.syncing_state.partial_response_last_progress = Some(50);
+
+        // The partial response isn't discarded while it's recent enough.
+        assert!(!state.time_out_partial_response(150));
+        assert!(state.syncing_state.response_to_process.is_some());
+
+        // The partial response is discarded once it made no progress for too long.
+        assert!(state.time_out_partial_response(151));
+        assert_eq!(state.syncing_state.response_to_process, None);
+        assert_eq!(state.syncing_state.partial_response_last_progress, None);
+        assert_eq!(state.syncing_state.num_partial_response_timeouts, 1);
+
+        // There's nothing left to time out.
+        assert!(!state.time_out_partial_response(1_000));
+        assert_eq!(state.syncing_state.num_partial_response_timeouts, 1);
+    }
+
+    #[test]
+    fn insert_block_rejects_blocks_not_after_median_time_past() {
+        let network = Network::Regtest;
+        let runtime = crate::runtime::MockRuntime::new(10_000, 0);
+
+        let genesis_block = BlockBuilder::genesis().with_time(1_000).build();
+        let mut state = State::new(0, network, genesis_block.clone());
+
+        // Insert blocks with out-of-order timestamps.
+        let mut prev_header = *genesis_block.header();
+        for time in [2_000, 6_000, 3_000] {
+            let block = BlockBuilder::with_prev_header(&prev_header)
+                .with_time(time)
+                .build();
+            assert_eq!(block.header().time, time);
+            insert_block_with_runtime(&mut state, block.clone(), &runtime).unwrap();
+            prev_header = *block.header();
+        }
+
+        // The median time of the last four blocks is 3,000.
+        let block = BlockBuilder::with_prev_header(&prev_header)
+            .with_time(3_000)
+            .build();
+        assert_eq!(
+            insert_block_with_runtime(&mut state, block, &runtime),
+            Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::HeaderIsOld
+            ))
+        );
+
+        let block = BlockBuilder::with_prev_header(&prev_header)
+            .with_time(3_001)
+            .build();
+        assert_eq!(
+            insert_block_with_runtime(&mut state, block, &runtime),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn reinserting_a_recent_block_skips_validation() {
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().with_time(1_000).build();
+        let block = BlockBuilder::with_prev_header(genesis_block.header())
+            .with_time(20_000)
+            .build();
+        let mut state = State::new(10, network, genesis_block);
+
+        let runtime = crate::runtime::MockRuntime::new(20_000, 0);
+        insert_block_with_runtime(&mut state, block.clone(), &runtime).unwrap();
+
+        // With the current time set well before the block's time, the block's header is invalid.
+        // Re-inserting the block nonetheless succeeds, as validation is skipped.
+        let runtime = crate::runtime::MockRuntime::new(0, 0);
+        assert_eq!(
+            insert_block_with_runtime(&mut state, block.clone(), &runtime),
+            Ok(())
+        );
+
+        // Once the block is no longer recent, its header is validated again.
+        state.recent_block_hashes.clear();
+        assert!(matches!(
+            insert_block_with_runtime(&mut state, block, &runtime),
+            Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::HeaderIsTooFarInFuture { .. }
+            ))
+        ));
+    }
+
+    // Builds a merkle proof for the transaction at the given index.
+    fn build_merkle_proof(txids: &[Txid], index: usize) -> MerkleProof {
+        let mut level: Vec<Vec<u8>> = txids.iter().map(|txid| txid.as_bytes().to_vec()).collect();
+        let mut position = index;
+        let mut siblings = vec![];
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                // The last node of a level with an odd length is paired with itself.
+                level.push(level.last().unwrap().clone());
+            }
+
+            siblings.push(<[u8; 32]>::try_from(&level[position ^ 1][..]).unwrap());
+            level = level
+                .chunks(2)
+                .map(|pair| sha256d::Hash::hash(&pair.concat()).into_inner().to_vec())
+                .collect();
+            position /= 2;
+        }
+
+        MerkleProof {
+            index: index as u32,
+            siblings,
+        }
+    }
+
+    #[test]
+    fn verify_merkle_proof() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 2, 5);
+        let mut state = State::new(10, network, blocks[0].clone());
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+
+        let block_hash = blocks[1].block_hash();
+        let txids: Vec<Txid> = blocks[1].txdata().iter().map(|tx| tx.txid()).collect();
+        for (index, txid) in txids.iter().enumerate() {
+            let proof = build_merkle_proof(&txids, index);
+            assert!(state.verify_merkle_proof(&block_hash, txid, &proof));
+
+            // The proof doesn't hold for another block or another transaction.
+            assert!(!state.verify_merkle_proof(&blocks[0].block_hash(), txid, &proof));
+            let other_txid = &txids[(index + 1) % txids.len()];
+            assert!(!state.verify_merkle_proof(&block_hash, other_txid, &proof));
+        }
+
+        // A tampered proof is rejected.
+        let mut proof = build_merkle_proof(&txids, 2);
+        proof.siblings[0][0] ^= 1;
+        assert!(!state.verify_merkle_proof(&block_hash, &txids[2], &proof));
+
+        // A proof with the wrong index is rejected.
+        let mut proof = build_merkle_proof(&txids, 2);
+        proof.index = 3;
+        assert!(!state.verify_merkle_proof(&block_hash, &txids[2], &proof));
+
+        // A proof that doesn't match the depth of the block's tree is rejected, even if it
+        // hashes to the merkle root from an inner node.
+        let mut proof = build_merkle_proof(&txids, 2);
+        let sibling = proof.siblings.remove(0);
+        let inner_node = sha256d::Hash::hash(&[&txids[2].as_bytes()[..], &sibling[..]].concat());
+        let inner_txid = Txid::from(inner_node.into_inner().to_vec());
+        proof.index = 1;
+        assert!(!state.verify_merkle_proof(&block_hash, &inner_txid, &proof));
+
+        // An empty proof is rejected for a block with more than one transaction.
+        let proof = MerkleProof {
+            index: 0,
+            siblings: vec![],
+        };
+        let root_txid = Txid::from(blocks[1].header().merkle_root.into_inner().to_vec());
+        assert!(!state.verify_merkle_proof(&block_hash, &root_txid, &proof));
+    }
+
+    #[test]
+    fn get_coinbase() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        let genesis_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1_000)
+            .build();
+        let genesis_block = BlockBuilder::genesis()
+            .with_transaction(genesis_tx.clone())
+            .build();
+
+        // A transaction paying a fee of 100 satoshis, and a coinbase claiming the block reward
+        // of 50 BTC plus that fee.
+        let fee = 100;
+        let block_reward = 50 * 100_000_000;
+        let tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(genesis_tx.txid(), 0))
+            .with_output(&address, 1_000 - fee)
+            .build();
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address, block_reward + fee)
+            .build();
+        let block = BlockBuilder::with_prev_header(genesis_block.header())
+            .with_transaction(coinbase_tx.clone())
+            .with_transaction(tx)
+            .build();
+
+        let mut state = State::new(10, network, genesis_block);
+        insert_block(&mut state, block).unwrap();
+
+        let coinbase = state.get_coinbase(1).unwrap();
+        assert!(coinbase.is_coin_base());
+        assert_eq!(coinbase, coinbase_tx);
+        assert_eq!(
+            coinbase
+                .output()
+                .iter()
+                .map(|output| output.value)
+                .sum::<u64>(),
+            block_reward + fee
+        );
+
+        assert_eq!(state.get_coinbase(0), Some(genesis_tx));
+        assert_eq!(state.get_coinbase(2), None);
+    }
+
+    #[test]
+    fn rejects_blocks_of_other_networks() {
+        let network = Network::Mainnet;
+        let mut state = State::new(10, network, crate::genesis_block(network));
+
+        // The testnet genesis block, and a block on top of it. The block is mined with the easy
+        // regtest target, as mining it at the testnet difficulty would take too long.
+        let testnet_genesis_block = crate::genesis_block(Network::Testnet);
+        let testnet_block = BlockBuilder::with_prev_header(testnet_genesis_block.header())
+            .with_target(crate::genesis_block(Network::Regtest).header().target())
+            .build();
+        assert_eq!(
+            insert_block(&mut state, testnet_genesis_block),
+            Err(InsertBlockError::WrongNetwork)
+        );
+        assert_eq!(
+            insert_block(&mut state, testnet_block),
+            Err(InsertBlockError::WrongNetwork)
+        );
+
+        // A block of an unknown chain can't be attributed to a network.
+        let unknown_block =
+            BlockBuilder::with_prev_header(BlockBuilder::genesis().build().header()).build();
+        assert_eq!(
+            insert_block(&mut state, unknown_block),
+            Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::PrevHeaderNotFound
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_blocks_below_stable_height() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        // A fork of a stable block, i.e. at a height below the stable height.
+        let old_fork_block = BlockBuilder::with_prev_header(blocks[1].header()).build();
+        assert_eq!(
+            insert_block(&mut state, old_fork_block),
+            Err(InsertBlockError::BelowStableHeight)
+        );
+
+        // A competitor of the anchor, i.e. at the stable height.
+        let anchor_fork_block = BlockBuilder::with_prev_header(blocks[2].header()).build();
+        assert_eq!(
+            insert_block(&mut state, anchor_fork_block),
+            Err(InsertBlockError::BelowStableHeight)
+        );
+
+        // Blocks extending the anchor are still accepted.
+        let fork_block = BlockBuilder::with_prev_header(blocks[3].header()).build();
+        assert_eq!(insert_block(&mut state, fork_block), Ok(()));
+    }
+
+    #[test]
+    fn accepts_children_of_an_anchor_being_ingested() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 10);
+        let mut state = State::new(0, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+
+        // Time-slice the ingestion so that the anchor is partially ingested.
+        crate::runtime::set_performance_counter_step(100_000_000);
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert!(state.utxos.ingesting_block.is_some());
+
+        // The anchor's header is already stored, but its children are still accepted.
+        let anchor = state.unstable_blocks.anchor().clone();
+        assert!(state
+            .stable_block_headers
+            .get_with_block_hash(&anchor.block_hash())
+            .is_some());
+        let fork_block = BlockBuilder::with_prev_header(anchor.header()).build();
+        assert_eq!(insert_block(&mut state, fork_block), Ok(()));
+    }
+
+    #[test]
+    fn compact_after_ingestion_trims_ingested_blocks() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 6, 2);
+        let mut state = State::new(10, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+
+        // Nothing has been ingested yet, so there's nothing to trim.
+        assert_eq!(state.compact_after_ingestion(), 0);
+
+        // Ingest the first three blocks without popping them from the unstable blocks.
+        for block in blocks[..3].iter() {
+            state
+                .stable_block_headers
+                .insert_block(block, state.utxos.next_height());
+            assert!(matches!(
+                state.utxos.ingest_block(block.clone()),
+                Slicing::Done(_)
+            ));
+        }
+        assert_eq!(state.stable_height(), 3);
+        assert_eq!(state.unstable_blocks.anchor(), &blocks[0]);
+
+        // Compacting makes the next block to ingest the anchor, with no leftover ancestors.
+        assert_eq!(state.compact_after_ingestion(), 3);
+        assert_eq!(state.unstable_blocks.anchor(), &blocks[3]);
+        assert_eq!(
+            get_unstable_blocks(&state),
+            blocks[3..].iter().collect::<Vec<_>>()
+        );
+
+        // Compacting again is a no-op.
+        assert_eq!(state.compact_after_ingestion(), 0);
+    }
+
+    #[test]
+    fn insert_block_returning_tip_returns_main_chain_tip() {
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_block = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let mut state = State::new(10, network, genesis_block);
+
+        // Blocks extending the main chain become its tip.
+        assert_eq!(
+            insert_block_returning_tip(&mut state, block_1.clone()),
+            Ok(block_1.block_hash())
+        );
+        assert_eq!(
+            insert_block_returning_tip(&mut state, block_2.clone()),
+            Ok(block_2.block_hash())
+        );
+
+        // A block extending a shorter fork leaves the tip unchanged.
+        assert_eq!(
+            insert_block_returning_tip(&mut state, fork_block),
+            Ok(block_2.block_hash())
+        );
+    }
+
+    #[test]
+    fn insert_block_uses_time_of_runtime() {
+        let network = Network::Regtest;
+        // The blocks are 10 minutes apart, starting at time zero.
+        let blocks = build_chain(network, 14, 1);
+        let runtime = crate::runtime::MockRuntime::new(0, 0);
+
+        let mut state = State::new(0, network, blocks[0].clone());
+        for block in blocks[1..13].iter() {
+            insert_block_with_runtime(&mut state, block.clone(), &runtime).unwrap();
+        }
+
+        // The last block is more than two hours ahead of the runtime's time.
+        assert_eq!(
+            insert_block_with_runtime(&mut state, blocks[13].clone(), &runtime),
+            Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::HeaderIsTooFarInFuture {
+                    block_time: 13 * 600,
+                    max_allowed_time: 7_200,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn ingestion_is_time_sliced_with_runtime() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 10);
+        let runtime = crate::runtime::MockRuntime::new(0, 400_000_000);
+
+        let mut state = State::new(0, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block_with_runtime(&mut state, block.clone(), &runtime).unwrap();
+        }
+
+        let mut num_rounds = 0;
+        runtime.performance_counter_reset();
+        while ingest_stable_blocks_into_utxoset_with_runtime(
+            &mut state,
+            &runtime,
+            crate::utxo_set::MAX_INSTRUCTIONS_THRESHOLD,
+        ) {
+            runtime.performance_counter_reset();
+            num_rounds += 1;
+        }
+
+        // The blocks have been ingested over multiple rounds.
+        assert_eq!(state.stable_height(), 2);
+        assert!(num_rounds > 1);
+    }
+
+    #[test]
+    fn ingestion_respects_instruction_budget() {
+        let network = Network::Regtest;
+        let runtime = crate::runtime::MockRuntime::new(0, 1);
+
+        // The states share the same stable memory, so each is built from a new chain in order
+        // for their outpoints to be distinct.
+        let new_state = || {
+            let blocks = build_chain(network, 3, 10);
+            let mut state = State::new(0, network, blocks[0].clone());
+            for block in blocks[1..].iter() {
+                insert_block(&mut state, block.clone()).unwrap();
+            }
+            state
+        };
+
+        // With a budget of three instructions, only two outputs are ingested per call: the
+        // output of the genesis block and the first output of block 1.
+        let mut state = new_state();
+        assert!(ingest_stable_blocks_into_utxoset_with_runtime(
+            &mut state, &runtime, 3
+        ));
+        assert_eq!(state.stable_height(), 1);
+        assert!(state.utxos.ingesting_block.is_some());
+
+        let mut num_rounds = 1;
+        while ingest_stable_blocks_into_utxoset_with_runtime(&mut state, &runtime, 3) {
+            num_rounds += 1;
+        }
+        assert_eq!(state.stable_height(), 2);
+        assert_eq!(num_rounds, 6);
+
+        // With an unlimited budget, all the stable blocks are ingested in a single call.
+        let mut state = new_state();
+        assert!(ingest_stable_blocks_into_utxoset_with_runtime(
+            &mut state,
+            &runtime,
+            u64::MAX
+        ));
+        assert_eq!(state.stable_height(), 2);
+        assert!(!ingest_stable_blocks_into_utxoset_with_runtime(
+            &mut state,
+            &runtime,
+            u64::MAX
+        ));
+    }
+
+    #[test]
+    fn get_utxos_mid_ingestion_has_no_duplicates() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let runtime = crate::runtime::MockRuntime::new(0, 1);
+
+        let genesis_block = BlockBuilder::genesis().build();
+        let mut tx = TransactionBuilder::coinbase();
+        for value in 1..=10 {
+            tx = tx.with_output(&address, value);
+        }
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header())
+            .with_transaction(tx.build())
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        let mut state = State::new(0, network, genesis_block);
+        insert_block(&mut state, block_1).unwrap();
+        insert_block(&mut state, block_2).unwrap();
+
+        let get_utxos = |state: &State| {
+            let mut address_utxos = state.get_utxos(address.clone());
+            for block in unstable_blocks::get_main_chain(&state.unstable_blocks).into_chain() {
+                address_utxos.apply_block(block);
+            }
+            address_utxos.into_iter(None).collect::<Vec<_>>()
+        };
+
+        // Force block 1 to be partially ingested.
+        assert!(ingest_stable_blocks_into_utxoset_with_runtime(
+            &mut state, &runtime, 3
+        ));
+        assert!(state.utxos.ingesting_block.is_some());
+
+        // Every UTXO of the address is returned exactly once.
+        let utxos = get_utxos(&state);
+        let outpoints: BTreeSet<_> = utxos.iter().map(|utxo| utxo.outpoint.clone()).collect();
+        assert_eq!(utxos.len(), 10);
+        assert_eq!(outpoints.len(), 10);
+
+        // The UTXOs are the same once the block is fully ingested.
+        while ingest_stable_blocks_into_utxoset_with_runtime(&mut state, &runtime, 3) {}
+        assert!(state.utxos.ingesting_block.is_none());
+        assert_eq!(get_utxos(&state), utxos);
+    }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
-pub struct SyncingState {
-    /// Whether or not new blocks should be fetched from the network.
-    pub syncing: Flag,
+    #[test]
+    fn confirmations() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        let genesis = BlockBuilder::genesis().build();
+        let stable_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(stable_tx.clone())
+            .build();
+
+        let mut state = State::new(2, network, genesis);
+        insert_block(&mut state, block_1.clone()).unwrap();
+
+        // Extend the chain until block 1 is ingested.
+        let mut prev_block = block_1;
+        while state.stable_height() < 2 {
+            let block = BlockBuilder::with_prev_header(prev_block.header()).build();
+            insert_block(&mut state, block.clone()).unwrap();
+            drain_ingestion(&mut state);
+            prev_block = block;
+        }
 
-    /// A flag used to ensure that only one request for fetching blocks is
-    /// being sent at a time.
-    pub is_fetching_blocks: bool,
+        // Add a few more blocks, the last of which has an unstable UTXO.
+        for _ in 0..5 {
+            let block = BlockBuilder::with_prev_header(prev_block.header()).build();
+            insert_block(&mut state, block.clone()).unwrap();
+            drain_ingestion(&mut state);
+            prev_block = block;
+        }
+        let unstable_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 2000)
+            .build();
+        let tip = BlockBuilder::with_prev_header(prev_block.header())
+            .with_transaction(unstable_tx.clone())
+            .build();
+        insert_block(&mut state, tip).unwrap();
+
+        let tip_height = main_chain_height(&state);
+        let stable_outpoint = OutPoint::new(stable_tx.txid(), 0);
+        assert!(state.utxos.get_utxo(&stable_outpoint).is_some());
+        assert_eq!(state.confirmations(&stable_outpoint), Some(tip_height));
+
+        let unstable_outpoint = OutPoint::new(unstable_tx.txid(), 0);
+        assert_eq!(state.confirmations(&unstable_outpoint), Some(1));
+
+        // Unknown outpoints have no confirmations.
+        assert_eq!(
+            state.confirmations(&OutPoint::new(stable_tx.txid(), 1)),
+            None
+        );
+    }
 
-    /// A response that needs to be processed.
-    pub response_to_process: Option<ResponseToProcess>,
+    #[test]
+    fn confirmations_of_spent_outpoint() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        let tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let genesis = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(tx.clone())
+            .build();
+
+        let mut state = State::new(2, network, genesis);
+        insert_block(&mut state, block_1.clone()).unwrap();
+
+        let outpoint = OutPoint::new(tx.txid(), 0);
+        assert_eq!(state.confirmations(&outpoint), Some(1));
+
+        // Spend the outpoint in the next block.
+        let block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(
+                TransactionBuilder::new()
+                    .with_input(outpoint.clone())
+                    .build(),
+            )
+            .build();
+        insert_block(&mut state, block_2).unwrap();
+
+        assert_eq!(state.confirmations(&outpoint), None);
+    }
 
-    /// The number of rejects received when calling GetSuccessors.
-    pub num_get_successors_rejects: u64,
+    #[test]
+    fn ingestion_is_halted_on_inconsistency() {
+        let network = Network::Regtest;
+        let blocks = BlockChainBuilder::new(3).build();
 
-    /// The number of errors occurred when deserializing blocks.
-    pub num_block_deserialize_errors: u64,
+        let mut state = State::new(0, network, blocks[0].clone());
+        insert_block(&mut state, blocks[1].clone()).unwrap();
 
-    /// The number of errors occurred when inserting a block.
-    pub num_insert_block_errors: u64,
-}
+        // Simulate an inconsistency by having the UTXO set ingest a block other than
+        // the stable block.
+        let other_block = BlockBuilder::genesis().build();
+        state.utxos.ingesting_block = Some(crate::utxo_set::IngestingBlock::new(other_block));
 
-impl Default for SyncingState {
-    fn default() -> Self {
-        Self {
-            syncing: Flag::Enabled,
-            is_fetching_blocks: false,
-            response_to_process: None,
-            num_get_successors_rejects: 0,
-            num_block_deserialize_errors: 0,
-            num_insert_block_errors: 0,
+        // The inconsistency is recorded instead of trapping.
+        assert!(ingest_stable_blocks_into_utxoset(&mut state));
+        assert_eq!(state.syncing_state.num_ingestion_inconsistencies, 1);
+        assert!(state.syncing_state.ingestion_halted);
+
+        // Ingestion no longer makes progress.
+        insert_block(&mut state, blocks[2].clone()).unwrap();
+        let stable_height = state.stable_height();
+        assert!(!ingest_stable_blocks_into_utxoset(&mut state));
+        assert_eq!(state.stable_height(), stable_height);
+        assert_eq!(state.syncing_state.num_ingestion_inconsistencies, 1);
+    }
+
+    #[test]
+    fn balances_are_consistent_with_utxos() {
+        // Asserts that the balance index of each address matches the sum of its stable UTXOs.
+        fn assert_balances_consistent(state: &State, addresses: &[Address]) {
+            for address in addresses {
+                let utxos_sum: u64 = state
+                    .utxos
+                    .get_address_outpoints(address, &None)
+                    .map(|outpoint| state.utxos.get_utxo(&outpoint).unwrap().0.value)
+                    .sum();
+                assert_eq!(state.utxos.get_balance(address), utxos_sum);
+            }
+        }
+
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+        let address_3 = random_p2pkh_address(network);
+        let addresses = [address_1.clone(), address_2.clone(), address_3.clone()];
+
+        let coinbase_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let genesis = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+
+        // Block 1 spends the genesis output of address 1.
+        let block_1 = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address_2, 500)
+                    .build(),
+            )
+            .with_transaction(
+                TransactionBuilder::new()
+                    .with_input(OutPoint::new(coinbase_tx.txid(), 0))
+                    .with_output(&address_2, 600)
+                    .with_output(&address_1, 400)
+                    .build(),
+            )
+            .build();
+
+        // A competing fork that pays address 3.
+        let fork_block = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address_3, 5000)
+                    .build(),
+            )
+            .build();
+
+        let mut state = State::new(1, network, genesis);
+        insert_block(&mut state, block_1.clone()).unwrap();
+        insert_block(&mut state, fork_block).unwrap();
+
+        // Time-slice the ingestion to also check the consistency while a block is
+        // partially ingested.
+        crate::runtime::set_performance_counter_step(100_000_000);
+
+        // Extend block 1 until it's ingested, abandoning the fork.
+        let mut prev_block = block_1;
+        while state.stable_height() < 2 {
+            let block = BlockBuilder::with_prev_header(prev_block.header()).build();
+            insert_block(&mut state, block.clone()).unwrap();
+            prev_block = block;
+
+            while ingest_stable_blocks_into_utxoset(&mut state) {
+                assert_balances_consistent(&state, &addresses);
+                crate::runtime::performance_counter_reset();
+            }
+            assert_balances_consistent(&state, &addresses);
         }
+
+        assert_eq!(state.utxos.get_balance(&address_1), 400);
+        assert_eq!(state.utxos.get_balance(&address_2), 1100);
+        assert_eq!(state.utxos.get_balance(&address_3), 0);
     }
-}
 
-/// Cache for storing last calculated fee percentiles
-///
-/// Stores last tip block hash and fee percentiles associated with it.
-#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct FeePercentilesCache {
-    pub tip_block_hash: BlockHash,
-    pub fee_percentiles: Vec<MillisatoshiPerByte>,
-}
+    #[test]
+    fn rich_list() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+        let address_3 = random_p2pkh_address(network);
+        let address_4 = random_p2pkh_address(network);
+
+        let genesis = BlockBuilder::genesis()
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address_1, 1000)
+                    .with_output(&address_2, 3000)
+                    .with_output(&address_3, 2000)
+                    .with_output(&address_4, 0)
+                    .build(),
+            )
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address_1, 1500)
+                    .build(),
+            )
+            .build();
+
+        let mut state = State::new(0, network, genesis);
+        insert_block(&mut state, block_1.clone()).unwrap();
+        insert_block(
+            &mut state,
+            BlockBuilder::with_prev_header(block_1.header()).build(),
+        )
+        .unwrap();
+        drain_ingestion(&mut state);
+        assert_eq!(state.stable_height(), 2);
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::test_utils::build_chain;
-    use proptest::prelude::*;
-
-    proptest! {
-        #![proptest_config(ProptestConfig::with_cases(10))]
-        #[test]
-        fn serialize_deserialize_state(
-            stability_threshold in 1..150u32,
-            num_blocks in 1..250u32,
-            num_transactions_in_block in 1..100u32,
-        ) {
-            let network = Network::Regtest;
-            let blocks = build_chain(network, num_blocks, num_transactions_in_block);
+        assert_eq!(
+            state.rich_list(10),
+            vec![
+                (address_2.clone(), 3000),
+                (address_1.clone(), 2500),
+                (address_3, 2000),
+            ]
+        );
+        assert_eq!(
+            state.rich_list(2),
+            vec![(address_2, 3000), (address_1, 2500)]
+        );
+        assert_eq!(state.rich_list(0), vec![]);
+    }
+
+    #[test]
+    fn main_chain_height_of_genesis_only_state() {
+        let network = Network::Regtest;
+        let genesis = BlockBuilder::genesis().build();
+        let mut state = State::new(0, network, genesis.clone());
 
-            let mut state = State::new(stability_threshold, network, blocks[0].clone());
+        assert_eq!(state.utxos.next_height(), 0);
+        assert_eq!(main_chain_height(&state), 0);
 
-            for block in blocks[1..].iter() {
-                insert_block(&mut state, block.clone()).unwrap();
-                ingest_stable_blocks_into_utxoset(&mut state);
+        // Extending the chain increments the height.
+        let block = BlockBuilder::with_prev_header(genesis.header()).build();
+        insert_block(&mut state, block).unwrap();
+        assert_eq!(main_chain_height(&state), 1);
+    }
+
+    #[test]
+    fn set_fee_of_single_endpoint() {
+        let mut state = State::new(0, Network::Regtest, BlockBuilder::genesis().build());
+        state
+            .set_fees(Fees {
+                get_balance_maximum: 1_000,
+                ..Fees::default()
+            })
+            .unwrap();
+        let fees_before = state.fees.clone();
+
+        state.set_fee(Endpoint::GetBalance, 123).unwrap();
+
+        assert_eq!(state.fee(Endpoint::GetBalance), 123);
+        assert_eq!(
+            state.fees,
+            Fees {
+                get_balance: 123,
+                ..fees_before
             }
+        );
+    }
+
+    #[test]
+    fn set_fee_of_each_endpoint() {
+        let mut state = State::new(0, Network::Regtest, BlockBuilder::genesis().build());
+        let endpoints = [
+            Endpoint::GetUtxos,
+            Endpoint::GetBalance,
+            Endpoint::GetCurrentFeePercentiles,
+            Endpoint::SendTransaction,
+            Endpoint::GetBlockHeaders,
+            Endpoint::GetTransaction,
+        ];
 
-            let mut bytes = vec![];
-            ciborium::ser::into_writer(&state, &mut bytes).unwrap();
-            let new_state: State = ciborium::de::from_reader(&bytes[..]).unwrap();
+        state
+            .set_fees(Fees {
+                get_utxos_maximum: 1_000,
+                get_balance_maximum: 1_000,
+                get_current_fee_percentiles_maximum: 1_000,
+                get_block_headers_maximum: 1_000,
+                get_transaction_maximum: 1_000,
+                ..Fees::default()
+            })
+            .unwrap();
+
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            state.set_fee(*endpoint, i as u128 + 1).unwrap();
+        }
 
-            // Verify the new state is the same as the old state.
-            assert!(state == new_state);
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            assert_eq!(state.fee(*endpoint), i as u128 + 1);
         }
     }
 
     #[test]
-    fn block_ingestion_stats_are_updated() {
-        let stability_threshold = 0;
-        let num_blocks = 3;
-        let num_transactions_per_block = 10;
+    fn set_fee_validates_fee() {
+        let mut state = State::new(0, Network::Regtest, BlockBuilder::genesis().build());
+        state
+            .set_fees(Fees {
+                get_balance_maximum: 1_000,
+                ..Fees::default()
+            })
+            .unwrap();
+        let fees_before = state.fees.clone();
+
+        assert_eq!(
+            state.set_fee(Endpoint::GetBalance, 1_001),
+            Err(FeeError::BaseAboveMaximum(Endpoint::GetBalance))
+        );
+        assert_eq!(
+            state.set_fee(Endpoint::SendTransaction, MAX_FEE + 1),
+            Err(FeeError::TooLarge(Endpoint::SendTransaction))
+        );
+        assert_eq!(state.fees, fees_before);
+    }
+
+    #[test]
+    fn set_fees_validates_fees() {
+        let mut state = State::new(0, Network::Regtest, BlockBuilder::genesis().build());
+        let fees = Fees {
+            get_utxos_base: 50_000_000,
+            get_utxos_cycles_per_ten_instructions: 10,
+            get_utxos_maximum: 10_000_000_000,
+            get_balance: 10_000_000,
+            get_balance_maximum: 100_000_000,
+            get_current_fee_percentiles: 10_000_000,
+            get_current_fee_percentiles_maximum: 100_000_000,
+            send_transaction_base: 5_000_000_000,
+            send_transaction_per_byte: 20_000_000,
+            get_block_headers_base: 50_000_000,
+            get_block_headers_cycles_per_ten_instructions: 10,
+            get_block_headers_maximum: 10_000_000_000,
+            get_transaction: 10_000_000,
+            get_transaction_maximum: 100_000_000,
+        };
+
+        assert_eq!(state.set_fees(fees.clone()), Ok(()));
+        assert_eq!(state.fees, fees);
+
+        // Out-of-range fees are rejected, leaving the fees unchanged.
+        assert_eq!(
+            state.set_fees(Fees {
+                send_transaction_per_byte: MAX_FEE + 1,
+                ..fees.clone()
+            }),
+            Err(FeeError::TooLarge(Endpoint::SendTransaction))
+        );
+        assert_eq!(
+            state.set_fees(Fees {
+                get_balance: 200_000_000,
+                ..fees.clone()
+            }),
+            Err(FeeError::BaseAboveMaximum(Endpoint::GetBalance))
+        );
+        assert_eq!(state.fees, fees);
+    }
+
+    #[test]
+    fn drain_ingestion_ingests_time_sliced_blocks() {
         let network = Network::Regtest;
-        let blocks = build_chain(network, num_blocks, num_transactions_per_block);
+        let blocks = build_chain(network, 3, 10);
 
-        let mut state = State::new(stability_threshold, network, blocks[0].clone());
+        let mut state = State::new(0, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
 
-        assert_eq!(state.stable_height(), 0);
-        insert_block(&mut state, blocks[1].clone()).unwrap();
+        // Time-slice the ingestion.
+        crate::runtime::set_performance_counter_step(100_000_000);
 
-        // The genesis block is now stable. Ingest it.
-        let metrics_before = state.metrics.block_ingestion_stats.clone();
+        let num_rounds = drain_ingestion(&mut state);
+
+        // The blocks have been fully ingested over multiple rounds.
+        assert!(num_rounds > 1);
+        assert_eq!(state.stable_height(), 2);
+        assert!(state.utxos.ingesting_block.is_none());
+
+        // There is nothing left to ingest.
+        assert_eq!(drain_ingestion(&mut state), 0);
+    }
+
+    #[test]
+    fn get_block_returns_unstable_blocks_only() {
+        let network = Network::Regtest;
+        let blocks = BlockChainBuilder::new(5).build();
+
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
         ingest_stable_blocks_into_utxoset(&mut state);
-        assert_eq!(state.stable_height(), 1);
 
-        // Verify that the stats have been updated.
-        assert_ne!(metrics_before, state.metrics.block_ingestion_stats);
+        // Stable blocks have been ingested and aren't returned.
+        let stable_height = state.stable_height();
+        assert!(stable_height > 0 && stable_height < 5);
+        for height in 0..stable_height {
+            assert_eq!(state.get_block(height), None);
+        }
 
-        // Ingest the next block. This time, the performance counter is set so that
-        // the ingestion is time-sliced.
-        crate::runtime::set_performance_counter_step(100_000_000);
+        // Unstable blocks are returned in full.
+        for height in stable_height..5 {
+            assert_eq!(
+                state.get_block(height),
+                Some(blocks[height as usize].clone())
+            );
+        }
 
-        insert_block(&mut state, blocks[2].clone()).unwrap();
-        let metrics_before = state.metrics.block_ingestion_stats.clone();
-        let mut num_rounds = 0;
-        while state.stable_height() == 1 {
-            assert_eq!(metrics_before, state.metrics.block_ingestion_stats);
+        // No blocks exist above the tip.
+        assert_eq!(state.get_block(5), None);
+    }
+
+    #[test]
+    fn set_blocks_source() {
+        let network = Network::Regtest;
+        let mut state = State::new(1, network, BlockBuilder::genesis().build());
+        assert_eq!(state.blocks_source, Principal::management_canister());
+
+        assert_eq!(
+            state.set_blocks_source(Principal::anonymous()),
+            Err(SourceError::AnonymousPrincipal)
+        );
+        assert_eq!(state.blocks_source, Principal::management_canister());
+
+        let source = Principal::from_slice(&[1, 2, 3]);
+        assert_eq!(state.set_blocks_source(source), Ok(()));
+        assert_eq!(state.blocks_source, source);
+    }
+
+    #[test]
+    fn get_block_info() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 4, 3);
+
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 2);
+
+        // Block 2 is the anchor, which is still unstable, so it's available at every verbosity.
+        let block = &blocks[2];
+        let mut bytes = vec![];
+        block.consensus_encode(&mut bytes).unwrap();
+        assert_eq!(
+            state.get_block_info(&block.block_hash(), BlockVerbosity::Raw),
+            Some(BlockInfo::Raw(bytes))
+        );
+
+        assert_eq!(
+            state.get_block_info(&block.block_hash(), BlockVerbosity::Txids),
+            Some(BlockInfo::Txids {
+                height: 2,
+                header: *block.header(),
+                txids: block.txdata().iter().map(|tx| tx.txid()).collect(),
+            })
+        );
+        assert_eq!(block.txdata().len(), 3);
+
+        assert_eq!(
+            state.get_block_info(&block.block_hash(), BlockVerbosity::Transactions),
+            Some(BlockInfo::Transactions {
+                height: 2,
+                header: *block.header(),
+                transactions: block.txdata().to_vec(),
+            })
+        );
+
+        // Only the header of the stable block 0 is available.
+        for verbosity in 0..=2 {
+            assert_eq!(
+                state.get_block_info(
+                    &blocks[0].block_hash(),
+                    BlockVerbosity::try_from(verbosity).unwrap()
+                ),
+                Some(BlockInfo::StableHeader {
+                    height: 0,
+                    header: *blocks[0].header(),
+                })
+            );
+        }
+
+        // Unknown blocks and verbosities.
+        let unknown_block = BlockBuilder::with_prev_header(blocks[3].header()).build();
+        assert_eq!(
+            state.get_block_info(&unknown_block.block_hash(), BlockVerbosity::Raw),
+            None
+        );
+        assert!(BlockVerbosity::try_from(3).is_err());
+    }
+
+    #[test]
+    fn utxo_flow_report() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 2);
+
+        let mut state = State::new(10, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+
+        // The genesis block creates one output and the other blocks create two outputs each.
+        assert_eq!(
+            state.utxo_flow_report(),
+            UtxoFlowReport {
+                num_created: 5,
+                num_spent: 0,
+                net_change: 5,
+                largest_block_contribution: Some((blocks[1].block_hash(), 2)),
+            }
+        );
+
+        // A block that consolidates the two outputs of block 1 into one.
+        let tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(blocks[1].txdata()[0].txid(), 0))
+            .with_input(OutPoint::new(blocks[1].txdata()[1].txid(), 0))
+            .with_output(&random_p2pkh_address(network), 1000)
+            .build();
+        let block = BlockBuilder::with_prev_header(blocks[2].header())
+            .with_transaction(tx)
+            .build();
+        insert_block(&mut state, block).unwrap();
+
+        assert_eq!(
+            state.utxo_flow_report(),
+            UtxoFlowReport {
+                num_created: 6,
+                num_spent: 2,
+                net_change: 4,
+                largest_block_contribution: Some((blocks[1].block_hash(), 2)),
+            }
+        );
+    }
+
+    #[test]
+    fn get_utxos_split() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        let genesis_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let genesis = BlockBuilder::genesis()
+            .with_transaction(genesis_tx.clone())
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 2000)
+                    .build(),
+            )
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        // An unstable block spending the UTXO of the genesis block.
+        let block_3 = BlockBuilder::with_prev_header(block_2.header())
+            .with_transaction(
+                TransactionBuilder::spending(OutPoint::new(genesis_tx.txid(), 0), &address, 500)
+                    .build(),
+            )
+            .build();
+        let block_4 = BlockBuilder::with_prev_header(block_3.header())
+            .with_transaction(
+                TransactionBuilder::coinbase()
+                    .with_output(&address, 4000)
+                    .build(),
+            )
+            .build();
+
+        let mut state = State::new(2, network, genesis);
+        for block in [block_1, block_2, block_3, block_4] {
+            insert_block(&mut state, block).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        let values =
+            |utxos: Vec<Utxo>| -> Vec<u64> { utxos.into_iter().map(|utxo| utxo.value).collect() };
+
+        let (confirmed, unconfirmed) = state.get_utxos_split(address);
+        assert_eq!(values(confirmed), vec![2000]);
+        assert_eq!(values(unconfirmed), vec![4000, 500]);
+    }
+
+    #[test]
+    fn new_with_network_defaults() {
+        for (network, stability_threshold) in [
+            (Network::Mainnet, 144),
+            (Network::Testnet, 144),
+            (Network::Regtest, 2),
+        ] {
+            let state = State::new_with_network_defaults(network, crate::genesis_block(network));
+            assert_eq!(state.network(), network);
+            assert_eq!(
+                state.unstable_blocks.stability_threshold(),
+                stability_threshold
+            );
+        }
+    }
+
+    #[test]
+    fn for_each_main_chain_block() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 3);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        let mut visited = vec![];
+        let mut num_transactions = 0;
+        state
+            .for_each_main_chain_block(|block, height| {
+                visited.push((block.block_hash(), height));
+                num_transactions += block.txdata().len();
+            })
+            .unwrap();
+
+        // All the blocks are visited in order.
+        assert_eq!(
+            visited,
+            blocks
+                .iter()
+                .enumerate()
+                .map(|(height, block)| (block.block_hash(), height as Height))
+                .collect::<Vec<_>>()
+        );
+
+        // Only the transactions of the unstable blocks are available.
+        let num_unstable_transactions: usize =
+            blocks[3..].iter().map(|block| block.txdata().len()).sum();
+        assert_eq!(num_transactions, num_unstable_transactions);
+        assert_eq!(num_transactions, 6);
+    }
+
+    #[test]
+    fn get_chain_with_tip() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        // Stabilized blocks are below the anchor.
+        assert_eq!(
+            state.get_chain_with_tip(&blocks[2].block_hash()),
+            ChainWithTip::BelowAnchor(2)
+        );
+
+        assert_eq!(
+            state.get_chain_with_tip(&blocks[4].block_hash()),
+            ChainWithTip::Unstable(BlockChain::new_with_successors(
+                &blocks[3],
+                vec![&blocks[4]]
+            ))
+        );
+
+        let unknown_block = BlockBuilder::with_prev_header(blocks[4].header()).build();
+        assert_eq!(
+            state.get_chain_with_tip(&unknown_block.block_hash()),
+            ChainWithTip::Unknown
+        );
+    }
+
+    #[test]
+    fn hash_at_height() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+
+        let mut state = State::new(2, network, blocks[0].clone());
+
+        // The genesis block is the anchor before any block is ingested.
+        assert_eq!(state.hash_at_height(0), Some(blocks[0].block_hash()));
+
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        // Stable heights, including the genesis block.
+        for height in 0..3 {
+            assert_eq!(
+                state.hash_at_height(height),
+                Some(blocks[height as usize].block_hash())
+            );
+        }
+
+        // Unstable heights, starting with the anchor.
+        for height in 3..5 {
+            assert_eq!(
+                state.hash_at_height(height),
+                Some(blocks[height as usize].block_hash())
+            );
+        }
+
+        // Heights above the tip.
+        assert_eq!(state.hash_at_height(5), None);
+    }
+
+    #[test]
+    fn is_on_main_chain() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        // A fork of the anchor that is shorter than the main chain.
+        let fork_block = BlockBuilder::with_prev_header(blocks[3].header()).build();
+        insert_block(&mut state, fork_block.clone()).unwrap();
+        let tip = BlockBuilder::with_prev_header(blocks[4].header()).build();
+        insert_block(&mut state, tip.clone()).unwrap();
+
+        // Both stable and unstable blocks of the main chain are on the main chain.
+        for block in blocks.iter().chain(std::iter::once(&tip)) {
+            assert_eq!(state.is_on_main_chain(&block.block_hash()), Some(true));
+        }
+
+        assert_eq!(
+            state.is_on_main_chain(&fork_block.block_hash()),
+            Some(false)
+        );
+
+        let unknown_block = BlockBuilder::with_prev_header(tip.header()).build();
+        assert_eq!(
+            state.is_on_main_chain(&unknown_block.block_hash()),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn export_and_import_main_chain_headers() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 6, 1);
+
+        let mut exporter = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut exporter, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut exporter);
+        assert_eq!(exporter.stable_height(), 4);
+
+        let headers = exporter.export_main_chain_headers().unwrap();
+        assert_eq!(headers.len(), 6 * HEADER_SIZE);
+        let exporter_tip = exporter
+            .hash_at_height(main_chain_height(&exporter))
+            .unwrap();
+
+        let mut importer = State::new(2, network, blocks[0].clone());
+        assert_eq!(
+            importer.import_main_chain_headers(&headers),
+            Ok(exporter_tip.clone())
+        );
+        assert_eq!(
+            importer.unstable_blocks.next_block_headers_max_height(),
+            Some(5)
+        );
+        assert!(importer
+            .unstable_blocks
+            .has_next_block_header(blocks[5].header()));
+
+        // Importing the same headers again is a no-op.
+        assert_eq!(
+            importer.import_main_chain_headers(&headers),
+            Ok(exporter_tip)
+        );
+
+        // Malformed headers are rejected.
+        assert_eq!(
+            importer.import_main_chain_headers(&headers[..headers.len() - 1]),
+            Err(ImportHeadersError::Malformed)
+        );
+        assert_eq!(
+            importer.import_main_chain_headers(&[]),
+            Err(ImportHeadersError::Malformed)
+        );
+
+        // Headers of a different chain are rejected.
+        let other_headers = State::new(2, network, build_chain(network, 1, 1)[0].clone())
+            .export_main_chain_headers()
+            .unwrap();
+        assert_eq!(
+            importer.import_main_chain_headers(&other_headers),
+            Err(ImportHeadersError::ConflictsWithMainChain { height: 0 })
+        );
+    }
+
+    #[test]
+    fn utxo_delta() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+
+        // Each block spends the first output of the block before it.
+        let tx_0 = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .build();
+        let tx_1 = TransactionBuilder::new()
+            .with_input(OutPoint::new(tx_0.txid(), 0))
+            .with_output(&address_1, 600)
+            .with_output(&address_2, 400)
+            .build();
+        let tx_2 = TransactionBuilder::new()
+            .with_input(OutPoint::new(tx_1.txid(), 0))
+            .with_output(&address_1, 600)
+            .build();
+        let tx_3 = TransactionBuilder::new()
+            .with_input(OutPoint::new(tx_2.txid(), 0))
+            .with_output(&address_2, 600)
+            .build();
+
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(tx_0.clone())
+            .build();
+        let mut state = State::new(10, network, block_0.clone());
+        let mut prev_block = block_0;
+        for tx in [&tx_1, &tx_2, &tx_3] {
+            let block = BlockBuilder::with_prev_header(prev_block.header())
+                .with_transaction(tx.clone())
+                .build();
+            insert_block(&mut state, block.clone()).unwrap();
+            prev_block = block;
+        }
+
+        let utxo = |tx: &Transaction, vout: u32, value: u64, height: u32| Utxo {
+            outpoint: OutPoint::new(tx.txid(), vout),
+            value,
+            height,
+        };
+
+        // The first output of block 1 is both created and spent in the range.
+        assert_eq!(
+            state.utxo_delta(0, 2),
+            Some((
+                vec![utxo(&tx_2, 0, 600, 2), utxo(&tx_1, 1, 400, 1)],
+                vec![utxo(&tx_0, 0, 1000, 0)]
+            ))
+        );
+
+        assert_eq!(
+            state.utxo_delta(1, 3),
+            Some((vec![utxo(&tx_3, 0, 600, 3)], vec![utxo(&tx_1, 0, 600, 1)]))
+        );
+
+        assert_eq!(state.utxo_delta(2, 2), Some((vec![], vec![])));
+
+        // Heights outside of the unstable blocks or reversed ranges aren't supported.
+        assert_eq!(state.utxo_delta(0, 4), None);
+        assert_eq!(state.utxo_delta(2, 1), None);
+    }
+
+    #[test]
+    fn get_utxos_by_script_bare_multisig() {
+        use bitcoin::blockdata::{opcodes::all as opcodes, script::Builder};
+
+        let network = Network::Regtest;
+
+        // A 1-of-2 bare multisig script, which doesn't map to any address.
+        let script = Builder::new()
+            .push_opcode(opcodes::OP_PUSHNUM_1)
+            .push_slice(&[2; 33])
+            .push_slice(&[3; 33])
+            .push_opcode(opcodes::OP_PUSHNUM_2)
+            .push_opcode(opcodes::OP_CHECKMULTISIG)
+            .into_script();
+        assert!(Address::from_script(&script, network).is_err());
+
+        let mut tx: bitcoin::Transaction = TransactionBuilder::coinbase().build().into();
+        tx.output[0].script_pubkey = script.clone();
+        tx.output[0].value = 1000;
+        let tx = Transaction::new(tx);
+
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(tx.clone())
+            .build();
+
+        let mut state = State::new(1, network, block_0.clone());
+        insert_block(&mut state, block_1.clone()).unwrap();
+
+        let expected_utxos = vec![Utxo {
+            outpoint: OutPoint::new(tx.txid(), 0),
+            value: 1000,
+            height: 1,
+        }];
+
+        // The UTXO is retrieved while block 1 is unstable.
+        assert_eq!(state.get_utxos_by_script(&script, false), expected_utxos);
+
+        // The UTXO is retrieved after block 1 is ingested into the UTXO set.
+        let mut prev_block = block_1;
+        while state.stable_height() < 2 {
+            let block = BlockBuilder::with_prev_header(prev_block.header()).build();
+            insert_block(&mut state, block.clone()).unwrap();
             ingest_stable_blocks_into_utxoset(&mut state);
-            crate::runtime::performance_counter_reset();
-            num_rounds += 1;
+            prev_block = block;
         }
+        assert_eq!(state.get_utxos_by_script(&script, false), expected_utxos);
+
+        // Spend the UTXO. It's no longer retrieved.
+        let spending_tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(tx.txid(), 0))
+            .build();
+        let block = BlockBuilder::with_prev_header(prev_block.header())
+            .with_transaction(spending_tx)
+            .build();
+        insert_block(&mut state, block).unwrap();
+        assert_eq!(state.get_utxos_by_script(&script, false), vec![]);
+    }
 
-        // Assert that the block has been ingested.
-        assert_eq!(state.stable_height(), 2);
+    #[test]
+    fn get_utxos_by_script_unspendable_outputs() {
+        use bitcoin::blockdata::{opcodes::all as opcodes, script::Builder};
 
-        // Assert that the block ingestion has been time-sliced.
-        assert!(num_rounds > 1);
+        let network = Network::Regtest;
+        let script = Builder::new()
+            .push_opcode(opcodes::OP_RETURN)
+            .push_slice(b"data")
+            .into_script();
+        assert!(script.is_provably_unspendable());
+
+        let mut tx: bitcoin::Transaction = TransactionBuilder::coinbase().build().into();
+        tx.output[0].script_pubkey = script.clone();
+        tx.output[0].value = 0;
+        let tx = Transaction::new(tx);
+
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(tx.clone())
+            .build();
+
+        let mut state = State::new(1, network, block_0);
+        insert_block(&mut state, block_1).unwrap();
+
+        // The OP_RETURN output is excluded by default.
+        assert_eq!(state.get_utxos_by_script(&script, false), vec![]);
+
+        // The OP_RETURN output is included when requested.
+        assert_eq!(
+            state.get_utxos_by_script(&script, true),
+            vec![Utxo {
+                outpoint: OutPoint::new(tx.txid(), 0),
+                value: 0,
+                height: 1,
+            }]
+        );
+    }
 
-        // Assert the stats have been updated.
-        assert_ne!(metrics_before, state.metrics.block_ingestion_stats);
+    #[test]
+    fn total_supply() {
+        use bitcoin::blockdata::{opcodes::all as opcodes, script::Builder};
+
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 6, 2);
+
+        // The sum of the output values created by the given blocks.
+        fn created_value(blocks: &[Block]) -> u64 {
+            blocks
+                .iter()
+                .flat_map(|block| block.txdata())
+                .flat_map(|tx| tx.output())
+                .map(|output| output.value)
+                .sum()
+        }
+
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 4);
+
+        assert_eq!(state.total_supply(), created_value(&blocks[..4]));
+        assert_eq!(
+            state.total_supply_including_unstable(),
+            created_value(&blocks)
+        );
+
+        // Spend a stable UTXO, burning part of its value in an OP_RETURN output and leaving
+        // the rest as a fee that isn't claimed.
+        let spent_tx = &blocks[1].txdata()[1];
+        let spent_value = spent_tx.output()[0].value;
+        let mut tx: bitcoin::Transaction = TransactionBuilder::spending(
+            OutPoint::new(spent_tx.txid(), 0),
+            &random_p2pkh_address(network),
+            1,
+        )
+        .build()
+        .into();
+        tx.output.push(bitcoin::TxOut {
+            value: spent_value - 1,
+            script_pubkey: Builder::new().push_opcode(opcodes::OP_RETURN).into_script(),
+        });
+        let block = BlockBuilder::with_prev_header(blocks[5].header())
+            .with_transaction(Transaction::new(tx))
+            .build();
+        insert_block(&mut state, block.clone()).unwrap();
+
+        assert_eq!(state.total_supply(), created_value(&blocks[..4]));
+        assert_eq!(
+            state.total_supply_including_unstable(),
+            created_value(&blocks) + created_value(&[block]) - spent_value - (spent_value - 1)
+        );
+    }
+
+    #[test]
+    fn pruned_headers_are_reported() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 3);
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        // Prune the headers below height 2 by storing a header that's far ahead of them.
+        let retention = HeaderRetention {
+            depth: MIN_RETENTION_DEPTH,
+            checkpoint_interval: 1_000,
+        };
+        state
+            .stable_block_headers
+            .set_retention(Some(retention))
+            .unwrap();
+        state
+            .stable_block_headers
+            .insert_block(&blocks[4], MIN_RETENTION_DEPTH + 1);
+        assert_eq!(
+            state.stable_block_headers.prune_headers(|| false),
+            Slicing::Done(())
+        );
+        assert_eq!(state.stable_block_headers.get_with_height(1), None);
+
+        assert_eq!(
+            state.for_each_main_chain_block(|_, _| panic!("no block must be visited")),
+            Err(HeadersPruned)
+        );
+        assert_eq!(state.export_main_chain_headers(), Err(HeadersPruned));
+        assert_eq!(
+            state.verify_no_spent_utxos(|_| None),
+            Err(AuditError::HeadersPruned)
+        );
     }
 }
@@ -0,0 +1,284 @@
+use super::State;
+use crate::{
+    descriptor::{validate_gap_limit, Descriptor, DescriptorError},
+    types::{Address, Utxo},
+    unstable_blocks,
+};
+use ic_btc_types::Block;
+use std::collections::BTreeSet;
+
+/// The maximum number of addresses that `State::get_utxos_multi` accepts.
+pub const MAX_MULTI_ADDRESSES: usize = 1_000;
+
+/// An error returned by `State::get_utxos_multi` and `State::get_utxos_for_descriptor` when
+/// more than `MAX_MULTI_ADDRESSES` addresses are queried.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TooManyAddresses {
+    pub num_addresses: usize,
+    pub max: usize,
+}
+
+/// An error returned by `State::get_utxos_for_descriptor`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DescriptorUtxosError {
+    /// The descriptor or the gap limit is invalid.
+    InvalidDescriptor(DescriptorError),
+    /// The gap limit wasn't reached within `MAX_MULTI_ADDRESSES` derived addresses.
+    TooManyAddresses(TooManyAddresses),
+}
+
+impl State {
+    /// Returns the combined UTXOs of the given addresses on the main chain, in descending
+    /// order by height. At most `MAX_MULTI_ADDRESSES` addresses can be queried at once.
+    pub fn get_utxos_multi(&self, addresses: &[Address]) -> Result<Vec<Utxo>, TooManyAddresses> {
+        if addresses.len() > MAX_MULTI_ADDRESSES {
+            return Err(TooManyAddresses {
+                num_addresses: addresses.len(),
+                max: MAX_MULTI_ADDRESSES,
+            });
+        }
+
+        let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain();
+
+        let mut utxos = BTreeSet::new();
+        for address in addresses {
+            utxos.extend(self.get_main_chain_utxos(address.clone(), &main_chain));
+        }
+
+        Ok(utxos.into_iter().collect())
+    }
+
+    /// Returns the combined UTXOs on the main chain of the addresses derived from the given
+    /// watch-only descriptor, in descending order by height.
+    ///
+    /// For a ranged descriptor, addresses are derived in order until `gap_limit` consecutive
+    /// addresses are unused, i.e. have never received an output on the main chain, and the
+    /// UTXOs of all the addresses derived are returned using `get_utxos_multi`, so at most
+    /// `MAX_MULTI_ADDRESSES` addresses can be derived.
+    pub fn get_utxos_for_descriptor(
+        &self,
+        descriptor: &str,
+        gap_limit: u32,
+    ) -> Result<Vec<Utxo>, DescriptorUtxosError> {
+        validate_gap_limit(gap_limit).map_err(DescriptorUtxosError::InvalidDescriptor)?;
+        let descriptor = Descriptor::parse(descriptor, self.network())
+            .map_err(DescriptorUtxosError::InvalidDescriptor)?;
+
+        let mut addresses = vec![];
+        if descriptor.is_ranged() {
+            let main_chain = unstable_blocks::get_main_chain(&self.unstable_blocks).into_chain();
+            let mut num_unused = 0;
+            // Deriving more than `MAX_MULTI_ADDRESSES` addresses is pointless, as
+            // `get_utxos_multi` rejects them.
+            while num_unused < gap_limit && addresses.len() <= MAX_MULTI_ADDRESSES {
+                let address = descriptor
+                    .address(addresses.len() as u32)
+                    .map_err(DescriptorUtxosError::InvalidDescriptor)?;
+                if self.is_address_used(&address, &main_chain) {
+                    num_unused = 0;
+                } else {
+                    num_unused += 1;
+                }
+                addresses.push(address);
+            }
+        } else {
+            addresses.push(
+                descriptor
+                    .address(0)
+                    .map_err(DescriptorUtxosError::InvalidDescriptor)?,
+            );
+        }
+
+        self.get_utxos_multi(&addresses)
+            .map_err(DescriptorUtxosError::TooManyAddresses)
+    }
+
+    // Returns true if the given address has received an output on the main chain, either in
+    // a stable block or in an unstable one.
+    fn is_address_used(&self, address: &Address, main_chain: &[&Block]) -> bool {
+        self.utxos.is_address_used(address)
+            || main_chain.iter().any(|block| {
+                !self
+                    .unstable_blocks
+                    .get_added_outpoints(&block.block_hash(), address)
+                    .is_empty()
+            })
+    }
+
+    // Returns the UTXOs of the given address on the main chain.
+    fn get_main_chain_utxos(&self, address: Address, main_chain: &[&Block]) -> Vec<Utxo> {
+        let mut address_utxos = self.get_utxos(address);
+        for block in main_chain.iter() {
+            address_utxos.apply_block(block);
+        }
+        address_utxos.into_iter(None).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::descriptor::{derive_addresses, MAX_GAP_LIMIT};
+    use crate::state::{ingest_stable_blocks_into_utxoset, insert_block};
+    use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use ic_btc_interface::Network;
+    use ic_btc_types::OutPoint;
+
+    #[test]
+    fn get_utxos_for_descriptor() {
+        let network = Network::Mainnet;
+        let descriptor = "wpkh(xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8/0/*)";
+        let addresses = derive_addresses(descriptor, 6, network).unwrap();
+
+        // Pay the addresses at indices 0, 2 and 5, as well as an unrelated address.
+        let tx = TransactionBuilder::coinbase()
+            .with_output(&addresses[0], 1000)
+            .with_output(&addresses[2], 2000)
+            .with_output(&addresses[5], 3000)
+            .with_output(&random_p2pkh_address(network), 4000)
+            .build();
+        let genesis_block = BlockBuilder::genesis().with_transaction(tx).build();
+        let state = State::new(1, network, genesis_block);
+
+        let values = |utxos: Vec<Utxo>| -> BTreeSet<u64> {
+            utxos.into_iter().map(|utxo| utxo.value).collect()
+        };
+
+        // Addresses are derived until `gap_limit` consecutive addresses are unused.
+        assert_eq!(
+            values(state.get_utxos_for_descriptor(descriptor, 1).unwrap()),
+            BTreeSet::from([1000])
+        );
+        assert_eq!(
+            values(state.get_utxos_for_descriptor(descriptor, 2).unwrap()),
+            BTreeSet::from([1000, 2000])
+        );
+        assert_eq!(
+            values(state.get_utxos_for_descriptor(descriptor, 3).unwrap()),
+            BTreeSet::from([1000, 2000, 3000])
+        );
+
+        // A non-ranged descriptor has a single address.
+        let non_ranged_descriptor = descriptor.replace("/0/*", "/0/2");
+        assert_eq!(
+            values(
+                state
+                    .get_utxos_for_descriptor(&non_ranged_descriptor, 3)
+                    .unwrap()
+            ),
+            BTreeSet::from([2000])
+        );
+
+        assert_eq!(
+            state.get_utxos_for_descriptor("tr(xpub/0/*)", 3),
+            Err(DescriptorUtxosError::InvalidDescriptor(
+                DescriptorError::UnsupportedDescriptor
+            ))
+        );
+        assert_eq!(
+            state.get_utxos_for_descriptor(descriptor, MAX_GAP_LIMIT + 1),
+            Err(DescriptorUtxosError::InvalidDescriptor(
+                DescriptorError::GapLimitTooLarge {
+                    gap_limit: MAX_GAP_LIMIT + 1,
+                    max: MAX_GAP_LIMIT
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn get_utxos_for_descriptor_counts_spent_addresses_as_used() {
+        let network = Network::Mainnet;
+        let descriptor = "wpkh(xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8/0/*)";
+        let addresses = derive_addresses(descriptor, 6, network).unwrap();
+
+        // Pay the addresses at indices 0, 2 and 5.
+        let tx = TransactionBuilder::coinbase()
+            .with_output(&addresses[0], 1000)
+            .with_output(&addresses[2], 2000)
+            .with_output(&addresses[5], 3000)
+            .build();
+        let genesis_block = BlockBuilder::genesis().with_transaction(tx.clone()).build();
+        // Spend the output of the address at index 2.
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header())
+            .with_transaction(
+                TransactionBuilder::spending(
+                    OutPoint::new(tx.txid(), 1),
+                    &random_p2pkh_address(network),
+                    2000,
+                )
+                .build(),
+            )
+            .build();
+
+        let values = |utxos: Vec<Utxo>| -> BTreeSet<u64> {
+            utxos.into_iter().map(|utxo| utxo.value).collect()
+        };
+
+        // The address at index 2 is used even though it has no UTXOs left, so the gap between
+        // indices 0 and 5 doesn't exceed the gap limit of 3. This holds whether the address
+        // was paid in an unstable block or in a stable one.
+        let mut state = State::new(0, network, genesis_block);
+        assert_eq!(
+            values(state.get_utxos_for_descriptor(descriptor, 3).unwrap()),
+            BTreeSet::from([1000, 2000, 3000])
+        );
+        insert_block(&mut state, block_1).unwrap();
+        assert_eq!(
+            values(state.get_utxos_for_descriptor(descriptor, 3).unwrap()),
+            BTreeSet::from([1000, 3000])
+        );
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 1);
+        assert_eq!(
+            values(state.get_utxos_for_descriptor(descriptor, 3).unwrap()),
+            BTreeSet::from([1000, 3000])
+        );
+    }
+
+    #[test]
+    fn get_utxos_for_descriptor_derives_at_most_max_multi_addresses() {
+        let network = Network::Mainnet;
+        let descriptor_str = "wpkh(xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8/0/*)";
+        let descriptor = Descriptor::parse(descriptor_str, network).unwrap();
+
+        // Pay the first `MAX_MULTI_ADDRESSES` addresses, so that reaching any gap limit
+        // requires deriving more than `MAX_MULTI_ADDRESSES` addresses.
+        let mut tx = TransactionBuilder::coinbase();
+        for index in 0..MAX_MULTI_ADDRESSES {
+            tx = tx.with_output(&descriptor.address(index as u32).unwrap(), 1000);
+        }
+        let genesis_block = BlockBuilder::genesis().with_transaction(tx.build()).build();
+        let state = State::new(1, network, genesis_block);
+
+        assert_eq!(
+            state.get_utxos_for_descriptor(descriptor_str, 1),
+            Err(DescriptorUtxosError::TooManyAddresses(TooManyAddresses {
+                num_addresses: MAX_MULTI_ADDRESSES + 1,
+                max: MAX_MULTI_ADDRESSES
+            }))
+        );
+    }
+
+    #[test]
+    fn get_utxos_multi_rejects_too_many_addresses() {
+        let network = Network::Regtest;
+        let state = State::new(1, network, BlockBuilder::genesis().build());
+
+        let addresses: Vec<Address> = (0..MAX_MULTI_ADDRESSES)
+            .map(|_| random_p2pkh_address(network))
+            .collect();
+        assert_eq!(state.get_utxos_multi(&addresses), Ok(vec![]));
+
+        let addresses: Vec<Address> = (0..MAX_MULTI_ADDRESSES + 1)
+            .map(|_| random_p2pkh_address(network))
+            .collect();
+        assert_eq!(
+            state.get_utxos_multi(&addresses),
+            Err(TooManyAddresses {
+                num_addresses: MAX_MULTI_ADDRESSES + 1,
+                max: MAX_MULTI_ADDRESSES
+            })
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use super::State;
+use ic_btc_interface::MillisatoshiPerByte;
+use ic_btc_types::BlockHash;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Cache for storing last calculated fee percentiles
+///
+/// Stores last tip block hash and fee percentiles associated with it.
+#[derive(Default, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeePercentilesCache {
+    pub tip_block_hash: BlockHash,
+    pub fee_percentiles: Vec<MillisatoshiPerByte>,
+
+    /// The time in seconds at which the fee percentiles were computed.
+    /// `None` if the fee percentiles were computed before this time was recorded.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub computed_at: Option<u64>,
+}
+
+/// The maximum number of blocks returned by `State::estimate_confirmation_blocks`,
+/// equivalent to roughly one day.
+pub const MAX_ESTIMATED_CONFIRMATION_BLOCKS: u32 = 144;
+
+impl State {
+    /// Returns how long ago the cached fee percentiles were computed, as of `now` in seconds.
+    ///
+    /// Returns `None` if there are no cached fee percentiles, or if the time at which they
+    /// were computed isn't known.
+    pub fn fee_percentiles_age(&self, now: u64) -> Option<Duration> {
+        let computed_at = self.fee_percentiles_cache.as_ref()?.computed_at?;
+        Some(Duration::from_secs(now.saturating_sub(computed_at)))
+    }
+
+    /// Estimates the number of blocks until a transaction with the given fee rate is confirmed,
+    /// based on the cached fee percentiles of the recent transactions.
+    ///
+    /// Transactions with a higher fee rate are assumed to be confirmed first. If a fraction `a` of
+    /// the recent transactions paid a higher fee rate, the transaction is assumed to have a
+    /// `1 - a` chance of being included in each block, i.e. to be confirmed within `1 / (1 - a)`
+    /// blocks, capped at `MAX_ESTIMATED_CONFIRMATION_BLOCKS`. With no fee percentiles to compare
+    /// with, the transaction is assumed to be confirmed in the next block.
+    pub fn estimate_confirmation_blocks(&self, fee_rate: MillisatoshiPerByte) -> u32 {
+        let fee_percentiles = match &self.fee_percentiles_cache {
+            Some(cache) if !cache.fee_percentiles.is_empty() => &cache.fee_percentiles,
+            _ => return 1,
+        };
+
+        let num_percentiles = fee_percentiles.len() as u32;
+        let num_higher = fee_percentiles
+            .iter()
+            .filter(|fee| **fee > fee_rate)
+            .count() as u32;
+        match num_percentiles - num_higher {
+            0 => MAX_ESTIMATED_CONFIRMATION_BLOCKS,
+            num_lower_or_equal => {
+                let blocks = (num_percentiles + num_lower_or_equal - 1) / num_lower_or_equal;
+                blocks.min(MAX_ESTIMATED_CONFIRMATION_BLOCKS)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::BlockBuilder;
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn estimate_confirmation_blocks() {
+        let network = Network::Regtest;
+        let mut state = State::new(1, network, BlockBuilder::genesis().build());
+
+        // Without any fee data, the next block is assumed.
+        assert_eq!(state.estimate_confirmation_blocks(0), 1);
+
+        // Recent fees ranging uniformly from 0 to 1000 millisatoshi per byte.
+        state.fee_percentiles_cache = Some(FeePercentilesCache {
+            fee_percentiles: (0..=100).map(|i| i * 10).collect(),
+            ..Default::default()
+        });
+
+        // High fees are expected to be confirmed in the next block.
+        assert_eq!(state.estimate_confirmation_blocks(1_000), 1);
+        assert_eq!(state.estimate_confirmation_blocks(5_000), 1);
+        assert_eq!(state.estimate_confirmation_blocks(900), 2);
+
+        // Lower fees are expected to take longer.
+        assert_eq!(state.estimate_confirmation_blocks(500), 2);
+        assert_eq!(state.estimate_confirmation_blocks(250), 4);
+        assert_eq!(state.estimate_confirmation_blocks(0), 101);
+
+        // Fees lower than all the recent fees are capped.
+        state.fee_percentiles_cache = Some(FeePercentilesCache {
+            fee_percentiles: vec![1_000; 101],
+            ..Default::default()
+        });
+        assert_eq!(
+            state.estimate_confirmation_blocks(999),
+            MAX_ESTIMATED_CONFIRMATION_BLOCKS
+        );
+    }
+}
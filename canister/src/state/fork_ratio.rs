@@ -0,0 +1,76 @@
+use super::State;
+use crate::unstable_blocks;
+use ic_btc_types::Block;
+
+/// The ratio of forks to unstable blocks above which `insert_block` rejects blocks that would
+/// start a new fork directly on top of the anchor with less work than the main chain.
+pub const MAX_FORK_RATIO: f64 = 0.5;
+
+// Returns true if the block starts a new fork on top of the anchor that must be rejected, as
+// the ratio of forks is above `MAX_FORK_RATIO`.
+//
+// Only forks with less work than the main chain are rejected, so that the honest chain can't
+// be crowded out by cheap forks.
+pub(super) fn is_rejected_anchor_fork(state: &State, block: &Block) -> bool {
+    state.unstable_blocks.is_new_anchor_fork(block)
+        && state.unstable_blocks.fork_ratio() > MAX_FORK_RATIO
+        && (block.difficulty(state.network()) as u128) < main_chain_work(state)
+}
+
+// Returns the work of the main chain beyond the anchor, i.e. the sum of the difficulties of
+// its unstable blocks excluding the anchor.
+fn main_chain_work(state: &State) -> u128 {
+    unstable_blocks::get_main_chain(&state.unstable_blocks)
+        .into_chain()
+        .iter()
+        .skip(1)
+        .map(|block| block.difficulty(state.network()) as u128)
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{insert_block, InsertBlockError};
+    use crate::test_utils::BlockBuilder;
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn rejects_anchor_forks_above_max_fork_ratio() {
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build();
+        let mut state = State::new(10, network, genesis_block.clone());
+
+        // A main chain of two blocks on top of the anchor.
+        let main_chain_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let main_chain_2 = BlockBuilder::with_prev_header(main_chain_1.header()).build();
+        let main_chain_work = main_chain_1.difficulty(network) + main_chain_2.difficulty(network);
+        insert_block(&mut state, main_chain_1).unwrap();
+        insert_block(&mut state, main_chain_2).unwrap();
+
+        // Keep forking the anchor with single blocks until the new forks are rejected.
+        let mut forks = vec![];
+        let err = loop {
+            let block = BlockBuilder::with_prev_header(genesis_block.header()).build();
+            match insert_block(&mut state, block.clone()) {
+                Ok(()) => forks.push(block),
+                Err(err) => break err,
+            }
+        };
+        assert_eq!(err, InsertBlockError::TooManyForks);
+
+        // With 4 forks, there are 4 forks beyond the first tip among 7 blocks, which is above
+        // the maximum ratio.
+        assert_eq!(forks.len(), 4);
+        assert!(state.unstable_blocks.fork_ratio() > MAX_FORK_RATIO);
+
+        // Existing forks can still be extended.
+        let block = BlockBuilder::with_prev_header(forks[0].header()).build();
+        assert_eq!(insert_block(&mut state, block), Ok(()));
+
+        // A new fork with as much work as the main chain is still accepted.
+        let heavy_fork = BlockBuilder::with_prev_header(genesis_block.header())
+            .build_with_mock_difficulty(main_chain_work);
+        assert_eq!(insert_block(&mut state, heavy_fork), Ok(()));
+    }
+}
@@ -0,0 +1,252 @@
+use super::{insert_block, State};
+use crate::{runtime::print, types::into_bitcoin_network, validation::ValidationContext};
+use bitcoin::consensus::Encodable;
+use ic_btc_types::Block;
+use ic_btc_validation::{validate_pow, ValidateHeaderError};
+use serde::{Deserialize, Serialize};
+
+impl State {
+    /// Evicts the orphans that have been in the orphan pool for longer than `max_orphan_age`.
+    ///
+    /// Returns the number of evicted orphans.
+    pub fn clear_orphan_pool(&mut self, now: u64) -> usize {
+        let max_orphan_age = self.max_orphan_age;
+        let num_orphans = self.orphan_pool.len();
+        self.orphan_pool
+            .retain(|orphan| now.saturating_sub(orphan.received_at) <= max_orphan_age);
+        num_orphans - self.orphan_pool.len()
+    }
+}
+
+/// A block whose parent isn't known yet.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Orphan {
+    pub block: Block,
+
+    /// The size of the block in bytes.
+    pub size: usize,
+
+    /// The time in seconds at which the block was added to the orphan pool.
+    pub received_at: u64,
+}
+
+pub(super) fn default_max_orphan_age() -> u64 {
+    // One hour.
+    3_600
+}
+
+pub(super) fn default_max_orphan_pool_size() -> usize {
+    // 16 MiB, i.e. at least four blocks of the maximum size.
+    16 * 1024 * 1024
+}
+
+/// Adds a block whose parent isn't known yet to the orphan pool, given the current time
+/// in seconds. If the pool would exceed `max_orphan_pool_size` bytes, the oldest orphans are
+/// evicted.
+///
+/// As the parent of the block is unknown, its header can't be fully validated. Only blocks
+/// with a valid proof of work for their own target, and with at least a quarter of the anchor's
+/// difficulty, are admitted, so that the pool can't be filled with blocks that are cheap to
+/// produce. On testnet, this excludes blocks mined with the minimum difficulty.
+pub fn add_orphan(state: &mut State, block: Block, now: u64) -> Result<(), AddOrphanError> {
+    let block_hash = block.block_hash();
+    if state
+        .orphan_pool
+        .iter()
+        .any(|orphan| orphan.block.block_hash() == block_hash)
+    {
+        // The block is already in the pool.
+        return Ok(());
+    }
+
+    validate_pow(&into_bitcoin_network(state.network()), block.header())
+        .map_err(AddOrphanError::InvalidHeader)?;
+    if block.difficulty(state.network()) < state.unstable_blocks.anchor_difficulty() / 4 {
+        return Err(AddOrphanError::DifficultyTooLow);
+    }
+
+    let mut size = vec![];
+    block
+        .consensus_encode(&mut size)
+        .expect("encoding a block must succeed");
+    let size = size.len();
+
+    let mut pool_size: usize = state.orphan_pool.iter().map(|orphan| orphan.size).sum();
+    while !state.orphan_pool.is_empty() && pool_size + size > state.max_orphan_pool_size {
+        pool_size -= state.orphan_pool.remove(0).size;
+    }
+    state.orphan_pool.push(Orphan {
+        block,
+        size,
+        received_at: now,
+    });
+    Ok(())
+}
+
+/// Inserts the orphans whose parent is now known, until no more orphans can be connected.
+/// Orphans that fail validation are dropped.
+///
+/// Returns the number of orphans that have been inserted.
+pub fn try_connect_orphans(state: &mut State) -> usize {
+    let mut num_connected = 0;
+    loop {
+        let mut has_connected = false;
+        for orphan in std::mem::take(&mut state.orphan_pool) {
+            if ValidationContext::new(state, orphan.block.header()).is_err() {
+                // The parent of the orphan is still unknown.
+                state.orphan_pool.push(orphan);
+                continue;
+            }
+
+            let block_hash = orphan.block.block_hash();
+            match insert_block(state, orphan.block) {
+                Ok(()) => {
+                    num_connected += 1;
+                    has_connected = true;
+                }
+                Err(err) => print(&format!(
+                    "ERROR: Failed to insert orphan block {:?}. Err: {:?}",
+                    block_hash, err
+                )),
+            }
+        }
+
+        // Inserted orphans may be the parents of other orphans, so try again.
+        if !has_connected {
+            return num_connected;
+        }
+    }
+}
+
+/// An error returned by `add_orphan`.
+#[derive(Debug, PartialEq)]
+pub enum AddOrphanError {
+    /// The header of the block doesn't have a valid proof of work for its target.
+    InvalidHeader(ValidateHeaderError),
+    /// The difficulty of the block is below a quarter of the anchor's difficulty.
+    DifficultyTooLow,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::InsertBlockError;
+    use crate::test_utils::{BlockBuilder, BlockChainBuilder};
+    use crate::unstable_blocks;
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn orphans_are_connected_once_their_parent_is_inserted() {
+        let network = Network::Regtest;
+        let blocks = BlockChainBuilder::new(4).build();
+        let mut state = State::new(0, network, blocks[0].clone());
+
+        // Insert the children before their parent.
+        for block in [&blocks[3], &blocks[2]] {
+            assert_eq!(
+                insert_block(&mut state, block.clone()),
+                Err(InsertBlockError::InvalidHeader(
+                    ValidateHeaderError::PrevHeaderNotFound
+                ))
+            );
+            add_orphan(&mut state, block.clone(), 0).unwrap();
+        }
+        assert_eq!(try_connect_orphans(&mut state), 0);
+        assert_eq!(state.orphan_pool.len(), 2);
+
+        // Once the parent is inserted, the orphans are connected.
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+        assert_eq!(try_connect_orphans(&mut state), 2);
+        assert!(state.orphan_pool.is_empty());
+        assert_eq!(
+            unstable_blocks::get_main_chain(&state.unstable_blocks).tip(),
+            &blocks[3]
+        );
+    }
+
+    #[test]
+    fn orphan_pool_is_capped() {
+        let network = Network::Regtest;
+        let blocks = BlockChainBuilder::new(6).build();
+        let mut state = State::new(0, network, blocks[0].clone());
+
+        // Cap the pool at the size of three blocks.
+        let mut block_size = vec![];
+        blocks[1].consensus_encode(&mut block_size).unwrap();
+        state.max_orphan_pool_size = 3 * block_size.len();
+
+        for block in blocks[1..].iter() {
+            assert_eq!(add_orphan(&mut state, block.clone(), 0), Ok(()));
+        }
+
+        // The oldest orphans have been evicted.
+        let orphans: Vec<_> = state.orphan_pool.iter().map(|o| o.block.clone()).collect();
+        assert_eq!(orphans, blocks[3..].to_vec());
+
+        // Adding an orphan that's already in the pool is a no-op.
+        assert_eq!(add_orphan(&mut state, blocks[3].clone(), 0), Ok(()));
+        assert_eq!(state.orphan_pool.len(), 3);
+        assert_eq!(state.orphan_pool[0].block, blocks[3]);
+    }
+
+    #[test]
+    fn orphans_without_valid_proof_of_work_are_rejected() {
+        let network = Network::Regtest;
+        let genesis = BlockBuilder::genesis().build_with_mock_difficulty(100);
+        let mut state = State::new(0, network, genesis.clone());
+        let unknown_parent = BlockBuilder::with_prev_header(genesis.header()).build();
+
+        // A block whose target is above the network's maximum target.
+        let mut bitcoin_block = BlockBuilder::with_prev_header(unknown_parent.header())
+            .build()
+            .internal_bitcoin_block()
+            .clone();
+        bitcoin_block.header.bits = 0x2100ffff;
+        assert_eq!(
+            add_orphan(&mut state, Block::new(bitcoin_block), 0),
+            Err(AddOrphanError::InvalidHeader(
+                ValidateHeaderError::TargetDifficultyAboveMax
+            ))
+        );
+
+        // A block with less than a quarter of the anchor's difficulty.
+        let block =
+            BlockBuilder::with_prev_header(unknown_parent.header()).build_with_mock_difficulty(24);
+        assert_eq!(
+            add_orphan(&mut state, block, 0),
+            Err(AddOrphanError::DifficultyTooLow)
+        );
+
+        let block =
+            BlockBuilder::with_prev_header(unknown_parent.header()).build_with_mock_difficulty(25);
+        assert_eq!(add_orphan(&mut state, block.clone(), 0), Ok(()));
+        assert_eq!(state.orphan_pool.len(), 1);
+        assert_eq!(state.orphan_pool[0].block, block);
+    }
+
+    #[test]
+    fn stale_orphans_are_evicted() {
+        let network = Network::Regtest;
+        let blocks = BlockChainBuilder::new(6).build();
+        let mut state = State::new(0, network, blocks[0].clone());
+        state.max_orphan_age = 100;
+
+        // Add orphans received at times 0, 50, 100, 150 and 200.
+        for (i, block) in blocks[1..].iter().enumerate() {
+            add_orphan(&mut state, block.clone(), i as u64 * 50).unwrap();
+        }
+
+        // Nothing is evicted while all the orphans are recent enough.
+        assert_eq!(state.clear_orphan_pool(100), 0);
+        assert_eq!(state.orphan_pool.len(), 5);
+
+        // The orphans received more than 100 seconds ago are evicted.
+        assert_eq!(state.clear_orphan_pool(220), 3);
+        let remaining: Vec<_> = state
+            .orphan_pool
+            .iter()
+            .map(|orphan| orphan.received_at)
+            .collect();
+        assert_eq!(remaining, vec![150, 200]);
+    }
+}
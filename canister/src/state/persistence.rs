@@ -0,0 +1,438 @@
+use super::State;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{types::Address, unstable_blocks::UnstableBlocks};
+#[cfg(not(target_arch = "wasm32"))]
+use ic_btc_interface::Height;
+use ic_btc_interface::Network;
+use ic_btc_types::BlockHash;
+#[cfg(not(target_arch = "wasm32"))]
+use ic_btc_types::{Block, OutPoint};
+use std::io::{Read, Write};
+
+impl State {
+    /// Serializes the state into the given writer.
+    ///
+    /// Stable structures live in stable memory and aren't part of the serialized bytes.
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), ciborium::ser::Error<std::io::Error>> {
+        ciborium::ser::into_writer(self, writer)
+    }
+
+    /// Deserializes a state from the given reader, as serialized by `State::save`.
+    ///
+    /// The loaded state is verified to be consistent with its network, and the indexes that
+    /// aren't serialized are rebuilt.
+    pub fn load<R: Read>(reader: R) -> Result<Self, LoadStateError> {
+        let mut state: Self = ciborium::de::from_reader(reader).map_err(LoadStateError::Decode)?;
+        state
+            .validate_network_consistency()
+            .map_err(LoadStateError::NetworkConsistency)?;
+        state.unstable_blocks.rebuild_tx_index();
+        Ok(state)
+    }
+
+    /// Verifies that the blocks in the state belong to the state's network.
+    ///
+    /// The genesis block is checked against the expected genesis block of `self.network()`. It's
+    /// the anchor if no blocks have been ingested yet, and the stable block header at height zero
+    /// otherwise. The latter may have been pruned, or never stored if the state was seeded from
+    /// a balances file, in which case only the networks are compared.
+    pub fn validate_network_consistency(&self) -> Result<(), NetworkConsistencyError> {
+        let network = self.network();
+        if self.unstable_blocks.get_network() != network {
+            return Err(NetworkConsistencyError::NetworkMismatch {
+                utxos: network,
+                unstable_blocks: self.unstable_blocks.get_network(),
+            });
+        }
+
+        let genesis_block_hash = if self.stable_height() == 0 {
+            Some(self.unstable_blocks.anchor().block_hash())
+        } else {
+            self.stable_block_headers
+                .get_with_height(0)
+                .map(|header| BlockHash::from(header.block_hash()))
+        };
+
+        let expected = crate::genesis_block(network).block_hash();
+        match genesis_block_hash {
+            Some(actual) if actual != expected => {
+                Err(NetworkConsistencyError::GenesisMismatch { expected, actual })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Creates a state seeded from a balances file, as produced by the `build-balances` script,
+    /// and the UTXOs the balances were computed from, rather than by ingesting all the blocks
+    /// from genesis.
+    ///
+    /// The `anchor` block is the block at the given `height`, and all blocks preceding it
+    /// are assumed to be already accounted for in the balances and the UTXOs. The UTXOs must
+    /// be given along with the balances, as blocks spending them can't be inserted otherwise.
+    ///
+    /// If the balances file has a manifest recording the height the balances were computed at,
+    /// an error is returned unless it matches the given `height`.
+    ///
+    /// NOTE: This is only suitable for tests and for bootstrapping tooling, and this isn't
+    /// compiled into the canister.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn from_balances_file<I: IntoIterator<Item = (OutPoint, crate::types::TxOut, Height)>>(
+        path: &std::path::Path,
+        stability_threshold: u32,
+        network: Network,
+        anchor: Block,
+        height: Height,
+        utxos: I,
+    ) -> std::io::Result<Self> {
+        if let Some(manifest_height) = read_manifest_height(path)? {
+            if manifest_height != height {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "the balances were computed at height {}, not {}",
+                        manifest_height, height
+                    ),
+                ));
+            }
+        }
+
+        let memory = ic_stable_structures::VectorMemory::default();
+        crate::memory::write(&memory, 0, &std::fs::read(path)?);
+        let balances: ic_stable_structures::StableBTreeMap<Address, u64, _> =
+            ic_stable_structures::StableBTreeMap::init(memory);
+
+        let mut state = Self::new(stability_threshold, network, anchor.clone());
+        state.utxos.next_height = height;
+        state.utxos.seed_balances(&balances);
+        state.utxos.seed_utxos(utxos);
+
+        // Recreate the unstable blocks so that the anchor is placed at the given height.
+        state.unstable_blocks =
+            UnstableBlocks::new(&state.utxos, stability_threshold, anchor, network);
+
+        Ok(state)
+    }
+
+    /// Returns the size in bytes of the state once serialized for an upgrade.
+    ///
+    /// Only the heap-allocated parts of the state are serialized, namely the large UTXOs,
+    /// the unstable blocks and the various configs and metrics. The UTXOs, balances and
+    /// block headers held in stable structures are already in stable memory and don't
+    /// contribute to the size.
+    ///
+    /// The bytes are counted as they're serialized, so no buffer for the state is allocated.
+    /// The state is still serialized in full, which takes instructions proportional to its
+    /// size, so this is only available outside of the canister (e.g. for tooling and tests).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn serialized_size(&self) -> usize {
+        struct ByteCounter(usize);
+
+        impl std::io::Write for ByteCounter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut counter = ByteCounter(0);
+        ciborium::ser::into_writer(self, &mut counter).expect("failed to encode state");
+        counter.0
+    }
+}
+
+// Returns the height recorded in the manifest that `build-balances` writes next to the given
+// balances file, or `None` if there's no manifest or it doesn't record a height.
+#[cfg(not(target_arch = "wasm32"))]
+fn read_manifest_height(balances_path: &std::path::Path) -> std::io::Result<Option<Height>> {
+    let mut manifest_path = balances_path.as_os_str().to_owned();
+    manifest_path.push(".manifest");
+    let manifest = match std::fs::read_to_string(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    for line in manifest.lines() {
+        if let Some(height) = line.strip_prefix("at_height=") {
+            return height.parse().map(Some).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid height in the balances manifest: {}", height),
+                )
+            });
+        }
+    }
+
+    Ok(None)
+}
+
+/// An error returned by `State::load`.
+#[derive(Debug)]
+pub enum LoadStateError {
+    /// The bytes couldn't be decoded into a state.
+    Decode(ciborium::de::Error<std::io::Error>),
+    /// The decoded state is inconsistent with its network.
+    NetworkConsistency(NetworkConsistencyError),
+}
+
+/// An error returned when the state is inconsistent with its network.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NetworkConsistencyError {
+    /// The unstable blocks belong to a different network than the UTXO set.
+    NetworkMismatch {
+        utxos: Network,
+        unstable_blocks: Network,
+    },
+    /// The genesis block isn't the genesis block of the state's network.
+    GenesisMismatch {
+        expected: BlockHash,
+        actual: BlockHash,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{drain_ingestion, ingest_stable_blocks_into_utxoset, insert_block};
+    use crate::test_utils::{build_chain, random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use crate::unstable_blocks;
+    use ic_stable_structures::{StableBTreeMap, VectorMemory};
+    use proptest::prelude::*;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(10))]
+        #[test]
+        fn serialize_deserialize_state(
+            stability_threshold in 1..150u32,
+            num_blocks in 1..250u32,
+            num_transactions_in_block in 1..100u32,
+        ) {
+            let network = Network::Regtest;
+            let blocks = build_chain(network, num_blocks, num_transactions_in_block);
+
+            let mut state = State::new(stability_threshold, network, blocks[0].clone());
+
+            for block in blocks[1..].iter() {
+                insert_block(&mut state, block.clone()).unwrap();
+                ingest_stable_blocks_into_utxoset(&mut state);
+            }
+
+            let mut bytes = vec![];
+            state.save(&mut bytes).unwrap();
+            let new_state = State::load(&bytes[..]).unwrap();
+
+            // Verify the new state is the same as the old state.
+            assert!(state == new_state);
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let network = Network::Regtest;
+        let genesis_block = crate::genesis_block(network);
+        let mut state = State::new(2, network, genesis_block.clone());
+        let mut prev_header = *genesis_block.header();
+        for _ in 0..4 {
+            let block = BlockBuilder::with_prev_header(&prev_header).build();
+            prev_header = *block.header();
+            insert_block(&mut state, block).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        let mut bytes = vec![];
+        state.save(&mut bytes).unwrap();
+        assert!(State::load(&bytes[..]).unwrap() == state);
+
+        // Truncated bytes can't be decoded.
+        assert!(matches!(
+            State::load(&bytes[..bytes.len() / 2]),
+            Err(LoadStateError::Decode(_))
+        ));
+
+        // A state whose genesis block belongs to a different network is rejected.
+        let state = State::new(1, Network::Mainnet, crate::genesis_block(Network::Regtest));
+        let mut bytes = vec![];
+        state.save(&mut bytes).unwrap();
+        assert!(matches!(
+            State::load(&bytes[..]),
+            Err(LoadStateError::NetworkConsistency(
+                NetworkConsistencyError::GenesisMismatch { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn validate_network_consistency() {
+        let state = State::new(1, Network::Regtest, crate::genesis_block(Network::Regtest));
+        assert_eq!(state.validate_network_consistency(), Ok(()));
+
+        // A state whose genesis block belongs to a different network.
+        let state = State::new(1, Network::Mainnet, crate::genesis_block(Network::Regtest));
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&state, &mut bytes).unwrap();
+        let state: State = ciborium::de::from_reader(&bytes[..]).unwrap();
+
+        assert_eq!(
+            state.validate_network_consistency(),
+            Err(NetworkConsistencyError::GenesisMismatch {
+                expected: crate::genesis_block(Network::Mainnet).block_hash(),
+                actual: crate::genesis_block(Network::Regtest).block_hash(),
+            })
+        );
+    }
+
+    #[test]
+    fn validate_network_consistency_after_ingesting_genesis() {
+        let network = Network::Regtest;
+        let genesis_block = crate::genesis_block(network);
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+
+        let mut state = State::new(0, network, genesis_block);
+        insert_block(&mut state, block_1).unwrap();
+        drain_ingestion(&mut state);
+
+        // The genesis block is now verified through the stable block headers.
+        assert_eq!(state.stable_height(), 1);
+        assert_eq!(state.validate_network_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn serialized_size_matches_serialized_state() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 20, 5);
+
+        let mut state = State::new(5, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+        }
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&state, &mut bytes).unwrap();
+
+        assert_eq!(state.serialized_size(), bytes.len());
+    }
+
+    #[test]
+    fn from_balances_file() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+
+        // Build a balances file similar to the one produced by `build-balances`.
+        let memory = VectorMemory::default();
+        let mut balances: StableBTreeMap<Address, u64, _> = StableBTreeMap::init(memory.clone());
+        balances.insert(address_1.clone(), 1000);
+        balances.insert(address_2.clone(), 2000);
+
+        let balances_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(balances_file.path(), &*memory.borrow()).unwrap();
+
+        // The UTXOs the balances were computed from, created before the anchor.
+        let seeded_tx = TransactionBuilder::coinbase()
+            .with_output(&address_1, 1000)
+            .with_output(&address_2, 2000)
+            .build();
+        let seeded_utxos: Vec<_> = seeded_tx
+            .output()
+            .iter()
+            .enumerate()
+            .map(|(vout, tx_out)| {
+                (
+                    OutPoint::new(seeded_tx.txid(), vout as u32),
+                    crate::types::TxOut::from(tx_out),
+                    50,
+                )
+            })
+            .collect();
+
+        let anchor = BlockBuilder::genesis().build();
+        let mut state = State::from_balances_file(
+            balances_file.path(),
+            1,
+            network,
+            anchor.clone(),
+            100,
+            seeded_utxos,
+        )
+        .unwrap();
+
+        assert_eq!(state.stable_height(), 100);
+        assert_eq!(state.utxos.balances_len(), 2);
+        assert_eq!(state.utxos.get_balance(&address_1), 1000);
+        assert_eq!(state.utxos.get_balance(&address_2), 2000);
+        assert_eq!(state.utxos.utxos_len(), 2);
+        assert_eq!(
+            state.get_utxos(address_1.clone()).into_iter(None).count(),
+            1
+        );
+        assert_eq!(
+            unstable_blocks::get_main_chain(&state.unstable_blocks).tip(),
+            &anchor
+        );
+
+        // A block spending a seeded UTXO can be inserted on top of the anchor.
+        let block = BlockBuilder::with_prev_header(anchor.header())
+            .with_transaction(
+                TransactionBuilder::spending(OutPoint::new(seeded_tx.txid(), 0), &address_2, 900)
+                    .build(),
+            )
+            .build();
+        insert_block(&mut state, block).unwrap();
+
+        assert_eq!(state.get_utxos(address_1).into_iter(None).count(), 0);
+        assert_eq!(state.get_utxos(address_2).into_iter(None).count(), 2);
+    }
+
+    #[test]
+    fn from_balances_file_checks_manifest_height() {
+        let network = Network::Regtest;
+        let dir = tempfile::tempdir().unwrap();
+        let balances_path = dir.path().join("balances.bin");
+        let manifest_path = dir.path().join("balances.bin.manifest");
+
+        let memory = VectorMemory::default();
+        let mut balances: StableBTreeMap<Address, u64, _> = StableBTreeMap::init(memory.clone());
+        balances.insert(random_p2pkh_address(network), 1000);
+        std::fs::write(&balances_path, &*memory.borrow()).unwrap();
+
+        let from_balances_file = |height| {
+            State::from_balances_file(
+                &balances_path,
+                1,
+                network,
+                BlockBuilder::genesis().build(),
+                height,
+                vec![],
+            )
+        };
+
+        // Without a manifest, or without a height in it, any height is accepted.
+        assert!(from_balances_file(100).is_ok());
+        std::fs::write(&manifest_path, "network=regtest\nnum_balances=1\n").unwrap();
+        assert!(from_balances_file(100).is_ok());
+
+        // Otherwise, the height must match the one the balances were computed at.
+        std::fs::write(
+            &manifest_path,
+            "network=regtest\nat_height=100\nnum_balances=1\n",
+        )
+        .unwrap();
+        assert_eq!(from_balances_file(100).unwrap().stable_height(), 100);
+        assert_eq!(
+            from_balances_file(99).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        std::fs::write(&manifest_path, "at_height=abc\n").unwrap();
+        assert_eq!(
+            from_balances_file(100).err().unwrap().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+}
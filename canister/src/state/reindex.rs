@@ -0,0 +1,286 @@
+use super::State;
+use crate::{
+    block_header_store::HeadersPruned,
+    types::Slicing,
+    unstable_blocks::{self, UnstableBlocks},
+};
+use ic_btc_interface::Height;
+use ic_btc_types::{Block, BlockHash, OutPoint};
+
+impl State {
+    /// Rebuilds the UTXO set by re-ingesting the stable blocks that it's missing, e.g. after
+    /// restoring it from a checkpoint because it was suspected to be corrupt.
+    ///
+    /// The UTXO set's next height is the checkpoint height. The blocks from there up to the
+    /// anchor's parent are identified using the stored headers, and their bodies are re-fetched
+    /// using `fetch_block`. Afterwards, the unstable blocks are rebuilt on top of the new UTXO
+    /// set. Returns the new stable height.
+    ///
+    /// NOTE: All the blocks are ingested in a single call without time slicing, so this is only
+    /// suitable for tooling running outside of a canister.
+    pub fn reindex_from_headers<F: FnMut(&BlockHash) -> Option<Block>>(
+        &mut self,
+        mut fetch_block: F,
+    ) -> Result<Height, ReindexError> {
+        if self.utxos.ingesting_block.is_some() {
+            return Err(ReindexError::IngestionInProgress);
+        }
+
+        // The stored headers must lead up to the anchor.
+        let anchor_parent_hash =
+            BlockHash::from(self.unstable_blocks.anchor().header().prev_blockhash);
+        let (last_height, _) = self
+            .stable_block_headers
+            .get_by_hash(&anchor_parent_hash)
+            .ok_or(ReindexError::AnchorMismatch)?;
+
+        for height in self.stable_height()..=last_height {
+            let block_hash = self
+                .stable_block_headers
+                .get_block_hash_with_height(height)
+                .ok_or(ReindexError::MissingHeader(height))?;
+            let block = fetch_block(&block_hash)
+                .ok_or_else(|| ReindexError::MissingBlock(block_hash.clone()))?;
+            if block.block_hash() != block_hash
+                || !block.internal_bitcoin_block().check_merkle_root()
+            {
+                return Err(ReindexError::BlockMismatch(height));
+            }
+
+            let mut result = self.utxos.ingest_block(block);
+            while let Slicing::Paused(()) = result {
+                result = self
+                    .utxos
+                    .ingest_block_continue()
+                    .expect("a block must be being ingested");
+            }
+        }
+
+        // Rebuild the unstable blocks, as their cache of outpoints depends on the UTXO set.
+        let blocks: Vec<Block> = unstable_blocks::get_blocks(&self.unstable_blocks)
+            .into_iter()
+            .cloned()
+            .collect();
+        let mut rebuilt = UnstableBlocks::new(
+            &self.utxos,
+            self.unstable_blocks.stability_threshold(),
+            blocks[0].clone(),
+            self.network(),
+        );
+        for block in blocks.into_iter().skip(1) {
+            unstable_blocks::push(&mut rebuilt, &self.utxos, block)
+                .expect("re-inserting an unstable block must succeed");
+        }
+        self.unstable_blocks = rebuilt;
+
+        Ok(self.stable_height())
+    }
+
+    /// Audits that no output spent by a stable block lingers in the stable UTXO set.
+    ///
+    /// The spends of stable blocks aren't kept once they're ingested, so the stable blocks are
+    /// identified using the stored headers and their bodies are re-fetched using `fetch_block`.
+    /// All the lingering outputs are reported, along with the height of the block spending them.
+    ///
+    /// NOTE: The entire stable chain is scanned in a single call, so this is only suitable for
+    /// tooling running outside of a canister.
+    pub fn verify_no_spent_utxos<F: FnMut(&BlockHash) -> Option<Block>>(
+        &self,
+        mut fetch_block: F,
+    ) -> Result<(), AuditError> {
+        if self.utxos.ingesting_block.is_some() {
+            return Err(AuditError::IngestionInProgress);
+        }
+
+        if self.utxos.next_height() > 0 {
+            self.stable_block_headers
+                .check_retained(&(0..=self.utxos.next_height() - 1))
+                .map_err(|HeadersPruned| AuditError::HeadersPruned)?;
+        }
+
+        let mut spent_utxos = vec![];
+        for height in 0..self.utxos.next_height() {
+            let block_hash = self
+                .stable_block_headers
+                .get_block_hash_with_height(height)
+                .ok_or(AuditError::MissingHeader(height))?;
+            let block = fetch_block(&block_hash)
+                .ok_or_else(|| AuditError::MissingBlock(block_hash.clone()))?;
+            if block.block_hash() != block_hash
+                || !block.internal_bitcoin_block().check_merkle_root()
+            {
+                return Err(AuditError::BlockMismatch(height));
+            }
+
+            for tx in block.txdata().iter().filter(|tx| !tx.is_coin_base()) {
+                for input in tx.input() {
+                    let outpoint = OutPoint::from(&input.previous_output);
+                    if self.utxos.get_utxo(&outpoint).is_some() {
+                        spent_utxos.push((outpoint, height));
+                    }
+                }
+            }
+        }
+
+        if spent_utxos.is_empty() {
+            Ok(())
+        } else {
+            Err(AuditError::SpentUtxos(spent_utxos))
+        }
+    }
+}
+
+/// An error returned by `State::reindex_from_headers`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReindexError {
+    /// A block is partially ingested into the UTXO set.
+    IngestionInProgress,
+    /// The parent of the anchor isn't among the stored headers.
+    AnchorMismatch,
+    /// There's no stored header at the given height.
+    MissingHeader(Height),
+    /// The block with the given hash couldn't be fetched.
+    MissingBlock(BlockHash),
+    /// The fetched block at the given height doesn't match the stored header.
+    BlockMismatch(Height),
+}
+
+/// An error returned by `State::verify_no_spent_utxos`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuditError {
+    /// A block is partially ingested into the UTXO set.
+    IngestionInProgress,
+    /// Some of the stable headers have been pruned by a retention policy, so the stable blocks
+    /// can't be identified.
+    HeadersPruned,
+    /// There's no stored header at the given height.
+    MissingHeader(Height),
+    /// The block with the given hash couldn't be fetched.
+    MissingBlock(BlockHash),
+    /// The fetched block at the given height doesn't match the stored header.
+    BlockMismatch(Height),
+    /// Outputs that are still in the UTXO set despite being spent, along with the height of
+    /// the block spending them.
+    SpentUtxos(Vec<(OutPoint, Height)>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{ingest_stable_blocks_into_utxoset, insert_block, main_chain_height};
+    use crate::test_utils::{build_chain, random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn verify_no_spent_utxos() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+
+        // A chain where block 1 spends an output of the genesis block.
+        let genesis_tx = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .with_output(&address, 2000)
+            .build();
+        let mut blocks = vec![BlockBuilder::genesis()
+            .with_transaction(genesis_tx.clone())
+            .build()];
+        blocks.push(
+            BlockBuilder::with_prev_header(blocks[0].header())
+                .with_transaction(
+                    TransactionBuilder::spending(
+                        OutPoint::new(genesis_tx.txid(), 0),
+                        &address,
+                        500,
+                    )
+                    .build(),
+                )
+                .build(),
+        );
+        for _ in 0..3 {
+            let block = BlockBuilder::with_prev_header(blocks.last().unwrap().header()).build();
+            blocks.push(block);
+        }
+
+        let mut state = State::new(2, network, blocks[0].clone());
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        ingest_stable_blocks_into_utxoset(&mut state);
+        assert_eq!(state.stable_height(), 3);
+
+        let fetch_block = |block_hash: &BlockHash| {
+            blocks
+                .iter()
+                .find(|block| block.block_hash() == *block_hash)
+                .cloned()
+        };
+        assert_eq!(state.verify_no_spent_utxos(fetch_block), Ok(()));
+
+        // Blocks that can't be fetched are reported.
+        assert_eq!(
+            state.verify_no_spent_utxos(|_| None),
+            Err(AuditError::MissingBlock(blocks[0].block_hash()))
+        );
+    }
+
+    #[test]
+    fn reindex_from_headers() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 6, 2);
+
+        // A state whose UTXO set has been truncated to the genesis block, while the headers up
+        // to the anchor (block 3) are intact.
+        let mut state = State::new(2, network, blocks[0].clone());
+        assert!(matches!(
+            state.utxos.ingest_block(blocks[0].clone()),
+            Slicing::Done(_)
+        ));
+        for (height, block) in blocks[..3].iter().enumerate() {
+            state
+                .stable_block_headers
+                .insert_block(block, height as Height);
+        }
+        state.unstable_blocks = UnstableBlocks::new(&state.utxos, 2, blocks[3].clone(), network);
+        for block in blocks[4..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        assert_eq!(state.stable_height(), 1);
+
+        // Blocks that can't be fetched are reported.
+        assert_eq!(
+            state.reindex_from_headers(|_| None),
+            Err(ReindexError::MissingBlock(blocks[1].block_hash()))
+        );
+
+        let mut fetched = vec![];
+        assert_eq!(
+            state.reindex_from_headers(|block_hash| {
+                fetched.push(block_hash.clone());
+                blocks
+                    .iter()
+                    .find(|block| block.block_hash() == *block_hash)
+                    .cloned()
+            }),
+            Ok(3)
+        );
+        assert_eq!(
+            fetched,
+            vec![blocks[1].block_hash(), blocks[2].block_hash()]
+        );
+
+        // The UTXO set now contains the outputs of the first three blocks, and the main chain
+        // reaches the same tip.
+        let num_outputs: usize = blocks[..3]
+            .iter()
+            .flat_map(|block| block.txdata())
+            .map(|tx| tx.output().len())
+            .sum();
+        assert_eq!(state.utxos.utxos_len(), num_outputs as u64);
+        assert_eq!(
+            state.utxo_set_hash(),
+            Some(state.utxos.compute_utxo_set_hash())
+        );
+        assert_eq!(main_chain_height(&state), 5);
+        assert_eq!(state.hash_at_height(5), Some(blocks[5].block_hash()));
+    }
+}
@@ -0,0 +1,317 @@
+use super::State;
+use crate::unstable_blocks;
+use ic_btc_interface::Height;
+use ic_btc_types::BlockHash;
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+};
+
+/// A change of the main chain's tip.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TipChanged {
+    /// The hash of the new tip.
+    pub tip: BlockHash,
+
+    /// The height of the new tip.
+    pub height: Height,
+}
+
+thread_local! {
+    // The observers registered with `subscribe_to_tip_changes`, by the key of the state they
+    // were registered for. Observers are closures, so they're kept outside of the `State`,
+    // which is serialized and compared in tests.
+    static TIP_OBSERVERS: RefCell<BTreeMap<u64, TipObservers>> = RefCell::new(BTreeMap::new());
+
+    // The key of the next state to be created.
+    static NEXT_TIP_OBSERVERS_KEY: Cell<u64> = Cell::new(0);
+}
+
+/// Registers an observer that is called whenever the main chain's tip of the given state
+/// changes, be it by extending the main chain or by a reorg.
+///
+/// Observers are called while the state is being modified, so they must neither access the
+/// state nor register other observers. They're dropped along with the state, and aren't
+/// persisted across upgrades.
+pub fn subscribe_to_tip_changes(state: &State, observer: impl FnMut(&TipChanged) + 'static) {
+    TIP_OBSERVERS.with(|tip_observers| {
+        let mut tip_observers = tip_observers.borrow_mut();
+        let tip_observers = tip_observers.entry(state.tip_observers_key.0).or_default();
+        tip_observers.tip = Some(
+            unstable_blocks::get_main_chain(&state.unstable_blocks)
+                .tip()
+                .block_hash(),
+        );
+        tip_observers.max_height = max_unstable_height(state);
+        tip_observers.observers.push(Box::new(observer));
+    });
+}
+
+impl State {
+    // Notifies the tip observers if inserting the given block at the given height changed the
+    // main chain's tip.
+    //
+    // Blocks below the highest unstable block don't change which chains are the longest, so
+    // they can't change the tip, and a block above it is the new tip. The main chain is only
+    // computed for a block at the same height as the highest one, which ties the longest chains.
+    pub(super) fn notify_tip_observers_of_block(&self, block_hash: &BlockHash, height: Height) {
+        self.tip_observers_key.with_observers(|tip_observers| {
+            if height < tip_observers.max_height {
+                return;
+            }
+
+            if height == tip_observers.max_height {
+                tip_observers.notify_if_tip_changed(self);
+                return;
+            }
+
+            tip_observers.max_height = height;
+            tip_observers.tip = Some(block_hash.clone());
+            tip_observers.notify(&TipChanged {
+                tip: block_hash.clone(),
+                height,
+            });
+        });
+    }
+
+    // Notifies the tip observers if the main chain's tip has changed since they were last
+    // notified. The tip is only computed if there are observers.
+    pub(super) fn notify_tip_observers(&self) {
+        self.tip_observers_key
+            .with_observers(|tip_observers| tip_observers.notify_if_tip_changed(self));
+    }
+}
+
+// Returns the height of the highest unstable block.
+fn max_unstable_height(state: &State) -> Height {
+    state.stable_height() + state.unstable_blocks.blocks_depth() as u32 - 1
+}
+
+// A key that ties the observers in `TIP_OBSERVERS` to a state. Every state gets a key of its
+// own, and the observers registered with it are removed when the state is dropped.
+//
+// The key is neither serialized nor taken into account when comparing states.
+pub(super) struct TipObserversKey(u64);
+
+impl TipObserversKey {
+    // Calls `f` with the observers of the state, if it has any.
+    fn with_observers(&self, f: impl FnOnce(&mut TipObservers)) {
+        TIP_OBSERVERS.with(|tip_observers| {
+            if let Some(tip_observers) = tip_observers.borrow_mut().get_mut(&self.0) {
+                f(tip_observers);
+            }
+        });
+    }
+}
+
+impl Default for TipObserversKey {
+    fn default() -> Self {
+        Self(NEXT_TIP_OBSERVERS_KEY.with(|next_key| next_key.replace(next_key.get() + 1)))
+    }
+}
+
+impl Drop for TipObserversKey {
+    fn drop(&mut self) {
+        // The registry may already be destroyed if the state is dropped when the thread exits.
+        let _ = TIP_OBSERVERS.try_with(|tip_observers| tip_observers.borrow_mut().remove(&self.0));
+    }
+}
+
+impl PartialEq for TipObserversKey {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+// The observers registered with `subscribe_to_tip_changes`, along with the tip they were
+// last notified of.
+#[derive(Default)]
+struct TipObservers {
+    observers: Vec<Box<dyn FnMut(&TipChanged)>>,
+
+    // The tip the observers were last notified of, or the tip at the time the last observer
+    // was registered.
+    tip: Option<BlockHash>,
+
+    // The height of the highest unstable block as of the last notification. It's updated
+    // whenever the tip is recomputed, as popping stable blocks can discard the highest blocks.
+    max_height: Height,
+}
+
+impl TipObservers {
+    fn notify(&mut self, event: &TipChanged) {
+        for observer in self.observers.iter_mut() {
+            observer(event);
+        }
+    }
+
+    // Notifies the observers if the main chain's tip of the given state has changed since they
+    // were last notified.
+    fn notify_if_tip_changed(&mut self, state: &State) {
+        if self.observers.is_empty() {
+            return;
+        }
+
+        self.max_height = max_unstable_height(state);
+        let main_chain = unstable_blocks::get_main_chain(&state.unstable_blocks);
+        let tip = main_chain.tip().block_hash();
+        if self.tip.as_ref() == Some(&tip) {
+            return;
+        }
+
+        let event = TipChanged {
+            height: state.stable_height() + main_chain.len() as u32 - 1,
+            tip: tip.clone(),
+        };
+        self.tip = Some(tip);
+        self.notify(&event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{drain_ingestion, insert_block};
+    use crate::test_utils::{BlockBuilder, BlockChainBuilder};
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn tip_observers_are_notified_of_tip_changes() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork = BlockChainBuilder::fork(&genesis_block, 3).build();
+        let mut state = State::new(10, network, genesis_block);
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let observed_events = events.clone();
+        subscribe_to_tip_changes(&state, move |event| {
+            observed_events.borrow_mut().push(event.clone())
+        });
+
+        // Blocks extending the main chain advance the tip.
+        insert_block(&mut state, block_1.clone()).unwrap();
+        insert_block(&mut state, block_2.clone()).unwrap();
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                TipChanged {
+                    tip: block_1.block_hash(),
+                    height: 1
+                },
+                TipChanged {
+                    tip: block_2.block_hash(),
+                    height: 2
+                },
+            ]
+        );
+
+        // A block extending a shorter fork leaves the tip unchanged.
+        insert_block(&mut state, fork[0].clone()).unwrap();
+        assert_eq!(events.borrow().len(), 2);
+
+        // The fork overtaking the main chain is a reorg.
+        for block in fork[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        assert_eq!(
+            events.borrow().last(),
+            Some(&TipChanged {
+                tip: fork[2].block_hash(),
+                height: 3
+            })
+        );
+
+        // Reconsidering the main chain doesn't notify the observers of the same tip again.
+        let num_events = events.borrow().len();
+        assert!(state.reconsider_best_chain(0).is_none());
+        assert_eq!(events.borrow().len(), num_events);
+    }
+
+    #[test]
+    fn tip_observers_are_tied_to_their_state() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let mut state = State::new(10, network, genesis_block.clone());
+        let mut other_state = State::new(10, network, genesis_block);
+
+        let num_events = Rc::new(RefCell::new(0));
+        let observed_num_events = num_events.clone();
+        subscribe_to_tip_changes(&state, move |_| *observed_num_events.borrow_mut() += 1);
+
+        // Changing the tip of another state doesn't notify the observers.
+        insert_block(&mut other_state, block_1.clone()).unwrap();
+        assert_eq!(*num_events.borrow(), 0);
+
+        insert_block(&mut state, block_1).unwrap();
+        assert_eq!(*num_events.borrow(), 1);
+
+        // The observers are dropped along with their state.
+        let key = state.tip_observers_key.0;
+        drop(state);
+        assert!(!TIP_OBSERVERS.with(|tip_observers| tip_observers.borrow().contains_key(&key)));
+    }
+
+    #[test]
+    fn tip_observers_are_notified_of_reorgs_onto_shorter_forks() {
+        use std::{cell::RefCell, rc::Rc};
+
+        let network = Network::Regtest;
+        let genesis_block = BlockBuilder::genesis().build_with_mock_difficulty(1);
+        let mut chain = vec![genesis_block.clone()];
+        for _ in 0..3 {
+            let block = BlockBuilder::with_prev_header(chain.last().unwrap().header())
+                .build_with_mock_difficulty(1);
+            chain.push(block);
+        }
+        let heavy_block =
+            BlockBuilder::with_prev_header(genesis_block.header()).build_with_mock_difficulty(100);
+        let mut state = State::new(2, network, genesis_block);
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let observed_events = events.clone();
+        subscribe_to_tip_changes(&state, move |event| {
+            observed_events.borrow_mut().push(event.clone())
+        });
+
+        for block in chain[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+        }
+        insert_block(&mut state, heavy_block.clone()).unwrap();
+        assert_eq!(
+            events.borrow().last(),
+            Some(&TipChanged {
+                tip: chain[3].block_hash(),
+                height: 3
+            })
+        );
+
+        // The heavy block is stable, so popping the anchor discards the longer chain and the
+        // main chain switches to the shorter fork.
+        drain_ingestion(&mut state);
+        assert_eq!(
+            events.borrow().last(),
+            Some(&TipChanged {
+                tip: heavy_block.block_hash(),
+                height: 1
+            })
+        );
+
+        // Blocks extending the new main chain below the discarded chain's height advance the tip.
+        let block = BlockBuilder::with_prev_header(heavy_block.header()).build();
+        insert_block(&mut state, block.clone()).unwrap();
+        assert_eq!(
+            events.borrow().last(),
+            Some(&TipChanged {
+                tip: block.block_hash(),
+                height: 2
+            })
+        );
+    }
+}
@@ -3,8 +3,8 @@ use crate::{
     types::{into_bitcoin_network, Address},
 };
 use bitcoin::{
-    hashes::Hash, secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, Address as BitcoinAddress,
-    BlockHeader, PublicKey, Script, WScriptHash, Witness,
+    hashes::Hash, secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, util::uint::Uint256,
+    Address as BitcoinAddress, BlockHeader, PublicKey, Script, WScriptHash, Witness,
 };
 use ic_btc_interface::Network;
 use ic_btc_test_utils::{
@@ -56,6 +56,16 @@ pub fn random_p2wsh_address(network: Network) -> Address {
     .into()
 }
 
+/// Returns a random address of each supported type, in the order p2pkh, p2tr, p2wpkh, p2wsh.
+pub fn random_address_of_each_type(network: Network) -> Vec<Address> {
+    vec![
+        random_p2pkh_address(network),
+        random_p2tr_address(network),
+        random_p2wpkh_address(network),
+        random_p2wsh_address(network),
+    ]
+}
+
 /// Builds a random chain with the given number of block and transactions.
 /// The genesis block used in the chain is also random.
 pub fn build_chain(
@@ -68,23 +78,36 @@ pub fn build_chain(
         BlockBuilder::genesis().build(),
         num_blocks,
         num_transactions_per_block,
+        None,
     )
 }
 
+/// Builds a chain on top of the given genesis block.
+///
+/// If a difficulty schedule is given, the i-th block after the genesis block has a
+/// difficulty of `difficulty_schedule[i]` times the genesis block's difficulty. Blocks beyond
+/// the end of the schedule keep the difficulty of their previous block.
 fn build_chain_with_genesis_block(
     network: Network,
     genesis_block: Block,
     num_blocks: u32,
     num_transactions_per_block: u32,
+    difficulty_schedule: Option<&[u64]>,
 ) -> Vec<Block> {
+    let genesis_target = genesis_block.header().target();
     let address = random_p2pkh_address(network);
     let mut blocks = vec![genesis_block.clone()];
     let mut prev_block: Block = genesis_block;
     let mut value = 1;
 
     // Since we start with a genesis block, we need `num_blocks - 1` additional blocks.
-    for _ in 0..num_blocks - 1 {
+    for i in 0..num_blocks as usize - 1 {
         let mut block_builder = BlockBuilder::with_prev_header(prev_block.header());
+        if let Some(difficulty) = difficulty_schedule.and_then(|schedule| schedule.get(i)) {
+            block_builder =
+                block_builder.with_target(genesis_target / Uint256::from_u64(*difficulty).unwrap());
+        }
+
         let mut transactions = vec![];
         for _ in 0..num_transactions_per_block {
             transactions.push(
@@ -110,6 +133,9 @@ fn build_chain_with_genesis_block(
 }
 
 /// Returns true if the instances of `StableBTreeMap` provided are equal.
+///
+/// The maps are compared by looking up each entry of `a` in `b`, so the result doesn't depend
+/// on the order in which the entries were inserted or are iterated.
 pub fn is_stable_btreemap_equal<
     M: Memory,
     K: BoundedStorable + Ord + Eq + Clone,
@@ -122,13 +148,9 @@ pub fn is_stable_btreemap_equal<
         return false;
     }
 
-    for (x, y) in a.iter().zip(b.iter()) {
-        if x != y {
-            return false;
-        }
-    }
-
-    true
+    // Since both maps have the same number of entries, finding all the entries of `a` in `b`
+    // means that the maps are equal.
+    a.iter().all(|(key, value)| b.get(&key) == Some(value))
 }
 
 /// A wrapper around `ic_btc_test_utils::BlockBuilder` that returns `crate::types::Block`
@@ -167,6 +189,23 @@ impl BlockBuilder {
         }
     }
 
+    /// Sets the target of the block's header. Unlike `with_difficulty`, this changes the
+    /// block's actual `bits`, and the block is mined to satisfy the target.
+    pub fn with_target(self, target: Uint256) -> Self {
+        Self {
+            builder: self.builder.with_target(target),
+            ..self
+        }
+    }
+
+    /// Sets the timestamp of the block's header.
+    pub fn with_time(self, time: u32) -> Self {
+        Self {
+            builder: self.builder.with_time(time),
+            ..self
+        }
+    }
+
     pub fn build(self) -> Block {
         let mut block = Block::new(self.builder.build());
         block.mock_difficulty = self.mock_difficulty;
@@ -199,6 +238,12 @@ impl TransactionBuilder {
         }
     }
 
+    /// Returns a builder of a non-coinbase transaction spending the given outpoint to a single
+    /// output of the given value.
+    pub fn spending(prev_out: OutPoint, to: &Address, value: u64) -> Self {
+        Self::new().with_input(prev_out).with_output(to, value)
+    }
+
     pub fn with_input(self, previous_output: OutPoint) -> Self {
         Self {
             builder: self.builder.with_input(previous_output.into(), None),
@@ -219,6 +264,19 @@ impl TransactionBuilder {
         }
     }
 
+    pub fn with_version(self, version: i32) -> Self {
+        Self {
+            builder: self.builder.with_version(version),
+        }
+    }
+
+    /// Sets the sequence number of the most recently added input.
+    pub fn with_input_sequence(self, sequence: u32) -> Self {
+        Self {
+            builder: self.builder.with_input_sequence(sequence),
+        }
+    }
+
     pub fn with_output(self, address: &Address, value: u64) -> Self {
         Self {
             builder: self.builder.with_output(
@@ -266,6 +324,14 @@ impl BlockChainBuilder {
         self
     }
 
+    /// Sets the difficulty of all the blocks in the chain.
+    ///
+    /// Difficulties set on specific ranges with `with_difficulty` before calling this
+    /// method take precedence.
+    pub fn with_mock_difficulty(self, difficulty: u64) -> Self {
+        self.with_difficulty(difficulty, ..)
+    }
+
     pub fn build(self) -> Vec<Block> {
         let mut blocks = Vec::with_capacity(self.num_blocks as usize);
 
@@ -311,3 +377,85 @@ fn target_difficulty() {
         1
     );
 }
+
+#[test]
+fn build_chain_with_difficulty_schedule() {
+    let network = Network::Regtest;
+    let schedule = [1, 2, 4, 8];
+    let blocks = build_chain_with_genesis_block(
+        network,
+        BlockBuilder::genesis().build(),
+        6,
+        1,
+        Some(&schedule),
+    );
+
+    let difficulties: Vec<_> = blocks
+        .iter()
+        .map(|block| Block::target_difficulty(network, block.header().target()))
+        .collect();
+
+    // The last block is beyond the schedule and keeps the difficulty of its previous block.
+    assert_eq!(difficulties, vec![1, 1, 2, 4, 8, 8]);
+
+    // The bits of the blocks are updated to reflect the increasing difficulty.
+    for window in blocks[1..5].windows(2) {
+        assert!(window[0].header().bits > window[1].header().bits);
+    }
+}
+
+#[test]
+fn stable_btreemaps_are_equal_regardless_of_insertion_order() {
+    use ic_stable_structures::VectorMemory;
+
+    let mut a = StableBTreeMap::init(VectorMemory::default());
+    let mut b = StableBTreeMap::init(VectorMemory::default());
+    for i in 0..100u32 {
+        a.insert(i, i * 2);
+        b.insert(99 - i, (99 - i) * 2);
+    }
+    assert!(is_stable_btreemap_equal(&a, &b));
+
+    // Maps with a different value, or a different key, aren't equal.
+    b.insert(50, 0);
+    assert!(!is_stable_btreemap_equal(&a, &b));
+    b.remove(&50);
+    b.insert(100, 100);
+    assert!(!is_stable_btreemap_equal(&a, &b));
+}
+
+#[test]
+fn random_address_of_each_type_returns_distinct_valid_addresses() {
+    for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+        let addresses = random_address_of_each_type(network);
+        assert_eq!(addresses.len(), 4);
+
+        for address in addresses.iter() {
+            assert_eq!(&Address::from_str(&address.to_string()).unwrap(), address);
+        }
+
+        let distinct: std::collections::BTreeSet<_> = addresses.iter().collect();
+        assert_eq!(distinct.len(), addresses.len());
+    }
+}
+
+#[test]
+fn spending_transaction() {
+    let network = Network::Regtest;
+    let address = random_p2wpkh_address(network);
+    let coinbase_tx = TransactionBuilder::coinbase()
+        .with_output(&random_p2pkh_address(network), 1_000)
+        .build();
+    let prev_out = OutPoint::new(coinbase_tx.txid(), 0);
+
+    let tx = TransactionBuilder::spending(prev_out.clone(), &address, 900).build();
+    assert!(!tx.is_coin_base());
+    assert_eq!(tx.input().len(), 1);
+    assert_eq!(OutPoint::from(&tx.input()[0].previous_output), prev_out);
+    assert_eq!(tx.output().len(), 1);
+    assert_eq!(tx.output()[0].value, 900);
+    assert_eq!(
+        Address::from_script(&tx.output()[0].script_pubkey, network).unwrap(),
+        address
+    );
+}
@@ -1,5 +1,6 @@
 use bitcoin::{
-    Address as BitcoinAddress, Network as BitcoinNetwork, Script, TxOut as BitcoinTxOut,
+    consensus::Decodable, Address as BitcoinAddress, Block as BitcoinBlock,
+    Network as BitcoinNetwork, Script, TxOut as BitcoinTxOut,
 };
 use candid::CandidType;
 use ic_btc_interface::{
@@ -7,7 +8,7 @@ use ic_btc_interface::{
     GetUtxosRequest as PublicGetUtxosRequest, Height, Network, Satoshi, UtxosFilter,
     UtxosFilterInRequest,
 };
-use ic_btc_types::{BlockHash, OutPoint, Txid};
+use ic_btc_types::{Block, BlockHash, OutPoint, Txid};
 use ic_stable_structures::{storable::Blob, BoundedStorable, Storable as StableStructuresStorable};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
@@ -425,6 +426,34 @@ pub struct GetSuccessorsPartialResponse {
     pub remaining_follow_ups: u8,
 }
 
+/// An error returned when reassembling a block from a partial response.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReassembleError {
+    /// The reassembled bytes can't be decoded into a block.
+    MalformedBlock,
+
+    /// The reassembled bytes are longer than the block they encode.
+    UnexpectedLength { expected: usize, actual: usize },
+}
+
+/// Decodes a block from the concatenated bytes of a partial response and its follow-ups.
+///
+/// The bytes must encode exactly one block.
+pub fn decode_reassembled_block(block_bytes: &[u8]) -> Result<Block, ReassembleError> {
+    let mut reader = block_bytes;
+    let block =
+        BitcoinBlock::consensus_decode(&mut reader).map_err(|_| ReassembleError::MalformedBlock)?;
+
+    if !reader.is_empty() {
+        return Err(ReassembleError::UnexpectedLength {
+            expected: block_bytes.len() - reader.len(),
+            actual: block_bytes.len(),
+        });
+    }
+
+    Ok(Block::new(block))
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct InvalidAddress;
 
@@ -540,7 +569,7 @@ pub enum Slicing<T, U> {
 }
 
 /// An unspent transaction output.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Utxo {
     pub height: u32,
     pub outpoint: OutPoint,
@@ -577,6 +606,64 @@ pub fn into_bitcoin_network(network: Network) -> BitcoinNetwork {
     }
 }
 
+/// Converts a `bitcoin::Network` into the canister's `Network`.
+///
+/// Returns `None` for networks the canister doesn't support (i.e. signet).
+pub fn from_bitcoin_network(network: BitcoinNetwork) -> Option<Network> {
+    match network {
+        BitcoinNetwork::Bitcoin => Some(Network::Mainnet),
+        BitcoinNetwork::Testnet => Some(Network::Testnet),
+        BitcoinNetwork::Regtest => Some(Network::Regtest),
+        BitcoinNetwork::Signet => None,
+    }
+}
+
+/// The hash of the mainnet genesis block, in its internal byte order.
+const MAINNET_GENESIS_BLOCK_HASH: [u8; 32] = [
+    0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72, 0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7, 0x4f,
+    0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// The hash of the testnet genesis block, in its internal byte order.
+const TESTNET_GENESIS_BLOCK_HASH: [u8; 32] = [
+    0x43, 0x49, 0x7f, 0xd7, 0xf8, 0x26, 0x95, 0x71, 0x08, 0xf4, 0xa3, 0x0f, 0xd9, 0xce, 0xc3, 0xae,
+    0xba, 0x79, 0x97, 0x20, 0x84, 0xe9, 0x0e, 0xad, 0x01, 0xea, 0x33, 0x09, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// The hash of the regtest genesis block, in its internal byte order.
+const REGTEST_GENESIS_BLOCK_HASH: [u8; 32] = [
+    0x06, 0x22, 0x6e, 0x46, 0x11, 0x1a, 0x0b, 0x59, 0xca, 0xaf, 0x12, 0x60, 0x43, 0xeb, 0x5b, 0xbf,
+    0x28, 0xc3, 0x4f, 0x3a, 0x5e, 0x33, 0x2a, 0x1f, 0xc7, 0xb2, 0xb7, 0x3c, 0xf1, 0x88, 0x91, 0x0f,
+];
+
+/// Returns the network whose genesis block has the given hash.
+///
+/// Returns `None` for unknown hashes, as well as for the genesis block of networks the
+/// canister doesn't support (i.e. signet).
+pub fn network_from_genesis_hash(hash: &BlockHash) -> Option<Network> {
+    match hash.to_bytes().as_ref() {
+        bytes if bytes == MAINNET_GENESIS_BLOCK_HASH => Some(Network::Mainnet),
+        bytes if bytes == TESTNET_GENESIS_BLOCK_HASH => Some(Network::Testnet),
+        bytes if bytes == REGTEST_GENESIS_BLOCK_HASH => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+/// Returns the default stability threshold of the given network.
+///
+/// The threshold is normalized by the difficulty of the blocks (see
+/// `UnstableBlocks::normalized_stability_threshold`), so it's larger than the number of
+/// confirmations commonly waited for. The defaults are:
+///   * Mainnet: 144, i.e. roughly one day of blocks.
+///   * Testnet: 144, as on mainnet.
+///   * Regtest: 2, so that blocks become stable quickly during local development.
+pub fn default_stability_threshold(network: Network) -> u32 {
+    match network {
+        Network::Mainnet | Network::Testnet => 144,
+        Network::Regtest => 2,
+    }
+}
+
 #[test]
 fn test_utxo_ordering() {
     let a = Utxo {
@@ -664,3 +751,37 @@ fn address_handles_script_edge_case() {
         Err(InvalidAddress)
     );
 }
+
+#[test]
+fn bitcoin_network_round_trip() {
+    for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+        assert_eq!(
+            from_bitcoin_network(into_bitcoin_network(network)),
+            Some(network)
+        );
+    }
+
+    // Signet isn't supported by the canister, so it has no counterpart.
+    assert_eq!(from_bitcoin_network(BitcoinNetwork::Signet), None);
+}
+
+#[test]
+fn network_from_genesis_hash_of_known_networks() {
+    for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+        let genesis_block_hash = crate::genesis_block(network).block_hash();
+        assert_eq!(
+            network_from_genesis_hash(&genesis_block_hash),
+            Some(network)
+        );
+    }
+
+    let signet_genesis_block_hash = BlockHash::from(
+        bitcoin::blockdata::constants::genesis_block(BitcoinNetwork::Signet).block_hash(),
+    );
+    assert_eq!(network_from_genesis_hash(&signet_genesis_block_hash), None);
+
+    assert_eq!(
+        network_from_genesis_hash(&BlockHash::from(vec![0; 32])),
+        None
+    );
+}
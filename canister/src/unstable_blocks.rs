@@ -1,16 +1,17 @@
 mod outpoints_cache;
 
 use crate::{
-    blocktree::{BlockChain, BlockDoesNotExtendTree, BlockTree},
+    blocktree::{BlockChain, BlockDoesNotExtendTree, BlockTree, BlockTreeIndex},
     runtime::print,
     types::{Address, TxOut},
     UtxoSet,
 };
 use bitcoin::BlockHeader;
 use ic_btc_interface::{Height, Network};
-use ic_btc_types::{Block, BlockHash, OutPoint};
+use ic_btc_types::{Block, BlockHash, OutPoint, Txid};
 use outpoints_cache::OutPointsCache;
 use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::BTreeMap};
 
 mod next_block_headers;
 use self::next_block_headers::NextBlockHeaders;
@@ -27,11 +28,54 @@ const TESTNET_CHAIN_MAX_DEPTH: u128 = 1000;
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UnstableBlocks {
     stability_threshold: u32,
+    #[serde(with = "crate::blocktree::serde")]
     tree: BlockTree,
     outpoints_cache: OutPointsCache,
     network: Network,
     // The headers of the blocks that are expected to be received.
     next_block_headers: NextBlockHeaders,
+    // An index of the blocks in `tree` to speed up `get_chain_with_tip`.
+    #[serde(skip)]
+    tree_index: LazyTreeIndex,
+    // An index of the transactions in `tree`, mapping a txid to the hash of every block that
+    // contains it along with the transaction's position in that block. A transaction can be in
+    // more than one block if it's in multiple forks.
+    //
+    // The index can always be rebuilt from the tree, so it isn't serialized and is rebuilt with
+    // `rebuild_tx_index` when the state is loaded.
+    #[serde(skip)]
+    tx_index: BTreeMap<Txid, Vec<(BlockHash, usize)>>,
+    // The sequence number of every pushed block in `tree`, reflecting the order in which the
+    // blocks were first seen. Used by `get_main_chain` to break ties between forks.
+    //
+    // Blocks that were pushed before sequence numbers were recorded aren't in the map, and are
+    // treated as if they were seen before any other block.
+    // NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    first_seen: BTreeMap<BlockHash, u64>,
+    // The sequence number of the next block to be pushed.
+    // NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    next_sequence_number: u64,
+}
+
+// An index of a `BlockTree` that is built on first use and cleared whenever the tree is modified.
+//
+// The index is a cache that can always be rebuilt from the tree, so it's neither serialized
+// nor taken into account when comparing `UnstableBlocks`.
+#[derive(Clone, Debug, Default)]
+struct LazyTreeIndex(RefCell<Option<BlockTreeIndex>>);
+
+impl LazyTreeIndex {
+    fn invalidate(&mut self) {
+        *self.0.get_mut() = None;
+    }
+}
+
+impl PartialEq for LazyTreeIndex {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
 }
 
 impl UnstableBlocks {
@@ -42,12 +86,33 @@ impl UnstableBlocks {
             .insert(utxos, &anchor, utxos.next_height())
             .expect("anchor block must be valid.");
 
+        let mut tx_index = BTreeMap::new();
+        index_transactions(&mut tx_index, &anchor);
+
         Self {
             stability_threshold,
             tree: BlockTree::new(anchor.clone()),
             outpoints_cache,
             network,
             next_block_headers: NextBlockHeaders::default(),
+            tree_index: LazyTreeIndex::default(),
+            tx_index,
+            first_seen: BTreeMap::new(),
+            next_sequence_number: 0,
+        }
+    }
+
+    /// Returns the hashes of the unstable blocks that contain the transaction with the given
+    /// txid, along with the position of the transaction in each block.
+    pub fn get_blocks_with_tx(&self, txid: &Txid) -> &[(BlockHash, usize)] {
+        self.tx_index.get(txid).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Rebuilds the index of the transactions in the unstable blocks from the block tree.
+    pub fn rebuild_tx_index(&mut self) {
+        self.tx_index.clear();
+        for (block, _) in self.tree.blocks_with_meta() {
+            index_transactions(&mut self.tx_index, block);
         }
     }
 
@@ -76,6 +141,11 @@ impl UnstableBlocks {
         self.stability_threshold = stability_threshold;
     }
 
+    /// Returns the anchor block, i.e. the root of the unstable block tree.
+    pub fn anchor(&self) -> &Block {
+        &self.tree.root
+    }
+
     pub fn anchor_difficulty(&self) -> u64 {
         self.tree.root.difficulty(self.network)
     }
@@ -84,12 +154,42 @@ impl UnstableBlocks {
         self.anchor_difficulty() as u128 * self.stability_threshold as u128
     }
 
+    /// Returns the number of blocks in the longest linear run of the unstable blocks.
+    /// See `BlockTree::longest_linear_run` for more details.
+    pub fn longest_linear_run(&self) -> u32 {
+        self.tree.longest_linear_run()
+    }
+
     /// Returns the number of tips available in the current block tree.
     pub fn num_tips(&self) -> u32 {
         self.tree.num_tips()
     }
 
-    fn get_network(&self) -> Network {
+    /// Returns the ratio of forks, i.e. tips beyond the first, to the number of unstable blocks.
+    /// A high ratio indicates many short forks, which inflate the cost of traversing the tree.
+    pub fn fork_ratio(&self) -> f64 {
+        let num_blocks: usize = self
+            .tree
+            .blocks_with_depths_by_heights()
+            .iter()
+            .map(|blocks_at_height| blocks_at_height.len())
+            .sum();
+        (self.num_tips() - 1) as f64 / num_blocks as f64
+    }
+
+    /// Returns true if the given block would start a new fork directly on top of the anchor,
+    /// i.e. if it's a new child of an anchor that already has children.
+    pub fn is_new_anchor_fork(&self, block: &Block) -> bool {
+        BlockHash::from(block.header().prev_blockhash) == self.tree.root.block_hash()
+            && !self.tree.children.is_empty()
+            && self
+                .tree
+                .children
+                .iter()
+                .all(|child| child.root.block_hash() != block.block_hash())
+    }
+
+    pub fn get_network(&self) -> Network {
         self.network
     }
 
@@ -110,7 +210,10 @@ impl UnstableBlocks {
     }
 
     /// Returns depth in BlockTree of Block with given BlockHash.
-    fn block_depth(&mut self, block_hash: &BlockHash) -> Result<u32, BlockDoesNotExtendTree> {
+    fn block_depth(
+        &mut self,
+        block_hash: &BlockHash,
+    ) -> Result<u32, BlockDoesNotExtendTree<BlockHash>> {
         let (_, depth) = self
             .tree
             .find_mut(block_hash)
@@ -123,7 +226,7 @@ impl UnstableBlocks {
         &mut self,
         block_header: BlockHeader,
         stable_height: Height,
-    ) -> Result<(), BlockDoesNotExtendTree> {
+    ) -> Result<(), BlockDoesNotExtendTree<BlockHash>> {
         let prev_block_hash = BlockHash::from(block_header.prev_blockhash);
         let height = match self.next_block_headers.get_height(&prev_block_hash) {
             Some(prev_height) => *prev_height,
@@ -205,34 +308,88 @@ pub fn peek(blocks: &UnstableBlocks) -> Option<&Block> {
 /// is stable. The child `C` becomes the new `anchor` block, and all its
 /// siblings are discarded.
 pub fn pop(blocks: &mut UnstableBlocks, stable_height: Height) -> Option<Block> {
-    match get_stable_child(blocks) {
-        Some(stable_child_idx) => {
-            let old_anchor = blocks.tree.root.clone();
-
-            // Replace the unstable block tree with that of the stable child.
-            blocks.tree = blocks.tree.children.swap_remove(stable_child_idx);
-
-            // Remove the outpoints of the old anchor from the cache.
-            blocks.outpoints_cache.remove(&old_anchor);
+    get_stable_child(blocks)
+        .map(|stable_child_idx| replace_anchor_with_child(blocks, stable_child_idx, stable_height))
+}
 
-            blocks.next_block_headers.remove_until_height(stable_height);
+/// Makes the child of the anchor with the given hash the new anchor, discarding the old anchor
+/// along with its other children, regardless of whether the child is stable.
+///
+/// Returns the old anchor, or `None` if the anchor has no child with the given hash.
+pub fn advance_anchor(
+    blocks: &mut UnstableBlocks,
+    child_hash: &BlockHash,
+    stable_height: Height,
+) -> Option<Block> {
+    let child_idx = blocks
+        .tree
+        .children
+        .iter()
+        .position(|child| &child.root.block_hash() == child_hash)?;
+    Some(replace_anchor_with_child(blocks, child_idx, stable_height))
+}
 
-            Some(old_anchor)
+// Replaces the unstable block tree with that of the anchor's child at the given index, and
+// returns the old anchor.
+fn replace_anchor_with_child(
+    blocks: &mut UnstableBlocks,
+    child_idx: usize,
+    stable_height: Height,
+) -> Block {
+    let old_anchor = blocks.tree.root.clone();
+
+    // Remove the old anchor and its discarded children from the indexes.
+    unindex_transactions(&mut blocks.tx_index, &old_anchor);
+    blocks.first_seen.remove(&old_anchor.block_hash());
+    for (i, sibling) in blocks.tree.children.iter().enumerate() {
+        if i != child_idx {
+            for (block, _) in sibling.blocks_with_meta() {
+                unindex_transactions(&mut blocks.tx_index, block);
+                blocks.first_seen.remove(&block.block_hash());
+            }
         }
-        None => None,
     }
+
+    // Replace the unstable block tree with that of the child.
+    blocks.tree = blocks.tree.children.swap_remove(child_idx);
+    blocks.tree_index.invalidate();
+
+    // Remove the outpoints of the old anchor from the cache.
+    blocks.outpoints_cache.remove(&old_anchor);
+
+    blocks.next_block_headers.remove_until_height(stable_height);
+
+    old_anchor
 }
 
 /// Pushes a new block into the store.
+///
+/// Pushing a block that is already in the store is a no-op, so the main chain doesn't change
+/// when a block is received again.
 pub fn push(
     blocks: &mut UnstableBlocks,
     utxos: &UtxoSet,
     block: Block,
-) -> Result<(), BlockDoesNotExtendTree> {
-    let (parent_block_tree, depth) = blocks
-        .tree
-        .find_mut(&block.header().prev_blockhash.into())
-        .ok_or_else(|| BlockDoesNotExtendTree(block.block_hash()))?;
+) -> Result<(), BlockDoesNotExtendTree<BlockHash>> {
+    let block_hash = block.block_hash();
+
+    let (parent_block_tree, depth) =
+        match blocks.tree.find_mut(&block.header().prev_blockhash.into()) {
+            Some(res) => res,
+            // The anchor has no parent in the tree, but it has been seen before.
+            None if block_hash == blocks.tree.root.block_hash() => return Ok(()),
+            None => return Err(BlockDoesNotExtendTree(block_hash)),
+        };
+
+    if parent_block_tree
+        .children
+        .iter()
+        .any(|child| child.root.block_hash() == block_hash)
+    {
+        // The block has been seen before. Return early to avoid inserting its outpoints
+        // into the cache twice, and to keep the sequence number it was first seen with.
+        return Ok(());
+    }
 
     let height = utxos.next_height() + depth + 1;
 
@@ -240,91 +397,123 @@ pub fn push(
         .outpoints_cache
         .insert(utxos, &block, height)
         .expect("inserting to outpoints cache must succeed.");
+    index_transactions(&mut blocks.tx_index, &block);
 
-    let block_hash = block.block_hash();
+    blocks
+        .first_seen
+        .insert(block_hash.clone(), blocks.next_sequence_number);
+    blocks.next_sequence_number += 1;
 
-    parent_block_tree.extend(block)?;
+    parent_block_tree.children.push(BlockTree::new(block));
+    blocks.tree_index.invalidate();
 
     blocks.next_block_headers.remove(&block_hash);
 
     Ok(())
 }
 
-/// Returns the best guess on what the main blockchain is.
-///
-/// The most likely chain to be "main", we hypothesize, is the longest
-/// chain of blocks with an "uncontested" tip. As in, there exists no other
-/// block at the same height as the tip.
-pub fn get_main_chain(blocks: &UnstableBlocks) -> BlockChain {
-    // Get all the blockchains that extend the anchor.
-    let blockchains: Vec<BlockChain> = blocks.tree.blockchains();
-
-    // Find the length of the longest blockchain.
-    let mut longest_blockchain_len = 0;
-    for blockchain in blockchains.iter() {
-        longest_blockchain_len = longest_blockchain_len.max(blockchain.len());
+// Adds the transactions of the given block to the transaction index.
+fn index_transactions(tx_index: &mut BTreeMap<Txid, Vec<(BlockHash, usize)>>, block: &Block) {
+    let block_hash = block.block_hash();
+    for (position, tx) in block.txdata().iter().enumerate() {
+        tx_index
+            .entry(tx.txid())
+            .or_default()
+            .push((block_hash.clone(), position));
     }
+}
 
-    // Get all the longest blockchains.
-    let longest_blockchains: Vec<Vec<&'_ Block>> = blockchains
-        .into_iter()
-        .filter(|bc| bc.len() == longest_blockchain_len)
-        .map(|bc| bc.into_chain())
-        .collect();
-
-    // A `BlockChain` contains at least one block which means we can safely index at
-    // height 0 of the chain.
-    let mut main_chain = BlockChain::new(longest_blockchains[0][0]);
-    for height_idx in 1..longest_blockchain_len {
-        // If all the blocks on the same height are identical, then this block is part of the
-        // "main" chain.
-        let block = longest_blockchains[0][height_idx];
-        let block_hash = block.block_hash();
-        for chain in longest_blockchains.iter().skip(1) {
-            if chain[height_idx].block_hash() != block_hash {
-                return main_chain;
+// Removes the transactions of the given block from the transaction index.
+fn unindex_transactions(tx_index: &mut BTreeMap<Txid, Vec<(BlockHash, usize)>>, block: &Block) {
+    let block_hash = block.block_hash();
+    for tx in block.txdata() {
+        let txid = tx.txid();
+        if let Some(locations) = tx_index.get_mut(&txid) {
+            locations.retain(|(hash, _)| hash != &block_hash);
+            if locations.is_empty() {
+                tx_index.remove(&txid);
             }
         }
+    }
+}
+
+/// Returns the best guess on what the main blockchain is.
+///
+/// The main chain is the longest chain of blocks. Ties between chains of equal length are
+/// broken in favor of the chain whose tip was seen first, so a fork that catches up with the
+/// main chain doesn't take it over until it's strictly longer. Re-pushing a block is a no-op,
+/// so the main chain is deterministic and doesn't flip when a block is received again.
+pub fn get_main_chain(blocks: &UnstableBlocks) -> BlockChain {
+    // The sequence number of the given chain's tip.
+    let tip_sequence_number = |chain: &BlockChain| {
+        blocks
+            .first_seen
+            .get(&chain.tip().block_hash())
+            .copied()
+            .unwrap_or(0)
+    };
+
+    // Pick the longest blockchain, with the earliest-seen tip. The tree contains at least the
+    // anchor, so there's always at least one blockchain.
+    let mut main_chain: Option<(BlockChain, u64)> = None;
+    for blockchain in blocks.tree.blockchains() {
+        let sequence_number = tip_sequence_number(&blockchain);
+        let is_better = match &main_chain {
+            None => true,
+            Some((main_chain, main_sequence_number)) => {
+                blockchain.len() > main_chain.len()
+                    || (blockchain.len() == main_chain.len()
+                        && sequence_number < *main_sequence_number)
+            }
+        };
 
-        main_chain.push(block);
+        if is_better {
+            main_chain = Some((blockchain, sequence_number));
+        }
     }
 
     main_chain
+        .expect("a block tree must contain at least one blockchain")
+        .0
 }
 
 /// Returns the length of the "main chain".
 /// See `get_main_chain` for what defines a main chain.
 pub fn get_main_chain_length(blocks: &UnstableBlocks) -> usize {
-    let blocks_by_height = blocks.blocks_with_depths_by_heights();
-
-    // Traverse the heights in reverse order. The highest height with a single block corresponds to
-    // the tip of the main chain.
-    for height in (0..blocks_by_height.len()).rev() {
-        if blocks_by_height[height].len() == 1 {
-            return height + 1;
-        }
-    }
-
-    unreachable!("There must be at least one height with exactly one block.");
+    // The main chain is a longest chain, so its length is the depth of the tree.
+    blocks.tree.depth() as usize
 }
 
+/// Returns all the unstable blocks, with every block appearing exactly once.
+///
+/// The blocks are ordered by height, and blocks at the same height are ordered by their hash.
+/// The order therefore only depends on the blocks in the tree and not on the order in which
+/// they were pushed, and the anchor is always the first block.
 pub fn get_blocks(blocks: &UnstableBlocks) -> Vec<&Block> {
     blocks
         .tree
-        .blockchains()
+        .blocks_with_depths_by_heights()
         .into_iter()
-        .flat_map(|bc| bc.into_chain())
+        .flat_map(|mut blocks_at_height| {
+            blocks_at_height.sort_by_cached_key(|(block, _)| block.block_hash());
+            blocks_at_height.into_iter().map(|(block, _)| block)
+        })
         .collect()
 }
 
 /// Returns a blockchain starting from the anchor and ending with the `tip`.
 ///
 /// If the `tip` doesn't exist in the tree, `None` is returned.
+///
+/// The lookup uses an index of the tree that is built on the first call after the tree is
+/// modified, so repeated lookups don't need to search the entire tree.
 pub fn get_chain_with_tip<'a>(
     blocks: &'a UnstableBlocks,
     tip: &BlockHash,
 ) -> Option<BlockChain<'a>> {
-    blocks.tree.get_chain_with_tip(tip)
+    let mut tree_index = blocks.tree_index.0.borrow_mut();
+    let tree_index = tree_index.get_or_insert_with(|| blocks.tree.index());
+    blocks.tree.get_chain_with_tip_indexed(tree_index, tip)
 }
 
 // Returns the index of the `anchor`'s stable child if it exists.
@@ -442,6 +631,135 @@ mod test {
         assert_eq!(pop(&mut unstable_blocks, 0), None);
     }
 
+    #[test]
+    fn get_blocks_is_ordered_by_height_then_hash() {
+        let network = Network::Regtest;
+        let utxos = UtxoSet::new(network);
+        let genesis_block = BlockBuilder::genesis().build();
+        let fork_1 = BlockChainBuilder::fork(&genesis_block, 3).build();
+        let fork_2 = BlockChainBuilder::fork(&genesis_block, 2).build();
+        let fork_3 = BlockChainBuilder::fork(&fork_1[0], 2).build();
+
+        // Push the forks in different orders.
+        let mut forest_1 = UnstableBlocks::new(&utxos, 1, genesis_block.clone(), network);
+        for block in fork_1.iter().chain(&fork_2).chain(&fork_3) {
+            push(&mut forest_1, &utxos, block.clone()).unwrap();
+        }
+
+        let mut forest_2 = UnstableBlocks::new(&utxos, 1, genesis_block.clone(), network);
+        for block in fork_2.iter().chain(&fork_1).chain(&fork_3) {
+            push(&mut forest_2, &utxos, block.clone()).unwrap();
+        }
+
+        let blocks = get_blocks(&forest_1);
+        assert_eq!(blocks, get_blocks(&forest_1));
+        assert_eq!(blocks, get_blocks(&forest_2));
+
+        // Every block appears exactly once, ordered by height and then by hash.
+        let mut expected = vec![(0, genesis_block.block_hash())];
+        for (i, block) in fork_1.iter().enumerate() {
+            expected.push((i + 1, block.block_hash()));
+        }
+        for (i, block) in fork_2.iter().enumerate() {
+            expected.push((i + 1, block.block_hash()));
+        }
+        for (i, block) in fork_3.iter().enumerate() {
+            expected.push((i + 2, block.block_hash()));
+        }
+        expected.sort();
+
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|block| block.block_hash())
+                .collect::<Vec<_>>(),
+            expected
+                .into_iter()
+                .map(|(_, block_hash)| block_hash)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn tx_index_tracks_pushed_and_discarded_blocks() {
+        let network = Network::Regtest;
+        let utxos = UtxoSet::new(network);
+
+        // Every block has a random coinbase transaction.
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let fork_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let txid = |block: &Block| block.txdata()[0].txid();
+
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
+        for block in [&block_1, &fork_1, &block_2] {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+        for block in [&block_0, &block_1, &fork_1, &block_2] {
+            assert_eq!(
+                forest.get_blocks_with_tx(&txid(block)),
+                &[(block.block_hash(), 0)]
+            );
+        }
+
+        // Popping the anchor discards it along with the fork.
+        assert_eq!(pop(&mut forest, 1), Some(block_0.clone()));
+        assert!(forest.get_blocks_with_tx(&txid(&block_0)).is_empty());
+        assert!(forest.get_blocks_with_tx(&txid(&fork_1)).is_empty());
+        for block in [&block_1, &block_2] {
+            assert_eq!(
+                forest.get_blocks_with_tx(&txid(block)),
+                &[(block.block_hash(), 0)]
+            );
+        }
+
+        // Rebuilding the index from the tree results in the same index.
+        let tx_index = forest.tx_index.clone();
+        forest.rebuild_tx_index();
+        assert_eq!(forest.tx_index, tx_index);
+    }
+
+    #[test]
+    fn get_chain_with_tip_after_tree_is_modified() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        let network = Network::Regtest;
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 2, block_0.clone(), network);
+
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        assert_eq!(
+            get_chain_with_tip(&forest, &block_1.block_hash()),
+            Some(BlockChain::new_with_successors(&block_0, vec![&block_1]))
+        );
+        assert_eq!(get_chain_with_tip(&forest, &block_2.block_hash()), None);
+
+        // The lookup reflects the newly pushed block.
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        assert_eq!(
+            get_chain_with_tip(&forest, &block_2.block_hash()),
+            Some(BlockChain::new_with_successors(
+                &block_0,
+                vec![&block_1, &block_2]
+            ))
+        );
+
+        // The lookup reflects the new anchor after popping.
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
+        assert_eq!(pop(&mut forest, 0), Some(block_0.clone()));
+        assert_eq!(get_chain_with_tip(&forest, &block_0.block_hash()), None);
+        assert_eq!(
+            get_chain_with_tip(&forest, &block_3.block_hash()),
+            Some(BlockChain::new_with_successors(
+                &block_1,
+                vec![&block_2, &block_3]
+            ))
+        );
+    }
+
     #[test]
     fn single_chain_same_difficulties() {
         let block_0 = BlockBuilder::genesis().build();
@@ -633,6 +951,39 @@ mod test {
         assert_eq!(pop(&mut forest, 0), None);
     }
 
+    #[test]
+    fn forks_with_mock_difficulty() {
+        let anchor = BlockBuilder::genesis().build_with_mock_difficulty(1);
+
+        // A longer fork with less work, and a shorter fork with more work.
+        let fork_1 = BlockChainBuilder::fork(&anchor, 3)
+            .with_mock_difficulty(2)
+            .build();
+        let fork_2 = BlockChainBuilder::fork(&anchor, 2)
+            .with_mock_difficulty(10)
+            .build();
+        assert!(fork_1.iter().all(|b| b.mock_difficulty == Some(2)));
+        assert!(fork_2.iter().all(|b| b.mock_difficulty == Some(10)));
+
+        let network = Network::Mainnet;
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 2, anchor.clone(), network);
+        for block in fork_1.iter().chain(fork_2.iter()) {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+
+        assert_eq!(forest.tree.children[0].difficulty_based_depth(network), 6);
+        assert_eq!(forest.tree.children[1].difficulty_based_depth(network), 20);
+
+        // The main chain is determined by length, so it follows the first fork.
+        assert_eq!(get_main_chain(&forest).tip(), fork_1.last().unwrap());
+
+        // Stability is determined by work, so the anchor's stable child is in the second fork.
+        assert_eq!(pop(&mut forest, 0), Some(anchor));
+        assert_eq!(forest.tree.root, fork_2[0]);
+        assert_eq!(get_main_chain(&forest).tip(), fork_2.last().unwrap());
+    }
+
     #[test]
     fn insert_in_order() {
         let block_0 = BlockBuilder::genesis().build();
@@ -681,7 +1032,8 @@ mod test {
     // * -> 1
     // * -> 2
     //
-    // Both blocks 1 and 2 contest with each other -> main chain is empty.
+    // Both blocks 1 and 2 contest with each other. Block 1 was seen first, so it's part of
+    // the main chain.
     #[test]
     fn get_main_chain_two_contesting_trees() {
         let block_0 = BlockBuilder::genesis().build();
@@ -692,9 +1044,49 @@ mod test {
         let utxos = UtxoSet::new(network);
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        push(&mut forest, &utxos, block_1).unwrap();
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
         push(&mut forest, &utxos, block_2).unwrap();
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(
+            get_main_chain(&forest),
+            BlockChain::new_with_successors(&block_0, vec![&block_1])
+        );
+    }
+
+    // Creating the following forest:
+    //
+    // * -> 1 -> 2
+    // * -> a -> b
+    //
+    // The forks are of equal length and work, so the main chain is the fork that was seen
+    // first. Re-pushing the blocks of either fork, in any order, doesn't change that.
+    #[test]
+    fn get_main_chain_equal_forks_does_not_flip_on_repush() {
+        let network = Network::Regtest;
+        let chain = BlockChainBuilder::new(3).build();
+        let fork = BlockChainBuilder::fork(&chain[0], 2).build();
+
+        for (first_seen, second_seen) in [(&chain[1..], &fork[..]), (&fork[..], &chain[1..])] {
+            let expected_main_chain =
+                BlockChain::new_with_successors(&chain[0], first_seen.iter().collect());
+
+            let utxos = UtxoSet::new(network);
+            let mut forest = UnstableBlocks::new(&utxos, 1, chain[0].clone(), network);
+            for block in first_seen.iter().chain(second_seen.iter()) {
+                push(&mut forest, &utxos, block.clone()).unwrap();
+            }
+            assert_eq!(get_main_chain(&forest), expected_main_chain);
+            assert_eq!(get_main_chain_length(&forest), 3);
+
+            let forest_before = forest.clone();
+            for block in second_seen.iter().chain(first_seen.iter()).rev() {
+                push(&mut forest, &utxos, block.clone()).unwrap();
+                assert_eq!(get_main_chain(&forest), expected_main_chain);
+            }
+            push(&mut forest, &utxos, chain[0].clone()).unwrap();
+
+            // Re-pushing the blocks, including the anchor, left the store unchanged.
+            assert_eq!(forest, forest_before);
+        }
     }
 
     // Creating the following forest:
@@ -728,8 +1120,8 @@ mod test {
     // * -> 1 -> 2 -> 3
     //       \-> a -> b
     //
-    // "1" should be returned in this case, as its the longest chain
-    // without a contested tip.
+    // Both forks are of equal length, so "1 -> 2 -> 3" is returned in this case, as it
+    // was seen first.
     #[test]
     fn get_main_chain_fork_at_first_block() {
         let block_0 = BlockBuilder::genesis().build();
@@ -744,13 +1136,13 @@ mod test {
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
         push(&mut forest, &utxos, block_1.clone()).unwrap();
-        push(&mut forest, &utxos, block_2).unwrap();
-        push(&mut forest, &utxos, block_3).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
         push(&mut forest, &utxos, block_a).unwrap();
         push(&mut forest, &utxos, block_b).unwrap();
         assert_eq!(
             get_main_chain(&forest),
-            BlockChain::new_with_successors(&block_0, vec![&block_1])
+            BlockChain::new_with_successors(&block_0, vec![&block_1, &block_2, &block_3])
         );
     }
 
@@ -760,7 +1152,7 @@ mod test {
     //       \-> a -> b
     //   -> x -> y -> z
     //
-    // All blocks are contested.
+    // All the forks are of equal length, and "x -> y -> z" was seen first.
     //
     // Then add block `c` that extends block `b`, at that point
     // `1 -> a -> b -> c` becomes the only longest chain, and therefore
@@ -781,15 +1173,18 @@ mod test {
         let utxos = UtxoSet::new(network);
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        push(&mut forest, &utxos, block_x).unwrap();
-        push(&mut forest, &utxos, block_y).unwrap();
-        push(&mut forest, &utxos, block_z).unwrap();
+        push(&mut forest, &utxos, block_x.clone()).unwrap();
+        push(&mut forest, &utxos, block_y.clone()).unwrap();
+        push(&mut forest, &utxos, block_z.clone()).unwrap();
         push(&mut forest, &utxos, block_1.clone()).unwrap();
         push(&mut forest, &utxos, block_2).unwrap();
         push(&mut forest, &utxos, block_3).unwrap();
         push(&mut forest, &utxos, block_a.clone()).unwrap();
         push(&mut forest, &utxos, block_b.clone()).unwrap();
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(
+            get_main_chain(&forest),
+            BlockChain::new_with_successors(&block_0, vec![&block_x, &block_y, &block_z])
+        );
 
         // Now add block c to b.
         let block_c = BlockBuilder::with_prev_header(block_b.header()).build();
@@ -802,7 +1197,8 @@ mod test {
         );
     }
 
-    // Same as the above test, with a different insertion order.
+    // Same as the above test, with a different insertion order, so that "1 -> 2 -> 3" is
+    // seen first.
     #[test]
     fn get_main_chain_multiple_forks_2() {
         let block_0 = BlockBuilder::genesis().build();
@@ -819,15 +1215,67 @@ mod test {
         let utxos = UtxoSet::new(network);
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        push(&mut forest, &utxos, block_1).unwrap();
-        push(&mut forest, &utxos, block_2).unwrap();
-        push(&mut forest, &utxos, block_3).unwrap();
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
         push(&mut forest, &utxos, block_a).unwrap();
         push(&mut forest, &utxos, block_b).unwrap();
         push(&mut forest, &utxos, block_x).unwrap();
         push(&mut forest, &utxos, block_y).unwrap();
         push(&mut forest, &utxos, block_z).unwrap();
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(
+            get_main_chain(&forest),
+            BlockChain::new_with_successors(&block_0, vec![&block_1, &block_2, &block_3])
+        );
+    }
+
+    // Creating the following forest:
+    //
+    // * -> x -> y -> z
+    //   -> 1 -> 2 -> 3
+    //
+    // "x" is seen first, but "1 -> 2 -> 3" is the longest chain by the time "y" and "z" are
+    // seen. The fork rooted at "x" catching up doesn't make it the main chain, as the tip "3"
+    // was seen before "z".
+    #[test]
+    fn get_main_chain_earlier_rooted_fork_catching_up_does_not_take_over() {
+        let block_0 = BlockBuilder::genesis().build();
+        let block_x = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_y = BlockBuilder::with_prev_header(block_x.header()).build();
+        let block_z = BlockBuilder::with_prev_header(block_y.header()).build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+
+        let network = Network::Mainnet;
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
+
+        push(&mut forest, &utxos, block_x.clone()).unwrap();
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
+        let expected_main_chain =
+            BlockChain::new_with_successors(&block_0, vec![&block_1, &block_2, &block_3]);
+        assert_eq!(get_main_chain(&forest), expected_main_chain);
+
+        push(&mut forest, &utxos, block_y.clone()).unwrap();
+        push(&mut forest, &utxos, block_z.clone()).unwrap();
+        assert_eq!(get_main_chain(&forest), expected_main_chain);
+
+        // Re-pushing the blocks of the fork doesn't change the main chain either.
+        for block in [&block_x, &block_y, &block_z] {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+        assert_eq!(get_main_chain(&forest), expected_main_chain);
+
+        // Once the fork is strictly longer, it becomes the main chain.
+        let block_w = BlockBuilder::with_prev_header(block_z.header()).build();
+        push(&mut forest, &utxos, block_w.clone()).unwrap();
+        assert_eq!(
+            get_main_chain(&forest),
+            BlockChain::new_with_successors(&block_0, vec![&block_x, &block_y, &block_z, &block_w])
+        );
     }
 
     #[test]
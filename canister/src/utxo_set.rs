@@ -1,19 +1,29 @@
 use crate::{
     memory::Memory,
     multi_iter::MultiIter,
-    runtime::{inc_performance_counter, performance_counter, print},
+    runtime::{inc_performance_counter, performance_counter, print, Runtime},
     types::{Address, AddressUtxo, AddressUtxoRange, Slicing, TxOut, Utxo},
 };
-use bitcoin::{Script, TxOut as BitcoinTxOut};
+use bitcoin::{
+    hashes::{sha256, Hash, HashEngine},
+    Script, TxOut as BitcoinTxOut,
+};
 use ic_btc_interface::{Height, Network, Satoshi};
 use ic_btc_types::{Block, BlockHash, OutPoint, Transaction, Txid};
 use ic_stable_structures::{storable::Blob, BoundedStorable, StableBTreeMap, Storable as _};
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeSet, iter::Iterator, str::FromStr};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap},
+    iter::Iterator,
+    str::FromStr,
+};
+mod tx_index;
 mod utxos;
 mod utxos_delta;
 use std::convert::TryFrom;
-use utxos::Utxos;
+pub use tx_index::TxIndex;
+use utxos::{SizeClass, UtxoStore, Utxos};
 use utxos_delta::UtxosDelta;
 
 lazy_static::lazy_static! {
@@ -24,8 +34,8 @@ lazy_static::lazy_static! {
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct UtxoSet {
-    pub utxos: Utxos,
+pub struct UtxoSet<S = Utxos> {
+    pub utxos: S,
 
     network: Network,
 
@@ -39,6 +49,11 @@ pub struct UtxoSet {
     #[serde(skip, default = "init_balances")]
     balances: StableBTreeMap<Address, u64, Memory>,
 
+    // The addresses that have received an output in an ingested block.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "init_used_addresses")]
+    used_addresses: StableBTreeMap<Address, (), Memory>,
+
     // The height of the block that will be ingested next.
     // NOTE: The `next_height` is stored, rather than the current height, because:
     //   * The `UtxoSet` is initialized as empty with no blocks.
@@ -55,18 +70,84 @@ pub struct UtxoSet {
 
     /// A block that is currently being ingested into the UtxoSet. Used for time slicing.
     pub ingesting_block: Option<IngestingBlock>,
+
+    /// The XOR of the hashes of all the UTXOs, updated whenever a UTXO is inserted or removed so
+    /// that `utxo_set_hash` doesn't require a full scan.
+    ///
+    /// `None` if the UTXOs were loaded without maintaining it (e.g. in a state predating it),
+    /// in which case it's restored with `rebuild_utxo_set_hash`.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    utxo_set_hash: Option<[u8; 32]>,
+
+    /// The progress of rebuilding `utxo_set_hash`, if it's being rebuilt in time-sliced steps.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    utxo_set_hash_rebuild: Option<UtxoSetHashRebuild>,
+
+    /// An optional index of the ingested transactions by txid. Disabled if `None`.
+    /// NOTE: serde(default) is used here for backward-compatibility.
+    #[serde(default)]
+    pub tx_index: Option<TxIndex>,
 }
 
 impl UtxoSet {
     pub fn new(network: Network) -> Self {
+        Self::with_store(network, Utxos::default())
+    }
+
+    /// Continues rebuilding the UTXO set hash if it isn't available, scanning the UTXOs until
+    /// `should_time_slice` returns true.
+    ///
+    /// Returns `Slicing::Paused` if the rebuild is time-sliced, in which case the UTXO set must
+    /// not be modified until the rebuild is done.
+    pub fn rebuild_utxo_set_hash(&mut self) -> Slicing<(), ()> {
+        if self.utxo_set_hash.is_some() {
+            return Slicing::Done(());
+        }
+
+        let mut rebuild = self.utxo_set_hash_rebuild.take().unwrap_or_default();
+        for (size_class, outpoint, tx_out_and_height) in
+            self.utxos.iter_after(rebuild.position.clone())
+        {
+            xor_utxo_hash(&mut rebuild.hash, &outpoint, &tx_out_and_height);
+            rebuild.position = Some((size_class, outpoint));
+
+            if (self.should_time_slice)() {
+                self.utxo_set_hash_rebuild = Some(rebuild);
+                return Slicing::Paused(());
+            }
+        }
+
+        self.utxo_set_hash = Some(rebuild.hash);
+        Slicing::Done(())
+    }
+}
+
+impl<S: UtxoStore> UtxoSet<S> {
+    /// Creates an empty `UtxoSet` that stores its UTXOs in the given store.
+    pub fn with_store(network: Network, utxos: S) -> Self {
         Self {
-            utxos: Utxos::default(),
+            utxos,
             balances: init_balances(),
             address_utxos: init_address_utxos(),
+            used_addresses: init_used_addresses(),
             network,
             next_height: 0,
             ingesting_block: None,
             should_time_slice: default_should_time_slice(),
+            utxo_set_hash: Some([0; 32]),
+            utxo_set_hash_rebuild: None,
+            tx_index: None,
+        }
+    }
+
+    /// Enables indexing of the transactions in the blocks ingested from now on.
+    ///
+    /// Transactions in blocks that were ingested before the index was enabled aren't indexed.
+    pub fn enable_tx_index(&mut self) {
+        if self.tx_index.is_none() {
+            self.tx_index = Some(TxIndex::new());
         }
     }
 
@@ -85,6 +166,10 @@ impl UtxoSet {
             self.next_height
         );
 
+        if let Some(tx_index) = &mut self.tx_index {
+            tx_index.insert_block(&block, self.next_height);
+        }
+
         // Store in the state the new block to be ingested.
         self.ingesting_block = Some(IngestingBlock::new(block));
 
@@ -157,6 +242,11 @@ impl UtxoSet {
         Some(Slicing::Done((block.block_hash(), stats)))
     }
 
+    /// Returns the stats of the block that's partially ingested, if any.
+    pub fn ingesting_block_stats(&self) -> Option<&BlockIngestionStats> {
+        self.ingesting_block.as_ref().map(|b| &b.stats)
+    }
+
     /// Returns the balance of the given address.
     pub fn get_balance(&self, address: &Address) -> Satoshi {
         let mut balance = self.balances.get(address).unwrap_or(0);
@@ -235,21 +325,135 @@ impl UtxoSet {
         MultiIter::new(stable_outpoints, removed_outpoints.into_iter().cloned())
     }
 
+    /// Sets the predicate used to determine whether or not to time-slice, returning the
+    /// previous predicate.
+    pub fn set_should_time_slice(
+        &mut self,
+        should_time_slice: Box<dyn FnMut() -> bool>,
+    ) -> Box<dyn FnMut() -> bool> {
+        std::mem::replace(&mut self.should_time_slice, should_time_slice)
+    }
+
+    /// Returns whether or not to time-slice, as determined by the set's predicate.
+    pub fn should_time_slice(&mut self) -> bool {
+        (self.should_time_slice)()
+    }
+
     /// Returns the number of UTXOs in the set.
     pub fn utxos_len(&self) -> u64 {
         self.utxos.len()
     }
 
+    /// Returns a hash of the UTXOs that doesn't depend on the order in which they're stored,
+    /// allowing two UTXO sets to be compared using a single value.
+    ///
+    /// The hash is the XOR of the SHA-256 hashes of all the UTXOs. It's maintained
+    /// incrementally, and is `None` until `rebuild_utxo_set_hash` completes if the UTXOs were
+    /// loaded without it. The changes of a block that's partially ingested are included as is.
+    pub fn utxo_set_hash(&self) -> Option<[u8; 32]> {
+        self.utxo_set_hash
+    }
+
+    /// Computes the hash returned by `utxo_set_hash` with a full scan of the UTXO set.
+    #[cfg(test)]
+    pub fn compute_utxo_set_hash(&self) -> [u8; 32] {
+        let mut hash = [0; 32];
+        for (outpoint, tx_out_and_height) in self.utxos.iter() {
+            xor_utxo_hash(&mut hash, &outpoint, &tx_out_and_height);
+        }
+        hash
+    }
+
     /// Returns the number of UTXOs that are owned by supported addresses.
     pub fn address_utxos_len(&self) -> u64 {
         self.address_utxos.len()
     }
 
+    /// Returns true if the given address has received an output in an ingested block, even if
+    /// that output has been spent since.
+    ///
+    /// NOTE: Addresses are only recorded as they receive outputs, so for outputs ingested before
+    /// that was the case, an address is only known to be used while it has UTXOs.
+    pub fn is_address_used(&self, address: &Address) -> bool {
+        self.used_addresses.contains_key(address)
+            || self.get_address_outpoints(address, &None).next().is_some()
+    }
+
     /// Returns the number of addresses that we have balances for.
     pub fn balances_len(&self) -> u64 {
         self.balances.len()
     }
 
+    /// Returns the `n` addresses with the largest balances, ordered by descending balance.
+    /// Addresses with equal balances are ordered by address. Zero balances are excluded.
+    pub fn top_balances(&self, n: usize) -> Vec<(Address, u64)> {
+        // A min-heap of the largest balances seen so far.
+        let mut heap = BinaryHeap::with_capacity(n + 1);
+        for (address, balance) in self.balances.iter() {
+            if balance == 0 {
+                continue;
+            }
+
+            heap.push(Reverse((balance, Reverse(address))));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((balance, Reverse(address)))| (address, balance))
+            .collect()
+    }
+
+    /// Seeds the balances with the entries of the given map.
+    ///
+    /// NOTE: This is used for bootstrapping the state from a prebuilt balances map, and only
+    /// the balances are updated. The UTXOs themselves are loaded with `seed_utxos`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn seed_balances<M: ic_stable_structures::Memory>(
+        &mut self,
+        balances: &StableBTreeMap<Address, u64, M>,
+    ) {
+        for (address, balance) in balances.iter() {
+            self.balances.insert(address, balance);
+        }
+    }
+
+    /// Seeds the UTXO set with the given UTXOs, along with the heights they were created at.
+    ///
+    /// NOTE: This is used alongside `seed_balances` for bootstrapping the state, so the
+    /// balances aren't updated.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn seed_utxos<I: IntoIterator<Item = (OutPoint, TxOut, Height)>>(&mut self, utxos: I) {
+        for (outpoint, tx_out, height) in utxos {
+            if let Ok(address) =
+                Address::from_script(&Script::from(tx_out.script_pubkey.clone()), self.network)
+            {
+                self.used_addresses.insert(address.clone(), ());
+                self.address_utxos.insert(
+                    Blob::try_from(
+                        AddressUtxo {
+                            address,
+                            height,
+                            outpoint: outpoint.clone(),
+                        }
+                        .to_bytes()
+                        .as_ref(),
+                    )
+                    .unwrap(),
+                    (),
+                );
+            }
+
+            let tx_out_and_height = (tx_out, height);
+            if let Some(hash) = self.utxo_set_hash.as_mut() {
+                xor_utxo_hash(hash, &outpoint, &tx_out_and_height);
+            }
+            self.utxos.insert(outpoint, tx_out_and_height);
+        }
+    }
+
     pub fn network(&self) -> Network {
         self.network
     }
@@ -308,7 +512,12 @@ impl UtxoSet {
             // Remove the input from the UTXOs. The input *must* exist in the UTXO set.
             let outpoint = (&input.previous_output).into();
             match self.utxos.remove(&outpoint) {
-                Some((txout, height)) => {
+                Some(tx_out_and_height) => {
+                    if let Some(hash) = self.utxo_set_hash.as_mut() {
+                        xor_utxo_hash(hash, &outpoint, &tx_out_and_height);
+                    }
+
+                    let (txout, height) = tx_out_and_height;
                     if let Ok(address) = Address::from_script(
                         &Script::from(txout.script_pubkey.clone()),
                         self.network,
@@ -412,6 +621,7 @@ impl UtxoSet {
                 .unwrap(),
                 (),
             );
+            self.used_addresses.insert(address.clone(), ());
 
             // Update the balance of the address.
             let address_balance = self.balances.get(&address).unwrap_or(0);
@@ -421,9 +631,18 @@ impl UtxoSet {
             utxos_delta.insert(address, outpoint.clone(), tx_out.clone(), self.next_height);
         }
 
-        let outpoint_already_exists = self
-            .utxos
-            .insert(outpoint.clone(), (tx_out, self.next_height));
+        let tx_out_and_height = (tx_out, self.next_height);
+        if let Some(hash) = self.utxo_set_hash.as_mut() {
+            // A duplicate transaction overwrites its previous outputs (see below).
+            if DUPLICATE_TX_IDS.contains(&outpoint.txid) {
+                if let Some(overwritten) = self.utxos.get(&outpoint) {
+                    xor_utxo_hash(hash, &outpoint, &overwritten);
+                }
+            }
+            xor_utxo_hash(hash, &outpoint, &tx_out_and_height);
+        }
+
+        let outpoint_already_exists = self.utxos.insert(outpoint.clone(), tx_out_and_height);
 
         // Verify that we aren't overwriting a previously seen outpoint.
         // NOTE: There was a bug where there were duplicate transactions. These transactions
@@ -440,7 +659,10 @@ impl UtxoSet {
         }
     }
 
-    #[cfg(test)]
+    /// Returns the total value of the UTXOs in the set.
+    ///
+    /// NOTE: This does a full scan of the UTXO set, so it's only available outside of the canister.
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn get_total_supply(&self) -> Satoshi {
         self.utxos.iter().map(|(_, (v, _))| v.value).sum()
     }
@@ -454,6 +676,23 @@ fn init_balances() -> StableBTreeMap<Address, u64, Memory> {
     StableBTreeMap::init(crate::memory::get_balances_memory())
 }
 
+fn init_used_addresses() -> StableBTreeMap<Address, (), Memory> {
+    StableBTreeMap::init(crate::memory::get_used_addresses_memory())
+}
+
+// XORs the SHA-256 hash of the given UTXO into `hash`. As XOR is its own inverse, this both adds
+// and removes a UTXO from the hash.
+fn xor_utxo_hash(hash: &mut [u8; 32], outpoint: &OutPoint, tx_out_and_height: &(TxOut, Height)) {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&outpoint.to_bytes());
+    engine.input(&crate::types::Storable::to_bytes(tx_out_and_height));
+    let utxo_hash = sha256::Hash::from_engine(engine).into_inner();
+
+    for (byte, utxo_byte) in hash.iter_mut().zip(utxo_hash.iter()) {
+        *byte ^= utxo_byte;
+    }
+}
+
 /// A state for maintaining a stable block that is partially ingested into the UTXO set.
 /// Used for time slicing.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
@@ -496,6 +735,15 @@ impl IngestingBlock {
     }
 }
 
+/// The progress of rebuilding the UTXO set hash.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct UtxoSetHashRebuild {
+    /// The hash of the UTXOs up to `position`.
+    hash: [u8; 32],
+    /// The position of the last UTXO included in `hash`, or `None` if no UTXO is included yet.
+    position: Option<(SizeClass, OutPoint)>,
+}
+
 // Various profiling stats for tracking the performance of block ingestion.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq, Default)]
 pub struct BlockIngestionStats {
@@ -539,7 +787,7 @@ impl BlockIngestionStats {
 // NOTE: `PartialEq` is only available in tests as it would be impractically
 // expensive in production.
 #[cfg(test)]
-impl PartialEq for UtxoSet {
+impl<S: PartialEq> PartialEq for UtxoSet<S> {
     fn eq(&self, other: &Self) -> bool {
         use crate::test_utils::is_stable_btreemap_equal;
         self.utxos == other.utxos
@@ -548,27 +796,42 @@ impl PartialEq for UtxoSet {
             && self.ingesting_block == other.ingesting_block
             && is_stable_btreemap_equal(&self.address_utxos, &other.address_utxos)
             && is_stable_btreemap_equal(&self.balances, &other.balances)
+            && is_stable_btreemap_equal(&self.used_addresses, &other.used_addresses)
+            && self.tx_index == other.tx_index
     }
 }
 
+/// The threshold at which time slicing kicks in.
+/// At the time of this writing it is equivalent to 20% of the maximum instructions limit.
+/// NOTE: We've reduced this limit from 4B to 1B in an effort to keep the FR stable.
+pub const MAX_INSTRUCTIONS_THRESHOLD: u64 = 1_000_000_000;
+
 // The default predicate to use for time-slicing.
 // Checks that we're not approaching the instructions limit.
 fn default_should_time_slice() -> Box<dyn FnMut() -> bool> {
-    // The threshold at which time slicing kicks in.
-    // At the time of this writing it is equivalent to 20% of the maximum instructions limit.
-    // NOTE: We've reduced this limit from 4B to 1B in an effort to keep the FR stable.
-    const MAX_INSTRUCTIONS_THRESHOLD: u64 = 1_000_000_000;
-
     // NOTE: We're using `inc_performance_counter` here to also increment the mock performance
     // counter in the unit tests.
     Box::new(|| inc_performance_counter() >= MAX_INSTRUCTIONS_THRESHOLD)
 }
 
+/// Returns a predicate for time-slicing that checks whether the instructions executed since the
+/// predicate was created, as reported by the given runtime, have reached `instruction_budget`.
+pub fn time_slice_predicate<R: Runtime + 'static>(
+    runtime: R,
+    instruction_budget: u64,
+) -> Box<dyn FnMut() -> bool> {
+    let start = runtime.performance_counter();
+    Box::new(move || runtime.performance_counter().saturating_sub(start) >= instruction_budget)
+}
+
 #[cfg(test)]
 mod test {
+    use super::utxos::MemoryUtxoStore;
     use super::*;
     use crate::runtime;
-    use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use crate::test_utils::{
+        random_address_of_each_type, random_p2pkh_address, BlockBuilder, TransactionBuilder,
+    };
     use crate::{address_utxoset::AddressUtxoSet, unstable_blocks::UnstableBlocks};
     use bitcoin::blockdata::{opcodes::all::OP_RETURN, script::Builder};
     use ic_btc_interface::Network;
@@ -608,6 +871,139 @@ mod test {
         }
     }
 
+    #[test]
+    fn incremental_utxo_set_hash_matches_full_recomputation() {
+        let network = Network::Regtest;
+        let mut utxo = UtxoSet::new(network);
+        assert_eq!(utxo.utxo_set_hash, Some([0; 32]));
+
+        let mut outpoints = vec![];
+        for i in 0..20 {
+            let tx = TransactionBuilder::coinbase()
+                .with_output(&random_p2pkh_address(network), 1_000 + i)
+                .with_output(&random_p2pkh_address(network), 2_000 + i)
+                .build();
+            ingest_tx(&mut utxo, &tx);
+            outpoints.push(OutPoint::new(tx.txid(), 0));
+            outpoints.push(OutPoint::new(tx.txid(), 1));
+            assert_eq!(utxo.utxo_set_hash(), Some(utxo.compute_utxo_set_hash()));
+        }
+
+        // Spend every third UTXO.
+        for (i, outpoint) in outpoints.into_iter().enumerate().step_by(3) {
+            let tx = TransactionBuilder::spending(
+                outpoint,
+                &random_p2pkh_address(network),
+                i as u64 + 1,
+            )
+            .build();
+            ingest_tx(&mut utxo, &tx);
+            assert_eq!(utxo.utxo_set_hash(), Some(utxo.compute_utxo_set_hash()));
+        }
+
+        // The incremental hash was maintained throughout, rather than recomputed.
+        assert!(utxo.utxo_set_hash.is_some());
+        assert_ne!(utxo.utxo_set_hash(), Some([0; 32]));
+
+        // A UTXO set that was loaded without the hash rebuilds it in time-sliced steps.
+        let hash = utxo.compute_utxo_set_hash();
+        utxo.utxo_set_hash = None;
+        assert_eq!(utxo.utxo_set_hash(), None);
+        utxo.set_should_time_slice(Box::new(|| true));
+        let mut num_steps = 1;
+        while utxo.rebuild_utxo_set_hash() == Slicing::Paused(()) {
+            assert_eq!(utxo.utxo_set_hash(), None);
+            num_steps += 1;
+        }
+        assert!(num_steps > 1);
+        assert_eq!(utxo.utxo_set_hash(), Some(hash));
+    }
+
+    // Ingests the given blocks into the UTXO set, returning its hash, its UTXOs, and the balance
+    // and outpoints of each of the given addresses.
+    #[allow(clippy::type_complexity)]
+    fn ingest_blocks<S: UtxoStore>(
+        mut utxo_set: UtxoSet<S>,
+        blocks: &[Block],
+        addresses: &[Address],
+    ) -> (
+        Option<[u8; 32]>,
+        Vec<(OutPoint, (TxOut, Height))>,
+        Vec<(Satoshi, Vec<OutPoint>)>,
+    ) {
+        for block in blocks {
+            let mut res = utxo_set.ingest_block(block.clone());
+            while let Slicing::Paused(()) = res {
+                res = utxo_set.ingest_block_continue().unwrap();
+            }
+        }
+        assert_eq!(
+            utxo_set.utxo_set_hash(),
+            Some(utxo_set.compute_utxo_set_hash())
+        );
+
+        let mut utxos: Vec<_> = utxo_set.utxos.iter().collect();
+        utxos.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(utxo_set.utxos_len(), utxos.len() as u64);
+
+        let address_utxos = addresses
+            .iter()
+            .map(|address| {
+                (
+                    utxo_set.get_balance(address),
+                    utxo_set.get_address_outpoints(address, &None).collect(),
+                )
+            })
+            .collect();
+
+        (utxo_set.utxo_set_hash(), utxos, address_utxos)
+    }
+
+    #[test]
+    fn stable_and_memory_stores_produce_identical_utxo_sets() {
+        let network = Network::Regtest;
+        let addresses = random_address_of_each_type(network);
+
+        let mut coinbase_tx = TransactionBuilder::coinbase();
+        for (i, address) in addresses.iter().enumerate() {
+            coinbase_tx = coinbase_tx
+                .with_output(address, 1_000 + i as u64)
+                .with_output(address, 2_000 + i as u64);
+        }
+        let coinbase_tx = coinbase_tx.build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_tx.clone())
+            .build();
+
+        // Spend every other output of the coinbase to the first address.
+        let mut block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(TransactionBuilder::coinbase().build());
+        for vout in (0..coinbase_tx.output().len() as u32).step_by(2) {
+            block_1 = block_1.with_transaction(
+                TransactionBuilder::spending(
+                    OutPoint::new(coinbase_tx.txid(), vout),
+                    &addresses[0],
+                    500 + vout as u64,
+                )
+                .build(),
+            );
+        }
+        let blocks = [block_0, block_1.build()];
+
+        let stable = ingest_blocks(UtxoSet::new(network), &blocks, &addresses);
+
+        // Both UTXO sets index the same addresses, so each needs a memory of its own.
+        crate::memory::reset_memory();
+        let memory = ingest_blocks(
+            UtxoSet::with_store(network, MemoryUtxoStore::default()),
+            &blocks,
+            &addresses,
+        );
+
+        assert_eq!(stable, memory);
+        assert_eq!(stable.1.len(), 2 * addresses.len() + 1);
+    }
+
     #[test]
     fn filter_provably_unspendable_utxos() {
         for network in [Network::Mainnet, Network::Regtest, Network::Testnet].iter() {
@@ -0,0 +1,196 @@
+use crate::memory::{get_tx_index_data_memory, get_tx_index_memory, Memory};
+use ic_btc_interface::Height;
+use ic_btc_types::{Block, Txid};
+use ic_stable_structures::{
+    storable::Blob, BoundedStorable, Memory as MemoryTrait, StableBTreeMap, Storable,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::convert::{TryFrom, TryInto};
+
+/// An index of the transactions ingested into the UTXO set, keyed by txid.
+///
+/// Raw transactions are appended to a dedicated memory, each prefixed with its length, and
+/// the index maps a txid to the height of its block and the offset of its entry.
+#[derive(Serialize, Deserialize)]
+pub struct TxIndex {
+    /// A map of a txid to the location of the transaction.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "init_locations")]
+    locations: StableBTreeMap<Blob<32>, TxLocation, Memory>,
+
+    /// The memory the raw transactions are appended to.
+    #[serde(skip, default = "get_tx_index_data_memory")]
+    data: Memory,
+
+    /// The offset in `data` at which the next transaction will be written.
+    next_offset: u64,
+
+    /// The height of the block that will be indexed next.
+    next_height: Height,
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        Self {
+            locations: init_locations(),
+            data: get_tx_index_data_memory(),
+            next_offset: 0,
+            next_height: 0,
+        }
+    }
+
+    /// Indexes all the transactions of the given block.
+    ///
+    /// Blocks below the next height are already indexed, e.g. when stable blocks are re-ingested
+    /// by `State::reindex_from_headers`, and are skipped so that their transactions aren't
+    /// appended to the data memory again.
+    pub fn insert_block(&mut self, block: &Block, height: Height) {
+        if height < self.next_height {
+            return;
+        }
+        self.next_height = height + 1;
+
+        for (tx, raw_tx) in block
+            .txdata()
+            .iter()
+            .zip(block.internal_bitcoin_block().txdata.iter())
+        {
+            let tx_bytes = bitcoin::consensus::serialize(raw_tx);
+            let offset = self.next_offset;
+            crate::memory::write(&self.data, offset, &(tx_bytes.len() as u32).to_le_bytes());
+            crate::memory::write(&self.data, offset + 4, &tx_bytes);
+            self.next_offset = offset + 4 + tx_bytes.len() as u64;
+
+            // NOTE: In the case of duplicate txids, the later transaction takes precedence.
+            self.locations
+                .insert(txid_key(&tx.txid()), TxLocation { height, offset });
+        }
+    }
+
+    /// Returns the raw transaction with the given txid along with the height of its block,
+    /// or `None` if the transaction isn't indexed.
+    pub fn get(&self, txid: &Txid) -> Option<(Vec<u8>, Height)> {
+        let TxLocation { height, offset } = self.locations.get(&txid_key(txid))?;
+
+        let mut len_bytes = [0; 4];
+        self.data.read(offset, &mut len_bytes);
+        let mut tx_bytes = vec![0; u32::from_le_bytes(len_bytes) as usize];
+        self.data.read(offset + 4, &mut tx_bytes);
+
+        Some((tx_bytes, height))
+    }
+}
+
+impl Default for TxIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// NOTE: `PartialEq` is only available in tests as it would be impractically
+// expensive in production.
+#[cfg(test)]
+impl PartialEq for TxIndex {
+    fn eq(&self, other: &Self) -> bool {
+        use crate::test_utils::is_stable_btreemap_equal;
+        self.next_offset == other.next_offset
+            && self.next_height == other.next_height
+            && is_stable_btreemap_equal(&self.locations, &other.locations)
+    }
+}
+
+/// The location of an indexed transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TxLocation {
+    height: Height,
+    offset: u64,
+}
+
+impl Storable for TxLocation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.height.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            height: Height::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for TxLocation {
+    const MAX_SIZE: u32 = 4 /* height bytes */ + 8 /* offset bytes */;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+fn txid_key(txid: &Txid) -> Blob<32> {
+    Blob::try_from(txid.as_bytes()).expect("txid must be 32 bytes")
+}
+
+fn init_locations() -> StableBTreeMap<Blob<32>, TxLocation, Memory> {
+    StableBTreeMap::init(get_tx_index_memory())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn indexed_transactions_can_be_retrieved() {
+        let address = random_p2pkh_address(Network::Regtest);
+        let coinbase_1 = TransactionBuilder::coinbase()
+            .with_output(&address, 1000)
+            .build();
+        let block_1 = BlockBuilder::genesis()
+            .with_transaction(coinbase_1.clone())
+            .build();
+
+        let coinbase_2 = TransactionBuilder::coinbase()
+            .with_output(&address, 2000)
+            .build();
+        let tx = TransactionBuilder::new()
+            .with_input(ic_btc_types::OutPoint::new(coinbase_1.txid(), 0))
+            .with_output(&address, 500)
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(coinbase_2.clone())
+            .with_transaction(tx.clone())
+            .build();
+
+        let mut tx_index = TxIndex::new();
+        tx_index.insert_block(&block_1, 0);
+        tx_index.insert_block(&block_2, 1);
+
+        assert_eq!(tx_index.locations.len(), 3);
+        for (tx, height) in [(coinbase_1, 0), (coinbase_2, 1), (tx, 1)] {
+            let expected = bitcoin::consensus::serialize(&bitcoin::Transaction::from(tx.clone()));
+            assert_eq!(tx_index.get(&tx.txid()), Some((expected, height)));
+        }
+
+        let unknown_txid = Txid::from(vec![1; 32]);
+        assert_eq!(tx_index.get(&unknown_txid), None);
+    }
+
+    #[test]
+    fn reindexed_blocks_are_not_appended_again() {
+        let block_1 = BlockBuilder::genesis().build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+
+        let mut tx_index = TxIndex::new();
+        tx_index.insert_block(&block_1, 0);
+        tx_index.insert_block(&block_2, 1);
+        let next_offset = tx_index.next_offset;
+
+        // Re-ingesting the blocks leaves the index unchanged.
+        tx_index.insert_block(&block_1, 0);
+        tx_index.insert_block(&block_2, 1);
+        assert_eq!(tx_index.next_offset, next_offset);
+        assert_eq!(tx_index.locations.len(), 2);
+    }
+}
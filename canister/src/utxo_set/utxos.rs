@@ -5,12 +5,37 @@ use crate::{
 };
 use ic_btc_interface::Height;
 use ic_btc_types::OutPoint;
-#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
 use ic_stable_structures::{btreemap, Memory as MemoryTrait};
 use ic_stable_structures::{storable::Blob, StableBTreeMap, Storable as StableStructuresStorable};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::ops::Bound;
+
+/// A storage backend for UTXOs, keyed by their outpoint.
+pub trait UtxoStore {
+    /// Inserts a utxo into the store.
+    /// Returns true if there was a previous value for the key in the store, false otherwise.
+    fn insert(&mut self, key: OutPoint, value: (TxOut, Height)) -> bool;
+
+    /// Returns the value associated with the given outpoint if it exists.
+    fn get(&self, key: &OutPoint) -> Option<(TxOut, Height)>;
+
+    /// Removes a key from the store, returning the previous value at the key if it exists.
+    fn remove(&mut self, key: &OutPoint) -> Option<(TxOut, Height)>;
+
+    /// Gets an iterator over the entries of the store, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (OutPoint, (TxOut, Height))> + '_>;
+
+    /// Returns the number of entries in the store.
+    fn len(&self) -> u64;
+
+    /// Returns true if the store has no entries.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
 
 /// A key-value store for UTXOs (unspent transaction outputs).
 ///
@@ -142,11 +167,66 @@ impl Utxos {
 
     /// Gets an iterator over the entries of the map.
     /// NOTE: The entries are not guaranteed to be sorted in any particular way.
-    #[cfg(test)]
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn iter(&self) -> Iter<Memory> {
         Iter::new(self)
     }
 
+    /// Gets an iterator over the entries of the map that come after the given position, along
+    /// with their size classes, so that an iteration can be resumed across calls.
+    ///
+    /// The entries are iterated over by size class and then by key, so that an entry's size
+    /// class and key are the position to resume from after it. Entries inserted behind the
+    /// position aren't returned.
+    pub fn iter_after(
+        &self,
+        position: Option<(SizeClass, OutPoint)>,
+    ) -> impl Iterator<Item = (SizeClass, OutPoint, (TxOut, Height))> + '_ {
+        // The lower bound of the keys to iterate over in each size class, or `None` if the size
+        // class has already been iterated over.
+        let lower_bound = |size_class: SizeClass| match &position {
+            None => Some(Bound::Unbounded),
+            Some((position_class, key)) if *position_class == size_class => {
+                Some(Bound::Excluded(key.clone()))
+            }
+            Some((position_class, _)) if *position_class < size_class => Some(Bound::Unbounded),
+            Some(_) => None,
+        };
+        let to_blob = |bound: Bound<OutPoint>| match bound {
+            Bound::Excluded(key) => {
+                Bound::Excluded(Blob::try_from(key.to_bytes().as_ref()).unwrap())
+            }
+            _ => Bound::Unbounded,
+        };
+
+        let small = lower_bound(SizeClass::Small)
+            .into_iter()
+            .flat_map(move |bound| self.small_utxos.range((to_blob(bound), Bound::Unbounded)))
+            .map(|(key_bytes, value_bytes)| {
+                (
+                    SizeClass::Small,
+                    OutPoint::from_bytes(std::borrow::Cow::Borrowed(key_bytes.as_slice())),
+                    <(TxOut, Height)>::from_bytes(value_bytes.as_slice().to_vec()),
+                )
+            });
+        let medium = lower_bound(SizeClass::Medium)
+            .into_iter()
+            .flat_map(move |bound| self.medium_utxos.range((to_blob(bound), Bound::Unbounded)))
+            .map(|(key_bytes, value_bytes)| {
+                (
+                    SizeClass::Medium,
+                    OutPoint::from_bytes(std::borrow::Cow::Borrowed(key_bytes.as_slice())),
+                    <(TxOut, Height)>::from_bytes(value_bytes.as_slice().to_vec()),
+                )
+            });
+        let large = lower_bound(SizeClass::Large)
+            .into_iter()
+            .flat_map(move |bound| self.large_utxos.range((bound, Bound::Unbounded)))
+            .map(|(key, value)| (SizeClass::Large, key.clone(), value.clone()));
+
+        small.chain(medium).chain(large)
+    }
+
     pub fn len(&self) -> u64 {
         self.large_utxos.len() as u64 + self.small_utxos.len() + self.medium_utxos.len()
     }
@@ -157,8 +237,69 @@ impl Utxos {
     }
 }
 
-/// An iterator over the entries in [`Utxos`].
+impl UtxoStore for Utxos {
+    fn insert(&mut self, key: OutPoint, value: (TxOut, Height)) -> bool {
+        Utxos::insert(self, key, value)
+    }
+
+    fn get(&self, key: &OutPoint) -> Option<(TxOut, Height)> {
+        Utxos::get(self, key)
+    }
+
+    fn remove(&mut self, key: &OutPoint) -> Option<(TxOut, Height)> {
+        Utxos::remove(self, key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (OutPoint, (TxOut, Height))> + '_> {
+        Box::new(
+            self.iter_after(None)
+                .map(|(_, outpoint, tx_out_and_height)| (outpoint, tx_out_and_height)),
+        )
+    }
+
+    fn len(&self) -> u64 {
+        Utxos::len(self)
+    }
+}
+
+/// A `UtxoStore` held entirely in heap memory, for fast unit tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryUtxoStore(BTreeMap<OutPoint, (TxOut, Height)>);
+
 #[cfg(test)]
+impl UtxoStore for MemoryUtxoStore {
+    fn insert(&mut self, key: OutPoint, value: (TxOut, Height)) -> bool {
+        self.0.insert(key, value).is_some()
+    }
+
+    fn get(&self, key: &OutPoint) -> Option<(TxOut, Height)> {
+        self.0.get(key).cloned()
+    }
+
+    fn remove(&mut self, key: &OutPoint) -> Option<(TxOut, Height)> {
+        self.0.remove(key)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (OutPoint, (TxOut, Height))> + '_> {
+        Box::new(self.0.iter().map(|(k, v)| (k.clone(), v.clone())))
+    }
+
+    fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+}
+
+/// The size classes of the UTXOs in [`Utxos`], in the order in which they're iterated over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SizeClass {
+    Small,
+    Medium,
+    Large,
+}
+
+/// An iterator over the entries in [`Utxos`].
+#[cfg(not(target_arch = "wasm32"))]
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub struct Iter<'a, M: MemoryTrait> {
     small_utxos_iter: btreemap::Iter<'a, Blob<UTXO_KEY_SIZE>, Blob<UTXO_VALUE_MAX_SIZE_SMALL>, M>,
@@ -166,7 +307,7 @@ pub struct Iter<'a, M: MemoryTrait> {
     large_utxos_iter: std::collections::btree_map::Iter<'a, OutPoint, (TxOut, Height)>,
 }
 
-#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
 impl<'a> Iter<'a, Memory> {
     fn new(utxos: &'a Utxos) -> Self {
         Self {
@@ -177,7 +318,7 @@ impl<'a> Iter<'a, Memory> {
     }
 }
 
-#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
 impl<M: MemoryTrait + Clone> Iterator for Iter<'_, M> {
     type Item = (OutPoint, (TxOut, Height));
 
@@ -214,3 +355,82 @@ fn init_medium_utxos(
 ) -> StableBTreeMap<Blob<UTXO_KEY_SIZE>, Blob<UTXO_VALUE_MAX_SIZE_MEDIUM>, Memory> {
     StableBTreeMap::init(get_utxos_medium_memory())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_btc_types::Txid;
+
+    // Runs the same sequence of operations against the given store, returning the results of
+    // each operation and the final entries of the store.
+    fn run_scenario<S: UtxoStore>(
+        store: &mut S,
+    ) -> (Vec<String>, Vec<(OutPoint, (TxOut, Height))>) {
+        let mut results = vec![];
+
+        // Scripts of different sizes, to cover all the size classes of `Utxos`.
+        for (i, script_size) in [10, 100, 300].iter().enumerate() {
+            for vout in 0..3 {
+                let outpoint = OutPoint::new(Txid::from(vec![i as u8; 32]), vout);
+                let tx_out = TxOut {
+                    value: vout as u64,
+                    script_pubkey: vec![i as u8; *script_size],
+                };
+                results.push(format!("{:?}", store.insert(outpoint, (tx_out, i as u32))));
+            }
+        }
+
+        let outpoint = OutPoint::new(Txid::from(vec![1; 32]), 1);
+        results.push(format!("{:?}", store.get(&outpoint)));
+        results.push(format!("{:?}", store.remove(&outpoint)));
+        results.push(format!("{:?}", store.get(&outpoint)));
+        results.push(format!("{:?}", store.remove(&outpoint)));
+
+        // Overwriting an existing entry.
+        let outpoint = OutPoint::new(Txid::from(vec![0; 32]), 0);
+        let tx_out = TxOut {
+            value: 1_000,
+            script_pubkey: vec![0; 5],
+        };
+        results.push(format!("{:?}", store.insert(outpoint, (tx_out, 10))));
+        results.push(format!("{:?}", store.len()));
+
+        let mut entries: Vec<_> = store.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        (results, entries)
+    }
+
+    #[test]
+    fn stable_and_memory_stores_behave_identically() {
+        let stable_results = run_scenario(&mut Utxos::default());
+        let memory_results = run_scenario(&mut MemoryUtxoStore::default());
+        assert_eq!(stable_results, memory_results);
+        assert_eq!(stable_results.1.len(), 8);
+    }
+
+    #[test]
+    fn iteration_can_be_resumed_after_any_entry() {
+        let mut utxos = Utxos::default();
+        // Scripts of different sizes, to cover all the size classes of `Utxos`.
+        for (i, script_size) in [10, 100, 300].iter().enumerate() {
+            for vout in 0..3 {
+                let outpoint = OutPoint::new(Txid::from(vec![i as u8; 32]), vout);
+                let tx_out = TxOut {
+                    value: vout as u64,
+                    script_pubkey: vec![i as u8; *script_size],
+                };
+                utxos.insert(outpoint, (tx_out, i as u32));
+            }
+        }
+
+        // Resuming from each entry in turn yields all the entries once, in order.
+        let mut entries = vec![];
+        let mut position = None;
+        while let Some((size_class, outpoint, value)) = utxos.iter_after(position.clone()).next() {
+            entries.push((outpoint.clone(), value));
+            position = Some((size_class, outpoint));
+        }
+        assert_eq!(entries, utxos.iter().collect::<Vec<_>>());
+        assert_eq!(entries.len(), 9);
+    }
+}
@@ -1,6 +1,72 @@
 use crate::{blocktree::BlockDoesNotExtendTree, state::State, unstable_blocks};
 use bitcoin::BlockHeader;
+use ic_btc_interface::Height;
+use ic_btc_types::BlockHash;
 use ic_btc_validation::HeaderStore;
+use std::{cell::RefCell, collections::BTreeMap};
+
+// The maximum number of headers held by each map of a `HeaderCache`.
+const MAX_CACHED_HEADERS: usize = 1_000;
+
+/// A cache of the stable block headers read while validating headers, so that inserting a batch
+/// of blocks doesn't repeatedly read and decode the same headers from stable memory.
+///
+/// Stable headers don't change once inserted, so the cache is only cleared to bound its size.
+/// It's neither serialized nor taken into account when comparing states.
+#[derive(Default)]
+pub struct HeaderCache {
+    by_height: RefCell<BTreeMap<Height, BlockHeader>>,
+    by_hash: RefCell<BTreeMap<BlockHash, BlockHeader>>,
+}
+
+impl HeaderCache {
+    pub fn clear(&mut self) {
+        self.by_height.get_mut().clear();
+        self.by_hash.get_mut().clear();
+    }
+
+    // Returns the header at the given height, reading it with `read` if it isn't cached.
+    fn get_with_height(
+        &self,
+        height: Height,
+        read: impl FnOnce() -> Option<BlockHeader>,
+    ) -> Option<BlockHeader> {
+        get_or_read(&self.by_height, height, read)
+    }
+
+    // Returns the header with the given hash, reading it with `read` if it isn't cached.
+    fn get_with_block_hash(
+        &self,
+        hash: BlockHash,
+        read: impl FnOnce() -> Option<BlockHeader>,
+    ) -> Option<BlockHeader> {
+        get_or_read(&self.by_hash, hash, read)
+    }
+}
+
+impl PartialEq for HeaderCache {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+fn get_or_read<K: Ord>(
+    cache: &RefCell<BTreeMap<K, BlockHeader>>,
+    key: K,
+    read: impl FnOnce() -> Option<BlockHeader>,
+) -> Option<BlockHeader> {
+    if let Some(header) = cache.borrow().get(&key) {
+        return Some(*header);
+    }
+
+    let header = read()?;
+    let mut cache = cache.borrow_mut();
+    if cache.len() >= MAX_CACHED_HEADERS {
+        cache.clear();
+    }
+    cache.insert(key, header);
+    Some(header)
+}
 
 /// A structure passed to the validation crate to validate a specific block header.
 pub struct ValidationContext<'a> {
@@ -12,7 +78,10 @@ pub struct ValidationContext<'a> {
 
 impl<'a> ValidationContext<'a> {
     /// Initialize a `ValidationContext` for the given block header.
-    pub fn new(state: &'a State, header: &BlockHeader) -> Result<Self, BlockDoesNotExtendTree> {
+    pub fn new(
+        state: &'a State,
+        header: &BlockHeader,
+    ) -> Result<Self, BlockDoesNotExtendTree<BlockHash>> {
         // Retrieve the chain that the given header extends.
         // The given header must extend one of the unstable blocks.
         let prev_block_hash = header.prev_blockhash.into();
@@ -31,7 +100,7 @@ impl<'a> ValidationContext<'a> {
     pub fn new_with_next_block_headers(
         state: &'a State,
         header: &BlockHeader,
-    ) -> Result<Self, BlockDoesNotExtendTree> {
+    ) -> Result<Self, BlockDoesNotExtendTree<BlockHash>> {
         let prev_block_hash = header.prev_blockhash.into();
         let next_block_headers_chain = state
             .unstable_blocks
@@ -60,7 +129,11 @@ impl<'a> HeaderStore for ValidationContext<'a> {
         }
 
         // The header is in the stable store.
-        self.state.stable_block_headers.get_with_block_hash(&hash)
+        self.state
+            .header_cache
+            .get_with_block_hash(hash.clone(), || {
+                self.state.stable_block_headers.get_with_block_hash(&hash)
+            })
     }
 
     fn height(&self) -> u32 {
@@ -73,7 +146,9 @@ impl<'a> HeaderStore for ValidationContext<'a> {
         if height < self.state.utxos.next_height() {
             // The height requested is for a stable block.
             // Retrieve the block header from the stable block headers.
-            self.state.stable_block_headers.get_with_height(height)
+            self.state.header_cache.get_with_height(height, || {
+                self.state.stable_block_headers.get_with_height(height)
+            })
         } else if height <= self.height() {
             // The height requested is for an unstable block.
             // Retrieve the block header from the chain.
@@ -89,10 +164,11 @@ impl<'a> HeaderStore for ValidationContext<'a> {
 mod test {
     use super::*;
     use crate::{
-        state::{ingest_stable_blocks_into_utxoset, insert_block},
+        state::{ingest_stable_blocks_into_utxoset, insert_block, InsertBlockError},
         test_utils::{build_chain, BlockBuilder},
     };
     use ic_btc_interface::Network;
+    use ic_btc_validation::ValidateHeaderError;
     use proptest::prelude::*;
     use std::str::FromStr;
 
@@ -142,6 +218,46 @@ mod test {
         ));
     }
 
+    #[test]
+    fn header_cache_does_not_change_validation_results() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 30, 1);
+
+        // Inserts the blocks, along with a block with an old timestamp on top of each of them,
+        // and returns the resulting state along with the results of the insertions.
+        let insert_batch = |use_cache: bool| {
+            // Both states ingest the same blocks, so each needs a memory of its own.
+            crate::memory::reset_memory();
+            let mut state = State::new(2, network, blocks[0].clone());
+            let mut results = vec![];
+            for block in blocks[1..].iter() {
+                let old_block = BlockBuilder::with_prev_header(block.header())
+                    .with_time(0)
+                    .build();
+                for block in [block.clone(), old_block] {
+                    if !use_cache {
+                        state.header_cache.clear();
+                    }
+                    results.push(insert_block(&mut state, block));
+                    ingest_stable_blocks_into_utxoset(&mut state);
+                }
+            }
+            (state, results)
+        };
+
+        let (state_with_cache, results_with_cache) = insert_batch(true);
+        let (state_without_cache, results_without_cache) = insert_batch(false);
+
+        assert!(!state_with_cache.header_cache.by_hash.borrow().is_empty());
+        assert!(
+            results_with_cache.contains(&Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::HeaderIsOld
+            )))
+        );
+        assert_eq!(results_with_cache, results_without_cache);
+        assert!(state_with_cache == state_without_cache);
+    }
+
     proptest! {
         #![proptest_config(ProptestConfig::with_cases(10))]
         #[test]
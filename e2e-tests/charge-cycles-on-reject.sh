@@ -32,6 +32,8 @@ dfx deploy bitcoin --argument "(record {
     get_block_headers_base = 1;
     get_block_headers_cycles_per_ten_instructions = 1;
     get_block_headers_maximum = 1;
+    get_transaction = 1;
+    get_transaction_maximum = 1;
   };
 })"
 
@@ -124,4 +126,10 @@ RECORD="(record { start_height = 1; end_height = opt 0; network = variant { regt
 EXPECTED="StartHeightLargerThanEndHeight"
 check_charging "${METHOD}" "${RECORD}" "${EXPECTED}" 1
 
+#test bitcoin_get_transaction
+METHOD="bitcoin_get_transaction"
+RECORD="(record { txid = blob \"\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\\00\"; network = variant { regtest } })"
+EXPECTED="TxIndexDisabled"
+check_charging "${METHOD}" "${RECORD}" "${EXPECTED}" 1
+
 echo "SUCCESS"
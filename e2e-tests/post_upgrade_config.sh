@@ -26,6 +26,10 @@ fi
 sed -i.bak 's/service bitcoin : (init_config)/service bitcoin : (opt set_config_request)/' ./canister/candid.did
 
 # Upgrade and update the fees.
+#
+# The base fee of `get_current_fee_percentiles` is above its maximum. Such fees are rejected by
+# `set_config`, but the config given on upgrade isn't validated so that upgrades never fail on
+# a config that used to be accepted.
 FEES="record {
   get_current_fee_percentiles = 123 : nat;
   get_utxos_maximum = 0 : nat;
@@ -39,6 +43,8 @@ FEES="record {
   get_balance_maximum = 0 : nat;
   send_transaction_base = 0 : nat;
   get_block_headers_maximum = 0 : nat;
+  get_transaction = 0 : nat;
+  get_transaction_maximum = 0 : nat;
 }";
 
 dfx deploy --upgrade-unchanged bitcoin --argument "opt (record {
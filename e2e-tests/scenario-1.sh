@@ -181,6 +181,7 @@ ACTUAL_HEADERS=$(dfx canister call bitcoin bitcoin_get_block_headers '(record {
 EXPECTED_HEADERS='(
   record {
     tip_height = 5 : nat32;
+    chain_height = 5 : nat32;
     block_headers = vec {
       blob "\01\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\3b\a3\ed\fd\7a\7b\12\b2\7a\c7\2c\3e\67\76\8f\61\7f\c8\1b\c3\88\8a\51\32\3a\9f\b8\aa\4b\1e\5e\4a\da\e5\49\4d\ff\ff\7f\20\02\00\00\00";
       blob "\01\00\00\00\06\22\6e\46\11\1a\0b\59\ca\af\12\60\43\eb\5b\bf\28\c3\4f\3a\5e\33\2a\1f\c7\b2\b7\3c\f1\88\91\0f\8b\c1\34\a4\62\25\ea\ec\84\54\1e\8c\0f\61\25\5d\cb\d4\16\b4\82\fd\17\94\c1\f3\24\90\30\1e\77\c3\32\e8\49\4d\ff\ff\7f\20\01\00\00\00";
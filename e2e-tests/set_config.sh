@@ -32,4 +32,35 @@ if ! [[ $CONFIG == *"stability_threshold = 17"* ]]; then
   exit 1
 fi
 
+# Fees with a base fee above its maximum are rejected.
+if dfx canister call bitcoin set_config '(record {
+  stability_threshold = opt (18: nat);
+  fees = opt record {
+    get_current_fee_percentiles = 123 : nat;
+    get_utxos_maximum = 0 : nat;
+    get_block_headers_cycles_per_ten_instructions = 0 : nat;
+    get_current_fee_percentiles_maximum = 0 : nat;
+    send_transaction_per_byte = 0 : nat;
+    get_balance = 0 : nat;
+    get_utxos_cycles_per_ten_instructions = 0 : nat;
+    get_block_headers_base = 0 : nat;
+    get_utxos_base = 0 : nat;
+    get_balance_maximum = 0 : nat;
+    send_transaction_base = 0 : nat;
+    get_block_headers_maximum = 0 : nat;
+    get_transaction = 0 : nat;
+    get_transaction_maximum = 0 : nat;
+  };
+})'; then
+  echo "FAIL"
+  exit 1
+fi
+
+# Verify that none of the rejected config has been applied.
+CONFIG=$(dfx canister call bitcoin get_config --query)
+if ! [[ $CONFIG == *"stability_threshold = 17"* ]]; then
+  echo "FAIL"
+  exit 1
+fi
+
 echo "SUCCESS"
@@ -345,6 +345,7 @@ pub enum GetUtxosError {
     MinConfirmationsTooLarge { given: u32, max: u32 },
     UnknownTipBlockHash { tip_block_hash: BlockHash },
     MalformedPage { err: String },
+    TooManyUtxos { count: u64, max: u64 },
 }
 
 /// A request for getting the block headers from a given height.
@@ -356,9 +357,18 @@ pub struct GetBlockHeadersRequest {
 }
 
 /// The response returned for a request for getting the block headers from a given height.
+///
+/// The number of headers in a response is bounded, so a long range of headers is retrieved
+/// by requesting the headers from `tip_height + 1` until the requested end height is reached.
 #[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
 pub struct GetBlockHeadersResponse {
+    /// The height of the last header in `block_headers`.
     pub tip_height: Height,
+
+    /// The height of the tip of the main chain when the request was processed.
+    pub chain_height: Height,
+
+    /// The consensus-encoded headers, starting from the requested start height.
     pub block_headers: Vec<BlockHeader>,
 }
 
@@ -377,6 +387,10 @@ pub enum GetBlockHeadersError {
         start_height: Height,
         end_height: Height,
     },
+    HeadersPruned {
+        start_height: Height,
+        end_height: Height,
+    },
 }
 
 impl fmt::Display for GetBlockHeadersError {
@@ -410,6 +424,56 @@ impl fmt::Display for GetBlockHeadersError {
                     f,
                     "The requested start_height is larger than the requested end_height. start_height: {}, end_height: {}", start_height, end_height)
             }
+            Self::HeadersPruned {
+                start_height,
+                end_height,
+            } => {
+                write!(
+                    f,
+                    "Some of the requested block headers have been pruned. start_height: {}, end_height: {}",
+                    start_height, end_height
+                )
+            }
+        }
+    }
+}
+
+/// A request for getting a transaction by its txid.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq)]
+pub struct GetTransactionRequest {
+    pub txid: Txid,
+    pub network: NetworkInRequest,
+}
+
+/// The response returned for a request for getting a transaction by its txid.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub struct GetTransactionResponse {
+    /// The consensus-encoded transaction.
+    #[serde(with = "serde_bytes")]
+    pub transaction: Vec<u8>,
+
+    /// The number of confirmations of the block containing the transaction.
+    pub confirmations: u32,
+}
+
+/// Errors when processing a `get_transaction` request.
+#[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
+pub enum GetTransactionError {
+    /// The transaction index isn't enabled in the canister's config.
+    TxIndexDisabled,
+    /// The transaction isn't in the main chain or wasn't indexed.
+    TransactionNotFound,
+}
+
+impl fmt::Display for GetTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TxIndexDisabled => {
+                write!(f, "The transaction index is disabled.")
+            }
+            Self::TransactionNotFound => {
+                write!(f, "The transaction was not found.")
+            }
         }
     }
 }
@@ -443,6 +507,13 @@ impl fmt::Display for GetUtxosError {
             Self::MalformedPage { err } => {
                 write!(f, "The provided page is malformed {}", err)
             }
+            Self::TooManyUtxos { count, max } => {
+                write!(
+                    f,
+                    "The address has too many UTXOs: at least {}, max supported: {}.",
+                    count, max
+                )
+            }
         }
     }
 }
@@ -533,6 +604,26 @@ pub struct SetConfigRequest {
     /// If enabled, fee percentiles are only computed when requested.
     /// Otherwise, they are computed whenever we receive a new block.
     pub lazily_evaluate_fee_percentiles: Option<Flag>,
+
+    /// The maximum number of UTXOs an address can have for `get_utxos` to serve it.
+    pub max_utxos_per_query: Option<Option<u64>>,
+
+    /// If true, resumes syncing and ingesting blocks after ingestion was halted due to an
+    /// inconsistency.
+    pub resume_ingestion: Option<bool>,
+
+    /// The fee returned for every fee percentile when there are no fees to compute them from.
+    pub fallback_fee_per_byte: Option<Option<MillisatoshiPerByte>>,
+
+    /// The maximum number of `get_utxos` responses that are cached.
+    pub utxos_cache_capacity: Option<u32>,
+
+    /// The policy for which stable block headers are retained. All headers are retained if
+    /// set to `None`.
+    pub header_retention: Option<Option<HeaderRetention>>,
+
+    /// The canister from which blocks are retrieved.
+    pub blocks_source: Option<Principal>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
@@ -561,6 +652,14 @@ pub struct InitConfig {
     pub watchdog_canister: Option<Option<Principal>>,
     pub burn_cycles: Option<Flag>,
     pub lazily_evaluate_fee_percentiles: Option<Flag>,
+    pub max_utxos_per_query: Option<Option<u64>>,
+    pub utxos_cache_capacity: Option<u32>,
+    pub fallback_fee_per_byte: Option<Option<MillisatoshiPerByte>>,
+    pub header_retention: Option<Option<HeaderRetention>>,
+
+    /// Whether or not to index the transactions of ingested blocks by txid.
+    /// Can't be changed after init, as blocks that are already ingested wouldn't be indexed.
+    pub txindex: Option<Flag>,
 }
 
 /// The config of the canister.
@@ -598,6 +697,29 @@ pub struct Config {
     /// If enabled, fee percentiles are only computed when requested.
     /// Otherwise, they are computed whenever we receive a new block.
     pub lazily_evaluate_fee_percentiles: Flag,
+
+    /// The maximum number of UTXOs an address can have for `get_utxos` to serve it.
+    /// Requests for addresses exceeding it are rejected. There's no limit if not set.
+    pub max_utxos_per_query: Option<u64>,
+
+    /// The maximum number of `get_utxos` responses that are cached, keyed by the address, the
+    /// requested page and the main chain's tip. Responses with a minimum number of
+    /// confirmations aren't cached. The cache is disabled if zero.
+    pub utxos_cache_capacity: u32,
+
+    /// The fee returned for every fee percentile of `get_current_fee_percentiles` when there
+    /// are no fees to compute them from, e.g. on a regtest chain of empty blocks. If not set,
+    /// Bitcoin Core's default minimum relay fee of 1 satoshi per byte is returned, whatever
+    /// the network.
+    pub fallback_fee_per_byte: Option<MillisatoshiPerByte>,
+
+    /// The policy for which stable block headers are retained. All headers are retained if not
+    /// set. Pruned headers can no longer be returned by `get_block_headers`.
+    pub header_retention: Option<HeaderRetention>,
+
+    /// If enabled, the transactions of ingested blocks are indexed by txid so that
+    /// they can be retrieved with `get_transaction`. Can only be set at init.
+    pub txindex: Flag,
 }
 
 impl From<InitConfig> for Config {
@@ -644,6 +766,26 @@ impl From<InitConfig> for Config {
             config.lazily_evaluate_fee_percentiles = lazily_evaluate_fee_percentiles;
         }
 
+        if let Some(max_utxos_per_query) = init_config.max_utxos_per_query {
+            config.max_utxos_per_query = max_utxos_per_query;
+        }
+
+        if let Some(utxos_cache_capacity) = init_config.utxos_cache_capacity {
+            config.utxos_cache_capacity = utxos_cache_capacity;
+        }
+
+        if let Some(fallback_fee_per_byte) = init_config.fallback_fee_per_byte {
+            config.fallback_fee_per_byte = fallback_fee_per_byte;
+        }
+
+        if let Some(header_retention) = init_config.header_retention {
+            config.header_retention = header_retention;
+        }
+
+        if let Some(txindex) = init_config.txindex {
+            config.txindex = txindex;
+        }
+
         config
     }
 }
@@ -661,10 +803,27 @@ impl Default for Config {
             watchdog_canister: None,
             burn_cycles: Flag::Disabled,
             lazily_evaluate_fee_percentiles: Flag::Disabled,
+            max_utxos_per_query: None,
+            utxos_cache_capacity: 0,
+            fallback_fee_per_byte: None,
+            header_retention: None,
+            txindex: Flag::Disabled,
         }
     }
 }
 
+/// A policy for which stable block headers the canister retains.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct HeaderRetention {
+    /// The number of most recent headers to retain. Must be at least the difficulty adjustment
+    /// interval, as validating new headers requires them.
+    pub depth: u32,
+
+    /// Headers at heights that are a multiple of this interval are retained as checkpoints,
+    /// regardless of their depth. Must be non-zero.
+    pub checkpoint_interval: u32,
+}
+
 #[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
 pub struct Fees {
     /// The base fee to charge for all `get_utxos` requests.
@@ -709,6 +868,15 @@ pub struct Fees {
     /// The maximum amount of cycles that can be charged in a `get_block_headers` request.
     /// A request must send at least this amount for it to be accepted.
     pub get_block_headers_maximum: u128,
+
+    #[serde(default)]
+    /// The flat fee to charge for a `get_transaction` request.
+    pub get_transaction: u128,
+
+    #[serde(default)]
+    /// The maximum amount of cycles that can be charged in a `get_transaction` request.
+    /// A request must send at least this amount for it to be accepted.
+    pub get_transaction_maximum: u128,
 }
 
 #[cfg(test)]
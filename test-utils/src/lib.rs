@@ -36,6 +36,8 @@ fn coinbase_input() -> TxIn {
 pub struct BlockBuilder {
     prev_header: Option<BlockHeader>,
     transactions: Vec<Transaction>,
+    target: Option<Uint256>,
+    time: Option<u32>,
 }
 
 impl BlockBuilder {
@@ -43,6 +45,8 @@ impl BlockBuilder {
         Self {
             prev_header: None,
             transactions: vec![],
+            target: None,
+            time: None,
         }
     }
 
@@ -50,6 +54,8 @@ impl BlockBuilder {
         Self {
             prev_header: Some(prev_header),
             transactions: vec![],
+            target: None,
+            time: None,
         }
     }
 
@@ -58,6 +64,19 @@ impl BlockBuilder {
         self
     }
 
+    /// Sets the target of the block. If not set, the target of the previous block is used.
+    pub fn with_target(mut self, target: Uint256) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Sets the timestamp of the block. If not set, the block is timestamped 10 minutes after
+    /// the previous block, or at time zero if it's a genesis block.
+    pub fn with_time(mut self, time: u32) -> Self {
+        self.time = Some(time);
+        self
+    }
+
     pub fn build(self) -> BitcoinBlock {
         let txdata = if self.transactions.is_empty() {
             // Create a random coinbase transaction.
@@ -72,8 +91,8 @@ impl BlockBuilder {
         let merkle_root = TxMerkleNode::from_hash(merkle_root);
 
         let header = match self.prev_header {
-            Some(prev_header) => header(&prev_header, merkle_root),
-            None => genesis(merkle_root),
+            Some(prev_header) => header(&prev_header, merkle_root, self.target, self.time),
+            None => genesis(merkle_root, self.target, self.time),
         };
 
         BitcoinBlock { header, txdata }
@@ -119,18 +138,18 @@ pub fn build_regtest_chain(num_blocks: u32, num_transactions_per_block: u32) ->
     blocks
 }
 
-fn genesis(merkle_root: TxMerkleNode) -> BlockHeader {
-    let target = Uint256([
+fn genesis(merkle_root: TxMerkleNode, target: Option<Uint256>, time: Option<u32>) -> BlockHeader {
+    let target = target.unwrap_or(Uint256([
         0xffffffffffffffffu64,
         0xffffffffffffffffu64,
         0xffffffffffffffffu64,
         0x7fffffffffffffffu64,
-    ]);
+    ]));
     let bits = BlockHeader::compact_target_from_u256(&target);
 
     let mut header = BlockHeader {
         version: 1,
-        time: 0,
+        time: time.unwrap_or(0),
         nonce: 0,
         bits,
         merkle_root,
@@ -142,6 +161,7 @@ fn genesis(merkle_root: TxMerkleNode) -> BlockHeader {
 }
 
 pub struct TransactionBuilder {
+    version: i32,
     input: Vec<TxIn>,
     output: Vec<TxOut>,
     lock_time: u32,
@@ -150,6 +170,7 @@ pub struct TransactionBuilder {
 impl TransactionBuilder {
     pub fn new() -> Self {
         Self {
+            version: 1,
             input: vec![],
             output: vec![],
             lock_time: 0,
@@ -158,6 +179,7 @@ impl TransactionBuilder {
 
     pub fn coinbase() -> Self {
         Self {
+            version: 1,
             input: vec![coinbase_input()],
             output: vec![],
             lock_time: 0,
@@ -193,6 +215,20 @@ impl TransactionBuilder {
         self
     }
 
+    pub fn with_version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets the sequence number of the most recently added input.
+    pub fn with_input_sequence(mut self, sequence: u32) -> Self {
+        self.input
+            .last_mut()
+            .expect("A call to `with_input_sequence` requires an input")
+            .sequence = sequence;
+        self
+    }
+
     pub fn build(self) -> Transaction {
         let input = if self.input.is_empty() {
             // Default to coinbase if no inputs provided.
@@ -211,7 +247,7 @@ impl TransactionBuilder {
         };
 
         Transaction {
-            version: 1,
+            version: self.version,
             lock_time: self.lock_time,
             input,
             output,
@@ -225,9 +261,15 @@ impl Default for TransactionBuilder {
     }
 }
 
-fn header(prev_header: &BlockHeader, merkle_root: TxMerkleNode) -> BlockHeader {
-    let time = prev_header.time + 60 * 10; // 10 minutes.
-    let bits = BlockHeader::compact_target_from_u256(&prev_header.target());
+fn header(
+    prev_header: &BlockHeader,
+    merkle_root: TxMerkleNode,
+    target: Option<Uint256>,
+    time: Option<u32>,
+) -> BlockHeader {
+    let time = time.unwrap_or(prev_header.time + 60 * 10); // 10 minutes.
+    let bits =
+        BlockHeader::compact_target_from_u256(&target.unwrap_or_else(|| prev_header.target()));
 
     let mut header = BlockHeader {
         version: 1,
@@ -370,5 +412,25 @@ mod test {
             assert_eq!(tx.output.len(), 1);
             assert_eq!(tx.output[0].value, 50_0000_0000);
         }
+
+        #[test]
+        fn with_version_and_input_sequence() {
+            let address = random_p2pkh_address(Network::Regtest);
+            let coinbase_tx = TransactionBuilder::coinbase()
+                .with_output(&address, 1000)
+                .build();
+
+            let tx = TransactionBuilder::new()
+                .with_version(2)
+                .with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0), None)
+                .with_input_sequence(0xfffffffd)
+                .with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 1), None)
+                .build();
+            assert_eq!(tx.version, 2);
+            assert_eq!(tx.input.len(), 2);
+            assert_eq!(tx.input[0].sequence, 0xfffffffd);
+            // Inputs without an explicit sequence keep the default.
+            assert_eq!(tx.input[1].sequence, 0xffffffff);
+        }
     }
 }
@@ -69,8 +69,36 @@ pub fn pow_limit_bits(network: &Network) -> u32 {
     }
 }
 
+/// The number of blocks between block subsidy halvings on mainnet, testnet and signet.
+const SUBSIDY_HALVING_INTERVAL: BlockHeight = 210_000;
+
+/// The number of blocks between block subsidy halvings on regtest.
+const REGTEST_SUBSIDY_HALVING_INTERVAL: BlockHeight = 150;
+
+/// The block subsidy before any halvings (50 BTC), in satoshi.
+const INITIAL_BLOCK_SUBSIDY: u64 = 50 * 100_000_000;
+
+/// Returns the block subsidy in satoshi at the given height, accounting for halvings.
+/// Transaction fees aren't included.
+pub fn block_reward(height: BlockHeight, network: &Network) -> u64 {
+    let halving_interval = match network {
+        Network::Bitcoin | Network::Testnet | Network::Signet => SUBSIDY_HALVING_INTERVAL,
+        Network::Regtest => REGTEST_SUBSIDY_HALVING_INTERVAL,
+    };
+
+    let halvings = height / halving_interval;
+    if halvings >= u64::BITS {
+        // The subsidy is shifted away completely.
+        return 0;
+    }
+
+    INITIAL_BLOCK_SUBSIDY >> halvings
+}
+
 #[cfg(test)]
 pub mod test {
+    use super::*;
+
     /// Mainnet 000000000000000000063108ecc1f03f7fd1481eb20f97307d532a612bc97f04
     pub const MAINNET_HEADER_586656: &str ="00008020cff0e07ab39db0f31d4ded81ba2339173155b9c57839110000000000000000007a2d75dce5981ec421a54df706d3d407f66dc9170f1e0d6e48ed1e8a1cad7724e9ed365d083a1f17bc43b10a";
     /// Mainnet 0000000000000000000d37dfef7fe1c7bd22c893dbe4a94272c8cf556e40be99
@@ -84,4 +112,32 @@ pub mod test {
     pub const TESTNET_HEADER_2132555: &str = "004000200e1ff99438666c67c649def743fb82117537c2017bcc6ad617000000000000007fa40cf82bf224909e3174281a57af2eb3a4a2a961d33f50ec0772c1221c9e61ddfdc061ffff001a64526636";
     /// Testnet 00000000383cd7fff4692410ccd9bd6201790043bb41b93bacb21e9b85620767
     pub const TESTNET_HEADER_2132556: &str = "00000020974f55e77dff100bc252a01aa7b00d16736c6e04a091b03be200000000000000c44f2d69fc200c4a2211885000b6b67512f42c1bec550f3754e103b6c4046e05a202c161ffff001d09ec1bc4";
+
+    #[test]
+    fn block_reward_genesis() {
+        for network in [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            assert_eq!(block_reward(0, &network), 50_0000_0000);
+        }
+    }
+
+    #[test]
+    fn block_reward_first_halving() {
+        assert_eq!(block_reward(209_999, &Network::Bitcoin), 50_0000_0000);
+        assert_eq!(block_reward(210_000, &Network::Bitcoin), 25_0000_0000);
+
+        assert_eq!(block_reward(149, &Network::Regtest), 50_0000_0000);
+        assert_eq!(block_reward(150, &Network::Regtest), 25_0000_0000);
+    }
+
+    #[test]
+    fn block_reward_far_future() {
+        assert_eq!(block_reward(64 * 210_000, &Network::Bitcoin), 0);
+        assert_eq!(block_reward(BlockHeight::MAX, &Network::Bitcoin), 0);
+        assert_eq!(block_reward(BlockHeight::MAX, &Network::Regtest), 0);
+    }
 }
@@ -53,8 +53,30 @@ pub trait HeaderStore {
     }
 }
 
+/// Validates the proof of work of a header against its own target, which must not be above
+/// the maximum target of the given `network`.
+///
+/// Unlike `validate_header`, this doesn't require the header's predecessors, and so doesn't
+/// check that the target is the one expected at the header's height.
+pub fn validate_pow(network: &Network, header: &BlockHeader) -> Result<(), ValidateHeaderError> {
+    let header_target = header.target();
+    if header_target > max_target(network) {
+        return Err(ValidateHeaderError::TargetDifficultyAboveMax);
+    }
+
+    if header.validate_pow(&header_target).is_err() {
+        return Err(ValidateHeaderError::InvalidPoWForHeaderTarget);
+    }
+
+    Ok(())
+}
+
 /// Validates a header. If a failure occurs, a
 /// [ValidateHeaderError](ValidateHeaderError) will be returned.
+///
+/// The difficulty checks are never skipped. Instead, the rules of the given `network` are
+/// applied, e.g. regtest has a much higher maximum target and no difficulty retargeting, so
+/// regtest headers are only valid when validated against `Network::Regtest`.
 pub fn validate_header(
     network: &Network,
     store: &impl HeaderStore,
@@ -71,14 +93,7 @@ pub fn validate_header(
 
     is_timestamp_valid(store, header, current_time)?;
 
-    let header_target = header.target();
-    if header_target > max_target(network) {
-        return Err(ValidateHeaderError::TargetDifficultyAboveMax);
-    }
-
-    if header.validate_pow(&header_target).is_err() {
-        return Err(ValidateHeaderError::InvalidPoWForHeaderTarget);
-    }
+    validate_pow(network, header)?;
 
     let target = get_next_target(network, store, &prev_header, prev_height, header.time);
     if let Err(err) = header.validate_pow(&target) {
@@ -617,6 +632,26 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_regtest_header_is_only_valid_on_regtest() {
+        let pow_regtest = pow_limit_bits(&Network::Regtest);
+        let h0 = genesis_header(pow_regtest);
+        let mut h1 = next_block_header(h0, pow_regtest);
+        while h1.validate_pow(&h1.target()).is_err() {
+            h1.nonce += 1;
+        }
+        let store = SimpleHeaderStore::new(h0, 0);
+
+        assert_eq!(
+            validate_header(&Network::Regtest, &store, &h1, MOCK_CURRENT_TIME),
+            Ok(())
+        );
+        assert_eq!(
+            validate_header(&Network::Bitcoin, &store, &h1, MOCK_CURRENT_TIME),
+            Err(ValidateHeaderError::TargetDifficultyAboveMax)
+        );
+    }
+
     fn test_next_targets(network: Network, headers_path: &str, up_to_height: usize) {
         use bitcoin::consensus::Decodable;
         use std::io::BufRead;
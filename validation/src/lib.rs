@@ -1,7 +1,7 @@
 mod constants;
 mod header;
 
-pub use crate::constants::max_target;
-pub use crate::header::{validate_header, HeaderStore, ValidateHeaderError};
+pub use crate::constants::{block_reward, max_target, DIFFICULTY_ADJUSTMENT_INTERVAL};
+pub use crate::header::{validate_header, validate_pow, HeaderStore, ValidateHeaderError};
 
 type BlockHeight = u32;